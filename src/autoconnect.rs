@@ -0,0 +1,456 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use {
+    Destination,
+    Destinations,
+    Endpoint,
+    Error,
+    EventList,
+    InputPort,
+    NotificationDispatcher,
+    NotificationFilter,
+    NotificationKind,
+    Object,
+    OutputPort,
+    PacketList,
+    Properties,
+    PropertyGetter,
+    Source,
+    Sources,
+    Subscription,
+    lock,
+};
+use backend::sys::MIDIEndpointRef;
+
+/// Selects the endpoints an `AutoConnector` keeps connected.
+///
+pub enum EndpointMatcher {
+    /// The endpoint with this unique ID.
+    UniqueId(u32),
+    /// The endpoint with this name or display name.
+    Name(String),
+    /// The endpoints for which the function returns `true`.
+    Predicate(Box<Fn(&Endpoint) -> bool + Send>),
+}
+
+impl EndpointMatcher {
+    /// Match the endpoint with this unique ID.
+    ///
+    pub fn unique_id(unique_id: u32) -> EndpointMatcher {
+        EndpointMatcher::UniqueId(unique_id)
+    }
+
+    /// Match the endpoint with this name or display name.
+    ///
+    pub fn name(name: &str) -> EndpointMatcher {
+        EndpointMatcher::Name(name.to_string())
+    }
+
+    /// Match the endpoints for which the function returns `true`.
+    ///
+    pub fn predicate<F>(predicate: F) -> EndpointMatcher
+        where F: Fn(&Endpoint) -> bool + Send + 'static
+    {
+        EndpointMatcher::Predicate(Box::new(predicate))
+    }
+
+    /// Check whether an endpoint is selected by the matcher.
+    ///
+    pub fn matches(&self, endpoint: &Endpoint) -> bool {
+        match *self {
+            EndpointMatcher::UniqueId(unique_id) => endpoint.unique_id() == Some(unique_id),
+            EndpointMatcher::Name(ref name) => {
+                endpoint.name().as_ref() == Some(name) || endpoint.display_name().as_ref() == Some(name)
+            },
+            EndpointMatcher::Predicate(ref predicate) => predicate(endpoint),
+        }
+    }
+}
+
+impl fmt::Debug for EndpointMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EndpointMatcher::UniqueId(unique_id) => f.debug_tuple("UniqueId").field(&unique_id).finish(),
+            EndpointMatcher::Name(ref name) => f.debug_tuple("Name").field(name).finish(),
+            EndpointMatcher::Predicate(_) => f.debug_tuple("Predicate").finish(),
+        }
+    }
+}
+
+/// The changes done by an `AutoConnector`, as they happen.
+///
+#[derive(Debug, PartialEq)]
+pub enum AutoConnectEvent {
+    /// A matching source appeared, or came back online, and it was connected to the input port.
+    SourceConnected(Source),
+    /// A connected source was removed or went offline, and it was disconnected from the input port.
+    SourceDisconnected(Source),
+    /// A matching source could not be connected to the input port. It will be tried again
+    /// with the next change in the setup.
+    ConnectFailed(Source, Error),
+    /// A matching destination appeared, or came back online, and the packets are sent to it.
+    DestinationFound(Destination),
+    /// A destination was removed or went offline, and the packets are not sent to it anymore.
+    DestinationLost(Destination),
+}
+
+// A source or destination wanted by the user, and the endpoint currently used for it
+struct Target {
+    matcher: EndpointMatcher,
+    endpoint: Option<MIDIEndpointRef>,
+}
+
+type EventCallback = Box<FnMut(&AutoConnectEvent) + Send>;
+
+struct State {
+    input_port: InputPort,
+    sources: Vec<Target>,
+    destinations: Vec<Target>,
+    // The events not reported yet, in the order they happened
+    pending: VecDeque<AutoConnectEvent>,
+    // Taken out while a thread is reporting the pending events to it
+    callback: Option<EventCallback>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+}
+
+/// Keeps an input port connected to some sources, and tracks some destinations to send packets to,
+/// while devices are plugged, unplugged, or go offline.
+///
+/// The wanted sources and destinations are described with an `EndpointMatcher`, instead of a
+/// `Source` or `Destination` object, which stops existing when a device is unplugged.
+/// The connector listens to the notifications of a client, through a `NotificationDispatcher`,
+/// and every time an object is added or removed, or a property changes (like going offline),
+/// it looks for the first online endpoint selected by each matcher, reconnects the input port,
+/// and retargets the packets sent with `AutoConnector::send`. The changes are reported to a callback as `AutoConnectEvent`s.
+///
+/// ```rust,no_run
+/// use coremidi::{AutoConnector, Client, EndpointMatcher, NotificationDispatcher, PacketBuffer};
+/// let dispatcher = NotificationDispatcher::new();
+/// let client = Client::new_with_dispatcher("example-client", &dispatcher).unwrap();
+/// let input_port = client.input_port("example-input", |packet_list| println!("{}", packet_list)).unwrap();
+/// let output_port = client.output_port("example-output").unwrap();
+///
+/// let connector = AutoConnector::new(&dispatcher, input_port, |event| println!("{:?}", event));
+/// connector.add_source(EndpointMatcher::name("USB MIDI Interface"));
+/// connector.add_destination(EndpointMatcher::name("USB MIDI Interface"));
+/// connector.send(&output_port, &PacketBuffer::new(0, &[0x90, 0x40, 0x7f])).unwrap();
+/// ```
+pub struct AutoConnector {
+    // Order is important, the subscription needs to be dropped first
+    _subscription: Subscription,
+    shared: Arc<Shared>,
+}
+
+impl AutoConnector {
+    /// Create a connector for the input port, which follows the notifications delivered by the dispatcher,
+    /// and reports the changes to the callback.
+    ///
+    /// The callback is called from the thread where the notifications are delivered, or from the
+    /// thread calling the methods of the connector that change the connections. It is called
+    /// without holding any lock, so it can use the connector, and the events caused meanwhile are
+    /// reported after it returns, in order.
+    ///
+    pub fn new<F>(dispatcher: &NotificationDispatcher, input_port: InputPort, callback: F) -> AutoConnector
+        where F: FnMut(&AutoConnectEvent) + Send + 'static
+    {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                input_port,
+                sources: Vec::new(),
+                destinations: Vec::new(),
+                pending: VecDeque::new(),
+                callback: Some(Box::new(callback)),
+            }),
+        });
+        let filter = NotificationFilter::new()
+            .kind(NotificationKind::ObjectAdded)
+            .kind(NotificationKind::ObjectRemoved)
+            .kind(NotificationKind::PropertyChanged);
+        let notified = shared.clone();
+        let subscription = dispatcher.subscribe(filter, move |_| notified.refresh());
+        AutoConnector { _subscription: subscription, shared }
+    }
+
+    /// Keep the input port connected to the first online source selected by the matcher.
+    ///
+    pub fn add_source(&self, matcher: EndpointMatcher) {
+        lock(&self.shared.state).sources.push(Target { matcher, endpoint: None });
+        self.shared.refresh();
+    }
+
+    /// Send the packets to the first online destination selected by the matcher.
+    ///
+    pub fn add_destination(&self, matcher: EndpointMatcher) {
+        lock(&self.shared.state).destinations.push(Target { matcher, endpoint: None });
+        self.shared.refresh();
+    }
+
+    /// Look for the wanted sources and destinations again, which is done automatically for every
+    /// change in the setup.
+    ///
+    pub fn refresh(&self) {
+        self.shared.refresh();
+    }
+
+    /// The sources currently connected to the input port.
+    ///
+    pub fn sources(&self) -> Vec<Source> {
+        lock(&self.shared.state).sources.iter()
+            .filter_map(|target| target.endpoint.map(source))
+            .collect()
+    }
+
+    /// The destinations the packets are currently sent to.
+    ///
+    pub fn destinations(&self) -> Vec<Destination> {
+        lock(&self.shared.state).destinations.iter()
+            .filter_map(|target| target.endpoint.map(destination))
+            .collect()
+    }
+
+    /// Send a list of packets to the current destinations through the output port.
+    /// The packets are dropped when no destination is available.
+    ///
+    pub fn send(&self, output_port: &OutputPort, packet_list: &PacketList) -> Result<(), Error> {
+        for destination in self.destinations() {
+            output_port.send(&destination, packet_list)?;
+        }
+        Ok(())
+    }
+
+    /// Send a list of Universal MIDI Packets to the current destinations through the output port.
+    /// The packets are dropped when no destination is available.
+    ///
+    pub fn send_event_list(&self, output_port: &OutputPort, event_list: &EventList) -> Result<(), Error> {
+        for destination in self.destinations() {
+            output_port.send_event_list(&destination, event_list)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for AutoConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AutoConnector")
+            .field("sources", &self.sources())
+            .field("destinations", &self.destinations())
+            .finish()
+    }
+}
+
+impl Shared {
+    fn refresh(&self) {
+        let mut callback = {
+            let mut state = lock(&self.state);
+            let mut events = Vec::new();
+            Self::refresh_sources(&mut state, &mut events);
+            Self::refresh_destinations(&mut state, &mut events);
+            state.pending.extend(events);
+            match state.callback.take() {
+                Some(callback) => callback,
+                // Another call is reporting the pending events, and it will report these ones too
+                None => return,
+            }
+        };
+        // The events are reported without holding the lock, so the callback can use the connector
+        loop {
+            let event = {
+                let mut state = lock(&self.state);
+                match state.pending.pop_front() {
+                    Some(event) => event,
+                    None => {
+                        state.callback = Some(callback);
+                        return;
+                    },
+                }
+            };
+            callback(&event);
+        }
+    }
+
+    fn refresh_sources(state: &mut State, events: &mut Vec<AutoConnectEvent>) {
+        let online: Vec<Source> = Sources.into_iter().filter(|source| is_online(source)).collect();
+        let input_port = &state.input_port;
+        for target in &mut state.sources {
+            let found = online.iter().find(|source| target.matcher.matches(source)).map(|source| source.object.0);
+            if found == target.endpoint {
+                continue;
+            }
+            if let Some(previous) = target.endpoint.take() {
+                // It fails when the source has been removed, but then it is already disconnected
+                let _ = input_port.disconnect_source(&source(previous));
+                events.push(AutoConnectEvent::SourceDisconnected(source(previous)));
+            }
+            if let Some(endpoint_ref) = found {
+                match input_port.connect_source(&source(endpoint_ref)) {
+                    Ok(()) => {
+                        target.endpoint = Some(endpoint_ref);
+                        events.push(AutoConnectEvent::SourceConnected(source(endpoint_ref)));
+                    },
+                    Err(error) => events.push(AutoConnectEvent::ConnectFailed(source(endpoint_ref), error)),
+                }
+            }
+        }
+    }
+
+    fn refresh_destinations(state: &mut State, events: &mut Vec<AutoConnectEvent>) {
+        let online: Vec<Destination> = Destinations.into_iter().filter(|destination| is_online(destination)).collect();
+        for target in &mut state.destinations {
+            let found = online.iter().find(|destination| target.matcher.matches(destination)).map(|destination| destination.object.0);
+            if found == target.endpoint {
+                continue;
+            }
+            if let Some(previous) = target.endpoint.take() {
+                events.push(AutoConnectEvent::DestinationLost(destination(previous)));
+            }
+            if let Some(endpoint_ref) = found {
+                target.endpoint = Some(endpoint_ref);
+                events.push(AutoConnectEvent::DestinationFound(destination(endpoint_ref)));
+            }
+        }
+    }
+}
+
+fn source(endpoint_ref: MIDIEndpointRef) -> Source {
+    Source { endpoint: Endpoint { object: Object(endpoint_ref) } }
+}
+
+fn destination(endpoint_ref: MIDIEndpointRef) -> Destination {
+    Destination { endpoint: Endpoint { object: Object(endpoint_ref) } }
+}
+
+// An endpoint is offline when either it or its device is marked as offline
+fn is_online(endpoint: &Endpoint) -> bool {
+    let offline = |object: &Object| Properties::offline().value_from(object).unwrap_or(false);
+    let device_offline = endpoint.entity()
+        .and_then(|entity| entity.device())
+        .map_or(false, |device| offline(&device));
+    !offline(endpoint) && !device_offline
+}
+
+#[cfg(all(test, not(target_os = "macos")))]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+
+    use {Client, NotificationDispatcher, Object, PacketBuffer};
+    use super::{AutoConnectEvent, AutoConnector, EndpointMatcher};
+
+    fn describe(event: &AutoConnectEvent) -> (&'static str, u32) {
+        match *event {
+            AutoConnectEvent::SourceConnected(ref source) => ("connected", source.object.0),
+            AutoConnectEvent::SourceDisconnected(ref source) => ("disconnected", source.object.0),
+            AutoConnectEvent::ConnectFailed(ref source, _) => ("failed", source.object.0),
+            AutoConnectEvent::DestinationFound(ref destination) => ("found", destination.object.0),
+            AutoConnectEvent::DestinationLost(ref destination) => ("lost", destination.object.0),
+        }
+    }
+
+    fn refs(events: &mpsc::Receiver<(&'static str, u32)>) -> Vec<(&'static str, u32)> {
+        events.try_iter().collect()
+    }
+
+    #[test]
+    fn reconnect_source_when_replugged() {
+        let dispatcher = NotificationDispatcher::new();
+        let client = Client::new_with_dispatcher("autoconnect-client", &dispatcher).unwrap();
+        let (packets_tx, packets_rx) = mpsc::channel();
+        let input_port = client.input_port("autoconnect-input", move |packet_list| {
+            for packet in packet_list.iter() {
+                packets_tx.send(packet.data().to_vec()).unwrap();
+            }
+        }).unwrap();
+        let (events_tx, events_rx) = mpsc::channel();
+        let connector = AutoConnector::new(&dispatcher, input_port, move |event| events_tx.send(describe(event)).unwrap());
+        connector.add_source(EndpointMatcher::name("autoconnect-source"));
+        assert_eq!(refs(&events_rx), vec![]);
+
+        let device = Client::new("autoconnect-device").unwrap();
+        let plugged = device.virtual_source("autoconnect-source").unwrap();
+        let plugged_ref = plugged.endpoint.object.0;
+        assert_eq!(refs(&events_rx), vec![("connected", plugged_ref)]);
+        plugged.received(&PacketBuffer::new(0, &[0x90, 0x40, 0x7f])).unwrap();
+
+        plugged.set_property_boolean("offline", true).unwrap();
+        assert_eq!(refs(&events_rx), vec![("disconnected", plugged_ref)]);
+        plugged.received(&PacketBuffer::new(0, &[0x90, 0x41, 0x7f])).unwrap();
+        plugged.set_property_boolean("offline", false).unwrap();
+        assert_eq!(refs(&events_rx), vec![("connected", plugged_ref)]);
+
+        drop(plugged);
+        assert_eq!(refs(&events_rx), vec![("disconnected", plugged_ref)]);
+        assert!(connector.sources().is_empty());
+
+        let replugged = device.virtual_source("autoconnect-source").unwrap();
+        let replugged_ref = replugged.endpoint.object.0;
+        assert_eq!(refs(&events_rx), vec![("connected", replugged_ref)]);
+        assert_eq!(connector.sources().iter().map(|source| source.object.0).collect::<Vec<_>>(), vec![replugged_ref]);
+        replugged.received(&PacketBuffer::new(0, &[0x90, 0x42, 0x7f])).unwrap();
+
+        assert_eq!(packets_rx.try_iter().collect::<Vec<_>>(), vec![vec![0x90, 0x40, 0x7f], vec![0x90, 0x42, 0x7f]]);
+    }
+
+    #[test]
+    fn retarget_destination_when_replugged() {
+        let dispatcher = NotificationDispatcher::new();
+        let client = Client::new_with_dispatcher("autoconnect-client", &dispatcher).unwrap();
+        let input_port = client.input_port("autoconnect-input", |_| {}).unwrap();
+        let output_port = client.output_port("autoconnect-output").unwrap();
+        let connector = AutoConnector::new(&dispatcher, input_port, |_| {});
+        let device = Client::new("autoconnect-device").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let first_tx = tx.clone();
+        let first = device.virtual_destination("autoconnect-first", move |packet_list| {
+            first_tx.send(("first", packet_list.iter().next().unwrap().data().to_vec())).unwrap();
+        }).unwrap();
+        let unique_id = first.unique_id().unwrap();
+        connector.add_destination(EndpointMatcher::predicate(move |endpoint| endpoint.unique_id() == Some(unique_id)));
+        connector.add_destination(EndpointMatcher::unique_id(0xdead_beef));
+        assert_eq!(connector.destinations().iter().map(|destination| Object(destination.object.0)).collect::<Vec<_>>(),
+                   vec![Object(first.endpoint.object.0)]);
+
+        connector.send(&output_port, &PacketBuffer::new(0, &[0xf8])).unwrap();
+        drop(first);
+        assert!(connector.destinations().is_empty());
+        connector.send(&output_port, &PacketBuffer::new(0, &[0xfa])).unwrap();
+
+        let second = device.virtual_destination("autoconnect-second", move |packet_list| {
+            tx.send(("second", packet_list.iter().next().unwrap().data().to_vec())).unwrap();
+        }).unwrap();
+        second.set_property_integer("uniqueID", 0xdead_beef_u32 as i32).unwrap();
+        connector.send(&output_port, &PacketBuffer::new(0, &[0xfc])).unwrap();
+
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![("first", vec![0xf8]), ("second", vec![0xfc])]);
+    }
+
+    #[test]
+    fn callback_can_use_the_connector() {
+        let dispatcher = NotificationDispatcher::new();
+        let client = Client::new_with_dispatcher("autoconnect-client", &dispatcher).unwrap();
+        let input_port = client.input_port("autoconnect-input", |_| {}).unwrap();
+        let device = Client::new("autoconnect-device").unwrap();
+        let destination = device.virtual_destination("autoconnect-reentrant-destination", |_| {}).unwrap();
+
+        let slot: Arc<Mutex<Option<Arc<AutoConnector>>>> = Arc::new(Mutex::new(None));
+        let callback_slot = slot.clone();
+        let (events_tx, events_rx) = mpsc::channel();
+        let connector = Arc::new(AutoConnector::new(&dispatcher, input_port, move |event| {
+            let connector = callback_slot.lock().unwrap().clone();
+            if let (&AutoConnectEvent::SourceConnected(_), Some(connector)) = (event, connector) {
+                connector.add_destination(EndpointMatcher::name("autoconnect-reentrant-destination"));
+            }
+            events_tx.send(describe(event)).unwrap();
+        }));
+        *slot.lock().unwrap() = Some(connector.clone());
+        connector.add_source(EndpointMatcher::name("autoconnect-reentrant-source"));
+
+        let source = device.virtual_source("autoconnect-reentrant-source").unwrap();
+        assert_eq!(refs(&events_rx), vec![("connected", source.endpoint.object.0), ("found", destination.endpoint.object.0)]);
+        slot.lock().unwrap().take();
+    }
+}
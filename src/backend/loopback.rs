@@ -0,0 +1,1269 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+use std::sync::{Condvar, Mutex, MutexGuard, Once};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use EventBuffer;
+use EventList;
+use MessageParser;
+use Midi1ToMidi2;
+use Midi2ToMidi1;
+use Object;
+use PacketBuffer;
+use PacketList;
+use Protocol;
+use UmpMessage;
+use notifications::{AddedRemovedInfo, Notification, PropertyChangedInfo};
+use object::ObjectType;
+use thru::ThruConnectionParams;
+
+use super::{
+    Backend,
+    deliver_event_list,
+    deliver_notification,
+    deliver_packets,
+};
+use super::sys::{
+    CFStringRef,
+    MIDIObjectRef,
+    MIDIObjectType,
+    MIDIProtocolID,
+    MIDISysexSendRequest,
+    MIDITimeStamp,
+    OSStatus,
+    SInt32,
+    kMIDIInvalidClient,
+    kMIDIInvalidPort,
+    kMIDINoConnection,
+    kMIDIObjectNotFound,
+    kMIDIProtocol_1_0,
+    kMIDIProtocol_2_0,
+    kMIDIUnknownError,
+    kMIDIUnknownProperty,
+    kMIDIWrongEndpointType,
+    kMIDIWrongPropertyType,
+};
+
+/// A backend that emulates the MIDI server within the current process.
+///
+/// It is used on hosts where CoreMIDI is not available. All the clients share the same
+/// in-process server, so the virtual endpoints created by one client are visible to the others
+/// as sources and destinations. Packets sent to a virtual destination, or received by a virtual
+/// source, are delivered synchronously from the calling thread, regardless of their timestamps.
+/// Disposing a client, port or endpoint waits until the deliveries to its callbacks from other threads end.
+/// Thru connections are kept with their params, but they don't forward any data.
+///
+/// Endpoints always speak MIDI 1.0. Input ports and event lists using the MIDI 1.0 protocol
+/// are supported by packing and unpacking the MIDI bytes into Universal MIDI Packets,
+/// and the ones using the MIDI 2.0 protocol by translating them with `Midi1ToMidi2` and `Midi2ToMidi1`.
+/// The translation state is not kept between packet lists.
+///
+pub struct Loopback;
+
+/// Property keys are plain strings in the loopback backend.
+pub enum PropertyKeyStorage {
+    Owned(String),
+    Constant(CFStringRef)
+}
+
+impl PropertyKeyStorage {
+    pub fn new(name: &str) -> Self {
+        PropertyKeyStorage::Owned(name.to_string())
+    }
+
+    pub fn constant(key: CFStringRef) -> Self {
+        PropertyKeyStorage::Constant(key)
+    }
+
+    pub fn name(&self) -> String {
+        self.as_str().to_string()
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            PropertyKeyStorage::Owned(owned) => owned,
+            PropertyKeyStorage::Constant(constant) => constant,
+        }
+    }
+}
+
+// A ref con owned by the crate, which is only dereferenced from `deliver_*`
+#[derive(Clone, Copy)]
+struct RefCon(*mut c_void);
+
+unsafe impl Send for RefCon {}
+
+enum Kind {
+    Client { notify_ref_con: RefCon },
+    OutputPort,
+    /// The protocol is only set for the ports that receive event lists.
+    InputPort { read_ref_con: RefCon, protocol: Option<MIDIProtocolID>, connections: Vec<(MIDIObjectRef, RefCon)> },
+    Source,
+    Destination { read_ref_con: RefCon },
+    // There are no drivers, so devices and their entities are only created by tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    Device { external: bool },
+    #[cfg_attr(not(test), allow(dead_code))]
+    Entity { external: bool },
+    ThruConnection { persistent_owner_id: Option<String>, params: Vec<u8> },
+}
+
+impl Kind {
+    /// The ref cons handed to the callbacks of the object, including the ones of its connections.
+    fn ref_cons(&self) -> Vec<RefCon> {
+        match *self {
+            Kind::Client { notify_ref_con } => vec![notify_ref_con],
+            Kind::InputPort { read_ref_con, ref connections, .. } => {
+                let mut ref_cons = vec![read_ref_con];
+                ref_cons.extend(connections.iter().map(|&(_, conn_ref_con)| conn_ref_con));
+                ref_cons
+            },
+            Kind::Destination { read_ref_con } => vec![read_ref_con],
+            _ => Vec::new(),
+        }
+    }
+}
+
+enum PropertyValue {
+    String(String),
+    Integer(SInt32),
+    Data(Vec<u8>),
+    Dictionary(BTreeMap<String, ::PropertyValue>),
+}
+
+impl PropertyValue {
+    fn to_value(&self) -> ::PropertyValue {
+        match *self {
+            PropertyValue::String(ref value) => ::PropertyValue::String(value.clone()),
+            PropertyValue::Integer(value) => ::PropertyValue::Integer(i64::from(value)),
+            PropertyValue::Data(ref value) => ::PropertyValue::Data(value.clone()),
+            PropertyValue::Dictionary(ref value) => ::PropertyValue::Dictionary(value.clone()),
+        }
+    }
+}
+
+struct Entry {
+    owner: MIDIObjectRef,
+    kind: Kind,
+    properties: HashMap<String, PropertyValue>,
+}
+
+impl Entry {
+    fn object_type(&self) -> ObjectType {
+        match self.kind {
+            Kind::Source => ObjectType::Source,
+            Kind::Destination { .. } => ObjectType::Destination,
+            Kind::Device { external: false } => ObjectType::Device,
+            Kind::Device { external: true } => ObjectType::ExternalDevice,
+            Kind::Entity { external: false } => ObjectType::Entity,
+            Kind::Entity { external: true } => ObjectType::ExternalEntity,
+            _ => ObjectType::Other,
+        }
+    }
+}
+
+struct Server {
+    next_object_ref: MIDIObjectRef,
+    next_unique_id: SInt32,
+    objects: HashMap<MIDIObjectRef, Entry>,
+    sources: Vec<MIDIObjectRef>,
+    destinations: Vec<MIDIObjectRef>,
+    // The ref cons being delivered to, and the threads delivering them
+    in_flight: Vec<(usize, ThreadId)>,
+}
+
+impl Server {
+    fn new() -> Server {
+        Server {
+            next_object_ref: 1,
+            next_unique_id: 1,
+            objects: HashMap::new(),
+            sources: Vec::new(),
+            destinations: Vec::new(),
+            in_flight: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, owner: MIDIObjectRef, kind: Kind, name: &str) -> MIDIObjectRef {
+        let object_ref = self.next_object_ref;
+        self.next_object_ref += 1;
+
+        let mut properties = HashMap::new();
+        properties.insert("name".to_string(), PropertyValue::String(name.to_string()));
+        match kind {
+            Kind::Source | Kind::Destination { .. } => {
+                properties.insert("displayName".to_string(), PropertyValue::String(name.to_string()));
+                properties.insert("uniqueID".to_string(), PropertyValue::Integer(self.next_unique_id));
+                self.next_unique_id += 1;
+            },
+            Kind::Device { .. } | Kind::Entity { .. } => {
+                properties.insert("uniqueID".to_string(), PropertyValue::Integer(self.next_unique_id));
+                self.next_unique_id += 1;
+            },
+            _ => {}
+        }
+        // The endpoints of external devices are not listed as sources and destinations
+        let external = match self.objects.get(&owner) {
+            Some(&Entry { kind: Kind::Entity { external }, .. }) => external,
+            _ => false
+        };
+        match kind {
+            Kind::Source if !external => self.sources.push(object_ref),
+            Kind::Destination { .. } if !external => self.destinations.push(object_ref),
+            _ => {}
+        }
+
+        self.objects.insert(object_ref, Entry { owner, kind, properties });
+        object_ref
+    }
+
+    fn remove(&mut self, object_ref: MIDIObjectRef) -> Option<Entry> {
+        let entry = self.objects.remove(&object_ref)?;
+        self.sources.retain(|source| *source != object_ref);
+        self.destinations.retain(|destination| *destination != object_ref);
+        for other in self.objects.values_mut() {
+            if let Kind::InputPort { ref mut connections, .. } = other.kind {
+                connections.retain(|&(source, _)| source != object_ref);
+            }
+        }
+        Some(entry)
+    }
+
+    fn client(&self, client: MIDIObjectRef) -> Result<&Entry, OSStatus> {
+        match self.objects.get(&client) {
+            Some(entry @ &Entry { kind: Kind::Client { .. }, .. }) => Ok(entry),
+            _ => Err(kMIDIInvalidClient),
+        }
+    }
+
+    fn object(&self, object_ref: MIDIObjectRef) -> Result<&Entry, OSStatus> {
+        self.objects.get(&object_ref).ok_or(kMIDIObjectNotFound)
+    }
+
+    /// The objects owned by `owner` that match `filter`, in order of creation.
+    fn children<F: Fn(&Kind) -> bool>(&self, owner: MIDIObjectRef, filter: F) -> Vec<MIDIObjectRef> {
+        let mut children: Vec<MIDIObjectRef> = self.objects.iter()
+            .filter(|&(_, entry)| entry.owner == owner && filter(&entry.kind))
+            .map(|(&object_ref, _)| object_ref)
+            .collect();
+        children.sort();
+        children
+    }
+
+    fn devices(&self, external: bool) -> Vec<MIDIObjectRef> {
+        self.children(0, |kind| match *kind {
+            Kind::Device { external: device_external } => device_external == external,
+            _ => false
+        })
+    }
+
+    fn entities(&self, device: MIDIObjectRef) -> Vec<MIDIObjectRef> {
+        self.children(device, |kind| match *kind { Kind::Entity { .. } => true, _ => false })
+    }
+
+    fn entity_sources(&self, entity: MIDIObjectRef) -> Vec<MIDIObjectRef> {
+        self.children(entity, |kind| match *kind { Kind::Source => true, _ => false })
+    }
+
+    fn entity_destinations(&self, entity: MIDIObjectRef) -> Vec<MIDIObjectRef> {
+        self.children(entity, |kind| match *kind { Kind::Destination { .. } => true, _ => false })
+    }
+
+    /// The properties of an object, and of its children when `deep`, under the same keys used by CoreMIDI.
+    fn properties(&self, object_ref: MIDIObjectRef, deep: bool) -> Result<BTreeMap<String, ::PropertyValue>, OSStatus> {
+        let entry = self.object(object_ref)?;
+        let mut properties: BTreeMap<String, ::PropertyValue> = entry.properties.iter()
+            .map(|(key, value)| (key.clone(), value.to_value()))
+            .collect();
+        if deep {
+            let children = match entry.kind {
+                Kind::Device { .. } => vec![("entities", self.entities(object_ref))],
+                Kind::Entity { .. } => vec![
+                    ("sources", self.entity_sources(object_ref)),
+                    ("destinations", self.entity_destinations(object_ref)),
+                ],
+                _ => Vec::new(),
+            };
+            for (key, children) in children {
+                let children = children.into_iter()
+                    .map(|child| self.properties(child, true).map(::PropertyValue::Dictionary))
+                    .collect::<Result<Vec<_>, _>>()?;
+                properties.insert(key.to_string(), ::PropertyValue::Array(children));
+            }
+        }
+        Ok(properties)
+    }
+
+    /// The owner of `object_ref`, when it is of the kind accepted by `filter`.
+    fn parent<F: Fn(&Kind) -> bool>(&self, object_ref: MIDIObjectRef, filter: F) -> Result<MIDIObjectRef, OSStatus> {
+        let owner = self.object(object_ref)?.owner;
+        match self.objects.get(&owner) {
+            Some(entry) if filter(&entry.kind) => Ok(owner),
+            _ => Err(kMIDIObjectNotFound)
+        }
+    }
+
+    fn notify_ref_cons(&self) -> Vec<RefCon> {
+        self.objects.values().filter_map(|entry| match entry.kind {
+            Kind::Client { notify_ref_con } if !notify_ref_con.0.is_null() => Some(notify_ref_con),
+            _ => None
+        }).collect()
+    }
+}
+
+// The server, and the condition signaled whenever a delivery ends
+fn shared() -> &'static (Mutex<Server>, Condvar) {
+    static INIT: Once = Once::new();
+    static mut SHARED: *const (Mutex<Server>, Condvar) = 0 as *const (Mutex<Server>, Condvar);
+    unsafe {
+        INIT.call_once(|| SHARED = Box::into_raw(Box::new((Mutex::new(Server::new()), Condvar::new()))));
+        &*SHARED
+    }
+}
+
+fn server() -> MutexGuard<'static, Server> {
+    shared().0.lock().unwrap()
+}
+
+/// A delivery to some callbacks, which keeps them from being disposed by other threads until it ends.
+///
+/// It has to be created while holding the server lock used to find the ref cons, so the objects
+/// can't be removed in between. The callbacks are then called without holding the lock.
+struct Delivery {
+    ref_cons: Vec<usize>,
+    thread: ThreadId,
+}
+
+impl Delivery {
+    fn new(server: &mut Server, ref_cons: &[RefCon]) -> Delivery {
+        let thread = thread::current().id();
+        let ref_cons: Vec<usize> = ref_cons.iter()
+            .filter(|ref_con| !ref_con.0.is_null())
+            .map(|ref_con| ref_con.0 as usize)
+            .collect();
+        server.in_flight.extend(ref_cons.iter().map(|&ref_con| (ref_con, thread)));
+        Delivery { ref_cons, thread }
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        let mut server = server();
+        for &ref_con in &self.ref_cons {
+            if let Some(index) = server.in_flight.iter().position(|&in_flight| in_flight == (ref_con, self.thread)) {
+                server.in_flight.swap_remove(index);
+            }
+        }
+        shared().1.notify_all();
+    }
+}
+
+/// Waits until the deliveries to the ref cons of removed objects end, so their callbacks can be freed.
+///
+/// The deliveries of the current thread are not waited for, as they are the ones calling this.
+fn wait_for_deliveries(mut server: MutexGuard<'static, Server>, ref_cons: &[RefCon]) {
+    let thread = thread::current().id();
+    let is_delivering = |server: &Server| server.in_flight.iter()
+        .any(|&(ref_con, delivering)| delivering != thread && ref_cons.iter().any(|removed| removed.0 as usize == ref_con));
+    while is_delivering(&server) {
+        server = shared().1.wait(server).unwrap();
+    }
+}
+
+/// Adds a device with the given entities, each with a number of sources and destinations.
+/// The loopback server has no drivers, so this is the only way to get devices in tests.
+#[cfg(test)]
+pub(crate) fn add_device(name: &str, external: bool, entities: &[(&str, usize, usize)]) -> MIDIObjectRef {
+    let mut server = server();
+    let device = server.add(0, Kind::Device { external }, name);
+    for &(entity_name, sources, destinations) in entities {
+        let entity = server.add(device, Kind::Entity { external }, entity_name);
+        for _ in 0..sources {
+            server.add(entity, Kind::Source, entity_name);
+        }
+        for _ in 0..destinations {
+            server.add(entity, Kind::Destination { read_ref_con: RefCon(ptr::null_mut()) }, entity_name);
+        }
+    }
+    device
+}
+
+/// Removes a device added with `add_device`, together with its entities and endpoints.
+#[cfg(test)]
+pub(crate) fn remove_device(device: MIDIObjectRef) {
+    let mut server = server();
+    for entity in server.entities(device) {
+        for endpoint in server.children(entity, |_| true) {
+            server.remove(endpoint);
+        }
+        server.remove(entity);
+    }
+    server.remove(device);
+}
+
+type TimeSource = Box<Fn() -> u64 + Send + Sync>;
+
+fn time_source() -> MutexGuard<'static, TimeSource> {
+    static INIT: Once = Once::new();
+    static mut TIME_SOURCE: *const Mutex<TimeSource> = 0 as *const Mutex<TimeSource>;
+    unsafe {
+        INIT.call_once(|| {
+            let start = Instant::now();
+            let default_source: TimeSource = Box::new(move || {
+                let elapsed = start.elapsed();
+                elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())
+            });
+            TIME_SOURCE = Box::into_raw(Box::new(Mutex::new(default_source)))
+        });
+        (*TIME_SOURCE).lock().unwrap()
+    }
+}
+
+/// Replaces the function used to read the host clock, which must return nanoseconds.
+pub fn set_time_source(source: TimeSource) {
+    *time_source() = source;
+}
+
+// Notifications are delivered without holding the server lock,
+// so the callbacks are free to call back into the backend.
+fn notify_all(notifications: Vec<Notification>) {
+    let (ref_cons, _delivery) = {
+        let mut server = server();
+        let ref_cons = server.notify_ref_cons();
+        let delivery = Delivery::new(&mut server, &ref_cons);
+        (ref_cons, delivery)
+    };
+    for notification in &notifications {
+        for ref_con in &ref_cons {
+            unsafe { deliver_notification(ref_con.0, notification) };
+        }
+    }
+}
+
+fn added_removed_info(object_ref: MIDIObjectRef, object_type: ObjectType) -> AddedRemovedInfo {
+    AddedRemovedInfo {
+        parent: Object(0),
+        parent_type: ObjectType::Other,
+        child: Object(object_ref),
+        child_type: object_type,
+    }
+}
+
+fn notify_added(object_ref: MIDIObjectRef, object_type: ObjectType) {
+    notify_all(vec![
+        Notification::ObjectAdded(added_removed_info(object_ref, object_type)),
+        Notification::SetupChanged,
+    ]);
+}
+
+fn notify_removed(removed: Vec<(MIDIObjectRef, ObjectType)>) {
+    if removed.is_empty() {
+        return;
+    }
+    let mut notifications: Vec<Notification> = removed.into_iter()
+        .map(|(object_ref, object_type)| Notification::ObjectRemoved(added_removed_info(object_ref, object_type)))
+        .collect();
+    notifications.push(Notification::SetupChanged);
+    notify_all(notifications);
+}
+
+fn set_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: PropertyValue) -> Result<(), OSStatus> {
+    let object_type = {
+        let mut server = server();
+        let entry = server.objects.get_mut(&object).ok_or(kMIDIObjectNotFound)?;
+        entry.properties.insert(key.as_str().to_string(), value);
+        entry.object_type()
+    };
+    notify_all(vec![Notification::PropertyChanged(PropertyChangedInfo {
+        object: Object(object),
+        object_type,
+        property_name: key.as_str().to_string(),
+        old_value: None,
+        new_value: None,
+    })]);
+    Ok(())
+}
+
+/// Packs the MIDI 1.0 messages in a packet list into Universal MIDI Packets of the MIDI 1.0 protocol, in group 0.
+/// Running status and SysEx messages split across packets are supported, and malformed bytes are skipped.
+fn event_list_from_packets(packet_list: &PacketList) -> EventBuffer {
+    let mut parser = MessageParser::new();
+    let mut event_list = EventBuffer::new(Protocol::Midi10);
+    for packet in packet_list.iter() {
+        for (timestamp, message) in parser.parse(packet) {
+            if let Ok(message) = message {
+                for ump in UmpMessage::from_midi1(0, &message) {
+                    event_list.push_message(timestamp, &ump);
+                }
+            }
+        }
+    }
+    event_list
+}
+
+/// The speed of a MIDI 1.0 cable in bytes per second (31250 bauds, with 10 bits per byte).
+const MIDI_BYTES_PER_SECOND: usize = 3125;
+
+const SYSEX_CHUNK_SIZE: usize = 256;
+
+/// Sends the data of a SysEx request to its destination in chunks, paced as if they were going through a MIDI cable.
+///
+/// The request is updated as CoreMIDI does: `data` and `bytesToSend` advance with every chunk sent,
+/// and the client can set `complete` to cancel it.
+struct SysExPump {
+    request: *mut MIDISysexSendRequest,
+}
+
+unsafe impl Send for SysExPump {}
+
+impl SysExPump {
+    /// Sends the next chunk and returns the time it takes to go through the cable,
+    /// or `None` when there is nothing else to send.
+    unsafe fn send_next_chunk(&mut self) -> Option<Duration> {
+        let request = &mut *self.request;
+        let bytes_to_send = ptr::read_volatile(&request.bytesToSend) as usize;
+        if ptr::read_volatile(&request.complete) != 0 || bytes_to_send == 0 {
+            return None;
+        }
+
+        let (read_ref_con, _delivery) = {
+            let mut server = server();
+            let read_ref_con = match server.object(request.destination) {
+                Ok(&Entry { kind: Kind::Destination { read_ref_con }, .. }) => read_ref_con,
+                _ => return None
+            };
+            (read_ref_con, Delivery::new(&mut server, &[read_ref_con]))
+        };
+
+        let len = cmp::min(bytes_to_send, SYSEX_CHUNK_SIZE);
+        if !read_ref_con.0.is_null() {
+            let chunk = PacketBuffer::new(0, slice::from_raw_parts(request.data, len));
+            deliver_packets(read_ref_con.0, ptr::null_mut(), &chunk);
+        }
+
+        request.data = request.data.add(len);
+        ptr::write_volatile(&mut request.bytesToSend, (bytes_to_send - len) as _);
+        Some(Duration::from_micros((len * 1_000_000 / MIDI_BYTES_PER_SECOND) as u64))
+    }
+
+    /// Marks the request as complete and calls its completion proc.
+    unsafe fn finish(self) {
+        ptr::write_volatile(&mut (*self.request).complete, 1);
+        if let Some(completion_proc) = (*self.request).completionProc {
+            completion_proc(self.request);
+        }
+    }
+}
+
+impl Backend for Loopback {
+    fn client_create(name: &str, notify_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        let kind = Kind::Client { notify_ref_con: RefCon(notify_ref_con) };
+        Ok(server().add(0, kind, name))
+    }
+
+    fn client_dispose(client: MIDIObjectRef) -> Result<(), OSStatus> {
+        let removed = {
+            let mut server = server();
+            server.client(client)?;
+            let mut ref_cons = server.remove(client).map(|entry| entry.kind.ref_cons()).unwrap_or_default();
+            let owned: Vec<MIDIObjectRef> = server.objects.iter()
+                .filter(|&(_, entry)| entry.owner == client)
+                .map(|(object_ref, _)| *object_ref)
+                .collect();
+            let mut removed = Vec::new();
+            for object_ref in owned {
+                if let Some(entry) = server.remove(object_ref) {
+                    ref_cons.extend(entry.kind.ref_cons());
+                    match entry.kind {
+                        Kind::Source | Kind::Destination { .. } => removed.push((object_ref, entry.object_type())),
+                        _ => {}
+                    }
+                }
+            }
+            wait_for_deliveries(server, &ref_cons);
+            removed
+        };
+        notify_removed(removed);
+        Ok(())
+    }
+
+    fn output_port_create(client: MIDIObjectRef, name: &str) -> Result<MIDIObjectRef, OSStatus> {
+        let mut server = server();
+        server.client(client)?;
+        Ok(server.add(client, Kind::OutputPort, name))
+    }
+
+    fn input_port_create(client: MIDIObjectRef, name: &str, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        let mut server = server();
+        server.client(client)?;
+        let kind = Kind::InputPort { read_ref_con: RefCon(read_ref_con), protocol: None, connections: Vec::new() };
+        Ok(server.add(client, kind, name))
+    }
+
+    fn input_port_create_with_protocol(client: MIDIObjectRef, name: &str, protocol: MIDIProtocolID, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        if protocol != kMIDIProtocol_1_0 && protocol != kMIDIProtocol_2_0 {
+            return Err(kMIDIUnknownError);
+        }
+        let mut server = server();
+        server.client(client)?;
+        let kind = Kind::InputPort { read_ref_con: RefCon(read_ref_con), protocol: Some(protocol), connections: Vec::new() };
+        Ok(server.add(client, kind, name))
+    }
+
+    fn port_dispose(port: MIDIObjectRef) -> Result<(), OSStatus> {
+        let mut server = server();
+        match server.object(port)?.kind {
+            Kind::OutputPort | Kind::InputPort { .. } => {
+                let ref_cons = server.remove(port).map(|entry| entry.kind.ref_cons()).unwrap_or_default();
+                wait_for_deliveries(server, &ref_cons);
+                Ok(())
+            },
+            _ => Err(kMIDIInvalidPort)
+        }
+    }
+
+    fn port_connect_source(port: MIDIObjectRef, source: MIDIObjectRef, conn_ref_con: *mut c_void) -> Result<(), OSStatus> {
+        let mut server = server();
+        match server.object(source)?.kind {
+            Kind::Source => {},
+            _ => return Err(kMIDIWrongEndpointType)
+        }
+        match server.objects.get_mut(&port) {
+            Some(&mut Entry { kind: Kind::InputPort { ref mut connections, .. }, .. }) => {
+                let replaced: Vec<RefCon> = connections.iter()
+                    .filter(|&&(connected, _)| connected == source)
+                    .map(|&(_, replaced)| replaced)
+                    .collect();
+                connections.retain(|&(connected, _)| connected != source);
+                connections.push((source, RefCon(conn_ref_con)));
+                wait_for_deliveries(server, &replaced);
+                Ok(())
+            },
+            _ => Err(kMIDIInvalidPort)
+        }
+    }
+
+    fn port_disconnect_source(port: MIDIObjectRef, source: MIDIObjectRef) -> Result<(), OSStatus> {
+        let mut server = server();
+        match server.objects.get_mut(&port) {
+            Some(&mut Entry { kind: Kind::InputPort { ref mut connections, .. }, .. }) => {
+                let removed: Vec<RefCon> = connections.iter()
+                    .filter(|&&(connected, _)| connected == source)
+                    .map(|&(_, removed)| removed)
+                    .collect();
+                if removed.is_empty() {
+                    return Err(kMIDINoConnection);
+                }
+                connections.retain(|&(connected, _)| connected != source);
+                wait_for_deliveries(server, &removed);
+                Ok(())
+            },
+            _ => Err(kMIDIInvalidPort)
+        }
+    }
+
+    fn send(port: MIDIObjectRef, destination: MIDIObjectRef, packet_list: &PacketList) -> Result<(), OSStatus> {
+        let (read_ref_con, _delivery) = {
+            let mut server = server();
+            match server.object(port)?.kind {
+                Kind::OutputPort => {},
+                _ => return Err(kMIDIInvalidPort)
+            }
+            let read_ref_con = match server.object(destination)?.kind {
+                Kind::Destination { read_ref_con } => read_ref_con,
+                _ => return Err(kMIDIWrongEndpointType)
+            };
+            (read_ref_con, Delivery::new(&mut server, &[read_ref_con]))
+        };
+        // The destinations of devices don't have anything attached
+        if !read_ref_con.0.is_null() {
+            unsafe { deliver_packets(read_ref_con.0, ptr::null_mut(), packet_list) };
+        }
+        Ok(())
+    }
+
+    fn send_event_list(port: MIDIObjectRef, destination: MIDIObjectRef, event_list: &EventList) -> Result<(), OSStatus> {
+        if let Protocol::Unknown(_) = event_list.protocol() {
+            return Err(kMIDIUnknownError);
+        }
+        Self::send(port, destination, &Midi2ToMidi1::new().translate_event_list(event_list))
+    }
+
+    fn received(source: MIDIObjectRef, packet_list: &PacketList) -> Result<(), OSStatus> {
+        let (targets, _delivery) = {
+            let mut server = server();
+            match server.object(source)?.kind {
+                Kind::Source => {},
+                _ => return Err(kMIDIWrongEndpointType)
+            }
+            let targets: Vec<(RefCon, Option<MIDIProtocolID>, RefCon)> = server.objects.values().filter_map(|entry| match entry.kind {
+                Kind::InputPort { read_ref_con, protocol, ref connections } => {
+                    connections.iter()
+                        .find(|&&(connected, _)| connected == source)
+                        .map(|&(_, conn_ref_con)| (read_ref_con, protocol, conn_ref_con))
+                },
+                _ => None
+            }).collect();
+            let ref_cons: Vec<RefCon> = targets.iter()
+                .flat_map(|&(read_ref_con, _, conn_ref_con)| vec![read_ref_con, conn_ref_con])
+                .collect();
+            let delivery = Delivery::new(&mut server, &ref_cons);
+            (targets, delivery)
+        };
+        let mut midi1_event_list = None;
+        let mut midi2_event_list = None;
+        for (read_ref_con, protocol, conn_ref_con) in targets {
+            let event_list = match protocol {
+                None => {
+                    unsafe { deliver_packets(read_ref_con.0, conn_ref_con.0, packet_list) };
+                    continue;
+                },
+                Some(protocol) if protocol == kMIDIProtocol_2_0 => midi2_event_list
+                    .get_or_insert_with(|| Midi1ToMidi2::new(0).translate_packet_list(packet_list)),
+                Some(_) => midi1_event_list.get_or_insert_with(|| event_list_from_packets(packet_list)),
+            };
+            unsafe { deliver_event_list(read_ref_con.0, conn_ref_con.0, event_list) };
+        }
+        Ok(())
+    }
+
+    unsafe fn send_sysex(request: *mut MIDISysexSendRequest) -> Result<(), OSStatus> {
+        match server().object((*request).destination)?.kind {
+            Kind::Destination { .. } => {},
+            _ => return Err(kMIDIWrongEndpointType)
+        }
+        let mut pump = SysExPump { request };
+        thread::spawn(move || {
+            while let Some(delay) = pump.send_next_chunk() {
+                thread::sleep(delay);
+            }
+            pump.finish();
+        });
+        Ok(())
+    }
+
+    fn source_create(client: MIDIObjectRef, name: &str) -> Result<MIDIObjectRef, OSStatus> {
+        let source = {
+            let mut server = server();
+            server.client(client)?;
+            server.add(client, Kind::Source, name)
+        };
+        notify_added(source, ObjectType::Source);
+        Ok(source)
+    }
+
+    fn destination_create(client: MIDIObjectRef, name: &str, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        let destination = {
+            let mut server = server();
+            server.client(client)?;
+            server.add(client, Kind::Destination { read_ref_con: RefCon(read_ref_con) }, name)
+        };
+        notify_added(destination, ObjectType::Destination);
+        Ok(destination)
+    }
+
+    fn endpoint_dispose(endpoint: MIDIObjectRef) -> Result<(), OSStatus> {
+        let object_type = {
+            let mut server = server();
+            let object_type = match server.object(endpoint)?.kind {
+                Kind::Source => ObjectType::Source,
+                Kind::Destination { .. } => ObjectType::Destination,
+                _ => return Err(kMIDIWrongEndpointType)
+            };
+            let ref_cons = server.remove(endpoint).map(|entry| entry.kind.ref_cons()).unwrap_or_default();
+            wait_for_deliveries(server, &ref_cons);
+            object_type
+        };
+        notify_removed(vec![(endpoint, object_type)]);
+        Ok(())
+    }
+
+    fn flush_output(destination: MIDIObjectRef) -> Result<(), OSStatus> {
+        // Packets are never scheduled, so there is nothing to flush
+        if destination != 0 {
+            match server().object(destination)?.kind {
+                Kind::Destination { .. } => {},
+                _ => return Err(kMIDIWrongEndpointType)
+            }
+        }
+        Ok(())
+    }
+
+    fn restart() -> Result<(), OSStatus> {
+        Ok(())
+    }
+
+    // The host clock counts nanoseconds, so the timebase is 1:1
+    fn host_time() -> MIDITimeStamp {
+        (time_source())()
+    }
+
+    fn host_timebase() -> (u32, u32) {
+        (1, 1)
+    }
+
+    fn thru_connection_create(persistent_owner_id: Option<&str>, params: &[u8]) -> Result<MIDIObjectRef, OSStatus> {
+        ThruConnectionParams::from_bytes(params).map_err(|_| kMIDIUnknownError)?;
+        let kind = Kind::ThruConnection {
+            persistent_owner_id: persistent_owner_id.map(|owner_id| owner_id.to_string()),
+            params: params.to_vec(),
+        };
+        let connection = server().add(0, kind, "");
+        notify_all(vec![Notification::ThruConnectionsChanged]);
+        Ok(connection)
+    }
+
+    fn thru_connection_dispose(connection: MIDIObjectRef) -> Result<(), OSStatus> {
+        {
+            let mut server = server();
+            match server.object(connection)?.kind {
+                Kind::ThruConnection { .. } => {},
+                _ => return Err(kMIDIObjectNotFound)
+            }
+            server.remove(connection);
+        }
+        notify_all(vec![Notification::ThruConnectionsChanged]);
+        Ok(())
+    }
+
+    fn thru_connection_get_params(connection: MIDIObjectRef) -> Result<Vec<u8>, OSStatus> {
+        match server().object(connection)?.kind {
+            Kind::ThruConnection { ref params, .. } => Ok(params.clone()),
+            _ => Err(kMIDIObjectNotFound)
+        }
+    }
+
+    fn thru_connection_set_params(connection: MIDIObjectRef, params: &[u8]) -> Result<(), OSStatus> {
+        ThruConnectionParams::from_bytes(params).map_err(|_| kMIDIUnknownError)?;
+        {
+            let mut server = server();
+            match server.objects.get_mut(&connection) {
+                Some(&mut Entry { kind: Kind::ThruConnection { params: ref mut current, .. }, .. }) => {
+                    *current = params.to_vec();
+                },
+                _ => return Err(kMIDIObjectNotFound)
+            }
+        }
+        notify_all(vec![Notification::ThruConnectionsChanged]);
+        Ok(())
+    }
+
+    fn thru_connection_find(persistent_owner_id: &str) -> Result<Vec<MIDIObjectRef>, OSStatus> {
+        let server = server();
+        let mut connections: Vec<MIDIObjectRef> = server.objects.iter().filter_map(|(&object_ref, entry)| match entry.kind {
+            Kind::ThruConnection { persistent_owner_id: Some(ref owner_id), .. } if owner_id == persistent_owner_id => Some(object_ref),
+            _ => None
+        }).collect();
+        connections.sort();
+        Ok(connections)
+    }
+
+    fn number_of_sources() -> usize {
+        server().sources.len()
+    }
+
+    fn source(index: usize) -> MIDIObjectRef {
+        server().sources.get(index).cloned().unwrap_or(0)
+    }
+
+    fn number_of_destinations() -> usize {
+        server().destinations.len()
+    }
+
+    fn destination(index: usize) -> MIDIObjectRef {
+        server().destinations.get(index).cloned().unwrap_or(0)
+    }
+
+    fn number_of_devices() -> usize {
+        server().devices(false).len()
+    }
+
+    fn device(index: usize) -> MIDIObjectRef {
+        server().devices(false).get(index).cloned().unwrap_or(0)
+    }
+
+    fn number_of_external_devices() -> usize {
+        server().devices(true).len()
+    }
+
+    fn external_device(index: usize) -> MIDIObjectRef {
+        server().devices(true).get(index).cloned().unwrap_or(0)
+    }
+
+    fn device_number_of_entities(device: MIDIObjectRef) -> usize {
+        server().entities(device).len()
+    }
+
+    fn device_entity(device: MIDIObjectRef, index: usize) -> MIDIObjectRef {
+        server().entities(device).get(index).cloned().unwrap_or(0)
+    }
+
+    fn entity_number_of_sources(entity: MIDIObjectRef) -> usize {
+        server().entity_sources(entity).len()
+    }
+
+    fn entity_source(entity: MIDIObjectRef, index: usize) -> MIDIObjectRef {
+        server().entity_sources(entity).get(index).cloned().unwrap_or(0)
+    }
+
+    fn entity_number_of_destinations(entity: MIDIObjectRef) -> usize {
+        server().entity_destinations(entity).len()
+    }
+
+    fn entity_destination(entity: MIDIObjectRef, index: usize) -> MIDIObjectRef {
+        server().entity_destinations(entity).get(index).cloned().unwrap_or(0)
+    }
+
+    fn endpoint_entity(endpoint: MIDIObjectRef) -> Result<MIDIObjectRef, OSStatus> {
+        server().parent(endpoint, |kind| match *kind { Kind::Entity { .. } => true, _ => false })
+    }
+
+    fn entity_device(entity: MIDIObjectRef) -> Result<MIDIObjectRef, OSStatus> {
+        server().parent(entity, |kind| match *kind { Kind::Device { .. } => true, _ => false })
+    }
+
+    fn object_find_by_unique_id(unique_id: SInt32) -> Result<(MIDIObjectRef, MIDIObjectType), OSStatus> {
+        let server = server();
+        let (&object_ref, entry) = server.objects.iter()
+            .find(|&(_, entry)| match entry.properties.get("uniqueID") {
+                Some(&PropertyValue::Integer(value)) => value == unique_id,
+                _ => false
+            })
+            .ok_or(kMIDIObjectNotFound)?;
+        // The endpoints of external entities are external endpoints
+        let external = match server.objects.get(&entry.owner) {
+            Some(&Entry { kind: Kind::Entity { external }, .. }) => external,
+            _ => false
+        };
+        let object_type = match entry.object_type() {
+            ObjectType::Source if external => ObjectType::ExternalSource,
+            ObjectType::Destination if external => ObjectType::ExternalDestination,
+            object_type => object_type,
+        };
+        Ok((object_ref, object_type.to_raw()))
+    }
+
+    fn object_get_string_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<String, OSStatus> {
+        match server().object(object)?.properties.get(key.as_str()) {
+            Some(PropertyValue::String(value)) => Ok(value.clone()),
+            Some(_) => Err(kMIDIWrongPropertyType),
+            None => Err(kMIDIUnknownProperty)
+        }
+    }
+
+    fn object_set_string_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &str) -> Result<(), OSStatus> {
+        set_property(object, key, PropertyValue::String(value.to_string()))
+    }
+
+    fn object_get_integer_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<SInt32, OSStatus> {
+        match server().object(object)?.properties.get(key.as_str()) {
+            Some(PropertyValue::Integer(value)) => Ok(*value),
+            Some(_) => Err(kMIDIWrongPropertyType),
+            None => Err(kMIDIUnknownProperty)
+        }
+    }
+
+    fn object_set_integer_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: SInt32) -> Result<(), OSStatus> {
+        set_property(object, key, PropertyValue::Integer(value))
+    }
+
+    fn object_get_data_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<Vec<u8>, OSStatus> {
+        match server().object(object)?.properties.get(key.as_str()) {
+            Some(PropertyValue::Data(value)) => Ok(value.clone()),
+            Some(_) => Err(kMIDIWrongPropertyType),
+            None => Err(kMIDIUnknownProperty)
+        }
+    }
+
+    fn object_set_data_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &[u8]) -> Result<(), OSStatus> {
+        set_property(object, key, PropertyValue::Data(value.to_vec()))
+    }
+
+    fn object_get_dictionary_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<BTreeMap<String, ::PropertyValue>, OSStatus> {
+        match server().object(object)?.properties.get(key.as_str()) {
+            Some(PropertyValue::Dictionary(value)) => Ok(value.clone()),
+            Some(_) => Err(kMIDIWrongPropertyType),
+            None => Err(kMIDIUnknownProperty)
+        }
+    }
+
+    fn object_set_dictionary_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &BTreeMap<String, ::PropertyValue>) -> Result<(), OSStatus> {
+        set_property(object, key, PropertyValue::Dictionary(value.clone()))
+    }
+
+    fn object_get_properties(object: MIDIObjectRef, deep: bool) -> Result<BTreeMap<String, ::PropertyValue>, OSStatus> {
+        server().properties(object, deep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::raw::c_void;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use backend::sys::MIDISysexSendRequest;
+    use {
+        Client, Destination, Destinations, Error, EventBuffer, Notification, ObjectType, PacketBuffer, PropertyKey,
+        PropertyValue, Protocol, Sources, ThruConnection, ThruConnectionParams, VirtualDestination,
+    };
+    use super::SysExPump;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn virtual_endpoints_are_listed_while_alive() {
+        let client = Client::new("Loopback Client").unwrap();
+        let source = client.virtual_source("Loopback Source").unwrap();
+        let destination = client.virtual_destination("Loopback Destination", |_| ()).unwrap();
+        let source_id = source.unique_id();
+        let destination_id = destination.unique_id();
+
+        assert!(Sources.into_iter().any(|s| s.unique_id() == source_id));
+        assert!(Destinations.into_iter().any(|d| d.unique_id() == destination_id));
+
+        drop(source);
+        drop(destination);
+
+        assert!(!Sources.into_iter().any(|s| s.unique_id() == source_id));
+        assert!(!Destinations.into_iter().any(|d| d.unique_id() == destination_id));
+    }
+
+    #[test]
+    fn dispose_waits_for_deliveries_from_other_threads() {
+        let client = Client::new("Loopback Client").unwrap();
+        let (started_tx, started_rx) = mpsc::channel();
+        let finished = Arc::new(AtomicBool::new(false));
+        let callback_finished = finished.clone();
+        let virtual_destination = client.virtual_destination("Loopback Slow Destination", move |_| {
+            started_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            callback_finished.store(true, Ordering::SeqCst);
+        }).unwrap();
+        let output_port = client.output_port("Loopback Output").unwrap();
+        let destination = Destination::find_by_name("Loopback Slow Destination").unwrap();
+        let sender = thread::spawn(move || {
+            output_port.send(&destination, &PacketBuffer::new(0, &[0x90, 0x40, 0x7f])).unwrap();
+        });
+
+        started_rx.recv_timeout(TIMEOUT).unwrap();
+        drop(virtual_destination);
+        assert!(finished.load(Ordering::SeqCst));
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn client_dispose_removes_its_endpoints() {
+        let client = Client::new("Loopback Client").unwrap();
+        let source = client.virtual_source("Loopback Source").unwrap();
+        let source_ref = source.endpoint.object.0;
+        drop(client);
+
+        assert!(!Sources.into_iter().any(|s| s.endpoint.object.0 == source_ref));
+    }
+
+    #[test]
+    fn notifications_for_added_and_removed_endpoints() {
+        let (tx, rx) = mpsc::channel();
+        let client = Client::new_with_notifications("Loopback Client", move |notification| {
+            match notification {
+                Notification::ObjectAdded(info) => tx.send((true, info.child.0, info.child_type)).unwrap(),
+                Notification::ObjectRemoved(info) => tx.send((false, info.child.0, info.child_type)).unwrap(),
+                _ => {}
+            }
+        }).unwrap();
+
+        let destination = client.virtual_destination("Loopback Destination", |_| ()).unwrap();
+        let destination_ref = destination.endpoint.object.0;
+        drop(destination);
+
+        let mut events = rx.try_iter().filter(|&(_, object_ref, _)| object_ref == destination_ref);
+        assert_eq!(events.next(), Some((true, destination_ref, ObjectType::Destination)));
+        assert_eq!(events.next(), Some((false, destination_ref, ObjectType::Destination)));
+    }
+
+    #[test]
+    fn notification_for_property_changed() {
+        let (tx, rx) = mpsc::channel();
+        let client = Client::new_with_notifications("Loopback Client", move |notification| {
+            if let Notification::PropertyChanged(info) = notification {
+                tx.send((info.object.0, info.object_type, info.property_name.clone())).unwrap();
+            }
+        }).unwrap();
+
+        let source = client.virtual_source("Loopback Source").unwrap();
+        source.set_property_integer("custom", 1).unwrap();
+
+        let source_ref = source.endpoint.object.0;
+        let changed = rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(changed, (source_ref, ObjectType::Source, "custom".to_string()));
+    }
+
+    #[test]
+    fn notification_for_watched_property_changed() {
+        let (tx, rx) = mpsc::channel();
+        let watched = [PropertyKey::Name, PropertyKey::Private];
+        let client = Client::new_with_watched_properties("Loopback Client", &watched, move |notification| {
+            if let Notification::PropertyChanged(info) = notification {
+                tx.send((info.object.0, info.property(), info.old_value.clone(), info.new_value.clone())).unwrap();
+            }
+        }).unwrap();
+
+        let source = client.virtual_source("Loopback Source").unwrap();
+        source.set_property_string("name", "Renamed Source").unwrap();
+        source.set_property_boolean("private", true).unwrap();
+        source.set_property_integer("custom", 1).unwrap();
+
+        let source_ref = source.endpoint.object.0;
+        let changes: Vec<_> = rx.try_iter().filter(|change| change.0 == source_ref).collect();
+        assert_eq!(changes, vec![
+            (source_ref, PropertyKey::Name,
+             Some(PropertyValue::String("Loopback Source".to_string())), Some(PropertyValue::String("Renamed Source".to_string()))),
+            (source_ref, PropertyKey::Private, None, Some(PropertyValue::Boolean(true))),
+            (source_ref, PropertyKey::Other("custom".to_string()), None, None),
+        ]);
+    }
+
+    #[test]
+    fn notifications_for_thru_connections() {
+        let (tx, rx) = mpsc::channel();
+        let _client = Client::new_with_notifications("Loopback Client", move |notification| {
+            if let Notification::ThruConnectionsChanged = notification {
+                tx.send(()).unwrap();
+            }
+        }).unwrap();
+
+        let connection = ThruConnection::create(&ThruConnectionParams::new()).unwrap();
+        connection.set_params(&ThruConnectionParams::new().filter_out_sysex(true)).unwrap();
+        connection.dispose().unwrap();
+
+        // Other tests may be changing thru connections concurrently
+        assert!(rx.try_iter().count() >= 3);
+    }
+
+    #[test]
+    fn midi2_protocol_is_translated() {
+        let client = Client::new("Loopback Client").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let destination = client.virtual_destination("Loopback Destination", move |packet_list| {
+            for packet in packet_list.iter() {
+                tx.send(packet.data().to_vec()).unwrap();
+            }
+        }).unwrap();
+        let destination = Destinations.into_iter().find(|d| d.unique_id() == destination.unique_id()).unwrap();
+        let output_port = client.output_port("Loopback Output").unwrap();
+        let mut event_list = EventBuffer::new(Protocol::Midi20);
+        event_list.push_data(0, &[0x4090_3c00, 0xffff_0000]);
+        output_port.send_event_list(&destination, &event_list).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), vec![0x90, 0x3c, 0x7f]);
+
+        let source = client.virtual_source("Loopback Source").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let input_port = client.input_port_with_protocol("Loopback Input", Protocol::Midi20, move |event_list| {
+            for packet in event_list.iter() {
+                tx.send((event_list.protocol(), packet.data().to_vec())).unwrap();
+            }
+        }).unwrap();
+        input_port.connect_source(&Sources.into_iter().find(|s| s.unique_id() == source.unique_id()).unwrap()).unwrap();
+        source.received(&PacketBuffer::new(0, &[0x90, 0x3c, 0x40])).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), (Protocol::Midi20, vec![0x4090_3c00, 0x8000_0000]));
+    }
+
+    #[test]
+    fn unknown_protocols_are_not_supported() {
+        let client = Client::new("Loopback Client").unwrap();
+        let destination = client.virtual_destination("Loopback Destination", |_| ()).unwrap();
+        let destination = Destinations.into_iter().find(|d| d.unique_id() == destination.unique_id()).unwrap();
+        let output_port = client.output_port("Loopback Output").unwrap();
+
+        assert_eq!(client.input_port_with_protocol("Loopback Input", Protocol::Unknown(3), |_| ()).err(), Some(Error::UnknownError));
+        let mut event_list = EventBuffer::new(Protocol::Unknown(3));
+        event_list.push_data(0, &[0x4090_3c00, 0xffff_0000]);
+        assert_eq!(output_port.send_event_list(&destination, &event_list), Err(Error::UnknownError));
+    }
+
+    #[test]
+    fn property_errors() {
+        let client = Client::new("Loopback Client").unwrap();
+        let source = client.virtual_source("Loopback Source").unwrap();
+
+        assert_eq!(source.get_property_integer("undefined"), Err(Error::UnknownProperty));
+
+        let source_ref = source.endpoint.object.0;
+        drop(source);
+        assert_eq!(::Object(source_ref).get_property_string("name"), Err(Error::ObjectNotFound));
+    }
+
+    extern "C" fn set_completed(request: *mut MIDISysexSendRequest) {
+        unsafe { (*((*request).completionRefCon as *const AtomicBool)).store(true, Ordering::SeqCst) };
+    }
+
+    fn sysex_request(destination: &VirtualDestination, data: &[u8], completed: &AtomicBool) -> MIDISysexSendRequest {
+        MIDISysexSendRequest {
+            destination: destination.endpoint.object.0,
+            data: data.as_ptr(),
+            bytesToSend: data.len() as _,
+            complete: 0,
+            reserved: [0; 3],
+            completionProc: Some(set_completed),
+            completionRefCon: completed as *const _ as *mut c_void,
+        }
+    }
+
+    #[test]
+    fn sysex_pump_sends_chunks_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let client = Client::new("Loopback Client").unwrap();
+        let destination = client.virtual_destination("Loopback Destination", move |packet_list| {
+            for packet in packet_list.iter() {
+                tx.send(packet.data().to_vec()).unwrap();
+            }
+        }).unwrap();
+
+        let data: Vec<u8> = (0..600).map(|i| (i % 128) as u8).collect();
+        let completed = AtomicBool::new(false);
+        let mut request = sysex_request(&destination, &data, &completed);
+        let mut pump = SysExPump { request: &mut request };
+        unsafe {
+            assert_eq!(pump.send_next_chunk(), Some(Duration::from_micros(81920)));
+            assert_eq!(pump.send_next_chunk(), Some(Duration::from_micros(81920)));
+            assert_eq!(pump.send_next_chunk(), Some(Duration::from_micros(28160)));
+            assert_eq!(pump.send_next_chunk(), None);
+            pump.finish();
+        }
+
+        assert!(completed.load(Ordering::SeqCst));
+        assert_eq!((request.complete, request.bytesToSend), (1, 0));
+        let chunks: Vec<Vec<u8>> = rx.try_iter().collect();
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), vec![256, 256, 88]);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn sysex_pump_stops_when_cancelled() {
+        let client = Client::new("Loopback Client").unwrap();
+        let destination = client.virtual_destination("Loopback Destination", |_| ()).unwrap();
+
+        let data = vec![0; 600];
+        let completed = AtomicBool::new(false);
+        let mut request = sysex_request(&destination, &data, &completed);
+        let request_ptr = &mut request as *mut MIDISysexSendRequest;
+        let mut pump = SysExPump { request: request_ptr };
+        unsafe {
+            assert!(pump.send_next_chunk().is_some());
+            (*request_ptr).complete = 1;
+            assert_eq!(pump.send_next_chunk(), None);
+            pump.finish();
+        }
+
+        assert!(completed.load(Ordering::SeqCst));
+        assert_eq!(request.bytesToSend, 344);
+    }
+}
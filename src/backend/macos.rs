@@ -0,0 +1,707 @@
+use core_foundation::{
+    array::{
+        CFArray,
+        CFArrayRef,
+    },
+    boolean::{
+        CFBoolean,
+        CFBooleanRef,
+        kCFBooleanTrue,
+    },
+    data::{
+        CFData,
+        CFDataRef,
+    },
+    dictionary::{
+        CFDictionary,
+        CFDictionaryRef,
+    },
+    number::{
+        CFNumber,
+        CFNumberRef,
+    },
+    string::{
+        CFString,
+        CFStringRef,
+    },
+    base::{
+        Boolean,
+        CFGetRetainCount,
+        CFGetTypeID,
+        CFType,
+        CFTypeRef,
+        CFIndex,
+        OSStatus,
+        TCFType,
+    }
+};
+
+use block::{Block, ConcreteBlock};
+
+use coremidi_sys::{
+    ItemCount,
+    MIDIClientCreate,
+    MIDIClientDispose,
+    MIDIDestinationCreate,
+    MIDIDeviceGetEntity,
+    MIDIDeviceGetNumberOfEntities,
+    MIDIEndpointDispose,
+    MIDIEndpointGetEntity,
+    MIDIEntityGetDestination,
+    MIDIEntityGetDevice,
+    MIDIEntityGetNumberOfDestinations,
+    MIDIEntityGetNumberOfSources,
+    MIDIEntityGetSource,
+    MIDIFlushOutput,
+    MIDIGetDestination,
+    MIDIGetDevice,
+    MIDIGetExternalDevice,
+    MIDIGetNumberOfDestinations,
+    MIDIGetNumberOfDevices,
+    MIDIGetNumberOfExternalDevices,
+    MIDIGetNumberOfSources,
+    MIDIGetSource,
+    MIDIInputPortCreate,
+    MIDINotification,
+    MIDIObjectFindByUniqueID,
+    MIDIObjectGetDataProperty,
+    MIDIObjectGetDictionaryProperty,
+    MIDIObjectGetIntegerProperty,
+    MIDIObjectGetProperties,
+    MIDIObjectGetStringProperty,
+    MIDIObjectRef,
+    MIDIObjectSetDataProperty,
+    MIDIObjectSetDictionaryProperty,
+    MIDIObjectSetIntegerProperty,
+    MIDIObjectSetStringProperty,
+    MIDIObjectType,
+    MIDIOutputPortCreate,
+    MIDIPacketList,
+    MIDIPortConnectSource,
+    MIDIPortDisconnectSource,
+    MIDIPortDispose,
+    MIDIReceived,
+    MIDIRestart,
+    MIDISend,
+    MIDISendSysex,
+    MIDISysexSendRequest,
+    MIDISourceCreate,
+    MIDITimeStamp,
+    SInt32,
+    kMIDIUnknownError,
+};
+
+use std::{
+    collections::BTreeMap,
+    mem::{self, MaybeUninit},
+    os::raw::{c_char, c_void},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use {
+    notifications::Notification,
+    EventList,
+    PacketList,
+    PropertyValue,
+};
+
+use super::{
+    Backend,
+    deliver_event_list,
+    deliver_notification,
+    deliver_packets,
+};
+use super::sys::{
+    MIDIEventList,
+    MIDIProtocolID,
+};
+
+type MIDIReceiveBlock = *const Block<(*const MIDIEventList, *mut c_void), ()>;
+
+// The MIDIThruConnection and Universal MIDI Packet functions are not part of coremidi-sys.
+#[link(name = "CoreMIDI", kind = "framework")]
+extern "C" {
+    fn MIDIThruConnectionCreate(inPersistentOwnerID: CFStringRef, inConnectionParams: CFDataRef, outConnection: *mut MIDIObjectRef) -> OSStatus;
+    fn MIDIThruConnectionDispose(connection: MIDIObjectRef) -> OSStatus;
+    fn MIDIThruConnectionGetParams(connection: MIDIObjectRef, outConnectionParams: *mut CFDataRef) -> OSStatus;
+    fn MIDIThruConnectionSetParams(connection: MIDIObjectRef, inConnectionParams: CFDataRef) -> OSStatus;
+    fn MIDIThruConnectionFind(inPersistentOwnerID: CFStringRef, outConnectionList: *mut CFDataRef) -> OSStatus;
+}
+
+// The Universal MIDI Packet functions were added in macOS 11, so they are looked up when first used
+// instead of being linked, and calling them on older systems fails with `kMIDIUnknownError`.
+macro_rules! weak_function {
+    ($getter:ident, $name:ident: fn($($argument:ty),*) -> $result:ty) => {
+        fn $getter() -> Option<unsafe extern "C" fn($($argument),*) -> $result> {
+            static ADDRESS: AtomicUsize = AtomicUsize::new(0);
+            let address = match ADDRESS.load(Ordering::Relaxed) {
+                0 => {
+                    let symbol = concat!(stringify!($name), "\0");
+                    let found = unsafe { dlsym(RTLD_DEFAULT, symbol.as_ptr() as *const c_char) } as usize;
+                    ADDRESS.store(found, Ordering::Relaxed);
+                    found
+                },
+                address => address,
+            };
+            match address {
+                0 => None,
+                address => Some(unsafe { mem::transmute(address) }),
+            }
+        }
+    }
+}
+
+weak_function!(midi_input_port_create_with_protocol,
+    MIDIInputPortCreateWithProtocol: fn(MIDIObjectRef, CFStringRef, MIDIProtocolID, *mut MIDIObjectRef, MIDIReceiveBlock) -> OSStatus);
+weak_function!(midi_send_event_list,
+    MIDISendEventList: fn(MIDIObjectRef, MIDIObjectRef, *const MIDIEventList) -> OSStatus);
+
+// RTLD_DEFAULT in <dlfcn.h>
+const RTLD_DEFAULT: *mut c_void = -2isize as *mut c_void;
+
+extern "C" {
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+}
+
+#[repr(C)]
+struct MachTimebaseInfo {
+    numer: u32,
+    denom: u32,
+}
+
+// Not part of core-foundation-sys 0.2
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFNumberIsFloatType(number: CFNumberRef) -> Boolean;
+}
+
+extern "C" {
+    fn mach_absolute_time() -> u64;
+    fn mach_timebase_info(info: *mut MachTimebaseInfo) -> i32;
+}
+
+/// The backend that forwards every operation to the CoreMIDI framework.
+///
+pub struct CoreMidi;
+
+impl CoreMidi {
+    extern "C" fn notify_proc(notification_ptr: *const MIDINotification, ref_con: *mut c_void) {
+        unsafe {
+            match Notification::from(&*notification_ptr) {
+                Ok(notification) => deliver_notification(ref_con, &notification),
+                Err(_) => {} // Skip unknown notifications
+            }
+        }
+    }
+
+    extern "C" fn read_proc(pktlist: *const MIDIPacketList, read_proc_ref_con: *mut c_void, src_conn_ref_con: *mut c_void) {
+        unsafe {
+            let packet_list = &*(pktlist as *const PacketList);
+            deliver_packets(read_proc_ref_con, src_conn_ref_con, packet_list);
+        }
+    }
+}
+
+impl Backend for CoreMidi {
+    fn client_create(name: &str, notify_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        let client_name = CFString::new(name);
+        let notify_proc = if notify_ref_con.is_null() {
+            None
+        } else {
+            Some(Self::notify_proc as extern "C" fn(_, _))
+        };
+        let mut client_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIClientCreate(
+                client_name.as_concrete_TypeRef(),
+                notify_proc,
+                notify_ref_con,
+                client_ref.as_mut_ptr()
+            )
+        };
+        result_from_status(status, || unsafe { client_ref.assume_init() })
+    }
+
+    fn client_dispose(client: MIDIObjectRef) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIClientDispose(client) })
+    }
+
+    fn output_port_create(client: MIDIObjectRef, name: &str) -> Result<MIDIObjectRef, OSStatus> {
+        let port_name = CFString::new(name);
+        let mut port_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIOutputPortCreate(
+                client,
+                port_name.as_concrete_TypeRef(),
+                port_ref.as_mut_ptr()
+            )
+        };
+        result_from_status(status, || unsafe { port_ref.assume_init() })
+    }
+
+    fn input_port_create(client: MIDIObjectRef, name: &str, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        let port_name = CFString::new(name);
+        let mut port_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIInputPortCreate(
+                client,
+                port_name.as_concrete_TypeRef(),
+                Some(Self::read_proc as extern "C" fn(_, _, _)),
+                read_ref_con,
+                port_ref.as_mut_ptr()
+            )
+        };
+        result_from_status(status, || unsafe { port_ref.assume_init() })
+    }
+
+    fn input_port_create_with_protocol(client: MIDIObjectRef, name: &str, protocol: MIDIProtocolID, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        let port_name = CFString::new(name);
+        // The block is the only way to receive event lists, so it carries the ref con that a read proc would get
+        let read_ref_con = read_ref_con as usize;
+        let receive_block = ConcreteBlock::new(move |evtlist: *const MIDIEventList, src_conn_ref_con: *mut c_void| {
+            unsafe {
+                let event_list = &*(evtlist as *const EventList);
+                deliver_event_list(read_ref_con as *mut c_void, src_conn_ref_con, event_list);
+            }
+        }).copy();
+        let input_port_create_with_protocol = midi_input_port_create_with_protocol().ok_or(kMIDIUnknownError)?;
+        let mut port_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            // CoreMIDI keeps its own copy of the block
+            input_port_create_with_protocol(
+                client,
+                port_name.as_concrete_TypeRef(),
+                protocol,
+                port_ref.as_mut_ptr(),
+                &*receive_block
+            )
+        };
+        result_from_status(status, || unsafe { port_ref.assume_init() })
+    }
+
+    fn port_dispose(port: MIDIObjectRef) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIPortDispose(port) })
+    }
+
+    fn port_connect_source(port: MIDIObjectRef, source: MIDIObjectRef, conn_ref_con: *mut c_void) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIPortConnectSource(port, source, conn_ref_con) })
+    }
+
+    fn port_disconnect_source(port: MIDIObjectRef, source: MIDIObjectRef) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIPortDisconnectSource(port, source) })
+    }
+
+    fn send(port: MIDIObjectRef, destination: MIDIObjectRef, packet_list: &PacketList) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDISend(port, destination, packet_list.as_ptr()) })
+    }
+
+    fn send_event_list(port: MIDIObjectRef, destination: MIDIObjectRef, event_list: &EventList) -> Result<(), OSStatus> {
+        let send_event_list = midi_send_event_list().ok_or(kMIDIUnknownError)?;
+        unit_result_from_status(unsafe { send_event_list(port, destination, event_list.as_ptr()) })
+    }
+
+    fn received(source: MIDIObjectRef, packet_list: &PacketList) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIReceived(source, packet_list.as_ptr()) })
+    }
+
+    unsafe fn send_sysex(request: *mut MIDISysexSendRequest) -> Result<(), OSStatus> {
+        unit_result_from_status(MIDISendSysex(request))
+    }
+
+    fn source_create(client: MIDIObjectRef, name: &str) -> Result<MIDIObjectRef, OSStatus> {
+        let virtual_source_name = CFString::new(name);
+        let mut virtual_source = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDISourceCreate(
+                client,
+                virtual_source_name.as_concrete_TypeRef(),
+                virtual_source.as_mut_ptr()
+            )
+        };
+        result_from_status(status, || unsafe { virtual_source.assume_init() })
+    }
+
+    fn destination_create(client: MIDIObjectRef, name: &str, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus> {
+        let virtual_destination_name = CFString::new(name);
+        let mut virtual_destination = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIDestinationCreate(
+                client,
+                virtual_destination_name.as_concrete_TypeRef(),
+                Some(Self::read_proc as extern "C" fn(_, _, _)),
+                read_ref_con,
+                virtual_destination.as_mut_ptr()
+            )
+        };
+        result_from_status(status, || unsafe { virtual_destination.assume_init() })
+    }
+
+    fn endpoint_dispose(endpoint: MIDIObjectRef) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIEndpointDispose(endpoint) })
+    }
+
+    fn flush_output(destination: MIDIObjectRef) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIFlushOutput(destination) })
+    }
+
+    fn restart() -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIRestart() })
+    }
+
+    fn host_time() -> MIDITimeStamp {
+        unsafe { mach_absolute_time() }
+    }
+
+    fn host_timebase() -> (u32, u32) {
+        let mut info = MachTimebaseInfo { numer: 1, denom: 1 };
+        unsafe { mach_timebase_info(&mut info) };
+        (info.numer, info.denom)
+    }
+
+    fn thru_connection_create(persistent_owner_id: Option<&str>, params: &[u8]) -> Result<MIDIObjectRef, OSStatus> {
+        let owner_id = persistent_owner_id.map(CFString::new);
+        let owner_id_ref = owner_id.as_ref().map_or(ptr::null(), |owner_id| owner_id.as_concrete_TypeRef());
+        let params_data = CFData::from_buffer(params);
+        let mut connection_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIThruConnectionCreate(
+                owner_id_ref,
+                params_data.as_concrete_TypeRef(),
+                connection_ref.as_mut_ptr()
+            )
+        };
+        result_from_status(status, || unsafe { connection_ref.assume_init() })
+    }
+
+    fn thru_connection_dispose(connection: MIDIObjectRef) -> Result<(), OSStatus> {
+        unit_result_from_status(unsafe { MIDIThruConnectionDispose(connection) })
+    }
+
+    fn thru_connection_get_params(connection: MIDIObjectRef) -> Result<Vec<u8>, OSStatus> {
+        let mut data_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIThruConnectionGetParams(connection, data_ref.as_mut_ptr())
+        };
+        result_from_status(status, || {
+            let data: CFData = unsafe { TCFType::wrap_under_create_rule(data_ref.assume_init()) };
+            data.bytes().to_vec()
+        })
+    }
+
+    fn thru_connection_set_params(connection: MIDIObjectRef, params: &[u8]) -> Result<(), OSStatus> {
+        let params_data = CFData::from_buffer(params);
+        let status = unsafe {
+            MIDIThruConnectionSetParams(connection, params_data.as_concrete_TypeRef())
+        };
+        unit_result_from_status(status)
+    }
+
+    fn thru_connection_find(persistent_owner_id: &str) -> Result<Vec<MIDIObjectRef>, OSStatus> {
+        let owner_id = CFString::new(persistent_owner_id);
+        let mut data_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIThruConnectionFind(owner_id.as_concrete_TypeRef(), data_ref.as_mut_ptr())
+        };
+        result_from_status(status, || {
+            let data: CFData = unsafe { TCFType::wrap_under_create_rule(data_ref.assume_init()) };
+            // The data is a packed array of connection references
+            data.bytes()
+                .chunks_exact(mem::size_of::<MIDIObjectRef>())
+                .map(|chunk| MIDIObjectRef::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect()
+        })
+    }
+
+    fn number_of_sources() -> usize {
+        unsafe { MIDIGetNumberOfSources() as usize }
+    }
+
+    fn source(index: usize) -> MIDIObjectRef {
+        unsafe { MIDIGetSource(index as ItemCount) }
+    }
+
+    fn number_of_destinations() -> usize {
+        unsafe { MIDIGetNumberOfDestinations() as usize }
+    }
+
+    fn destination(index: usize) -> MIDIObjectRef {
+        unsafe { MIDIGetDestination(index as ItemCount) }
+    }
+
+    fn number_of_devices() -> usize {
+        unsafe { MIDIGetNumberOfDevices() as usize }
+    }
+
+    fn device(index: usize) -> MIDIObjectRef {
+        unsafe { MIDIGetDevice(index as ItemCount) }
+    }
+
+    fn number_of_external_devices() -> usize {
+        unsafe { MIDIGetNumberOfExternalDevices() as usize }
+    }
+
+    fn external_device(index: usize) -> MIDIObjectRef {
+        unsafe { MIDIGetExternalDevice(index as ItemCount) }
+    }
+
+    fn device_number_of_entities(device: MIDIObjectRef) -> usize {
+        unsafe { MIDIDeviceGetNumberOfEntities(device) as usize }
+    }
+
+    fn device_entity(device: MIDIObjectRef, index: usize) -> MIDIObjectRef {
+        unsafe { MIDIDeviceGetEntity(device, index as ItemCount) }
+    }
+
+    fn entity_number_of_sources(entity: MIDIObjectRef) -> usize {
+        unsafe { MIDIEntityGetNumberOfSources(entity) as usize }
+    }
+
+    fn entity_source(entity: MIDIObjectRef, index: usize) -> MIDIObjectRef {
+        unsafe { MIDIEntityGetSource(entity, index as ItemCount) }
+    }
+
+    fn entity_number_of_destinations(entity: MIDIObjectRef) -> usize {
+        unsafe { MIDIEntityGetNumberOfDestinations(entity) as usize }
+    }
+
+    fn entity_destination(entity: MIDIObjectRef, index: usize) -> MIDIObjectRef {
+        unsafe { MIDIEntityGetDestination(entity, index as ItemCount) }
+    }
+
+    fn endpoint_entity(endpoint: MIDIObjectRef) -> Result<MIDIObjectRef, OSStatus> {
+        let mut entity_ref = MaybeUninit::uninit();
+        let status = unsafe { MIDIEndpointGetEntity(endpoint, entity_ref.as_mut_ptr()) };
+        result_from_status(status, || unsafe { entity_ref.assume_init() })
+    }
+
+    fn entity_device(entity: MIDIObjectRef) -> Result<MIDIObjectRef, OSStatus> {
+        let mut device_ref = MaybeUninit::uninit();
+        let status = unsafe { MIDIEntityGetDevice(entity, device_ref.as_mut_ptr()) };
+        result_from_status(status, || unsafe { device_ref.assume_init() })
+    }
+
+    fn object_find_by_unique_id(unique_id: SInt32) -> Result<(MIDIObjectRef, MIDIObjectType), OSStatus> {
+        let mut object_ref = MaybeUninit::uninit();
+        let mut object_type = MaybeUninit::uninit();
+        let status = unsafe { MIDIObjectFindByUniqueID(unique_id, object_ref.as_mut_ptr(), object_type.as_mut_ptr()) };
+        result_from_status(status, || unsafe { (object_ref.assume_init(), object_type.assume_init()) })
+    }
+
+    fn object_get_string_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<String, OSStatus> {
+        let mut string_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIObjectGetStringProperty(object, key.as_string_ref(), string_ref.as_mut_ptr())
+        };
+        result_from_status(status, || {
+            let string_ref = unsafe { string_ref.assume_init() };
+            if string_ref.is_null() { return "".to_string() };
+            let cf_string: CFString = unsafe { TCFType::wrap_under_create_rule(string_ref) };
+            cf_string.to_string()
+        })
+    }
+
+    fn object_set_string_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &str) -> Result<(), OSStatus> {
+        let string = CFString::new(value);
+        let status = unsafe {
+            MIDIObjectSetStringProperty(object, key.as_string_ref(), string.as_concrete_TypeRef())
+        };
+        unit_result_from_status(status)
+    }
+
+    fn object_get_integer_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<SInt32, OSStatus> {
+        let mut value = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIObjectGetIntegerProperty(object, key.as_string_ref(), value.as_mut_ptr())
+        };
+        result_from_status(status, || unsafe { value.assume_init() })
+    }
+
+    fn object_set_integer_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: SInt32) -> Result<(), OSStatus> {
+        let status = unsafe {
+            MIDIObjectSetIntegerProperty(object, key.as_string_ref(), value)
+        };
+        unit_result_from_status(status)
+    }
+
+    fn object_get_data_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<Vec<u8>, OSStatus> {
+        let mut data_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIObjectGetDataProperty(object, key.as_string_ref(), data_ref.as_mut_ptr())
+        };
+        result_from_status(status, || {
+            let data_ref = unsafe { data_ref.assume_init() };
+            if data_ref.is_null() { return Vec::new() };
+            let data: CFData = unsafe { TCFType::wrap_under_create_rule(data_ref) };
+            data.bytes().to_vec()
+        })
+    }
+
+    fn object_set_data_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &[u8]) -> Result<(), OSStatus> {
+        let data = CFData::from_buffer(value);
+        let status = unsafe {
+            MIDIObjectSetDataProperty(object, key.as_string_ref(), data.as_concrete_TypeRef())
+        };
+        unit_result_from_status(status)
+    }
+
+    fn object_get_dictionary_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<BTreeMap<String, PropertyValue>, OSStatus> {
+        let mut dictionary_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIObjectGetDictionaryProperty(object, key.as_string_ref(), dictionary_ref.as_mut_ptr())
+        };
+        result_from_status(status, || {
+            let dictionary_ref: CFDictionaryRef = unsafe { dictionary_ref.assume_init() };
+            if dictionary_ref.is_null() { return BTreeMap::new() };
+            let dictionary: CFDictionary = unsafe { TCFType::wrap_under_create_rule(dictionary_ref) };
+            dictionary_from_cf(&dictionary)
+        })
+    }
+
+    fn object_set_dictionary_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &BTreeMap<String, PropertyValue>) -> Result<(), OSStatus> {
+        let dictionary = dictionary_to_cf(value);
+        let status = unsafe {
+            MIDIObjectSetDictionaryProperty(object, key.as_string_ref(), dictionary.as_concrete_TypeRef())
+        };
+        unit_result_from_status(status)
+    }
+
+    fn object_get_properties(object: MIDIObjectRef, deep: bool) -> Result<BTreeMap<String, PropertyValue>, OSStatus> {
+        let mut properties_ref = MaybeUninit::uninit();
+        let status = unsafe {
+            MIDIObjectGetProperties(object, properties_ref.as_mut_ptr(), deep as Boolean)
+        };
+        result_from_status(status, || {
+            let properties_ref: CFTypeRef = unsafe { properties_ref.assume_init() };
+            if properties_ref.is_null() { return BTreeMap::new() };
+            let properties: CFType = unsafe { TCFType::wrap_under_create_rule(properties_ref) };
+            match unsafe { property_value_from_cf(properties.as_CFTypeRef()) } {
+                Some(PropertyValue::Dictionary(properties)) => properties,
+                _ => BTreeMap::new(),
+            }
+        })
+    }
+}
+
+/// Convert a property list value into a `PropertyValue`, or None for the types that are not property list types.
+///
+/// `value` must be a valid CoreFoundation object, which is not released.
+unsafe fn property_value_from_cf(value: CFTypeRef) -> Option<PropertyValue> {
+    let type_id = CFGetTypeID(value);
+    if type_id == CFString::type_id() {
+        let string: CFString = TCFType::wrap_under_get_rule(value as CFStringRef);
+        Some(PropertyValue::String(string.to_string()))
+    } else if type_id == CFBoolean::type_id() {
+        Some(PropertyValue::Boolean(value as CFBooleanRef == kCFBooleanTrue))
+    } else if type_id == CFNumber::type_id() {
+        let number: CFNumber = TCFType::wrap_under_get_rule(value as CFNumberRef);
+        if CFNumberIsFloatType(value as CFNumberRef) != 0 {
+            number.to_f64().map(PropertyValue::Float)
+        } else {
+            number.to_i64().map(PropertyValue::Integer)
+        }
+    } else if type_id == CFData::type_id() {
+        let data: CFData = TCFType::wrap_under_get_rule(value as CFDataRef);
+        Some(PropertyValue::Data(data.bytes().to_vec()))
+    } else if type_id == CFArray::type_id() {
+        let array: CFArray = TCFType::wrap_under_get_rule(value as CFArrayRef);
+        Some(PropertyValue::Array(array.iter().filter_map(|item| property_value_from_cf(item as CFTypeRef)).collect()))
+    } else if type_id == CFDictionary::type_id() {
+        let dictionary: CFDictionary = TCFType::wrap_under_get_rule(value as CFDictionaryRef);
+        Some(PropertyValue::Dictionary(dictionary_from_cf(&dictionary)))
+    } else {
+        None
+    }
+}
+
+/// Convert the items of a dictionary that have string keys and property list values.
+fn dictionary_from_cf(dictionary: &CFDictionary) -> BTreeMap<String, PropertyValue> {
+    let (keys, values) = dictionary.get_keys_and_values();
+    keys.into_iter().zip(values).filter_map(|(key, value)| unsafe {
+        if CFGetTypeID(key as CFTypeRef) != CFString::type_id() {
+            return None;
+        }
+        let key: CFString = TCFType::wrap_under_get_rule(key as CFStringRef);
+        property_value_from_cf(value as CFTypeRef).map(|value| (key.to_string(), value))
+    }).collect()
+}
+
+fn property_value_to_cf(value: &PropertyValue) -> CFType {
+    match value {
+        PropertyValue::String(string) => CFString::new(string).as_CFType(),
+        PropertyValue::Integer(integer) => CFNumber::from_i64(*integer).as_CFType(),
+        PropertyValue::Float(float) => CFNumber::from_f64(*float).as_CFType(),
+        PropertyValue::Boolean(true) => CFBoolean::true_value().as_CFType(),
+        PropertyValue::Boolean(false) => CFBoolean::false_value().as_CFType(),
+        PropertyValue::Data(data) => CFData::from_buffer(data).as_CFType(),
+        PropertyValue::Array(items) => {
+            let items: Vec<CFType> = items.iter().map(property_value_to_cf).collect();
+            CFArray::from_CFTypes(&items).as_CFType()
+        },
+        PropertyValue::Dictionary(dictionary) => dictionary_to_cf(dictionary).as_CFType(),
+    }
+}
+
+fn dictionary_to_cf(dictionary: &BTreeMap<String, PropertyValue>) -> CFDictionary {
+    let pairs: Vec<(CFString, CFType)> = dictionary.iter()
+        .map(|(key, value)| (CFString::new(key), property_value_to_cf(value)))
+        .collect();
+    CFDictionary::from_CFType_pairs(&pairs)
+}
+
+/// Because Property structs can be constructed from strings that have been
+/// passed in from the user or are constants CFStringRefs from CoreMidi, we
+/// need to abstract over how we store their keys.
+pub enum PropertyKeyStorage {
+    Owned(CFString),
+    Constant(CFStringRef)
+}
+
+impl PropertyKeyStorage {
+    pub fn new(name: &str) -> Self {
+        PropertyKeyStorage::Owned(CFString::new(name))
+    }
+
+    /// Note: Should only be used internally with predefined CoreMidi constants,
+    /// since it does not bump the retain count of the CFStringRef.
+    pub fn constant(string_ref: CFStringRef) -> Self {
+        PropertyKeyStorage::Constant(string_ref)
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            PropertyKeyStorage::Owned(owned) => owned.to_string(),
+            PropertyKeyStorage::Constant(constant) => unsafe { CFString::wrap_under_get_rule(*constant) }.to_string(),
+        }
+    }
+
+    /// Return a raw CFStringRef pointing to this property key
+    fn as_string_ref(&self) -> CFStringRef {
+        match self {
+            PropertyKeyStorage::Owned(owned) => owned.as_concrete_TypeRef(),
+            PropertyKeyStorage::Constant(constant) => *constant,
+        }
+    }
+
+    /// For checking the retain count when debugging
+    #[allow(dead_code)]
+    fn retain_count(&self) -> CFIndex {
+        match self {
+            PropertyKeyStorage::Owned(owned) => owned.retain_count(),
+            PropertyKeyStorage::Constant(constant) => unsafe { CFGetRetainCount(*constant as CFTypeRef) },
+        }
+    }
+}
+
+/// Convert an OSStatus into a Result<T, OSStatus> given a mapping closure
+fn result_from_status<T, F: FnOnce() -> T>(status: OSStatus, f: F) -> Result<T, OSStatus> {
+    match status {
+        0 => Ok(f()),
+        _ => Err(status),
+    }
+}
+
+/// Convert an OSSStatus into a Result<(), OSStatus>
+fn unit_result_from_status(status: OSStatus) -> Result<(), OSStatus> {
+    result_from_status(status, || ())
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::os::raw::c_void;
+use std::ptr;
+use std::panic::catch_unwind;
+
+use {
+    BoxedCallback,
+    EventList,
+    PacketList,
+    PropertyValue,
+};
+use notifications::Notification;
+
+use self::sys::{
+    MIDIObjectRef,
+    MIDIObjectType,
+    MIDIProtocolID,
+    MIDISysexSendRequest,
+    MIDITimeStamp,
+    OSStatus,
+    SInt32,
+};
+
+pub mod sys;
+
+#[cfg(target_os = "macos")]
+mod macos;
+
+#[cfg(not(target_os = "macos"))]
+mod loopback;
+
+#[cfg(target_os = "macos")]
+pub use self::macos::{CoreMidi as Current, PropertyKeyStorage};
+
+#[cfg(not(target_os = "macos"))]
+pub use self::loopback::{Loopback as Current, PropertyKeyStorage, set_time_source};
+
+#[cfg(all(test, not(target_os = "macos")))]
+pub(crate) use self::loopback::{add_device, remove_device};
+
+/// The set of MIDI server operations used by this crate.
+///
+/// On macOS it is implemented by `CoreMidi`, which forwards every call to the CoreMIDI framework.
+/// On other hosts the `Loopback` backend emulates the MIDI server in process, so the crate can be
+/// built and tested without CoreMIDI.
+///
+/// The `ref_con` pointers are owned by the caller, and are handed back to
+/// `deliver_notification`, `deliver_packets` and `deliver_event_list` whenever there is something to deliver.
+///
+pub trait Backend {
+    fn client_create(name: &str, notify_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn client_dispose(client: MIDIObjectRef) -> Result<(), OSStatus>;
+
+    fn output_port_create(client: MIDIObjectRef, name: &str) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn input_port_create(client: MIDIObjectRef, name: &str, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus>;
+
+    /// Like `input_port_create`, but the port receives the events as `EventList`s of the given protocol.
+    fn input_port_create_with_protocol(client: MIDIObjectRef, name: &str, protocol: MIDIProtocolID, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn port_dispose(port: MIDIObjectRef) -> Result<(), OSStatus>;
+
+    fn port_connect_source(port: MIDIObjectRef, source: MIDIObjectRef, conn_ref_con: *mut c_void) -> Result<(), OSStatus>;
+
+    fn port_disconnect_source(port: MIDIObjectRef, source: MIDIObjectRef) -> Result<(), OSStatus>;
+
+    fn send(port: MIDIObjectRef, destination: MIDIObjectRef, packet_list: &PacketList) -> Result<(), OSStatus>;
+
+    fn send_event_list(port: MIDIObjectRef, destination: MIDIObjectRef, event_list: &EventList) -> Result<(), OSStatus>;
+
+    fn received(source: MIDIObjectRef, packet_list: &PacketList) -> Result<(), OSStatus>;
+
+    /// Starts sending the data in `request` asynchronously, like `MIDISendSysex` does.
+    ///
+    /// The request must stay valid until its `completionProc` is called, which will always happen
+    /// once the request has been accepted. Setting `complete` to true cancels the request.
+    unsafe fn send_sysex(request: *mut MIDISysexSendRequest) -> Result<(), OSStatus>;
+
+    fn source_create(client: MIDIObjectRef, name: &str) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn destination_create(client: MIDIObjectRef, name: &str, read_ref_con: *mut c_void) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn endpoint_dispose(endpoint: MIDIObjectRef) -> Result<(), OSStatus>;
+
+    /// Passing 0 as the destination flushes all the destinations.
+    fn flush_output(destination: MIDIObjectRef) -> Result<(), OSStatus>;
+
+    fn restart() -> Result<(), OSStatus>;
+
+    /// The current time of the host clock, in ticks.
+    fn host_time() -> MIDITimeStamp;
+
+    /// The (numerator, denominator) ratio that converts host clock ticks into nanoseconds.
+    fn host_timebase() -> (u32, u32);
+
+    /// Passing `None` as the owner id creates a connection that is disposed when the process exits.
+    fn thru_connection_create(persistent_owner_id: Option<&str>, params: &[u8]) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn thru_connection_dispose(connection: MIDIObjectRef) -> Result<(), OSStatus>;
+
+    fn thru_connection_get_params(connection: MIDIObjectRef) -> Result<Vec<u8>, OSStatus>;
+
+    fn thru_connection_set_params(connection: MIDIObjectRef, params: &[u8]) -> Result<(), OSStatus>;
+
+    fn thru_connection_find(persistent_owner_id: &str) -> Result<Vec<MIDIObjectRef>, OSStatus>;
+
+    fn number_of_sources() -> usize;
+
+    /// Returns 0 when there is no source at that index.
+    fn source(index: usize) -> MIDIObjectRef;
+
+    fn number_of_destinations() -> usize;
+
+    /// Returns 0 when there is no destination at that index.
+    fn destination(index: usize) -> MIDIObjectRef;
+
+    fn number_of_devices() -> usize;
+
+    /// Returns 0 when there is no device at that index.
+    fn device(index: usize) -> MIDIObjectRef;
+
+    fn number_of_external_devices() -> usize;
+
+    /// Returns 0 when there is no external device at that index.
+    fn external_device(index: usize) -> MIDIObjectRef;
+
+    fn device_number_of_entities(device: MIDIObjectRef) -> usize;
+
+    /// Returns 0 when there is no entity at that index.
+    fn device_entity(device: MIDIObjectRef, index: usize) -> MIDIObjectRef;
+
+    fn entity_number_of_sources(entity: MIDIObjectRef) -> usize;
+
+    /// Returns 0 when there is no source at that index.
+    fn entity_source(entity: MIDIObjectRef, index: usize) -> MIDIObjectRef;
+
+    fn entity_number_of_destinations(entity: MIDIObjectRef) -> usize;
+
+    /// Returns 0 when there is no destination at that index.
+    fn entity_destination(entity: MIDIObjectRef, index: usize) -> MIDIObjectRef;
+
+    /// Virtual endpoints don't belong to any entity.
+    fn endpoint_entity(endpoint: MIDIObjectRef) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn entity_device(entity: MIDIObjectRef) -> Result<MIDIObjectRef, OSStatus>;
+
+    fn object_find_by_unique_id(unique_id: SInt32) -> Result<(MIDIObjectRef, MIDIObjectType), OSStatus>;
+
+    fn object_get_string_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<String, OSStatus>;
+
+    fn object_set_string_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &str) -> Result<(), OSStatus>;
+
+    fn object_get_integer_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<SInt32, OSStatus>;
+
+    fn object_set_integer_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: SInt32) -> Result<(), OSStatus>;
+
+    fn object_get_data_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<Vec<u8>, OSStatus>;
+
+    fn object_set_data_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &[u8]) -> Result<(), OSStatus>;
+
+    fn object_get_dictionary_property(object: MIDIObjectRef, key: &PropertyKeyStorage) -> Result<BTreeMap<String, PropertyValue>, OSStatus>;
+
+    fn object_set_dictionary_property(object: MIDIObjectRef, key: &PropertyKeyStorage, value: &BTreeMap<String, PropertyValue>) -> Result<(), OSStatus>;
+
+    /// With `deep`, the properties of the children are included under the "entities",
+    /// "sources" and "destinations" keys.
+    fn object_get_properties(object: MIDIObjectRef, deep: bool) -> Result<BTreeMap<String, PropertyValue>, OSStatus>;
+}
+
+/// Delivers a notification to the client callback registered as `ref_con`.
+///
+/// `ref_con` must be the raw pointer of a live `BoxedCallback<Notification>`.
+pub unsafe fn deliver_notification(ref_con: *mut c_void, notification: &Notification) {
+    let _ = catch_unwind(|| {
+        BoxedCallback::call_from_raw_ptr(ref_con, ptr::null_mut(), notification);
+    });
+}
+
+/// Delivers a list of packets to the port or destination callback registered as `read_ref_con`.
+///
+/// `read_ref_con` must be the raw pointer of a live `BoxedCallback<PacketList>`, and `src_conn_ref_con`
+/// the pointer given when connecting the source to the port, or null.
+pub unsafe fn deliver_packets(read_ref_con: *mut c_void, src_conn_ref_con: *mut c_void, packet_list: &PacketList) {
+    let _ = catch_unwind(|| {
+        BoxedCallback::call_from_raw_ptr(read_ref_con, src_conn_ref_con, packet_list);
+    });
+}
+
+/// Delivers a list of events to the port callback registered as `read_ref_con`.
+///
+/// `read_ref_con` must be the raw pointer of a live `BoxedCallback<EventList>`, and `src_conn_ref_con`
+/// the pointer given when connecting the source to the port, or null.
+pub unsafe fn deliver_event_list(read_ref_con: *mut c_void, src_conn_ref_con: *mut c_void, event_list: &EventList) {
+    let _ = catch_unwind(|| {
+        BoxedCallback::call_from_raw_ptr(read_ref_con, src_conn_ref_con, event_list);
+    });
+}
@@ -0,0 +1,271 @@
+//! Low-level CoreMIDI definitions shared by the backends.
+//!
+//! On macOS everything is re-exported from coremidi-sys. On other hosts the subset of types,
+//! constants and property keys used by this crate is defined here with the same names, layouts
+//! and values, so the rest of the crate does not need to care about which backend is in use.
+
+#[cfg(target_os = "macos")]
+pub use self::macos::*;
+
+#[cfg(not(target_os = "macos"))]
+pub use self::portable::*;
+
+// The names are listed explicitly, so they take precedence over the glob imports above
+pub use self::ump::{
+    MIDIEventList,
+    MIDIEventPacket,
+    MIDIEventPacketNext,
+    MIDIProtocolID,
+    kMIDIProtocol_1_0,
+    kMIDIProtocol_2_0,
+};
+
+#[cfg(target_os = "macos")]
+#[allow(non_upper_case_globals)]
+mod macos {
+    pub use core_foundation_sys::base::OSStatus;
+    pub use core_foundation_sys::string::CFStringRef;
+    pub use coremidi_sys::*;
+}
+
+#[cfg(not(target_os = "macos"))]
+#[allow(dead_code, non_upper_case_globals, non_camel_case_types, non_snake_case)]
+mod portable {
+    use std::os::raw::{c_int, c_uchar, c_uint, c_ulong, c_void};
+
+    pub type OSStatus = i32;
+
+    pub type Boolean = c_uchar;
+    pub type Byte = u8;
+    pub type UInt16 = u16;
+    pub type UInt32 = u32;
+    pub type UInt64 = u64;
+    pub type SInt32 = c_int;
+    pub type ItemCount = c_ulong;
+
+    /// Property keys are plain strings when CoreFoundation is not available.
+    pub type CFStringRef = &'static str;
+
+    pub type MIDIObjectRef = UInt32;
+    pub type MIDIClientRef = MIDIObjectRef;
+    pub type MIDIPortRef = MIDIObjectRef;
+    pub type MIDIDeviceRef = MIDIObjectRef;
+    pub type MIDIEntityRef = MIDIObjectRef;
+    pub type MIDIEndpointRef = MIDIObjectRef;
+    pub type MIDITimeStamp = UInt64;
+    pub type MIDIObjectType = SInt32;
+    pub type MIDINotificationMessageID = SInt32;
+
+    pub const kMIDIInvalidClient: c_int = -10830;
+    pub const kMIDIInvalidPort: c_int = -10831;
+    pub const kMIDIWrongEndpointType: c_int = -10832;
+    pub const kMIDINoConnection: c_int = -10833;
+    pub const kMIDIUnknownEndpoint: c_int = -10834;
+    pub const kMIDIUnknownProperty: c_int = -10835;
+    pub const kMIDIWrongPropertyType: c_int = -10836;
+    pub const kMIDINoCurrentSetup: c_int = -10837;
+    pub const kMIDIMessageSendErr: c_int = -10838;
+    pub const kMIDIServerStartErr: c_int = -10839;
+    pub const kMIDISetupFormatErr: c_int = -10840;
+    pub const kMIDIWrongThread: c_int = -10841;
+    pub const kMIDIObjectNotFound: c_int = -10842;
+    pub const kMIDIIDNotUnique: c_int = -10843;
+    pub const kMIDINotPermitted: c_int = -10844;
+    pub const kMIDIUnknownError: c_int = -10845;
+
+    pub const kMIDIObjectType_Other: c_int = -1;
+    pub const kMIDIObjectType_Device: c_int = 0;
+    pub const kMIDIObjectType_Entity: c_int = 1;
+    pub const kMIDIObjectType_Source: c_int = 2;
+    pub const kMIDIObjectType_Destination: c_int = 3;
+    pub const kMIDIObjectType_ExternalDevice: c_int = 16;
+    pub const kMIDIObjectType_ExternalEntity: c_int = 17;
+    pub const kMIDIObjectType_ExternalSource: c_int = 18;
+    pub const kMIDIObjectType_ExternalDestination: c_int = 19;
+
+    pub const kMIDIMsgSetupChanged: c_uint = 1;
+    pub const kMIDIMsgObjectAdded: c_uint = 2;
+    pub const kMIDIMsgObjectRemoved: c_uint = 3;
+    pub const kMIDIMsgPropertyChanged: c_uint = 4;
+    pub const kMIDIMsgThruConnectionsChanged: c_uint = 5;
+    pub const kMIDIMsgSerialPortOwnerChanged: c_uint = 6;
+    pub const kMIDIMsgIOError: c_uint = 7;
+
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    #[repr(C, packed(4))]
+    pub struct MIDIPacket {
+        pub timeStamp: MIDITimeStamp,
+        pub length: UInt16,
+        pub data: [Byte; 256usize],
+    }
+
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[repr(C, packed)]
+    pub struct MIDIPacket {
+        pub timeStamp: MIDITimeStamp,
+        pub length: UInt16,
+        pub data: [Byte; 256usize],
+    }
+
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    #[repr(C, packed(4))]
+    pub struct MIDIPacketList {
+        pub numPackets: UInt32,
+        pub packet: [MIDIPacket; 1usize],
+    }
+
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[repr(C, packed)]
+    pub struct MIDIPacketList {
+        pub numPackets: UInt32,
+        pub packet: [MIDIPacket; 1usize],
+    }
+
+    // The notifications are packed to 4 bytes, as in the CoreMIDI headers
+    #[repr(C, packed(4))]
+    pub struct MIDINotification {
+        pub messageID: MIDINotificationMessageID,
+        pub messageSize: UInt32,
+    }
+
+    #[repr(C, packed(4))]
+    pub struct MIDIObjectAddRemoveNotification {
+        pub messageID: MIDINotificationMessageID,
+        pub messageSize: UInt32,
+        pub parent: MIDIObjectRef,
+        pub parentType: MIDIObjectType,
+        pub child: MIDIObjectRef,
+        pub childType: MIDIObjectType,
+    }
+
+    #[repr(C, packed(4))]
+    pub struct MIDIObjectPropertyChangeNotification {
+        pub messageID: MIDINotificationMessageID,
+        pub messageSize: UInt32,
+        pub object: MIDIObjectRef,
+        pub objectType: MIDIObjectType,
+        pub propertyName: CFStringRef,
+    }
+
+    #[repr(C, packed(4))]
+    pub struct MIDIIOErrorNotification {
+        pub messageID: MIDINotificationMessageID,
+        pub messageSize: UInt32,
+        pub driverDevice: MIDIDeviceRef,
+        pub errorCode: OSStatus,
+    }
+
+    pub type MIDICompletionProc = Option<extern "C" fn(request: *mut MIDISysexSendRequest)>;
+
+    #[repr(C)]
+    pub struct MIDISysexSendRequest {
+        pub destination: MIDIEndpointRef,
+        pub data: *const Byte,
+        pub bytesToSend: UInt32,
+        pub complete: Boolean,
+        pub reserved: [Byte; 3usize],
+        pub completionProc: MIDICompletionProc,
+        pub completionRefCon: *mut c_void,
+    }
+
+    const MIDI_PACKET_LENGTH_OFFSET: usize = 8;
+    const MIDI_PACKET_DATA_OFFSET: usize = 10;
+
+    #[inline]
+    pub unsafe fn MIDIPacketNext(pkt: *const MIDIPacket) -> *const MIDIPacket {
+        let ptr = pkt as *const u8;
+        let length_bytes = [*ptr.add(MIDI_PACKET_LENGTH_OFFSET), *ptr.add(MIDI_PACKET_LENGTH_OFFSET + 1)];
+        let length = UInt16::from_ne_bytes(length_bytes) as usize;
+        let next = ptr.add(MIDI_PACKET_DATA_OFFSET + length);
+        if cfg!(any(target_arch = "arm", target_arch = "aarch64")) {
+            ((next as usize + 3) & !(3usize)) as *const MIDIPacket
+        } else {
+            next as *const MIDIPacket
+        }
+    }
+
+    // The values of the property keys exported by CoreMIDI. They are `static mut` so that
+    // they are read exactly like the extern statics from coremidi-sys.
+    pub static mut kMIDIPropertyName: CFStringRef = "name";
+    pub static mut kMIDIPropertyManufacturer: CFStringRef = "manufacturer";
+    pub static mut kMIDIPropertyModel: CFStringRef = "model";
+    pub static mut kMIDIPropertyUniqueID: CFStringRef = "uniqueID";
+    pub static mut kMIDIPropertyDeviceID: CFStringRef = "deviceID";
+    pub static mut kMIDIPropertyReceiveChannels: CFStringRef = "receiveChannels";
+    pub static mut kMIDIPropertyTransmitChannels: CFStringRef = "transmitChannels";
+    pub static mut kMIDIPropertyMaxSysExSpeed: CFStringRef = "maxSysExSpeed";
+    pub static mut kMIDIPropertyAdvanceScheduleTimeMuSec: CFStringRef = "scheduleAheadMuSec";
+    pub static mut kMIDIPropertyIsEmbeddedEntity: CFStringRef = "embedded";
+    pub static mut kMIDIPropertyIsBroadcast: CFStringRef = "broadcast";
+    pub static mut kMIDIPropertySingleRealtimeEntity: CFStringRef = "singleRealtimeEntity";
+    pub static mut kMIDIPropertyConnectionUniqueID: CFStringRef = "connUniqueID";
+    pub static mut kMIDIPropertyOffline: CFStringRef = "offline";
+    pub static mut kMIDIPropertyPrivate: CFStringRef = "private";
+    pub static mut kMIDIPropertyDriverOwner: CFStringRef = "driver";
+    pub static mut kMIDIPropertyFactoryPatchNameFile: CFStringRef = "factoryPatchNameFile";
+    pub static mut kMIDIPropertyUserPatchNameFile: CFStringRef = "userPatchNameFile";
+    pub static mut kMIDIPropertyNameConfiguration: CFStringRef = "nameConfiguration";
+    pub static mut kMIDIPropertyImage: CFStringRef = "image";
+    pub static mut kMIDIPropertyDriverVersion: CFStringRef = "driverVersion";
+    pub static mut kMIDIPropertySupportsGeneralMIDI: CFStringRef = "supportsGeneralMIDI";
+    pub static mut kMIDIPropertySupportsMMC: CFStringRef = "supportsMMC";
+    pub static mut kMIDIPropertyCanRoute: CFStringRef = "canRoute";
+    pub static mut kMIDIPropertyReceivesClock: CFStringRef = "receivesClock";
+    pub static mut kMIDIPropertyReceivesMTC: CFStringRef = "receivesMTC";
+    pub static mut kMIDIPropertyReceivesNotes: CFStringRef = "receivesNotes";
+    pub static mut kMIDIPropertyReceivesProgramChanges: CFStringRef = "receivesProgramChanges";
+    pub static mut kMIDIPropertyReceivesBankSelectMSB: CFStringRef = "receivesBankSelectMSB";
+    pub static mut kMIDIPropertyReceivesBankSelectLSB: CFStringRef = "receivesBankSelectLSB";
+    pub static mut kMIDIPropertyTransmitsBankSelectMSB: CFStringRef = "transmitsBankSelectMSB";
+    pub static mut kMIDIPropertyTransmitsBankSelectLSB: CFStringRef = "transmitsBankSelectLSB";
+    pub static mut kMIDIPropertyTransmitsClock: CFStringRef = "transmitsClock";
+    pub static mut kMIDIPropertyTransmitsMTC: CFStringRef = "transmitsMTC";
+    pub static mut kMIDIPropertyTransmitsNotes: CFStringRef = "transmitsNotes";
+    pub static mut kMIDIPropertyTransmitsProgramChanges: CFStringRef = "transmitsProgramChanges";
+    pub static mut kMIDIPropertyPanDisruptsStereo: CFStringRef = "panDisruptsStereo";
+    pub static mut kMIDIPropertyIsSampler: CFStringRef = "isSampler";
+    pub static mut kMIDIPropertyIsDrumMachine: CFStringRef = "isDrumMachine";
+    pub static mut kMIDIPropertyIsMixer: CFStringRef = "isMixer";
+    pub static mut kMIDIPropertyIsEffectUnit: CFStringRef = "isEffectUnit";
+    pub static mut kMIDIPropertyMaxReceiveChannels: CFStringRef = "maxReceiveChannels";
+    pub static mut kMIDIPropertyMaxTransmitChannels: CFStringRef = "maxTransmitChannels";
+    pub static mut kMIDIPropertyDriverDeviceEditorApp: CFStringRef = "driverDeviceEditorApp";
+    pub static mut kMIDIPropertySupportsShowControl: CFStringRef = "supportsShowControl";
+    pub static mut kMIDIPropertyDisplayName: CFStringRef = "displayName";
+}
+
+/// The Universal MIDI Packet definitions, which are not part of coremidi-sys 2.0.
+#[allow(non_upper_case_globals, non_camel_case_types, non_snake_case)]
+mod ump {
+    use super::{MIDITimeStamp, SInt32, UInt32};
+
+    pub type MIDIProtocolID = SInt32;
+
+    pub const kMIDIProtocol_1_0: MIDIProtocolID = 1;
+    pub const kMIDIProtocol_2_0: MIDIProtocolID = 2;
+
+    #[repr(C, packed(4))]
+    pub struct MIDIEventPacket {
+        pub timeStamp: MIDITimeStamp,
+        pub wordCount: UInt32,
+        pub words: [UInt32; 64usize],
+    }
+
+    #[repr(C)]
+    pub struct MIDIEventList {
+        pub protocol: MIDIProtocolID,
+        pub numPackets: UInt32,
+        pub packet: [MIDIEventPacket; 1usize],
+    }
+
+    // Offsets in words, as everything in an event packet is 4-byte aligned
+    const MIDI_EVENT_PACKET_WORD_COUNT_OFFSET: usize = 2;
+    const MIDI_EVENT_PACKET_WORDS_OFFSET: usize = 3;
+
+    #[inline]
+    pub unsafe fn MIDIEventPacketNext(pkt: *const MIDIEventPacket) -> *const MIDIEventPacket {
+        let ptr = pkt as *const UInt32;
+        let word_count = *ptr.add(MIDI_EVENT_PACKET_WORD_COUNT_OFFSET) as usize;
+        ptr.add(MIDI_EVENT_PACKET_WORDS_OFFSET + word_count) as *const MIDIEventPacket
+    }
+}
@@ -1,197 +1,208 @@
-use core_foundation::{
-    base::{
-        OSStatus,
-        TCFType
-    },
-    string::CFString,
-};
-
-use coremidi_sys::{
-    MIDIClientCreate, 
-    MIDIClientDispose,
-    MIDIDestinationCreate,
-    MIDIInputPortCreate,
-    MIDINotification,
-    MIDIOutputPortCreate,
-    MIDIPacketList,
-    MIDISourceCreate,
-};
-
-use std::{
-    mem::MaybeUninit,
-    ops::Deref, 
-    os::raw::c_void,
-    panic::catch_unwind,
-    ptr, 
-};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
 
 use {
     BoxedCallback,
     Client,
     Endpoint,
+    Error,
+    EventList,
     InputPort,
-    notifications::Notification,
+    InputPortCallback,
+    InputPortWithContext,
+    NotificationDispatcher,
+    notifications::{Notification, PropertyCache},
     Object,
     OutputPort,
     PacketList,
     Port,
-    result_from_status,
+    PropertyKey,
+    Protocol,
+    receiver::{packet_channel, OverflowPolicy, PacketReceiver},
     VirtualSource,
     VirtualDestination,
+    lock,
+};
+use backend::{
+    Backend,
+    Current,
 };
+use backend::sys::MIDIObjectRef;
 
 impl Client {
     /// Creates a new CoreMIDI client with support for notifications.
     /// See [MIDIClientCreate](https://developer.apple.com/reference/coremidi/1495360-midiclientcreate).
     ///
-    pub fn new_with_notifications<F>(name: &str, callback: F) -> Result<Client, OSStatus>
+    pub fn new_with_notifications<F>(name: &str, callback: F) -> Result<Client, Error>
         where F: FnMut(&Notification) + Send + 'static
     {
-        let client_name = CFString::new(name);
-        let mut client_ref = MaybeUninit::uninit();
         let mut boxed_callback = BoxedCallback::new(callback);
-        let status = unsafe {
-            MIDIClientCreate(
-                client_name.as_concrete_TypeRef(),
-                Some(Self::notify_proc as extern "C" fn(_, _)),
-                boxed_callback.raw_ptr(),
-                client_ref.as_mut_ptr()
-            )
-        };
-        result_from_status(status, || {
-            let client_ref = unsafe { client_ref.assume_init() };
+        Current::client_create(name, boxed_callback.raw_ptr()).map(|client_ref| {
             Client { object: Object(client_ref), callback: boxed_callback }
+        }).map_err(Error::from)
+    }
+
+    /// Creates a new CoreMIDI client with support for notifications, which keeps track of the values
+    /// of some properties, so the notifications for them come with the previous and the current values.
+    /// See [MIDIClientCreate](https://developer.apple.com/reference/coremidi/1495360-midiclientcreate).
+    ///
+    /// The values of the watched properties are read for every object in the system when the client
+    /// is created, and then for the objects added later on.
+    ///
+    /// ```rust,no_run
+    /// use coremidi::{Client, Notification, PropertyKey};
+    /// let client = Client::new_with_watched_properties("example-client", &[PropertyKey::Offline], |notification| {
+    ///     if let Notification::PropertyChanged(info) = notification {
+    ///         if info.property() == PropertyKey::Offline {
+    ///             println!("{:?} went from {:?} to {:?}", info.object, info.old_value, info.new_value);
+    ///         }
+    ///     }
+    /// }).unwrap();
+    /// ```
+    pub fn new_with_watched_properties<F>(name: &str, watched: &[PropertyKey], mut callback: F) -> Result<Client, Error>
+        where F: FnMut(&Notification) + Send + 'static
+    {
+        let mut cache = PropertyCache::new(watched);
+        Self::new_with_notifications(name, move |notification| {
+            match cache.update(notification) {
+                Some(notification) => callback(&notification),
+                None => callback(notification),
+            }
         })
     }
 
+    /// Creates a new CoreMIDI client which notifications are delivered to the subscribers of a dispatcher.
+    /// See [MIDIClientCreate](https://developer.apple.com/reference/coremidi/1495360-midiclientcreate).
+    ///
+    /// The dispatcher can be shared with several components, which subscribe and unsubscribe
+    /// at any time (see `NotificationDispatcher`).
+    ///
+    pub fn new_with_dispatcher(name: &str, dispatcher: &NotificationDispatcher) -> Result<Client, Error> {
+        let dispatcher = dispatcher.clone();
+        Self::new_with_notifications(name, move |notification| dispatcher.dispatch(notification))
+    }
+
     /// Creates a new CoreMIDI client.
     /// See [MIDIClientCreate](https://developer.apple.com/reference/coremidi/1495360-midiclientcreate).
     ///
-    pub fn new(name: &str) -> Result<Client, OSStatus> {
-        let client_name = CFString::new(name);
-        let mut client_ref = MaybeUninit::uninit();
-        let status = unsafe {
-            MIDIClientCreate(
-                client_name.as_concrete_TypeRef(),
-                None,
-                ptr::null_mut(),
-                client_ref.as_mut_ptr()
-            )
-        };
-        result_from_status(status, || {
-            let client_ref = unsafe { client_ref.assume_init() };
-            Client { object: Object(client_ref), callback: BoxedCallback::null() }
-        })
+    pub fn new(name: &str) -> Result<Client, Error> {
+        let mut boxed_callback = BoxedCallback::null();
+        Current::client_create(name, boxed_callback.raw_ptr()).map(|client_ref| {
+            Client { object: Object(client_ref), callback: boxed_callback }
+        }).map_err(Error::from)
     }
 
     /// Creates an output port through which the client may send outgoing MIDI messages to any MIDI destination.
     /// See [MIDIOutputPortCreate](https://developer.apple.com/reference/coremidi/1495166-midioutputportcreate).
     ///
-    pub fn output_port(&self, name: &str) -> Result<OutputPort, OSStatus> {
-        let port_name = CFString::new(name);
-        let mut port_ref = MaybeUninit::uninit();
-        let status = unsafe {
-            MIDIOutputPortCreate(
-                self.object.0,
-                port_name.as_concrete_TypeRef(),
-                port_ref.as_mut_ptr()
-            )
-        };
-        result_from_status(status, || {
-            let port_ref = unsafe { port_ref.assume_init() };
+    pub fn output_port(&self, name: &str) -> Result<OutputPort, Error> {
+        Current::output_port_create(self.object.0, name).map(|port_ref| {
             OutputPort { port: Port { object: Object(port_ref) } }
-        })
+        }).map_err(Error::from)
     }
 
     /// Creates an input port through which the client may receive incoming MIDI messages from any MIDI source.
     /// See [MIDIInputPortCreate](https://developer.apple.com/reference/coremidi/1495225-midiinputportcreate).
     ///
-    pub fn input_port<F>(&self, name: &str, callback: F) -> Result<InputPort, OSStatus>
+    pub fn input_port<F>(&self, name: &str, callback: F) -> Result<InputPort, Error>
         where F: FnMut(&PacketList) + Send + 'static
     {
-        let port_name = CFString::new(name);
-        let mut port_ref = MaybeUninit::uninit();
         let mut box_callback = BoxedCallback::new(callback);
-        let status = unsafe {
-            MIDIInputPortCreate(
-                self.object.0,
-                port_name.as_concrete_TypeRef(),
-                Some(Self::read_proc as extern "C" fn(_, _, _)),
-                box_callback.raw_ptr(),
-                port_ref.as_mut_ptr()
-            )
-        };
-        result_from_status(status, || {
-            let port_ref = unsafe { port_ref.assume_init() };
+        Current::input_port_create(self.object.0, name, box_callback.raw_ptr()).map(|port_ref| {
             InputPort {
+                port: Port { object: Object(port_ref) },
+                callback: InputPortCallback::Packets(box_callback),
+            }
+        }).map_err(Error::from)
+    }
+
+    /// Creates an input port which callback gets, together with the incoming MIDI messages, the context
+    /// given when connecting the source they come from (see `InputPortWithContext`).
+    /// See [MIDIInputPortCreate](https://developer.apple.com/reference/coremidi/1495225-midiinputportcreate).
+    ///
+    pub fn input_port_with_context<T, F>(&self, name: &str, mut callback: F) -> Result<InputPortWithContext<T>, Error>
+        where T: Send + 'static, F: FnMut(&PacketList, &mut T) + Send + 'static
+    {
+        let contexts = Arc::new(Mutex::new(HashMap::new()));
+        let callback_contexts = contexts.clone();
+        let mut box_callback = BoxedCallback::with_connection(move |packet_list, conn_ref_con| {
+            // The connection refCon is the source the packets come from
+            if let Some(context) = lock(&callback_contexts).get_mut(&(conn_ref_con as usize as MIDIObjectRef)) {
+                callback(packet_list, context);
+            }
+        });
+        Current::input_port_create(self.object.0, name, box_callback.raw_ptr()).map(|port_ref| {
+            InputPortWithContext {
                 port: Port { object: Object(port_ref) },
                 callback: box_callback,
+                contexts,
             }
-        })
+        }).map_err(Error::from)
+    }
+
+    /// Creates an input port that receives the incoming MIDI messages as Universal MIDI Packets,
+    /// translated into the given protocol when the source uses a different one.
+    /// It fails with `Error::UnknownError` before macOS 11, where it is not available.
+    /// See `MIDIInputPortCreateWithProtocol`.
+    ///
+    /// ```rust,no_run
+    /// let client = coremidi::Client::new("example-client").unwrap();
+    /// let input_port = client.input_port_with_protocol("example-port", coremidi::Protocol::Midi20, |event_list| {
+    ///     for packet in event_list.iter() {
+    ///         for message in packet.messages() {
+    ///             println!("{:?}", message);
+    ///         }
+    ///     }
+    /// }).unwrap();
+    /// ```
+    pub fn input_port_with_protocol<F>(&self, name: &str, protocol: Protocol, callback: F) -> Result<InputPort, Error>
+        where F: FnMut(&EventList) + Send + 'static
+    {
+        let mut box_callback = BoxedCallback::new(callback);
+        Current::input_port_create_with_protocol(self.object.0, name, protocol.to_raw(), box_callback.raw_ptr()).map(|port_ref| {
+            InputPort {
+                port: Port { object: Object(port_ref) },
+                callback: InputPortCallback::Events(box_callback),
+            }
+        }).map_err(Error::from)
+    }
+
+    /// Creates an input port that queues the incoming packets, instead of handling them in a callback.
+    /// The packets are copied into a queue of the given capacity, and the `OverflowPolicy` decides
+    /// which ones are dropped when it is full.
+    /// See [MIDIInputPortCreate](https://developer.apple.com/reference/coremidi/1495225-midiinputportcreate).
+    ///
+    pub fn input_port_channel(&self, name: &str, capacity: usize, overflow: OverflowPolicy) -> Result<(InputPort, PacketReceiver), Error> {
+        let (sender, receiver) = packet_channel(capacity, overflow);
+        self.input_port(name, move |packet_list| sender.send(packet_list))
+            .map(|input_port| (input_port, receiver))
     }
 
     /// Creates a virtual source in the client.
     /// See [MIDISourceCreate](https://developer.apple.com/reference/coremidi/1495212-midisourcecreate).
     ///
-    pub fn virtual_source(&self, name: &str) -> Result<VirtualSource, OSStatus> {
-        let virtual_source_name = CFString::new(name);
-        let mut virtual_source = MaybeUninit::uninit();
-        let status = unsafe { 
-            MIDISourceCreate(
-                self.object.0,
-                virtual_source_name.as_concrete_TypeRef(),
-                virtual_source.as_mut_ptr()
-            )
-        };
-        result_from_status(status, || {
-            let virtual_source = unsafe { virtual_source.assume_init() };
+    pub fn virtual_source(&self, name: &str) -> Result<VirtualSource, Error> {
+        Current::source_create(self.object.0, name).map(|virtual_source| {
             VirtualSource { endpoint: Endpoint { object: Object(virtual_source) } }
-        })
+        }).map_err(Error::from)
     }
 
     /// Creates a virtual destination in the client.
     /// See [MIDIDestinationCreate](https://developer.apple.com/reference/coremidi/1495347-mididestinationcreate).
     ///
-    pub fn virtual_destination<F>(&self, name: &str, callback: F) -> Result<VirtualDestination, OSStatus>
+    pub fn virtual_destination<F>(&self, name: &str, callback: F) -> Result<VirtualDestination, Error>
         where F: FnMut(&PacketList) + Send + 'static 
     {
-        let virtual_destination_name = CFString::new(name);
-        let mut virtual_destination = MaybeUninit::uninit();
         let mut boxed_callback = BoxedCallback::new(callback);
-        let status = unsafe { 
-            MIDIDestinationCreate(
-                self.object.0,
-                virtual_destination_name.as_concrete_TypeRef(),
-                Some(Self::read_proc as extern "C" fn(_, _, _)),
-                boxed_callback.raw_ptr(),
-                virtual_destination.as_mut_ptr()
-            )
-        };
-        result_from_status(status, || {
-            let virtual_destination = unsafe { virtual_destination.assume_init() };
+        Current::destination_create(self.object.0, name, boxed_callback.raw_ptr()).map(|virtual_destination| {
             VirtualDestination {
                 endpoint: Endpoint {
                     object: Object(virtual_destination),
                 },
                 callback: boxed_callback,
             }
-        })
-    }
-
-    extern "C" fn notify_proc(notification_ptr: *const MIDINotification, ref_con: *mut c_void) {
-        let _ = catch_unwind(|| unsafe {
-            match Notification::from(&*notification_ptr) {
-                Ok(notification) => BoxedCallback::call_from_raw_ptr(ref_con, &notification),
-                Err(_) => {} // Skip unknown notifications
-            }
-        });
-    }
-
-    extern "C" fn read_proc(pktlist: *const MIDIPacketList, read_proc_ref_con: *mut c_void, _src_conn_ref_con: *mut c_void) {
-        let _ = catch_unwind(|| unsafe {
-            let packet_list = &*(pktlist as *const PacketList);
-            BoxedCallback::call_from_raw_ptr(read_proc_ref_con, packet_list);
-        });
+        }).map_err(Error::from)
     }
 }
 
@@ -205,6 +216,6 @@ impl Deref for Client {
 
 impl Drop for Client {
     fn drop(&mut self) {
-        unsafe { MIDIClientDispose(self.object.0) };
+        let _ = Current::client_dispose(self.object.0);
     }
 }
@@ -0,0 +1,661 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use {
+    Clock,
+    Destination,
+    Error,
+    HostClock,
+    HostTime,
+    MidiMessage,
+    OutputPort,
+    PacketBuffer,
+    Properties,
+    PropertyGetter,
+    VirtualSource,
+    lock,
+};
+
+/// The number of MIDI clocks in a quarter note.
+pub const CLOCKS_PER_QUARTER_NOTE: u64 = 24;
+
+// How long before they play the clocks are sent by default
+const DEFAULT_LOOKAHEAD: Duration = Duration::from_millis(50);
+
+fn duration_to_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+// A period with clocks at a steady tempo, from a clock number played at a host time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Run {
+    start_nanos: u64,
+    start_clock: u64,
+    tempo: u32,
+    end_nanos: Option<u64>,
+}
+
+impl Run {
+    // Every clock is placed from the start of the run, so the rounding errors don't add up
+    fn clock_nanos(&self, clock: u64) -> u64 {
+        let nanos_per_quarter = u128::from(self.tempo) * 1000;
+        self.start_nanos + (u128::from(clock - self.start_clock) * nanos_per_quarter / u128::from(CLOCKS_PER_QUARTER_NOTE)) as u64
+    }
+
+    // The first clock played at or after a host time
+    fn clock_at(&self, nanos: u64) -> u64 {
+        if nanos <= self.start_nanos {
+            return self.start_clock;
+        }
+        let nanos_per_quarter = u128::from(self.tempo) * 1000;
+        let elapsed = u128::from(nanos - self.start_nanos) * u128::from(CLOCKS_PER_QUARTER_NOTE);
+        self.start_clock + ((elapsed + nanos_per_quarter - 1) / nanos_per_quarter) as u64
+    }
+
+    fn is_running(&self) -> bool {
+        self.end_nanos.is_none()
+    }
+}
+
+// A destination of the clocks, with how long ahead it wants them, and until when they have been scheduled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Output {
+    ahead: u64,
+    scheduled_until: Option<u64>,
+}
+
+/// Schedules MIDI beat clock ahead of time, into packets with the host time they have to play at.
+///
+/// It sends 24 Timing Clock messages per quarter note while running, together with the Start, Stop,
+/// Continue and Song Position Pointer messages. Every clock is placed from the last tempo change,
+/// so the rounding errors don't add up into drift.
+///
+/// It does the timing of a `ClockGenerator`, without sending anything, for several outputs which
+/// can be scheduled further ahead than the lookahead, and it reads the time from a `Clock`,
+/// so it can be driven with a simulated one.
+///
+/// The changes, like a new tempo or stopping, apply from the first clock that hasn't been scheduled
+/// for any output yet, so they take effect after the lookahead.
+///
+/// ```
+/// use std::time::Duration;
+/// use coremidi::{Clock, ClockScheduler, HostTime};
+///
+/// struct FixedClock;
+/// impl Clock for FixedClock {
+///     fn now(&self) -> HostTime { HostTime::from_nanos(1_000_000_000) }
+/// }
+///
+/// let mut scheduler = ClockScheduler::with_clock(FixedClock, 500_000);
+/// let output = scheduler.add_output(Duration::from_millis(0));
+/// scheduler.start();
+/// // The Start message and the clocks in the next 50 milliseconds, one every 500 / 24 milliseconds
+/// let packets = scheduler.schedule(output);
+/// assert_eq!(packets.len(), 3);
+/// ```
+#[derive(Debug)]
+pub struct ClockScheduler<C = HostClock> {
+    clock: C,
+    lookahead: Duration,
+    tempo: u32,
+    // The clocks played so far while stopped, or when the last run ends
+    position: u64,
+    runs: Vec<Run>,
+    transport: Vec<(u64, MidiMessage)>,
+    outputs: Vec<Output>,
+}
+
+impl ClockScheduler<HostClock> {
+    /// Create a stopped scheduler following the host clock, with a tempo in microseconds per quarter note.
+    ///
+    pub fn new(tempo: u32) -> ClockScheduler<HostClock> {
+        ClockScheduler::with_clock(HostClock, tempo)
+    }
+}
+
+impl<C: Clock> ClockScheduler<C> {
+    /// Create a stopped scheduler following a clock, with a tempo in microseconds per quarter note.
+    ///
+    pub fn with_clock(clock: C, tempo: u32) -> ClockScheduler<C> {
+        ClockScheduler {
+            clock,
+            lookahead: DEFAULT_LOOKAHEAD,
+            tempo: cmp::max(tempo, 1),
+            position: 0,
+            runs: Vec::new(),
+            transport: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Get how long before they play the clocks are scheduled.
+    ///
+    pub fn lookahead(&self) -> Duration {
+        self.lookahead
+    }
+
+    /// Set how long before they play the clocks are scheduled, which is 50 milliseconds by default.
+    /// It has to be longer than the time between calls to `schedule`.
+    ///
+    pub fn set_lookahead(&mut self, lookahead: Duration) {
+        self.lookahead = lookahead;
+    }
+
+    /// Add an output, which is scheduled at least as far ahead as `advance`, and get its index for `schedule`.
+    ///
+    pub fn add_output(&mut self, advance: Duration) -> usize {
+        self.outputs.push(Output { ahead: duration_to_nanos(advance), scheduled_until: None });
+        self.outputs.len() - 1
+    }
+
+    /// Get the tempo, in microseconds per quarter note.
+    ///
+    pub fn tempo(&self) -> u32 {
+        self.tempo
+    }
+
+    /// Change the tempo, in microseconds per quarter note, from the first clock that hasn't been scheduled yet.
+    ///
+    pub fn set_tempo(&mut self, tempo: u32) {
+        let tempo = cmp::max(tempo, 1);
+        self.tempo = tempo;
+        let nanos = self.unscheduled_nanos();
+        if let Some(run) = self.running() {
+            let clock = run.clock_at(nanos);
+            let clock_nanos = run.clock_nanos(clock);
+            self.end_run(clock_nanos);
+            self.runs.push(Run { start_nanos: clock_nanos, start_clock: clock, tempo, end_nanos: None });
+        }
+    }
+
+    /// Whether the clocks are running.
+    ///
+    pub fn is_running(&self) -> bool {
+        self.running().is_some()
+    }
+
+    /// Get the number of clocks played since the start of the song.
+    ///
+    pub fn song_position(&self) -> u64 {
+        match self.running() {
+            Some(run) => run.clock_at(self.now_nanos() + 1),
+            None => self.position,
+        }
+    }
+
+    /// Send a Start, and run the clocks from the start of the song.
+    ///
+    pub fn start(&mut self) {
+        let nanos = self.unscheduled_nanos();
+        self.end_run(nanos);
+        self.transport.push((nanos, MidiMessage::Start));
+        self.position = 0;
+        self.runs.push(Run { start_nanos: nanos, start_clock: 0, tempo: self.tempo, end_nanos: None });
+    }
+
+    /// Send a Stop, and stop the clocks.
+    ///
+    pub fn stop(&mut self) {
+        if self.is_running() {
+            let nanos = self.unscheduled_nanos();
+            self.end_run(nanos);
+            self.transport.push((nanos, MidiMessage::Stop));
+        }
+    }
+
+    /// Send a Continue, and run the clocks from where they stopped.
+    ///
+    pub fn resume(&mut self) {
+        if !self.is_running() {
+            let nanos = self.unscheduled_nanos();
+            self.transport.push((nanos, MidiMessage::Continue));
+            self.runs.push(Run { start_nanos: nanos, start_clock: self.position, tempo: self.tempo, end_nanos: None });
+        }
+    }
+
+    /// Send a Song Position Pointer, in sixteenth notes (6 clocks) since the start of the song.
+    /// While running, the clocks are stopped before, and continued after it.
+    ///
+    pub fn set_song_position(&mut self, sixteenths: u16) {
+        let sixteenths = cmp::min(sixteenths, 0x3FFF);
+        let running = self.is_running();
+        self.stop();
+        self.transport.push((self.unscheduled_nanos(), MidiMessage::SongPosition(sixteenths)));
+        self.position = u64::from(sixteenths) * 6;
+        if running {
+            self.resume();
+        }
+    }
+
+    /// Get the messages to send to an output, which are the ones that play before the current time plus
+    /// the lookahead, or the advance of the output when it is longer, and were not scheduled yet.
+    /// The clocks missed when this is called late are skipped, rather than sent in a burst, but the
+    /// missed transport messages, and the first clock after a Start or a Continue, are sent right away.
+    ///
+    pub fn schedule(&mut self, output: usize) -> PacketBuffer {
+        let now = self.now_nanos();
+        let Output { ahead, scheduled_until } = self.outputs[output];
+        let from = cmp::max(scheduled_until.unwrap_or(now), now);
+        let to = now + cmp::max(duration_to_nanos(self.lookahead), ahead);
+        let mut buffer = PacketBuffer::with_capacity(256);
+        if to <= from {
+            return buffer;
+        }
+        let unscheduled = |nanos: u64| scheduled_until.map_or(true, |until| until <= nanos);
+
+        // The transport messages go before the clocks played at the same time
+        let mut messages: Vec<(u64, u8, MidiMessage)> = self.transport.iter()
+            .filter(|&&(nanos, _)| unscheduled(nanos) && nanos < to)
+            .map(|&(nanos, ref message)| (cmp::max(nanos, now), 0, message.clone()))
+            .collect();
+        for run in &self.runs {
+            let end = run.end_nanos.map_or(to, |end| cmp::min(end, to));
+            // The clocks are counted from the first one, so it is not skipped even when it is late
+            if unscheduled(run.start_nanos) && run.start_nanos < from && run.end_nanos.map_or(true, |end| end > run.start_nanos) {
+                messages.push((now, 1, MidiMessage::TimingClock));
+            }
+            let mut clock = run.clock_at(from);
+            while run.clock_nanos(clock) < end {
+                messages.push((run.clock_nanos(clock), 1, MidiMessage::TimingClock));
+                clock += 1;
+            }
+        }
+        messages.sort_by_key(|&(nanos, order, _)| (nanos, order));
+        for (nanos, _, message) in messages {
+            buffer.push_message(HostTime::from_nanos(nanos).timestamp(), &message);
+        }
+
+        self.outputs[output].scheduled_until = Some(to);
+        self.forget_scheduled();
+        buffer
+    }
+
+    fn now_nanos(&self) -> u64 {
+        self.clock.now().as_nanos()
+    }
+
+    fn running(&self) -> Option<Run> {
+        self.runs.last().cloned().filter(Run::is_running)
+    }
+
+    // The earliest time that hasn't been scheduled for any output
+    fn unscheduled_nanos(&self) -> u64 {
+        self.outputs.iter().filter_map(|output| output.scheduled_until).fold(self.now_nanos(), cmp::max)
+    }
+
+    fn end_run(&mut self, nanos: u64) {
+        if let Some(run) = self.runs.last_mut().filter(|run| run.is_running()) {
+            let end_nanos = cmp::max(nanos, run.start_nanos);
+            run.end_nanos = Some(end_nanos);
+            self.position = run.clock_at(end_nanos);
+        }
+    }
+
+    // Drop what has been scheduled for every output
+    fn forget_scheduled(&mut self) {
+        let scheduled = self.outputs.iter().map(|output| output.scheduled_until.unwrap_or(0)).min().unwrap_or(0);
+        self.transport.retain(|&(nanos, _)| nanos >= scheduled);
+        self.runs.retain(|run| run.end_nanos.map_or(true, |end| end > scheduled));
+    }
+}
+
+// Where the clocks are sent, with the index of each destination in the scheduler
+#[derive(Debug)]
+enum ClockTarget {
+    Destinations(OutputPort, Vec<(Destination, usize)>),
+    VirtualSource(VirtualSource, usize),
+}
+
+#[derive(Debug)]
+struct GeneratorState<C> {
+    scheduler: ClockScheduler<C>,
+    target: ClockTarget,
+}
+
+impl<C: Clock> GeneratorState<C> {
+    fn update(&mut self) -> Result<(), Error> {
+        match self.target {
+            ClockTarget::Destinations(ref output_port, ref destinations) => {
+                for &(ref destination, output) in destinations {
+                    let packets = self.scheduler.schedule(output);
+                    if !packets.is_empty() {
+                        output_port.send(destination, &packets)?;
+                    }
+                }
+            },
+            ClockTarget::VirtualSource(ref virtual_source, output) => {
+                let packets = self.scheduler.schedule(output);
+                if !packets.is_empty() {
+                    virtual_source.received(&packets)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Sends MIDI beat clock to some destinations, or from a virtual source, as a clock master.
+///
+/// A worker thread sends the clocks a bit ahead of time, with the timestamps they have to play at,
+/// instead of sleeping until each one is due, so they don't jitter. The timing is done by
+/// a `ClockScheduler`, which sets how long ahead the clocks are sent, and the clock followed.
+/// The destinations are sent as far ahead as their `advance_schedule_time_musec` property asks for.
+///
+/// ```rust,no_run
+/// use coremidi::{Client, ClockGenerator, ClockScheduler, Destination};
+///
+/// let client = Client::new("example-client").unwrap();
+/// let output_port = client.output_port("example-port").unwrap();
+/// let destination = Destination::from_index(0).unwrap();
+/// let generator = ClockGenerator::new(ClockScheduler::new(500_000), output_port, vec![destination]);
+/// generator.start().unwrap();
+/// // ...
+/// generator.set_tempo(400_000).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ClockGenerator<C: Clock + Send + 'static = HostClock> {
+    state: Arc<Mutex<GeneratorState<C>>>,
+    stop: Option<Sender<()>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<C: Clock + Send + 'static> ClockGenerator<C> {
+    /// Create a generator sending to some destinations through an output port, and start its worker thread.
+    ///
+    pub fn new(mut scheduler: ClockScheduler<C>, output_port: OutputPort, destinations: Vec<Destination>) -> ClockGenerator<C> {
+        let destinations = destinations.into_iter().map(|destination| {
+            let advance: i32 = Properties::advance_schedule_time_musec().value_from(&destination).unwrap_or(0);
+            let output = scheduler.add_output(Duration::from_micros(cmp::max(advance, 0) as u64));
+            (destination, output)
+        }).collect();
+        Self::with_target(scheduler, ClockTarget::Destinations(output_port, destinations))
+    }
+
+    /// Create a generator sending from a virtual source, and start its worker thread.
+    ///
+    pub fn with_virtual_source(mut scheduler: ClockScheduler<C>, virtual_source: VirtualSource) -> ClockGenerator<C> {
+        let output = scheduler.add_output(Duration::from_millis(0));
+        Self::with_target(scheduler, ClockTarget::VirtualSource(virtual_source, output))
+    }
+
+    fn with_target(scheduler: ClockScheduler<C>, target: ClockTarget) -> ClockGenerator<C> {
+        // Wake up often enough to always have the next clocks scheduled
+        let interval = cmp::max(scheduler.lookahead() / 4, Duration::from_millis(1));
+        let state = Arc::new(Mutex::new(GeneratorState { scheduler, target }));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let worker_state = state.clone();
+        let worker = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // There is nobody to report the errors to, the next update tries again
+                let _ = lock(&worker_state).update();
+            }
+        });
+        ClockGenerator { state, stop: Some(stop), worker: Some(worker) }
+    }
+
+    /// Send a Start, and run the clocks from the start of the song.
+    ///
+    pub fn start(&self) -> Result<(), Error> {
+        self.update(|scheduler| scheduler.start())
+    }
+
+    /// Send a Stop, and stop the clocks.
+    ///
+    pub fn stop(&self) -> Result<(), Error> {
+        self.update(|scheduler| scheduler.stop())
+    }
+
+    /// Send a Continue, and run the clocks from where they stopped.
+    ///
+    pub fn resume(&self) -> Result<(), Error> {
+        self.update(|scheduler| scheduler.resume())
+    }
+
+    /// Send a Song Position Pointer, in sixteenth notes since the start of the song.
+    ///
+    pub fn set_song_position(&self, sixteenths: u16) -> Result<(), Error> {
+        self.update(|scheduler| scheduler.set_song_position(sixteenths))
+    }
+
+    /// Change the tempo, in microseconds per quarter note.
+    ///
+    pub fn set_tempo(&self, tempo: u32) -> Result<(), Error> {
+        self.update(|scheduler| scheduler.set_tempo(tempo))
+    }
+
+    /// Get the tempo, in microseconds per quarter note.
+    ///
+    pub fn tempo(&self) -> u32 {
+        lock(&self.state).scheduler.tempo()
+    }
+
+    /// Whether the clocks are running.
+    ///
+    pub fn is_running(&self) -> bool {
+        lock(&self.state).scheduler.is_running()
+    }
+
+    /// Get the number of clocks played since the start of the song.
+    ///
+    pub fn song_position(&self) -> u64 {
+        lock(&self.state).scheduler.song_position()
+    }
+
+    fn update<F: FnOnce(&mut ClockScheduler<C>)>(&self, f: F) -> Result<(), Error> {
+        let mut state = lock(&self.state);
+        f(&mut state.scheduler);
+        state.update()
+    }
+}
+
+impl<C: Clock + Send + 'static> Drop for ClockGenerator<C> {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    use {Clock, HostTime, PacketList};
+    use super::ClockScheduler;
+
+    const MS: u64 = 1_000_000;
+
+    #[derive(Clone, Debug, Default)]
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl FakeClock {
+        fn set(&self, millis: u64) {
+            self.0.store(millis * MS, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> HostTime {
+            HostTime::from_nanos(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    // The packets as the nanoseconds they play at and their data
+    fn sent(packet_list: &PacketList) -> Vec<(u64, Vec<u8>)> {
+        packet_list.iter()
+            .map(|packet| (HostTime::from_timestamp(packet.timestamp()).as_nanos(), packet.data().to_vec()))
+            .collect()
+    }
+
+    fn clocks(times: &[u64]) -> Vec<(u64, Vec<u8>)> {
+        times.iter().map(|&nanos| (nanos, vec![0xF8])).collect()
+    }
+
+    fn scheduler(clock: &FakeClock) -> ClockScheduler<FakeClock> {
+        let mut scheduler = ClockScheduler::with_clock(clock.clone(), 500_000);
+        scheduler.set_lookahead(Duration::from_millis(100));
+        scheduler
+    }
+
+    #[test]
+    fn schedules_clocks_at_exact_times() {
+        let clock = FakeClock::default();
+        let mut scheduler = scheduler(&clock);
+        let output = scheduler.add_output(Duration::from_millis(0));
+        clock.set(1000);
+        assert_eq!(sent(&scheduler.schedule(output)), vec![]);
+        assert!(!scheduler.is_running());
+
+        clock.set(1100);
+        scheduler.start();
+        assert!(scheduler.is_running());
+        let mut expected = vec![(1100 * MS, vec![0xFA, 0xF8])];
+        expected.extend(clocks(&[1_120_833_333, 1_141_666_666, 1_162_500_000, 1_183_333_333]));
+        assert_eq!(sent(&scheduler.schedule(output)), expected);
+        assert_eq!(sent(&scheduler.schedule(output)), vec![]);
+
+        clock.set(1150);
+        assert_eq!(sent(&scheduler.schedule(output)), clocks(&[1_204_166_666, 1_225_000_000, 1_245_833_333]));
+
+        // after a thousand seconds, the clocks are still where they should
+        clock.set(1_001_100);
+        assert_eq!(sent(&scheduler.schedule(output))[0], (1_001_100 * MS, vec![0xF8]));
+        assert_eq!(scheduler.song_position(), 48_001);
+    }
+
+    #[test]
+    fn tempo_changes_from_the_next_clock() {
+        let clock = FakeClock::default();
+        let mut scheduler = scheduler(&clock);
+        let output = scheduler.add_output(Duration::from_millis(0));
+        clock.set(1000);
+        scheduler.start();
+        scheduler.schedule(output);
+
+        scheduler.set_tempo(250_000);
+        assert_eq!(scheduler.tempo(), 250_000);
+        clock.set(1050);
+        assert_eq!(sent(&scheduler.schedule(output)), clocks(&[
+            1_104_166_666, 1_114_583_332, 1_124_999_999, 1_135_416_666, 1_145_833_332,
+        ]));
+    }
+
+    #[test]
+    fn transport_messages() {
+        let clock = FakeClock::default();
+        let mut scheduler = scheduler(&clock);
+        let output = scheduler.add_output(Duration::from_millis(0));
+        clock.set(1000);
+        scheduler.start();
+        scheduler.schedule(output);
+
+        scheduler.stop();
+        assert!(!scheduler.is_running());
+        assert_eq!(scheduler.song_position(), 5);
+        scheduler.set_song_position(8);
+        assert_eq!(scheduler.song_position(), 48);
+        scheduler.resume();
+
+        clock.set(1050);
+        let mut expected = vec![(1100 * MS, vec![0xFC, 0xF2, 0x08, 0x00, 0xFB, 0xF8])];
+        expected.extend(clocks(&[1_120_833_333, 1_141_666_666]));
+        assert_eq!(sent(&scheduler.schedule(output)), expected);
+
+        clock.set(1130);
+        assert_eq!(scheduler.song_position(), 50);
+    }
+
+    #[test]
+    fn late_transport_messages_are_sent_right_away() {
+        let clock = FakeClock::default();
+        let mut scheduler = scheduler(&clock);
+        let output = scheduler.add_output(Duration::from_millis(0));
+        clock.set(1000);
+        scheduler.start();
+
+        // the clocks missed are skipped, but not the Start and the first clock
+        clock.set(1030);
+        let mut expected = vec![(1030 * MS, vec![0xFA, 0xF8])];
+        expected.extend(clocks(&[1_041_666_666, 1_062_500_000, 1_083_333_333, 1_104_166_666, 1_125_000_000]));
+        assert_eq!(sent(&scheduler.schedule(output)), expected);
+
+        scheduler.stop();
+        clock.set(1200);
+        assert_eq!(sent(&scheduler.schedule(output)), vec![(1200 * MS, vec![0xFC])]);
+        assert_eq!(scheduler.song_position(), 7);
+    }
+
+    #[test]
+    fn outputs_are_scheduled_as_far_ahead_as_they_ask() {
+        let clock = FakeClock::default();
+        let mut scheduler = scheduler(&clock);
+        let near = scheduler.add_output(Duration::from_millis(0));
+        let far = scheduler.add_output(Duration::from_millis(200));
+        clock.set(1000);
+        scheduler.start();
+        assert_eq!(sent(&scheduler.schedule(near)).len(), 5);
+        assert_eq!(sent(&scheduler.schedule(far)).len(), 10);
+
+        // the tempo changes after what the far output has already scheduled
+        scheduler.set_tempo(250_000);
+        clock.set(1100);
+        assert_eq!(sent(&scheduler.schedule(near)), clocks(&[
+            1_104_166_666, 1_125_000_000, 1_145_833_333, 1_166_666_666, 1_187_500_000,
+        ]));
+        clock.set(1150);
+        assert_eq!(sent(&scheduler.schedule(near)), clocks(&[
+            1_208_333_333, 1_218_749_999, 1_229_166_666, 1_239_583_333, 1_249_999_999,
+        ]));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn generator_sends_to_destinations_and_virtual_sources() {
+        use std::sync::mpsc;
+        use {Client, Destination, Properties, PropertySetter, Source};
+        use super::ClockGenerator;
+
+        let client = Client::new("clock-client").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let near_sender = sender.clone();
+        let _near = client.virtual_destination("clock-near", move |packet_list| {
+            let _ = near_sender.send(("near", sent(packet_list)));
+        }).unwrap();
+        let far = client.virtual_destination("clock-far", move |packet_list| {
+            let _ = sender.send(("far", sent(packet_list)));
+        }).unwrap();
+        Properties::advance_schedule_time_musec().set_value(&far, 200_000).unwrap();
+
+        let clock = FakeClock::default();
+        clock.set(1000);
+        let destinations = vec![
+            Destination::find_by_name("clock-near").unwrap(),
+            Destination::find_by_name("clock-far").unwrap(),
+        ];
+        let generator = ClockGenerator::new(scheduler(&clock), client.output_port("clock-port").unwrap(), destinations);
+        generator.start().unwrap();
+        assert!(generator.is_running());
+        let received: Vec<_> = receiver.try_iter().map(|(name, packets)| (name, packets.len())).collect();
+        assert_eq!(received, vec![("near", 5), ("far", 10)]);
+        drop(generator);
+
+        let (sender, receiver) = mpsc::channel();
+        let input_port = client.input_port("clock-input", move |packet_list| {
+            let _ = sender.send(sent(packet_list));
+        }).unwrap();
+        let virtual_source = client.virtual_source("clock-source").unwrap();
+        input_port.connect_source(&Source::find_by_name("clock-source").unwrap()).unwrap();
+        let generator = ClockGenerator::with_virtual_source(scheduler(&clock), virtual_source);
+        generator.resume().unwrap();
+        let mut resumed = vec![(1000 * MS, vec![0xFB, 0xF8])];
+        resumed.extend(clocks(&[1_020_833_333, 1_041_666_666, 1_062_500_000, 1_083_333_333]));
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![resumed]);
+        assert_eq!(generator.song_position(), 1);
+    }
+}
@@ -1,8 +1,53 @@
 use Object;
 use Device;
+use Entity;
+use backend::{Backend, Current};
+use backend::sys::MIDIObjectRef;
 
 use std::ops::Deref;
 
+impl Device {
+    /// Create a device from its index.
+    /// See `MIDIGetDevice`.
+    ///
+    pub fn from_index(index: usize) -> Option<Device> {
+        match Current::device(index) {
+            0 => None,
+            device_ref => Some(Device { object: Object(device_ref) })
+        }
+    }
+
+    /// Create an external device from its index.
+    /// See `MIDIGetExternalDevice`.
+    ///
+    pub fn external_from_index(index: usize) -> Option<Device> {
+        match Current::external_device(index) {
+            0 => None,
+            device_ref => Some(Device { object: Object(device_ref) })
+        }
+    }
+
+    /// Get the number of entities in the device.
+    /// See `MIDIDeviceGetNumberOfEntities`.
+    ///
+    pub fn number_of_entities(&self) -> usize {
+        Current::device_number_of_entities(self.object.0)
+    }
+
+    /// Get an iterator for the entities in the device.
+    ///
+    /// ```rust,no_run
+    /// for device in coremidi::Devices {
+    ///     for entity in device.entities() {
+    ///         println!("{}: {}", device.display_name().unwrap(), entity.display_name().unwrap());
+    ///     }
+    /// }
+    /// ```
+    pub fn entities(&self) -> EntitiesIterator {
+        EntitiesIterator { device: self.object.0, index: 0, count: self.number_of_entities() }
+    }
+}
+
 impl Deref for Device {
     type Target = Object;
 
@@ -10,3 +55,231 @@ impl Deref for Device {
         &self.object
     }
 }
+
+/// Devices available in the system, which are the drivers' representation of MIDI hardware.
+///
+/// The devices in the system can be iterated as:
+///
+/// ```rust,no_run
+/// for device in coremidi::Devices {
+///   println!("{}", device.display_name().unwrap());
+/// }
+/// ```
+///
+pub struct Devices;
+
+impl Devices {
+    /// Get the number of devices in the system.
+    /// See `MIDIGetNumberOfDevices`.
+    ///
+    pub fn count() -> usize {
+        Current::number_of_devices()
+    }
+}
+
+impl IntoIterator for Devices {
+    type Item = Device;
+    type IntoIter = DevicesIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DevicesIterator { index: 0, count: Self::count() }
+    }
+}
+
+pub struct DevicesIterator {
+    index: usize,
+    count: usize
+}
+
+impl Iterator for DevicesIterator {
+    type Item = Device;
+
+    fn next(&mut self) -> Option<Device> {
+        if self.index < self.count {
+            let device = Device::from_index(self.index);
+            self.index += 1;
+            device
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// External devices in the system, which are the devices added by the user to the MIDI setup,
+/// such as the synthesizers connected to the MIDI ports of an interface.
+///
+/// The external devices in the system can be iterated as:
+///
+/// ```rust,no_run
+/// for device in coremidi::ExternalDevices {
+///   println!("{}", device.display_name().unwrap());
+/// }
+/// ```
+///
+pub struct ExternalDevices;
+
+impl ExternalDevices {
+    /// Get the number of external devices in the system.
+    /// See `MIDIGetNumberOfExternalDevices`.
+    ///
+    pub fn count() -> usize {
+        Current::number_of_external_devices()
+    }
+}
+
+impl IntoIterator for ExternalDevices {
+    type Item = Device;
+    type IntoIter = ExternalDevicesIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ExternalDevicesIterator { index: 0, count: Self::count() }
+    }
+}
+
+pub struct ExternalDevicesIterator {
+    index: usize,
+    count: usize
+}
+
+impl Iterator for ExternalDevicesIterator {
+    type Item = Device;
+
+    fn next(&mut self) -> Option<Device> {
+        if self.index < self.count {
+            let device = Device::external_from_index(self.index);
+            self.index += 1;
+            device
+        }
+        else {
+            None
+        }
+    }
+}
+
+pub struct EntitiesIterator {
+    device: MIDIObjectRef,
+    index: usize,
+    count: usize
+}
+
+impl Iterator for EntitiesIterator {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        while self.index < self.count {
+            let entity_ref = Current::device_entity(self.device, self.index);
+            self.index += 1;
+            if entity_ref != 0 {
+                return Some(Entity { object: Object(entity_ref) });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(all(test, not(target_os = "macos")))]
+mod tests {
+    use backend::{add_device, remove_device};
+    use {Destination, Device, Devices, ExternalDevices, Object, ObjectType, PropertyValue, Source};
+    use super::DevicesIterator;
+
+    #[test]
+    fn device_graph() {
+        let device_ref = add_device("Loopback Interface", false, &[("Port 1", 1, 1), ("Port 2", 0, 2)]);
+        let device = Devices.into_iter().find(|device| device.object.0 == device_ref).unwrap();
+        assert_eq!(device.name(), Some("Loopback Interface".to_string()));
+        assert!(!ExternalDevices.into_iter().any(|device| device.object.0 == device_ref));
+
+        assert_eq!(device.number_of_entities(), 2);
+        let entities: Vec<_> = device.entities().collect();
+        assert_eq!(entities.iter().map(|entity| entity.name().unwrap()).collect::<Vec<_>>(), vec!["Port 1", "Port 2"]);
+        assert!(entities.iter().all(|entity| entity.device().as_ref() == Some(&device)));
+        remove_device(device_ref);
+    }
+
+    #[test]
+    fn external_device_graph() {
+        let device_ref = add_device("Loopback Synth", true, &[("Synth", 1, 1)]);
+        let device = ExternalDevices.into_iter().find(|device| device.object.0 == device_ref).unwrap();
+        assert!(!Devices.into_iter().any(|device| device.object.0 == device_ref));
+
+        let entity = device.entities().next().unwrap();
+        assert_eq!(entity.name(), Some("Synth".to_string()));
+        assert_eq!(entity.device(), Some(device));
+        remove_device(device_ref);
+    }
+
+    #[test]
+    fn missing_devices_end_the_iteration_while_missing_entities_are_skipped() {
+        let device_ref = add_device("Loopback Vanishing", false, &[("Gone 1", 1, 0), ("Gone 2", 0, 1)]);
+        let device = Device { object: Object(device_ref) };
+        let mut entities = device.entities();
+        remove_device(device_ref);
+        assert!(entities.next().is_none());
+        assert_eq!(entities.index, 2);
+
+        // Far enough from the devices added by the tests running in parallel
+        let missing = Devices::count() + 1000;
+        let mut devices = DevicesIterator { index: missing, count: missing + 2 };
+        assert!(devices.next().is_none());
+        assert_eq!(devices.index, missing + 1);
+    }
+
+    #[test]
+    fn find_objects_by_unique_id() {
+        let device_ref = add_device("Loopback Finder", false, &[("Finder Port", 1, 0)]);
+        let external_device_ref = add_device("Loopback External Finder", true, &[("Finder Synth", 0, 1)]);
+        let device = Device { object: Object(device_ref) };
+        let entity = device.entities().next().unwrap();
+        let source = entity.sources().next().unwrap();
+        let external_device = Device { object: Object(external_device_ref) };
+        let external_entity = external_device.entities().next().unwrap();
+        let external_destination = external_entity.destinations().next().unwrap();
+
+        let types: Vec<_> = [&*device, &*entity, &*source, &*external_device, &*external_entity, &*external_destination].iter()
+            .map(|object| Object::from_unique_id(object.unique_id().unwrap()).map(|found| (found.object_type(), found.0)))
+            .collect();
+        assert_eq!(types, vec![
+            Ok((ObjectType::Device, device.object.0)),
+            Ok((ObjectType::Entity, entity.object.0)),
+            Ok((ObjectType::Source, source.endpoint.object.0)),
+            Ok((ObjectType::ExternalDevice, external_device.object.0)),
+            Ok((ObjectType::ExternalEntity, external_entity.object.0)),
+            Ok((ObjectType::ExternalDestination, external_destination.endpoint.object.0)),
+        ]);
+        assert_eq!(Source::find_by_unique_id(source.unique_id().unwrap()), Some(source));
+        assert_eq!(Destination::find_by_unique_id(external_destination.unique_id().unwrap()), None);
+        assert_eq!(Destination::find_by_name("Finder Synth"), None);
+        remove_device(device_ref);
+        remove_device(external_device_ref);
+    }
+
+    #[test]
+    fn device_properties_include_children_when_deep() {
+        let device_ref = add_device("Loopback Properties", false, &[("Ports", 1, 2)]);
+        let device = Object(device_ref);
+        device.set_property_integer("offline", 1).unwrap();
+
+        let shallow = device.properties(false).unwrap();
+        assert_eq!(shallow.get("name"), Some(&PropertyValue::String("Loopback Properties".to_string())));
+        assert_eq!(shallow.get("offline"), Some(&PropertyValue::Integer(1)));
+        assert!(!shallow.contains_key("entities"));
+
+        let deep = device.properties(true).unwrap();
+        let entities = match deep.get("entities") {
+            Some(PropertyValue::Array(entities)) => entities.clone(),
+            other => panic!("unexpected entities: {:?}", other),
+        };
+        assert_eq!(entities.len(), 1);
+        match entities[0] {
+            PropertyValue::Dictionary(ref entity) => {
+                assert_eq!(entity.get("name"), Some(&PropertyValue::String("Ports".to_string())));
+                assert_eq!(entity.get("sources").map(|s| match s { PropertyValue::Array(a) => a.len(), _ => 0 }), Some(1));
+                assert_eq!(entity.get("destinations").map(|d| match d { PropertyValue::Array(a) => a.len(), _ => 0 }), Some(2));
+            },
+            ref other => panic!("unexpected entity: {:?}", other),
+        }
+        remove_device(device_ref);
+    }
+}
@@ -0,0 +1,430 @@
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex, Weak};
+
+#[cfg(feature = "stream")]
+use std::collections::VecDeque;
+#[cfg(feature = "stream")]
+use std::pin::Pin;
+#[cfg(feature = "stream")]
+use std::task::{Context, Poll, Waker};
+
+#[cfg(feature = "stream")]
+use futures_core::Stream;
+
+use {Notification, NotificationKind, ObjectType};
+use lock;
+
+/// Selects the notifications delivered to a subscriber of a `NotificationDispatcher`.
+///
+/// An empty filter accepts every notification. Adding kinds restricts it to those kinds of
+/// notifications, and adding object types restricts it to the notifications about objects
+/// of those types (see `Notification::object_type`):
+///
+/// ```
+/// use coremidi::{NotificationFilter, NotificationKind, ObjectType};
+/// let filter = NotificationFilter::new()
+///     .kind(NotificationKind::ObjectAdded)
+///     .kind(NotificationKind::ObjectRemoved)
+///     .object_type(ObjectType::Source)
+///     .object_type(ObjectType::Destination);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NotificationFilter {
+    kinds: Vec<NotificationKind>,
+    object_types: Vec<ObjectType>,
+}
+
+impl NotificationFilter {
+    /// Create a filter that accepts every notification.
+    ///
+    pub fn new() -> NotificationFilter {
+        NotificationFilter::default()
+    }
+
+    /// Accept the notifications of the given kind.
+    ///
+    pub fn kind(mut self, kind: NotificationKind) -> NotificationFilter {
+        self.kinds.push(kind);
+        self
+    }
+
+    /// Accept the notifications about objects of the given type.
+    ///
+    pub fn object_type(mut self, object_type: ObjectType) -> NotificationFilter {
+        self.object_types.push(object_type);
+        self
+    }
+
+    /// Check whether a notification passes the filter.
+    ///
+    pub fn matches(&self, notification: &Notification) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&notification.kind())) &&
+            (self.object_types.is_empty() ||
+                notification.object_type().map_or(false, |object_type| self.object_types.contains(&object_type)))
+    }
+}
+
+type NotificationCallback = Box<FnMut(&Notification) + Send>;
+
+type SubscriberCallback = Arc<Mutex<NotificationCallback>>;
+
+struct Subscriber {
+    id: u64,
+    filter: NotificationFilter,
+    callback: SubscriberCallback,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    list: Vec<Subscriber>,
+}
+
+/// Delivers the notifications of a client to any number of subscribers.
+///
+/// Subscribers can be added and removed at any time, and each one gets the notifications that
+/// pass its `NotificationFilter`, either in a callback, through a channel, or (with the `stream`
+/// feature enabled) as a `futures_core::Stream`. Subscribing returns a `Subscription` handle,
+/// and the subscriber is removed when the handle is dropped.
+///
+/// The dispatcher is a cheap handle that can be cloned and shared between components:
+///
+/// ```rust,no_run
+/// use coremidi::{Client, NotificationDispatcher, NotificationFilter, NotificationKind};
+/// let dispatcher = NotificationDispatcher::new();
+/// let client = Client::new_with_dispatcher("example-client", &dispatcher).unwrap();
+///
+/// let filter = NotificationFilter::new().kind(NotificationKind::ObjectAdded);
+/// let (subscription, receiver) = dispatcher.channel(filter);
+/// for notification in receiver.iter() {
+///     println!("{:?}", notification);
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct NotificationDispatcher {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl NotificationDispatcher {
+    /// Create a dispatcher without subscribers.
+    ///
+    pub fn new() -> NotificationDispatcher {
+        NotificationDispatcher::default()
+    }
+
+    /// Deliver a notification to the subscribers whose filter accepts it.
+    /// The callbacks are called from the calling thread, which for the notifications of a client
+    /// is the thread where CoreMIDI delivers them.
+    ///
+    pub fn dispatch(&self, notification: &Notification) {
+        // The subscribers are called without holding the lock, so they can subscribe and unsubscribe
+        let callbacks: Vec<SubscriberCallback> = lock(&self.subscribers).list.iter()
+            .filter(|subscriber| subscriber.filter.matches(notification))
+            .map(|subscriber| subscriber.callback.clone())
+            .collect();
+        for callback in callbacks {
+            (&mut *lock(&callback))(notification);
+        }
+    }
+
+    /// Add a subscriber that gets the notifications accepted by the filter in a callback.
+    ///
+    pub fn subscribe<F>(&self, filter: NotificationFilter, callback: F) -> Subscription
+        where F: FnMut(&Notification) + Send + 'static
+    {
+        let mut subscribers = lock(&self.subscribers);
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.list.push(Subscriber { id, filter, callback: Arc::new(Mutex::new(Box::new(callback))) });
+        Subscription { id, subscribers: Arc::downgrade(&self.subscribers) }
+    }
+
+    /// Add a subscriber that gets a copy of the notifications accepted by the filter through a channel,
+    /// so they can be handled in any thread.
+    /// The receiver disconnects when the subscription, or every handle of the dispatcher, is dropped.
+    ///
+    pub fn channel(&self, filter: NotificationFilter) -> (Subscription, mpsc::Receiver<Notification>) {
+        let (sender, receiver) = mpsc::channel();
+        let subscription = self.subscribe(filter, move |notification| {
+            let _ = sender.send(notification.clone());
+        });
+        (subscription, receiver)
+    }
+
+    /// Add a subscriber that gets a copy of the notifications accepted by the filter as a stream.
+    /// The subscriber is removed when the stream is dropped, and the stream finishes when every
+    /// handle of the dispatcher, including the one owned by the client, is dropped.
+    ///
+    #[cfg(feature = "stream")]
+    pub fn stream(&self, filter: NotificationFilter) -> NotificationStream {
+        let sender = StreamSender { state: Arc::new(Mutex::new(StreamState::default())) };
+        let state = sender.state.clone();
+        let subscription = self.subscribe(filter, move |notification| sender.send(notification));
+        NotificationStream { state, _subscription: subscription }
+    }
+
+    /// The number of subscribers.
+    ///
+    pub fn subscribers(&self) -> usize {
+        lock(&self.subscribers).list.len()
+    }
+}
+
+impl fmt::Debug for NotificationDispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NotificationDispatcher").field("subscribers", &self.subscribers()).finish()
+    }
+}
+
+/// The handle of a subscriber of a `NotificationDispatcher`.
+///
+/// The subscriber is removed when the handle is dropped, or `unsubscribe` is called.
+///
+pub struct Subscription {
+    id: u64,
+    subscribers: Weak<Mutex<Subscribers>>,
+}
+
+impl Subscription {
+    /// Remove the subscriber from the dispatcher.
+    ///
+    pub fn unsubscribe(self) {}
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription").field("id", &self.id).finish()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(subscribers) = self.subscribers.upgrade() {
+            let id = self.id;
+            let removed: Vec<Subscriber> = {
+                let mut subscribers = lock(&subscribers);
+                let (removed, kept) = subscribers.list.drain(..).partition(|subscriber| subscriber.id == id);
+                subscribers.list = kept;
+                removed
+            };
+            // The callback is dropped out of the lock, as it might need to notify a stream
+            drop(removed);
+        }
+    }
+}
+
+#[cfg(feature = "stream")]
+#[derive(Default)]
+struct StreamState {
+    queue: VecDeque<Notification>,
+    waker: Option<Waker>,
+    finished: bool,
+}
+
+#[cfg(feature = "stream")]
+impl StreamState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// The sending half of a stream, owned by the subscriber callback
+#[cfg(feature = "stream")]
+struct StreamSender {
+    state: Arc<Mutex<StreamState>>,
+}
+
+#[cfg(feature = "stream")]
+impl StreamSender {
+    fn send(&self, notification: &Notification) {
+        let mut state = lock(&self.state);
+        state.queue.push_back(notification.clone());
+        state.wake();
+    }
+}
+
+#[cfg(feature = "stream")]
+impl Drop for StreamSender {
+    fn drop(&mut self) {
+        let mut state = lock(&self.state);
+        state.finished = true;
+        state.wake();
+    }
+}
+
+/// The notifications accepted by a filter, as a `futures_core::Stream`.
+/// It is created with `NotificationDispatcher::stream`.
+///
+#[cfg(feature = "stream")]
+pub struct NotificationStream {
+    state: Arc<Mutex<StreamState>>,
+    _subscription: Subscription,
+}
+
+#[cfg(feature = "stream")]
+impl Stream for NotificationStream {
+    type Item = Notification;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Notification>> {
+        let mut state = lock(&self.state);
+        match state.queue.pop_front() {
+            Some(notification) => Poll::Ready(Some(notification)),
+            None if state.finished => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+
+    use {AddedRemovedInfo, Notification, NotificationKind, Object, ObjectType};
+    use super::{NotificationDispatcher, NotificationFilter};
+
+    fn added(child_type: ObjectType) -> Notification {
+        Notification::ObjectAdded(AddedRemovedInfo {
+            parent: Object(0),
+            parent_type: ObjectType::Other,
+            child: Object(1),
+            child_type,
+        })
+    }
+
+    #[test]
+    fn filter_by_kind_and_object_type() {
+        let all = NotificationFilter::new();
+        assert!(all.matches(&Notification::SetupChanged));
+        assert!(all.matches(&added(ObjectType::Source)));
+
+        let added_sources = NotificationFilter::new()
+            .kind(NotificationKind::ObjectAdded)
+            .object_type(ObjectType::Source);
+        assert!(added_sources.matches(&added(ObjectType::Source)));
+        assert!(!added_sources.matches(&added(ObjectType::Destination)));
+        assert!(!added_sources.matches(&Notification::SetupChanged));
+
+        let setup = NotificationFilter::new().kind(NotificationKind::SetupChanged).kind(NotificationKind::ThruConnectionsChanged);
+        assert!(setup.matches(&Notification::ThruConnectionsChanged));
+        assert!(!setup.matches(&added(ObjectType::Source)));
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let dispatcher = NotificationDispatcher::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let all_received = received.clone();
+        let all = dispatcher.subscribe(NotificationFilter::new(), move |notification| {
+            all_received.lock().unwrap().push(("all", notification.kind()));
+        });
+        let setup_received = received.clone();
+        let setup = dispatcher.subscribe(NotificationFilter::new().kind(NotificationKind::SetupChanged), move |notification| {
+            setup_received.lock().unwrap().push(("setup", notification.kind()));
+        });
+        assert_eq!(dispatcher.subscribers(), 2);
+
+        dispatcher.dispatch(&Notification::SetupChanged);
+        dispatcher.dispatch(&added(ObjectType::Device));
+        all.unsubscribe();
+        dispatcher.dispatch(&Notification::SetupChanged);
+        drop(setup);
+        dispatcher.dispatch(&Notification::SetupChanged);
+
+        assert_eq!(dispatcher.subscribers(), 0);
+        assert_eq!(*received.lock().unwrap(), vec![
+            ("all", NotificationKind::SetupChanged),
+            ("setup", NotificationKind::SetupChanged),
+            ("all", NotificationKind::ObjectAdded),
+            ("setup", NotificationKind::SetupChanged),
+        ]);
+    }
+
+    #[test]
+    fn unsubscribe_from_the_callback() {
+        let dispatcher = NotificationDispatcher::new();
+        let (tx, rx) = mpsc::channel();
+        let subscription = Arc::new(Mutex::new(None));
+        let own_subscription = subscription.clone();
+        *subscription.lock().unwrap() = Some(dispatcher.subscribe(NotificationFilter::new(), move |notification| {
+            tx.send(notification.clone()).unwrap();
+            own_subscription.lock().unwrap().take();
+        }));
+
+        dispatcher.dispatch(&Notification::SetupChanged);
+        dispatcher.dispatch(&Notification::SetupChanged);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![Notification::SetupChanged]);
+        assert_eq!(dispatcher.subscribers(), 0);
+    }
+
+    #[test]
+    fn channel_receives_owned_notifications() {
+        let dispatcher = NotificationDispatcher::new();
+        let (subscription, receiver) = dispatcher.channel(NotificationFilter::new().object_type(ObjectType::Source));
+
+        dispatcher.dispatch(&added(ObjectType::Source));
+        dispatcher.dispatch(&added(ObjectType::Destination));
+        dispatcher.dispatch(&Notification::SetupChanged);
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![added(ObjectType::Source)]);
+
+        drop(subscription);
+        assert!(receiver.recv().is_err());
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn stream_yields_notifications_until_the_dispatcher_is_dropped() {
+        use std::pin::Pin;
+        use std::ptr;
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+        use futures_core::Stream;
+
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker { noop_raw_waker() }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut context = Context::from_waker(&waker);
+        let dispatcher = NotificationDispatcher::new();
+        let mut stream = dispatcher.stream(NotificationFilter::new().kind(NotificationKind::SetupChanged));
+
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Pending);
+        dispatcher.dispatch(&added(ObjectType::Source));
+        dispatcher.dispatch(&Notification::SetupChanged);
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Ready(Some(Notification::SetupChanged)));
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Pending);
+        drop(dispatcher);
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Ready(None));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn client_notifications_are_dispatched() {
+        use Client;
+
+        let dispatcher = NotificationDispatcher::new();
+        let client = Client::new_with_dispatcher("dispatcher-client", &dispatcher).unwrap();
+        let (subscription, receiver) = dispatcher.channel(NotificationFilter::new()
+            .kind(NotificationKind::ObjectAdded)
+            .object_type(ObjectType::Destination));
+
+        let source = client.virtual_source("dispatcher-source").unwrap();
+        let destination = client.virtual_destination("dispatcher-destination", |_| {}).unwrap();
+        let added: Vec<_> = receiver.try_iter().filter_map(|notification| match notification {
+            Notification::ObjectAdded(info) => Some(info.child),
+            _ => None,
+        }).collect();
+        // Other tests create endpoints concurrently, so only the ones created here are checked
+        assert!(added.contains(&Object(destination.endpoint.object.0)));
+        assert!(!added.contains(&Object(source.endpoint.object.0)));
+        subscription.unsubscribe();
+    }
+}
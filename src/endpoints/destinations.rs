@@ -1,25 +1,45 @@
-use coremidi_sys::{
-    MIDIGetNumberOfDestinations, MIDIGetDestination, MIDIEndpointDispose, ItemCount
-};
-
 use std::ops::Deref;
 
+use AnyObject;
 use Object;
 use Endpoint;
 use Destination;
 use VirtualDestination;
+use backend::{Backend, Current};
 
 impl Destination {
     /// Create a destination endpoint from its index.
     /// See [MIDIGetDestination](https://developer.apple.com/reference/coremidi/1495108-midigetdestination)
     ///
     pub fn from_index(index: usize) -> Option<Destination> {
-        let endpoint_ref = unsafe { MIDIGetDestination(index as ItemCount) };
+        let endpoint_ref = Current::destination(index);
         match endpoint_ref {
             0 => None,
             _ => Some(Destination { endpoint: Endpoint { object: Object(endpoint_ref) } })
         }
     }
+
+    /// Find the destination with the given unique ID, which doesn't change when other endpoints come and go.
+    /// See `MIDIObjectFindByUniqueID`.
+    ///
+    pub fn find_by_unique_id(unique_id: u32) -> Option<Destination> {
+        match Object::from_unique_id(unique_id) {
+            Ok(AnyObject::Destination(destination)) => Some(destination),
+            _ => None
+        }
+    }
+
+    /// Find the first destination with the given name.
+    ///
+    pub fn find_by_name(name: &str) -> Option<Destination> {
+        Destinations.into_iter().find(|destination| destination.name().map_or(false, |destination_name| destination_name == name))
+    }
+
+    /// Find the first destination with the given display name, which includes the names of its device and entity.
+    ///
+    pub fn find_by_display_name(display_name: &str) -> Option<Destination> {
+        Destinations.into_iter().find(|destination| destination.display_name().map_or(false, |destination_name| destination_name == display_name))
+    }
 }
 
 impl Deref for Destination {
@@ -53,7 +73,7 @@ impl Destinations {
     /// See [MIDIGetNumberOfDestinations](https://developer.apple.com/reference/coremidi/1495309-midigetnumberofdestinations).
     ///
     pub fn count() -> usize {
-        unsafe { MIDIGetNumberOfDestinations() as usize }
+        Current::number_of_destinations()
     }
 }
 
@@ -100,6 +120,6 @@ impl Deref for VirtualDestination {
 
 impl Drop for VirtualDestination {
     fn drop(&mut self) {
-        unsafe { MIDIEndpointDispose(self.endpoint.object.0) };
+        let _ = Current::endpoint_dispose(self.endpoint.object.0);
     }
 }
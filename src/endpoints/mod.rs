@@ -1,19 +1,27 @@
-use core_foundation_sys::base::OSStatus;
-
-use coremidi_sys::MIDIFlushOutput;
-
 use std::ops::Deref;
 
+use Error;
 use Object;
 use Endpoint;
+use Entity;
+use backend::{Backend, Current};
 
 impl Endpoint {
     /// Unschedules previously-sent packets.
     /// See [MIDIFlushOutput](https://developer.apple.com/reference/coremidi/1495312-midiflushoutput).
     ///
-    pub fn flush(&self) -> Result<(), OSStatus> {
-        let status = unsafe { MIDIFlushOutput(self.object.0) };
-        if status == 0 { Ok(()) } else { Err(status) }
+    pub fn flush(&self) -> Result<(), Error> {
+        Current::flush_output(self.object.0).map_err(Error::from)
+    }
+
+    /// Get the entity that owns the endpoint, or `None` for virtual endpoints, which don't belong to any entity.
+    /// See `MIDIEndpointGetEntity`.
+    ///
+    pub fn entity(&self) -> Option<Entity> {
+        match Current::endpoint_entity(self.object.0) {
+            Ok(0) | Err(_) => None,
+            Ok(entity_ref) => Some(Entity { object: Object(entity_ref) })
+        }
     }
 }
 
@@ -1,28 +1,47 @@
-use core_foundation_sys::base::OSStatus;
-
-use coremidi_sys::{
-    MIDIGetNumberOfSources, MIDIGetSource, MIDIReceived, MIDIEndpointDispose, ItemCount
-};
-
 use std::ops::Deref;
 
+use Error;
+use AnyObject;
 use Object;
 use Endpoint;
 use Source;
 use VirtualSource;
 use PacketList;
+use backend::{Backend, Current};
 
 impl Source {
     /// Create a source endpoint from its index.
     /// See [MIDIGetSource](https://developer.apple.com/reference/coremidi/1495168-midigetsource)
     ///
     pub fn from_index(index: usize) -> Option<Source> {
-        let endpoint_ref = unsafe { MIDIGetSource(index as ItemCount) };
+        let endpoint_ref = Current::source(index);
         match endpoint_ref {
             0 => None,
             _ => Some(Source { endpoint: Endpoint { object: Object(endpoint_ref) } })
         }
     }
+
+    /// Find the source with the given unique ID, which doesn't change when other endpoints come and go.
+    /// See `MIDIObjectFindByUniqueID`.
+    ///
+    pub fn find_by_unique_id(unique_id: u32) -> Option<Source> {
+        match Object::from_unique_id(unique_id) {
+            Ok(AnyObject::Source(source)) => Some(source),
+            _ => None
+        }
+    }
+
+    /// Find the first source with the given name.
+    ///
+    pub fn find_by_name(name: &str) -> Option<Source> {
+        Sources.into_iter().find(|source| source.name().map_or(false, |source_name| source_name == name))
+    }
+
+    /// Find the first source with the given display name, which includes the names of its device and entity.
+    ///
+    pub fn find_by_display_name(display_name: &str) -> Option<Source> {
+        Sources.into_iter().find(|source| source.display_name().map_or(false, |source_name| source_name == display_name))
+    }
 }
 
 impl Deref for Source {
@@ -56,7 +75,7 @@ impl Sources {
     /// See [MIDIGetNumberOfSources](https://developer.apple.com/reference/coremidi/1495116-midigetnumberofsources).
     ///
     pub fn count() -> usize {
-        unsafe { MIDIGetNumberOfSources() as usize }
+        Current::number_of_sources()
     }
 }
 
@@ -93,12 +112,10 @@ impl VirtualSource {
     /// Distributes incoming MIDI from a source to the client input ports which are connected to that source.
     /// See [MIDIReceived](https://developer.apple.com/reference/coremidi/1495276-midireceived)
     ///
-    pub fn received(&self, packet_list: &PacketList) -> Result<(), OSStatus> {
-        let status = unsafe { MIDIReceived(
+    pub fn received(&self, packet_list: &PacketList) -> Result<(), Error> {
+        Current::received(
             self.endpoint.object.0,
-            packet_list.as_ptr())
-        };
-        if status == 0 { Ok(()) } else { Err(status) }
+            packet_list).map_err(Error::from)
     }
 }
 
@@ -112,6 +129,6 @@ impl Deref for VirtualSource {
 
 impl Drop for VirtualSource {
     fn drop(&mut self) {
-        unsafe { MIDIEndpointDispose(self.endpoint.object.0) };
+        let _ = Current::endpoint_dispose(self.endpoint.object.0);
     }
 }
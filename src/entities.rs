@@ -0,0 +1,146 @@
+use std::ops::Deref;
+
+use Object;
+use Device;
+use Endpoint;
+use Entity;
+use Source;
+use Destination;
+use backend::{Backend, Current};
+use backend::sys::MIDIObjectRef;
+
+impl Entity {
+    /// Get the device that owns the entity.
+    /// See `MIDIEntityGetDevice`.
+    ///
+    pub fn device(&self) -> Option<Device> {
+        match Current::entity_device(self.object.0) {
+            Ok(0) | Err(_) => None,
+            Ok(device_ref) => Some(Device { object: Object(device_ref) })
+        }
+    }
+
+    /// Get the number of sources in the entity.
+    /// See `MIDIEntityGetNumberOfSources`.
+    ///
+    pub fn number_of_sources(&self) -> usize {
+        Current::entity_number_of_sources(self.object.0)
+    }
+
+    /// Get an iterator for the sources in the entity.
+    ///
+    pub fn sources(&self) -> EntitySourcesIterator {
+        EntitySourcesIterator { entity: self.object.0, index: 0, count: self.number_of_sources() }
+    }
+
+    /// Get the number of destinations in the entity.
+    /// See `MIDIEntityGetNumberOfDestinations`.
+    ///
+    pub fn number_of_destinations(&self) -> usize {
+        Current::entity_number_of_destinations(self.object.0)
+    }
+
+    /// Get an iterator for the destinations in the entity.
+    ///
+    pub fn destinations(&self) -> EntityDestinationsIterator {
+        EntityDestinationsIterator { entity: self.object.0, index: 0, count: self.number_of_destinations() }
+    }
+}
+
+impl Deref for Entity {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        &self.object
+    }
+}
+
+pub struct EntitySourcesIterator {
+    entity: MIDIObjectRef,
+    index: usize,
+    count: usize
+}
+
+impl Iterator for EntitySourcesIterator {
+    type Item = Source;
+
+    fn next(&mut self) -> Option<Source> {
+        while self.index < self.count {
+            let source_ref = Current::entity_source(self.entity, self.index);
+            self.index += 1;
+            if source_ref != 0 {
+                return Some(Source { endpoint: Endpoint { object: Object(source_ref) } });
+            }
+        }
+        None
+    }
+}
+
+pub struct EntityDestinationsIterator {
+    entity: MIDIObjectRef,
+    index: usize,
+    count: usize
+}
+
+impl Iterator for EntityDestinationsIterator {
+    type Item = Destination;
+
+    fn next(&mut self) -> Option<Destination> {
+        while self.index < self.count {
+            let destination_ref = Current::entity_destination(self.entity, self.index);
+            self.index += 1;
+            if destination_ref != 0 {
+                return Some(Destination { endpoint: Endpoint { object: Object(destination_ref) } });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(all(test, not(target_os = "macos")))]
+mod tests {
+    use backend::{add_device, remove_device};
+    use {Client, Destinations, Device, Object, PacketBuffer, Sources};
+
+    #[test]
+    fn entity_endpoints() {
+        let device_ref = add_device("Loopback Endpoints", false, &[("Port 1", 1, 1), ("Port 2", 0, 2)]);
+        let device = Device { object: Object(device_ref) };
+        let entities: Vec<_> = device.entities().collect();
+        assert_eq!((entities[0].number_of_sources(), entities[0].number_of_destinations()), (1, 1));
+        assert_eq!(entities[1].sources().count(), 0);
+        assert_eq!(entities[1].destinations().count(), 2);
+
+        let source = entities[0].sources().next().unwrap();
+        let destination = entities[1].destinations().next().unwrap();
+        assert!(Sources.into_iter().any(|s| s == source));
+        assert_eq!(source.entity().as_ref(), Some(&entities[0]));
+        assert_eq!(destination.entity().as_ref(), Some(&entities[1]));
+
+        let client = Client::new("Loopback Client").unwrap();
+        let output_port = client.output_port("Loopback Output").unwrap();
+        assert!(Destinations.into_iter().any(|d| d == destination));
+        output_port.send(&destination, &PacketBuffer::new(0, &[0x90, 0x40, 0x7f])).unwrap();
+        remove_device(device_ref);
+    }
+
+    #[test]
+    fn external_endpoints_are_not_listed() {
+        let device_ref = add_device("Loopback External Endpoints", true, &[("Synth", 1, 1)]);
+        let device = Device { object: Object(device_ref) };
+        let entity = device.entities().next().unwrap();
+        let source = entity.sources().next().unwrap();
+        let destination = entity.destinations().next().unwrap();
+        assert!(!Sources.into_iter().any(|s| s == source));
+        assert!(!Destinations.into_iter().any(|d| d == destination));
+        assert_eq!(destination.entity(), Some(entity));
+        remove_device(device_ref);
+    }
+
+    #[test]
+    fn virtual_endpoints_have_no_entity() {
+        let client = Client::new("Loopback Client").unwrap();
+        let source = client.virtual_source("Loopback Source").unwrap();
+        assert_eq!(source.entity(), None);
+    }
+}
@@ -0,0 +1,175 @@
+use std::error;
+use std::fmt;
+
+use backend::sys::{
+    OSStatus,
+    kMIDIIDNotUnique,
+    kMIDIInvalidClient,
+    kMIDIInvalidPort,
+    kMIDIMessageSendErr,
+    kMIDINoConnection,
+    kMIDINoCurrentSetup,
+    kMIDINotPermitted,
+    kMIDIObjectNotFound,
+    kMIDIServerStartErr,
+    kMIDISetupFormatErr,
+    kMIDIUnknownEndpoint,
+    kMIDIUnknownError,
+    kMIDIUnknownProperty,
+    kMIDIWrongEndpointType,
+    kMIDIWrongPropertyType,
+    kMIDIWrongThread,
+};
+
+/// The errors returned by the MIDI server, one for each of the `kMIDI*` error codes.
+///
+/// It can be converted from and into the raw `OSStatus`, so code that still deals with
+/// the numeric codes can keep doing it:
+///
+/// ```
+/// use coremidi::Error;
+/// assert_eq!(Error::from(-10830), Error::InvalidClient);
+/// assert_eq!(Error::InvalidClient.code(), -10830);
+/// assert_eq!(Error::from(-50), Error::Other(-50));
+/// ```
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Error {
+    /// An invalid client was passed.
+    InvalidClient,
+    /// An invalid port was passed.
+    InvalidPort,
+    /// A source was passed where a destination was expected, or vice versa.
+    WrongEndpointType,
+    /// An attempt to close a connection that didn't exist.
+    NoConnection,
+    /// An invalid endpoint was passed.
+    UnknownEndpoint,
+    /// An attempt to query a property that is not set on the object.
+    UnknownProperty,
+    /// An attempt to set a property with a value of the wrong type.
+    WrongPropertyType,
+    /// There is no current MIDI setup.
+    NoCurrentSetup,
+    /// The communication with the MIDI server failed.
+    MessageSendErr,
+    /// The MIDI server could not be started.
+    ServerStartErr,
+    /// The saved state of the MIDI setup could not be read.
+    SetupFormatErr,
+    /// A driver was called from a thread other than the main one.
+    WrongThread,
+    /// The requested object does not exist.
+    ObjectNotFound,
+    /// An attempt to set a unique ID that is already taken.
+    IDNotUnique,
+    /// The process is not allowed to do the operation, like using CoreMIDI from a sandboxed app without permission.
+    NotPermitted,
+    /// An internal error of the MIDI server.
+    UnknownError,
+    /// A status code which is not a MIDI error, like the ones from the rest of the operating system.
+    Other(OSStatus),
+}
+
+impl Error {
+    /// Get the raw `OSStatus` code of the error.
+    ///
+    pub fn code(self) -> OSStatus {
+        match self {
+            Error::InvalidClient => kMIDIInvalidClient,
+            Error::InvalidPort => kMIDIInvalidPort,
+            Error::WrongEndpointType => kMIDIWrongEndpointType,
+            Error::NoConnection => kMIDINoConnection,
+            Error::UnknownEndpoint => kMIDIUnknownEndpoint,
+            Error::UnknownProperty => kMIDIUnknownProperty,
+            Error::WrongPropertyType => kMIDIWrongPropertyType,
+            Error::NoCurrentSetup => kMIDINoCurrentSetup,
+            Error::MessageSendErr => kMIDIMessageSendErr,
+            Error::ServerStartErr => kMIDIServerStartErr,
+            Error::SetupFormatErr => kMIDISetupFormatErr,
+            Error::WrongThread => kMIDIWrongThread,
+            Error::ObjectNotFound => kMIDIObjectNotFound,
+            Error::IDNotUnique => kMIDIIDNotUnique,
+            Error::NotPermitted => kMIDINotPermitted,
+            Error::UnknownError => kMIDIUnknownError,
+            Error::Other(status) => status,
+        }
+    }
+}
+
+impl From<OSStatus> for Error {
+    fn from(status: OSStatus) -> Error {
+        [
+            Error::InvalidClient,
+            Error::InvalidPort,
+            Error::WrongEndpointType,
+            Error::NoConnection,
+            Error::UnknownEndpoint,
+            Error::UnknownProperty,
+            Error::WrongPropertyType,
+            Error::NoCurrentSetup,
+            Error::MessageSendErr,
+            Error::ServerStartErr,
+            Error::SetupFormatErr,
+            Error::WrongThread,
+            Error::ObjectNotFound,
+            Error::IDNotUnique,
+            Error::NotPermitted,
+            Error::UnknownError,
+        ].iter().cloned().find(|error| error.code() == status).unwrap_or(Error::Other(status))
+    }
+}
+
+impl From<Error> for OSStatus {
+    fn from(error: Error) -> OSStatus {
+        error.code()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidClient => write!(f, "invalid MIDI client"),
+            Error::InvalidPort => write!(f, "invalid MIDI port"),
+            Error::WrongEndpointType => write!(f, "wrong endpoint type, a source was used as a destination or vice versa"),
+            Error::NoConnection => write!(f, "the connection does not exist"),
+            Error::UnknownEndpoint => write!(f, "unknown MIDI endpoint"),
+            Error::UnknownProperty => write!(f, "the property is not set on the object"),
+            Error::WrongPropertyType => write!(f, "the property value has the wrong type"),
+            Error::NoCurrentSetup => write!(f, "there is no current MIDI setup"),
+            Error::MessageSendErr => write!(f, "the communication with the MIDI server failed"),
+            Error::ServerStartErr => write!(f, "the MIDI server could not be started"),
+            Error::SetupFormatErr => write!(f, "the MIDI setup could not be read"),
+            Error::WrongThread => write!(f, "a driver was called from the wrong thread"),
+            Error::ObjectNotFound => write!(f, "the MIDI object was not found"),
+            Error::IDNotUnique => write!(f, "the unique ID is already in use"),
+            Error::NotPermitted => write!(f, "the operation is not permitted"),
+            Error::UnknownError => write!(f, "unknown error in the MIDI server"),
+            Error::Other(status) => write!(f, "error {}", status),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use Error;
+
+    #[test]
+    fn error_codes_round_trip() {
+        for code in -10845..-10829 {
+            let error = Error::from(code);
+            assert!(match error { Error::Other(_) => false, _ => true }, "{} is a MIDI error", code);
+            assert_eq!(error.code(), code);
+        }
+        assert_eq!(Error::from(-10846), Error::Other(-10846));
+        assert_eq!(Error::from(0), Error::Other(0));
+        assert_eq!(i32::from(Error::WrongPropertyType), -10836);
+    }
+
+    #[test]
+    fn error_display() {
+        assert_eq!(Error::UnknownProperty.to_string(), "the property is not set on the object");
+        assert_eq!(Error::Other(-50).to_string(), "error -50");
+    }
+}
@@ -0,0 +1,600 @@
+use backend::sys::{
+    MIDIEventList, MIDIEventPacket, MIDIEventPacketNext, MIDIProtocolID, MIDITimeStamp,
+    kMIDIProtocol_1_0, kMIDIProtocol_2_0,
+};
+
+use std::borrow::{Borrow, ToOwned};
+use std::cmp;
+use std::fmt;
+use std::slice;
+
+use packets::Timestamp;
+use ump::{UmpError, UmpMessage};
+
+const MAX_EVENT_PACKET_WORDS: usize = 64;
+
+const EVENT_LIST_HEADER_WORDS: usize = 2;   // MIDIEventList::protocol: MIDIProtocolID + MIDIEventList::numPackets: UInt32
+const EVENT_PACKET_HEADER_WORDS: usize = 2 + // MIDIEventPacket::timeStamp: MIDITimeStamp/UInt64
+                                         1;  // MIDIEventPacket::wordCount: UInt32
+
+/// The MIDI protocol used by the Universal MIDI Packets in an `EventList`.
+/// See [MIDIProtocolID](https://developer.apple.com/reference/coremidi/midiprotocolid).
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Protocol {
+    /// MIDI 1.0 messages, carried in MIDI 1.0 channel voice, system and SysEx7 packets.
+    Midi10,
+    /// MIDI 2.0 messages, carried in MIDI 2.0 channel voice packets among others.
+    Midi20,
+    /// A protocol that this library does not know about.
+    Unknown(MIDIProtocolID),
+}
+
+impl Protocol {
+    /// Get the protocol for a raw `MIDIProtocolID`.
+    ///
+    pub fn from_raw(protocol: MIDIProtocolID) -> Protocol {
+        if protocol == kMIDIProtocol_1_0 {
+            Protocol::Midi10
+        } else if protocol == kMIDIProtocol_2_0 {
+            Protocol::Midi20
+        } else {
+            Protocol::Unknown(protocol)
+        }
+    }
+
+    /// Get the raw `MIDIProtocolID` for this protocol.
+    ///
+    pub fn to_raw(self) -> MIDIProtocolID {
+        match self {
+            Protocol::Midi10 => kMIDIProtocol_1_0,
+            Protocol::Midi20 => kMIDIProtocol_2_0,
+            Protocol::Unknown(other) => other,
+        }
+    }
+}
+
+/// A [list of Universal MIDI Packets](https://developer.apple.com/reference/coremidi/midieventlist)
+/// being received from, or being sent to, one endpoint.
+///
+/// It is the MIDI 2.0 counterpart of a `PacketList`: the events are 32-bit words instead of bytes.
+///
+#[repr(C)]
+pub struct EventList {
+    // NOTE: This type must only exist in the form of immutable references
+    //       pointing to valid instances of MIDIEventList.
+    //       This type must NOT implement `Copy`!
+    inner: EventListInner,
+    _do_not_construct: [u32; 0]
+}
+
+#[repr(C)]
+struct EventListInner {
+    protocol: MIDIProtocolID,
+    num_packets: u32,
+    data: [MIDIEventPacket; 0]
+}
+
+impl EventList {
+    /// For internal usage only.
+    /// Requires this instance to actually point to a valid MIDIEventList
+    pub(crate) unsafe fn as_ptr(&self) -> *const MIDIEventList {
+        self as *const EventList as *const MIDIEventList
+    }
+
+    /// Get the protocol of the messages in the list.
+    ///
+    pub fn protocol(&self) -> Protocol {
+        Protocol::from_raw(self.inner.protocol)
+    }
+
+    /// Get the number of packets in the list.
+    ///
+    pub fn len(&self) -> usize {
+        self.inner.num_packets as usize
+    }
+
+    /// Check whether the list has no packets.
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get an iterator for the packets in the list.
+    ///
+    pub fn iter(&self) -> EventListIterator {
+        EventListIterator {
+            count: self.len(),
+            packet_ptr: self.inner.data.as_ptr(),
+            _phantom: ::std::marker::PhantomData::default(),
+        }
+    }
+}
+
+impl fmt::Debug for EventList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventList(ptr={:x}, protocol={:?}, packets=[", unsafe { self.as_ptr() as usize }, self.protocol())?;
+        for (i, packet) in self.iter().enumerate() {
+            let sep = if i != 0 { ", " } else { "" };
+            write!(f, "{}{:?}", sep, packet)?;
+        }
+        write!(f, "])")
+    }
+}
+
+impl fmt::Display for EventList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventList(protocol={:?}, len={})", self.protocol(), self.len())?;
+        for packet in self.iter() {
+            write!(f, "\n  {}", packet)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct EventListIterator<'a> {
+    count: usize,
+    packet_ptr: *const MIDIEventPacket,
+    _phantom: ::std::marker::PhantomData<&'a EventPacket>,
+}
+
+impl<'a> Iterator for EventListIterator<'a> {
+    type Item = &'a EventPacket;
+
+    fn next(&mut self) -> Option<&'a EventPacket> {
+        if self.count > 0 {
+            let packet = unsafe { &*(self.packet_ptr as *const EventPacket) };
+            self.count -= 1;
+            self.packet_ptr = unsafe { MIDIEventPacketNext(self.packet_ptr) };
+            Some(packet)
+        }
+        else {
+            None
+        }
+    }
+}
+
+/// A collection of simultaneous Universal MIDI Packets.
+/// See [MIDIEventPacket](https://developer.apple.com/reference/coremidi/midieventpacket).
+///
+#[repr(C)]
+pub struct EventPacket {
+    // NOTE: At runtime this type must only be used behind immutable references
+    //       that point to valid instances of MIDIEventPacket.
+    //       This type must NOT implement `Copy`!
+    inner: EventPacketInner,
+    _alignment_marker: [u32; 0]
+}
+
+#[repr(C, packed(4))]
+struct EventPacketInner {
+    timestamp: MIDITimeStamp,
+    word_count: u32,
+    // the words follow, but a zero-length field can't be borrowed from a packed struct
+}
+
+impl EventPacket {
+    /// Get the packet timestamp.
+    ///
+    pub fn timestamp(&self) -> Timestamp {
+        self.inner.timestamp as Timestamp
+    }
+
+    /// Get the packet data, which is a sequence of Universal MIDI Packets
+    /// made of one to four 32-bit words each.
+    ///
+    pub fn data(&self) -> &[u32] {
+        let data_ptr = unsafe { (self as *const EventPacket as *const u32).add(EVENT_PACKET_HEADER_WORDS) };
+        let data_len = self.inner.word_count as usize;
+        unsafe { slice::from_raw_parts(data_ptr, data_len) }
+    }
+
+    /// Get an iterator that decodes the packet data into `UmpMessage`s.
+    ///
+    /// ```
+    /// use coremidi::{EventBuffer, MidiMessage, Protocol, UmpMessage};
+    /// let mut event_list = EventBuffer::new(Protocol::Midi10);
+    /// event_list.push_data(0, &[0x2090_3c7f]);
+    /// let packet = event_list.iter().next().unwrap();
+    /// let messages: Vec<_> = packet.messages().collect();
+    /// assert_eq!(messages, vec![Ok(UmpMessage::Midi1ChannelVoice {
+    ///     group: 0,
+    ///     message: MidiMessage::NoteOn { channel: 0, note: 0x3c, velocity: 0x7f },
+    /// })]);
+    /// ```
+    pub fn messages(&self) -> EventPacketMessageIterator {
+        EventPacketMessageIterator {
+            data: self.data(),
+        }
+    }
+}
+
+pub struct EventPacketMessageIterator<'a> {
+    data: &'a [u32],
+}
+
+impl<'a> Iterator for EventPacketMessageIterator<'a> {
+    type Item = Result<UmpMessage, UmpError>;
+
+    fn next(&mut self) -> Option<Result<UmpMessage, UmpError>> {
+        UmpMessage::decode(self.data).map(|result| {
+            let (result, len) = match result {
+                Ok((message, len)) => (Ok(message), len),
+                Err((err, len)) => (Err(err), len),
+            };
+            self.data = &self.data[len..];
+            result
+        })
+    }
+}
+
+impl fmt::Debug for EventPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventPacket(ptr={:x}, ts={:016x}, data=[", self as *const _ as usize, self.timestamp())?;
+        for (i, word) in self.data().iter().enumerate() {
+            let sep = if i > 0 { ", " } else { "" };
+            write!(f, "{}{:08x}", sep, word)?;
+        }
+        write!(f, "])")
+    }
+}
+
+impl fmt::Display for EventPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}:", self.timestamp())?;
+        for word in self.data() {
+            write!(f, " {:08x}", word)?;
+        }
+        Ok(())
+    }
+}
+
+/// A mutable `EventList` builder, and the owned version of an `EventList`.
+///
+/// It works like a `PacketBuffer`, but for Universal MIDI Packets, so it dereferences to an
+/// `EventList` and it can be used whenever an `EventList` is needed:
+///
+/// ```
+/// use coremidi::{EventBuffer, EventList, Protocol};
+/// let mut buffer = EventBuffer::new(Protocol::Midi20);
+/// buffer.push_data(0, &[0x4090_3c00, 0xffff_0000]);
+/// buffer.push_data(0, &[0x4090_3e00, 0xffff_0000]);
+/// assert_eq!(buffer.len(), 1);
+/// let repr = format!("{}", &buffer as &EventList);
+/// assert_eq!(repr, "EventList(protocol=Midi20, len=1)\n  0000000000000000: 40903c00 ffff0000 40903e00 ffff0000");
+/// ```
+#[derive(Clone)]
+pub struct EventBuffer {
+    // NOTE: using u32 ensures the alignment required by MIDIEventList
+    storage: Vec<u32>,
+    last_packet_offset: usize,
+}
+
+impl ::std::ops::Deref for EventBuffer {
+    type Target = EventList;
+
+    #[inline]
+    fn deref(&self) -> &EventList {
+        unsafe { &*(self.storage.as_ptr() as *const EventList) }
+    }
+}
+
+impl EventBuffer {
+    /// Create an empty `EventBuffer` for messages of the given protocol.
+    ///
+    pub fn new(protocol: Protocol) -> EventBuffer {
+        Self::with_capacity(protocol, 0)
+    }
+
+    /// Create an empty `EventBuffer` with room for `capacity` bytes in total (including headers).
+    ///
+    /// ```
+    /// use coremidi::{EventBuffer, Protocol};
+    /// let buffer = EventBuffer::with_capacity(Protocol::Midi10, 128);
+    /// assert_eq!(buffer.len(), 0);
+    /// assert!(buffer.capacity() >= 128);
+    /// ```
+    pub fn with_capacity(protocol: Protocol, capacity: usize) -> EventBuffer {
+        let words = cmp::max((capacity + 3) / 4, EVENT_LIST_HEADER_WORDS);
+        let mut storage = Vec::with_capacity(words);
+        storage.push(protocol.to_raw() as u32);
+        storage.push(0);
+        EventBuffer {
+            storage,
+            last_packet_offset: EVENT_LIST_HEADER_WORDS,
+        }
+    }
+
+    /// Get underlying buffer capacity in bytes
+    pub fn capacity(&self) -> usize {
+        self.storage.capacity() * 4
+    }
+
+    /// Add a new event containing the provided timestamp and Universal MIDI Packets.
+    ///
+    /// It is merged into the last packet when they have the same timestamp and the packet
+    /// has room for it, as `MIDIEventListAdd` does. A packet holds up to 64 words,
+    /// and the data must contain complete Universal MIDI Packets.
+    ///
+    /// An event must not have a timestamp that is smaller than that of a previous event
+    /// in the same `EventList`.
+    ///
+    pub fn push_data(&mut self, time: MIDITimeStamp, data: &[u32]) -> &mut Self {
+        assert!(data.len() <= MAX_EVENT_PACKET_WORDS, "packet data too long");
+
+        let can_merge = match self.last_packet() {
+            Some(packet) => packet.timestamp() == time && packet.data().len() + data.len() <= MAX_EVENT_PACKET_WORDS,
+            None => false
+        };
+
+        if can_merge {
+            let word_count_offset = self.last_packet_offset + EVENT_PACKET_HEADER_WORDS - 1;
+            self.storage[word_count_offset] += data.len() as u32;
+            self.storage.extend_from_slice(data);
+        } else {
+            self.push_packet(time, data);
+        }
+
+        self
+    }
+
+    /// Add a new event containing the provided timestamp and the encoded message.
+    ///
+    /// ```
+    /// use coremidi::{EventBuffer, EventList, MidiMessage, Protocol, UmpMessage};
+    /// let mut buffer = EventBuffer::new(Protocol::Midi10);
+    /// buffer.push_message(0, &UmpMessage::Midi1ChannelVoice {
+    ///     group: 1,
+    ///     message: MidiMessage::ControlChange { channel: 0, control: 7, value: 100 },
+    /// });
+    /// let repr = format!("{}", &buffer as &EventList);
+    /// assert_eq!(repr, "EventList(protocol=Midi10, len=1)\n  0000000000000000: 21b00764");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// For the messages that can't be encoded, as documented in `UmpMessage::to_words`.
+    pub fn push_message(&mut self, time: MIDITimeStamp, message: &UmpMessage) -> &mut Self {
+        let mut words = [0u32; 4];
+        let len = message.write_to_slice(&mut words);
+        self.push_data(time, &words[..len])
+    }
+
+    /// Clears the buffer, removing all packets.
+    /// Note that this method has no effect on the allocated capacity of the buffer.
+    pub fn clear(&mut self) {
+        self.storage.truncate(EVENT_LIST_HEADER_WORDS);
+        self.storage[1] = 0;
+        self.last_packet_offset = EVENT_LIST_HEADER_WORDS;
+    }
+
+    /// Appends a new packet, without trying to merge it into the last one
+    fn push_packet(&mut self, time: MIDITimeStamp, data: &[u32]) {
+        self.last_packet_offset = self.storage.len();
+        let timestamp = time.to_ne_bytes();
+        self.storage.push(u32::from_ne_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3]]));
+        self.storage.push(u32::from_ne_bytes([timestamp[4], timestamp[5], timestamp[6], timestamp[7]]));
+        self.storage.push(data.len() as u32);
+        self.storage.extend_from_slice(data);
+        self.storage[1] += 1;
+    }
+
+    fn last_packet(&self) -> Option<&EventPacket> {
+        if self.is_empty() {
+            None
+        } else {
+            let packet_slot = &self.storage[self.last_packet_offset..];
+            Some(unsafe { &*(packet_slot.as_ptr() as *const EventPacket) })
+        }
+    }
+}
+
+impl<'a> From<&'a EventList> for EventBuffer {
+    /// Copy an `EventList` into a new `EventBuffer`, packet by packet.
+    ///
+    fn from(event_list: &'a EventList) -> EventBuffer {
+        let words = event_list.iter().fold(EVENT_LIST_HEADER_WORDS, |words, packet| {
+            words + EVENT_PACKET_HEADER_WORDS + packet.data().len()
+        });
+        let mut buffer = EventBuffer::with_capacity(event_list.protocol(), words * 4);
+        for packet in event_list.iter() {
+            buffer.push_packet(packet.timestamp(), packet.data());
+        }
+        buffer
+    }
+}
+
+impl Borrow<EventList> for EventBuffer {
+    fn borrow(&self) -> &EventList {
+        self
+    }
+}
+
+impl ToOwned for EventList {
+    type Owned = EventBuffer;
+
+    fn to_owned(&self) -> EventBuffer {
+        EventBuffer::from(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for EventPacket {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("EventPacket", 2)?;
+        state.serialize_field("timestamp", &self.timestamp())?;
+        state.serialize_field("data", self.data())?;
+        state.end()
+    }
+}
+
+/// An `EventList` is serialized as its protocol together with the sequence of its packets.
+///
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for EventList {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let packets: Vec<&EventPacket> = self.iter().collect();
+        let mut state = serializer.serialize_struct("EventList", 2)?;
+        state.serialize_field("protocol", &self.protocol())?;
+        state.serialize_field("packets", &packets)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for EventBuffer {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+/// The owned versions of a serialized `EventList` and its `EventPacket`s.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct EventListData {
+    protocol: Protocol,
+    packets: Vec<EventPacketData>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct EventPacketData {
+    timestamp: Timestamp,
+    data: Vec<u32>,
+}
+
+/// An `EventBuffer` is deserialized from what was written for an `EventList`, keeping the packet boundaries.
+///
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for EventBuffer {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<EventBuffer, D::Error> {
+        use serde::de::Error;
+        let event_list: EventListData = ::serde::Deserialize::deserialize(deserializer)?;
+        let mut buffer = EventBuffer::new(event_list.protocol);
+        for packet in event_list.packets {
+            if packet.data.len() > MAX_EVENT_PACKET_WORDS {
+                return Err(D::Error::custom("packet data too long"));
+            }
+            buffer.push_packet(packet.timestamp, &packet.data);
+        }
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use backend::sys::{MIDIEventList, MIDIEventPacket};
+    use {EventBuffer, EventList, EventPacket, MidiMessage, Protocol, UmpError, UmpMessage};
+    use super::{EVENT_LIST_HEADER_WORDS, EVENT_PACKET_HEADER_WORDS};
+
+    fn packets(event_list: &EventList) -> Vec<(u64, Vec<u32>)> {
+        event_list.iter().map(|packet| (packet.timestamp(), packet.data().to_vec())).collect()
+    }
+
+    #[test]
+    fn event_struct_layout() {
+        assert_eq!(4, mem::align_of::<EventPacket>());
+        assert_eq!(4, mem::align_of::<EventList>());
+        assert_eq!(EVENT_PACKET_HEADER_WORDS * 4 + 64 * 4, mem::size_of::<MIDIEventPacket>());
+        assert_eq!(EVENT_LIST_HEADER_WORDS * 4 + mem::size_of::<MIDIEventPacket>(), mem::size_of::<MIDIEventList>());
+
+        assert_eq!(EVENT_PACKET_HEADER_WORDS * 4, mem::size_of::<EventPacket>());
+
+        let dummy_event_list: EventList = unsafe { mem::zeroed() };
+        let ptr = &dummy_event_list as *const _ as *const u8;
+        assert_eq!(EVENT_LIST_HEADER_WORDS * 4, dummy_event_list.inner.data.as_ptr() as usize - ptr as usize);
+    }
+
+    #[test]
+    fn protocol_raw_values() {
+        assert_eq!(Protocol::from_raw(1), Protocol::Midi10);
+        assert_eq!(Protocol::from_raw(2), Protocol::Midi20);
+        assert_eq!(Protocol::from_raw(3), Protocol::Unknown(3));
+        assert_eq!(Protocol::Midi20.to_raw(), 2);
+        assert_eq!(EventBuffer::new(Protocol::Midi20).protocol(), Protocol::Midi20);
+    }
+
+    #[test]
+    fn event_buffer_push_data() {
+        let mut buffer = EventBuffer::new(Protocol::Midi10);
+        assert!(buffer.is_empty());
+        buffer.push_data(42, &[0x2090_3c7f]);
+        buffer.push_data(42, &[0x2090_3e7f]);
+        buffer.push_data(43, &[0x10f8_0000]);
+        buffer.push_data(u64::max_value(), &[0x3016_0102, 0x0304_0506]);
+        assert_eq!(packets(&buffer), vec![
+            (42, vec![0x2090_3c7f, 0x2090_3e7f]),
+            (43, vec![0x10f8_0000]),
+            (u64::max_value(), vec![0x3016_0102, 0x0304_0506]),
+        ]);
+    }
+
+    #[test]
+    fn event_buffer_packets_hold_up_to_64_words() {
+        let mut buffer = EventBuffer::new(Protocol::Midi20);
+        for _ in 0..33 {
+            buffer.push_data(0, &[0x4090_3c00, 0xffff_0000]);
+        }
+        let lengths: Vec<usize> = buffer.iter().map(|packet| packet.data().len()).collect();
+        assert_eq!(lengths, vec![64, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "packet data too long")]
+    fn event_buffer_push_too_much_data() {
+        EventBuffer::new(Protocol::Midi20).push_data(0, &[0; 65]);
+    }
+
+    #[test]
+    fn event_buffer_clear() {
+        let mut buffer = EventBuffer::with_capacity(Protocol::Midi10, 64);
+        buffer.push_data(1, &[0x2090_3c7f]).push_data(2, &[0x2080_3c00]);
+        assert_eq!(buffer.len(), 2);
+        buffer.clear();
+        assert_eq!(buffer.len(), 0);
+        buffer.push_data(1, &[0x10fa_0000]);
+        assert_eq!(packets(&buffer), vec![(1, vec![0x10fa_0000])]);
+    }
+
+    #[test]
+    fn event_buffer_from_event_list() {
+        let mut buffer = EventBuffer::new(Protocol::Midi10);
+        buffer.push_data(10, &[0x2090_3c7f]);
+        buffer.push_data(20, &[0x2080_3c00]);
+        let copy = EventBuffer::from(&buffer as &EventList);
+        assert_eq!(copy.protocol(), Protocol::Midi10);
+        assert_eq!(packets(&copy), packets(&buffer));
+
+        let mut owned = (&buffer as &EventList).to_owned();
+        owned.push_data(20, &[0x10f8_0000]);
+        assert_eq!(packets(&owned).last(), Some(&(20, vec![0x2080_3c00, 0x10f8_0000])));
+        assert_eq!(packets(&buffer.clone()), packets(&buffer));
+    }
+
+    #[test]
+    fn event_packet_messages() {
+        let mut buffer = EventBuffer::new(Protocol::Midi10);
+        buffer.push_message(0, &UmpMessage::System { group: 0, message: MidiMessage::Start });
+        buffer.push_data(0, &[0x6000_0000, 0x4090_3c00]);
+        let packet = buffer.iter().next().unwrap();
+        let messages: Vec<_> = packet.messages().collect();
+        assert_eq!(messages, vec![
+            Ok(UmpMessage::System { group: 0, message: MidiMessage::Start }),
+            Err(UmpError::UnsupportedType(0x6)),
+            Err(UmpError::Truncated(0x4)),
+        ]);
+    }
+
+    #[test]
+    fn event_list_display() {
+        let mut buffer = EventBuffer::new(Protocol::Midi10);
+        buffer.push_data(1, &[0x2090_3c7f]);
+        buffer.push_data(2, &[0x2080_3c00]);
+        assert_eq!(format!("{}", &buffer as &EventList),
+                   "EventList(protocol=Midi10, len=2)\n  0000000000000001: 20903c7f\n  0000000000000002: 20803c00");
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::time::{Duration, Instant};
+
+use backend::{Backend, Current};
+use backend::sys::MIDITimeStamp;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A point in time expressed in host clock ticks, which is what CoreMIDI uses for timestamps.
+/// See [Mach Absolute Time Units](https://developer.apple.com/library/archive/qa/qa1398/_index.html).
+///
+/// The duration of a tick depends on the host (it is given by the mach timebase ratio on macOS),
+/// so `HostTime` takes care of converting from and to nanoseconds, `Duration` and `Instant`.
+///
+/// A simple example to schedule a note half a second from now:
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use coremidi::{HostTime, PacketBuffer};
+/// let time = HostTime::now() + Duration::from_millis(500);
+/// let note_on = PacketBuffer::new(time.timestamp(), &[0x90, 0x40, 0x7f]);
+/// ```
+///
+/// Where CoreMIDI is not available, the host clock counts nanoseconds since the first time
+/// it is read, unless a different time source is installed with `HostTime::set_time_source`.
+///
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct HostTime(MIDITimeStamp);
+
+impl HostTime {
+    /// The current host time.
+    ///
+    pub fn now() -> HostTime {
+        HostTime(Current::host_time())
+    }
+
+    /// Create a `HostTime` from a raw timestamp, as found in `Packet::timestamp`.
+    ///
+    pub fn from_timestamp(timestamp: MIDITimeStamp) -> HostTime {
+        HostTime(timestamp)
+    }
+
+    /// The raw timestamp, as expected by `PacketBuffer`.
+    ///
+    pub fn timestamp(self) -> MIDITimeStamp {
+        self.0
+    }
+
+    /// Create a `HostTime` from the number of nanoseconds since the host clock started.
+    ///
+    pub fn from_nanos(nanos: u64) -> HostTime {
+        HostTime(nanos_to_ticks(nanos, Current::host_timebase()))
+    }
+
+    /// The number of nanoseconds since the host clock started.
+    ///
+    pub fn as_nanos(self) -> u64 {
+        ticks_to_nanos(self.0, Current::host_timebase())
+    }
+
+    /// Convert an `Instant` into the host time that corresponds to it.
+    ///
+    pub fn from_instant(instant: Instant) -> HostTime {
+        let (now, now_instant) = (HostTime::now(), Instant::now());
+        if instant >= now_instant {
+            now + (instant - now_instant)
+        } else {
+            now.checked_sub(now_instant - instant).unwrap_or_default()
+        }
+    }
+
+    /// Convert this host time into the `Instant` that corresponds to it.
+    ///
+    pub fn to_instant(self) -> Instant {
+        let (now, now_instant) = (HostTime::now(), Instant::now());
+        if self >= now {
+            now_instant + self.duration_since(now)
+        } else {
+            now_instant - now.duration_since(self)
+        }
+    }
+
+    /// The time elapsed from `earlier` to this host time, or zero if `earlier` is later.
+    ///
+    pub fn duration_since(self, earlier: HostTime) -> Duration {
+        let nanos = ticks_to_nanos(self.0.saturating_sub(earlier.0), Current::host_timebase());
+        Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32)
+    }
+
+    /// The time elapsed since this host time, or zero if it is in the future.
+    ///
+    pub fn elapsed(self) -> Duration {
+        HostTime::now().duration_since(self)
+    }
+
+    /// Returns `None` if the result would overflow.
+    ///
+    pub fn checked_add(self, duration: Duration) -> Option<HostTime> {
+        duration_to_ticks(duration).and_then(|ticks| self.0.checked_add(ticks)).map(HostTime)
+    }
+
+    /// Returns `None` if the result would be before the start of the host clock.
+    ///
+    pub fn checked_sub(self, duration: Duration) -> Option<HostTime> {
+        duration_to_ticks(duration).and_then(|ticks| self.0.checked_sub(ticks)).map(HostTime)
+    }
+
+    /// Replace the source of the host clock, which by default counts nanoseconds since the first time it is read.
+    /// The function must return a monotonic number of nanoseconds, and it is called for every `HostTime::now()`.
+    /// This is only available where CoreMIDI is not, and it is useful to simulate time in tests.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use coremidi::HostTime;
+    /// static CLOCK: AtomicUsize = AtomicUsize::new(0);
+    /// HostTime::set_time_source(|| CLOCK.load(Ordering::SeqCst) as u64);
+    /// CLOCK.store(1500, Ordering::SeqCst);
+    /// assert_eq!(HostTime::now().as_nanos(), 1500);
+    /// ```
+    #[cfg(not(target_os = "macos"))]
+    pub fn set_time_source<F>(source: F) where F: Fn() -> u64 + Send + Sync + 'static {
+        ::backend::set_time_source(Box::new(source));
+    }
+}
+
+impl From<HostTime> for MIDITimeStamp {
+    fn from(time: HostTime) -> MIDITimeStamp {
+        time.0
+    }
+}
+
+impl Add<Duration> for HostTime {
+    type Output = HostTime;
+
+    fn add(self, duration: Duration) -> HostTime {
+        self.checked_add(duration).expect("overflow when adding duration to host time")
+    }
+}
+
+impl AddAssign<Duration> for HostTime {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for HostTime {
+    type Output = HostTime;
+
+    fn sub(self, duration: Duration) -> HostTime {
+        self.checked_sub(duration).expect("overflow when subtracting duration from host time")
+    }
+}
+
+impl SubAssign<Duration> for HostTime {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<HostTime> for HostTime {
+    type Output = Duration;
+
+    fn sub(self, earlier: HostTime) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Debug for HostTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostTime({})", self.0)
+    }
+}
+
+/// A source of host time for the schedulers, like `Player`, which can be replaced to test them deterministically.
+///
+pub trait Clock {
+    /// The current host time.
+    ///
+    fn now(&self) -> HostTime;
+}
+
+/// The clock of the host, as read by `HostTime::now`.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HostClock;
+
+impl Clock for HostClock {
+    fn now(&self) -> HostTime {
+        HostTime::now()
+    }
+}
+
+fn duration_to_ticks(duration: Duration) -> Option<u64> {
+    let nanos = duration.as_secs().checked_mul(NANOS_PER_SEC)?.checked_add(u64::from(duration.subsec_nanos()))?;
+    Some(nanos_to_ticks(nanos, Current::host_timebase()))
+}
+
+// The timebase is the (numerator, denominator) ratio that converts ticks into nanoseconds
+fn ticks_to_nanos(ticks: u64, (numer, denom): (u32, u32)) -> u64 {
+    (u128::from(ticks) * u128::from(numer) / u128::from(denom)) as u64
+}
+
+fn nanos_to_ticks(nanos: u64, (numer, denom): (u32, u32)) -> u64 {
+    (u128::from(nanos) * u128::from(denom) / u128::from(numer)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{HostTime, nanos_to_ticks, ticks_to_nanos};
+
+    #[test]
+    fn timebase_conversions() {
+        assert_eq!(ticks_to_nanos(24_000_000, (125, 3)), 1_000_000_000);
+        assert_eq!(nanos_to_ticks(1_000_000_000, (125, 3)), 24_000_000);
+        assert_eq!(ticks_to_nanos(u64::max_value() / 2, (1, 1)), u64::max_value() / 2);
+    }
+
+    #[test]
+    fn duration_arithmetic() {
+        let time = HostTime::from_nanos(1_000_000);
+        let later = time + Duration::from_millis(2);
+        assert_eq!(later.as_nanos(), 3_000_000);
+        assert_eq!(later - time, Duration::from_millis(2));
+        assert_eq!(time - later, Duration::from_secs(0));
+        assert_eq!(later - Duration::from_millis(2), time);
+        assert_eq!(time.checked_sub(Duration::from_millis(2)), None);
+        assert_eq!(HostTime::from_timestamp(u64::max_value()).checked_add(Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn instant_conversions() {
+        let instant = Instant::now() + Duration::from_millis(100);
+        let time = HostTime::from_instant(instant);
+        assert!(time > HostTime::now());
+        let round_trip = time.to_instant();
+        let difference = if round_trip > instant { round_trip - instant } else { instant - round_trip };
+        assert!(difference < Duration::from_millis(10));
+    }
+}
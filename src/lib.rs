@@ -24,6 +24,11 @@ thread::sleep(Duration::from_millis(1000));
 output_port.send(&destination, &note_off).unwrap();
 ```
 
+On hosts other than macOS, where CoreMIDI is not available, the library runs on top of an in-process
+loopback backend. Every client in the process shares it, so the virtual sources and destinations created
+by one client show up as sources and destinations for the others, which allows to build and test code
+depending on this library anywhere.
+
 If you are looking for a portable MIDI library then you can look into:
 
 - [midir](https://github.com/Boddlnagg/midir) (which is using this lib)
@@ -36,21 +41,33 @@ For handling low level MIDI data you may look into:
 
 */
 
+#[cfg(target_os = "macos")]
 extern crate core_foundation_sys;
+#[cfg(target_os = "macos")]
 extern crate core_foundation;
+#[cfg(target_os = "macos")]
 extern crate coremidi_sys;
-
-use core_foundation_sys::base::OSStatus;
-
-use coremidi_sys::{
-    MIDIObjectRef, MIDIFlushOutput, MIDIRestart, MIDIPacket, MIDIPacketList
+#[cfg(target_os = "macos")]
+extern crate block;
+#[cfg(feature = "stream")]
+extern crate futures_core;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use backend::{Backend, Current};
+use backend::sys::{
+    MIDIObjectRef, MIDIPacket, MIDIPacketList
 };
 
 /// A [MIDI Object](https://developer.apple.com/reference/coremidi/midiobjectref).
 ///
 /// The base class of many CoreMIDI objects.
 ///
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct Object(MIDIObjectRef);
 
 /// A [MIDI client](https://developer.apple.com/reference/coremidi/midiclientref).
@@ -69,12 +86,17 @@ pub struct Client {
     callback: BoxedCallback<Notification>,
 }
 
-// A lifetime-managed wrapper for callback functions
+// A lifetime-managed wrapper for callback functions.
+// Besides the argument, they get the refCon of the source connection the data comes from, or null.
 #[derive(Debug, PartialEq)]
-struct BoxedCallback<T>(*mut Box<FnMut(&T)>);
+struct BoxedCallback<T>(*mut Box<FnMut(&T, *mut ::std::os::raw::c_void)>);
 
 impl<T> BoxedCallback<T> {
-    fn new<F: FnMut(&T) + Send + 'static>(f: F) -> BoxedCallback<T> {
+    fn new<F: FnMut(&T) + Send + 'static>(mut f: F) -> BoxedCallback<T> {
+        Self::with_connection(move |arg, _| f(arg))
+    }
+
+    fn with_connection<F: FnMut(&T, *mut ::std::os::raw::c_void) + Send + 'static>(f: F) -> BoxedCallback<T> {
         BoxedCallback(Box::into_raw(Box::new(Box::new(f))))
     }
 
@@ -87,14 +109,21 @@ impl<T> BoxedCallback<T> {
     }
 
     // must not be null
-    unsafe fn call_from_raw_ptr(raw_ptr: *mut ::std::os::raw::c_void, arg: &T) {
-        let callback = &mut *(raw_ptr as *mut Box<FnMut(&T)>);
-        callback(arg);
+    unsafe fn call_from_raw_ptr(raw_ptr: *mut ::std::os::raw::c_void, conn_ref_con: *mut ::std::os::raw::c_void, arg: &T) {
+        let callback = &mut *(raw_ptr as *mut Box<FnMut(&T, *mut ::std::os::raw::c_void)>);
+        callback(arg, conn_ref_con);
     }
 }
 
 unsafe impl<T> Send for BoxedCallback<T> {}
 
+// The callback of an input port, which depends on whether it receives packets or events
+#[derive(Debug)]
+enum InputPortCallback {
+    Packets(BoxedCallback<PacketList>),
+    Events(BoxedCallback<EventList>),
+}
+
 impl<T> Drop for BoxedCallback<T> {
     fn drop(&mut self) {
         unsafe {
@@ -105,6 +134,11 @@ impl<T> Drop for BoxedCallback<T> {
     }
 }
 
+// Locks a mutex shared with the callbacks, ignoring the poisoning left by a callback that panicked
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 /// A MIDI connection port owned by a client.
 /// See [MIDIPortRef](https://developer.apple.com/reference/coremidi/midiportref).
 ///
@@ -139,9 +173,36 @@ pub struct OutputPort { port: Port }
 /// ```
 #[derive(Debug)]
 pub struct InputPort {
+    // Note: the order is important here, port needs to be dropped first
+    port: Port,
+    callback: InputPortCallback,
+}
+
+/// An input [MIDI port](https://developer.apple.com/reference/coremidi/midiportref) owned by a client,
+/// which keeps a context for every source connected to it.
+///
+/// The context is given when connecting a source, and it is handed to the callback together with
+/// the packets coming from that source, so a port connected to several sources can tell them apart.
+/// The callback is the only one with access to the contexts. A context is dropped as soon as its source
+/// is disconnected or connected again with another context, as the callback gets it under the same lock:
+///
+/// ```rust,no_run
+/// let client = coremidi::Client::new("example-client").unwrap();
+/// let mut input_port = client.input_port_with_context("example-port", |packet_list, unique_id: &mut u32| {
+///     println!("{:08x}: {}", unique_id, packet_list)
+/// }).unwrap();
+/// for source in coremidi::Sources {
+///     let unique_id = source.unique_id().unwrap_or(0);
+///     input_port.connect_source(&source, unique_id).unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct InputPortWithContext<T> {
     // Note: the order is important here, port needs to be dropped first
     port: Port,
     callback: BoxedCallback<PacketList>,
+    // Shared with the callback, which finds the context from the source given as the connection refCon
+    contexts: Arc<Mutex<HashMap<MIDIObjectRef, T>>>,
 }
 
 /// A MIDI source or source, owned by an entity.
@@ -149,7 +210,7 @@ pub struct InputPort {
 ///
 /// You don't need to create an endpoint directly, instead you can create system sources and sources or virtual ones from a client.
 ///
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Endpoint { object: Object }
 
 /// A [MIDI source](https://developer.apple.com/reference/coremidi/midiendpointref) owned by an entity.
@@ -161,7 +222,7 @@ pub struct Endpoint { object: Object }
 /// println!("The source at index 0 has display name '{}'", source.display_name().unwrap());
 /// ```
 ///
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Destination { endpoint: Endpoint }
 
 /// A [MIDI source](https://developer.apple.com/reference/coremidi/midiendpointref) owned by an entity.
@@ -173,7 +234,7 @@ pub struct Destination { endpoint: Endpoint }
 /// println!("The source at index 0 has display name '{}'", source.display_name().unwrap());
 /// ```
 ///
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Source { endpoint: Endpoint }
 
 /// A [MIDI virtual source](https://developer.apple.com/reference/coremidi/1495212-midisourcecreate) owned by a client.
@@ -208,10 +269,46 @@ pub struct VirtualDestination {
 ///
 /// A MIDI device or external device, containing entities.
 ///
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct Device { object: Object }
 
+/// A [MIDI entity](https://developer.apple.com/reference/coremidi/midientityref).
+///
+/// A logical subcomponent of a device, which groups related sources and destinations,
+/// such as the MIDI in and out of one of the ports of an interface.
+///
+/// ```rust,no_run
+/// let source = coremidi::Source::from_index(0).unwrap();
+/// if let Some(entity) = source.entity() {
+///     let device = entity.device().unwrap();
+///     println!("{} belongs to {}", source.display_name().unwrap(), device.display_name().unwrap());
+/// }
+/// ```
+///
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Entity { object: Object }
+
+/// A [MIDI thru connection](https://developer.apple.com/reference/coremidi/midithruconnectionref).
+///
+/// A connection that forwards the MIDI events from some sources to some destinations within the MIDI server,
+/// optionally filtering and transforming them on the way. A connection can be created like:
+///
+/// ```rust,no_run
+/// let source = coremidi::Source::from_index(0).unwrap();
+/// let destination = coremidi::Destination::from_index(0).unwrap();
+/// let params = coremidi::ThruConnectionParams::new().source(&source).unwrap().destination(&destination).unwrap();
+/// let connection = coremidi::ThruConnection::create(&params).unwrap();
+/// ```
+///
+#[derive(Debug)]
+pub struct ThruConnection {
+    object: Object,
+    persistent: bool,
+}
+
 /// A [list of MIDI events](https://developer.apple.com/reference/coremidi/midipacketlist) being received from, or being sent to, one endpoint.
 ///
 #[repr(C)]
@@ -237,51 +334,94 @@ impl PacketList {
     }
 }
 
+mod backend;
+mod error;
 mod object;
 mod devices;
+mod entities;
 mod client;
 mod ports;
+mod receiver;
+mod dispatcher;
+mod autoconnect;
+mod recorder;
+mod player;
+mod clock_generator;
 mod packets;
+mod events;
+mod ump;
+mod host_time;
+mod messages;
+mod sysex;
+mod thru;
+mod translation;
 mod properties;
+mod setup;
 mod endpoints;
 mod notifications;
+pub mod smf;
+pub use error::Error;
+pub use devices::{Devices, DevicesIterator, ExternalDevices, ExternalDevicesIterator, EntitiesIterator};
+pub use entities::{EntitySourcesIterator, EntityDestinationsIterator};
 pub use endpoints::destinations::Destinations;
 pub use endpoints::sources::Sources;
-pub use packets::{PacketListIterator, Packet, PacketBuffer};
-pub use properties::{Properties, PropertyGetter, PropertySetter};
+pub use host_time::{Clock, HostClock, HostTime};
+pub use packets::{PacketListIterator, PacketMessageIterator, Packet, PacketBuffer};
+pub use events::{EventBuffer, EventList, EventListIterator, EventPacket, EventPacketMessageIterator, Protocol};
+pub use ump::{ChunkStatus, FlexDataAddress, Midi2ChannelVoice, UmpError, UmpMessage, UtilityMessage};
+pub use receiver::{OverflowPolicy, PacketReceiver, ReceivedPacket, RecvError, TryIter};
+pub use dispatcher::{NotificationDispatcher, NotificationFilter, Subscription};
+#[cfg(feature = "stream")]
+pub use dispatcher::NotificationStream;
+pub use autoconnect::{AutoConnectEvent, AutoConnector, EndpointMatcher};
+pub use player::{Player, Sequencer, Timeline};
+pub use clock_generator::{ClockGenerator, ClockScheduler, CLOCKS_PER_QUARTER_NOTE};
+pub use recorder::{RecordedEvent, RecordedTrack, Recorder, RecorderPort, Recording, TrackInput};
+pub use messages::{MidiMessage, MessageError, MessageParser, ParsedMessage, ParsedMessageIterator};
+pub use sysex::{SysExAssembler, SysExEvent, SysExAbortReason, SysExSender, SysExSendResult};
+pub use translation::{Midi1ToMidi2, Midi2ToMidi1};
+pub use thru::{
+    ControlTransform,
+    ControlType,
+    ThruConnectionEndpoint,
+    ThruConnectionParams,
+    ThruParamsError,
+    Transform,
+    ValueMap,
+    THRU_CONNECTION_MAX_ENDPOINTS,
+};
+pub use properties::{
+    BooleanProperty,
+    DataProperty,
+    DictionaryProperty,
+    IntegerProperty,
+    Properties,
+    PropertyGetter,
+    PropertyKey,
+    PropertySetter,
+    PropertyValue,
+    StringProperty,
+};
 pub use notifications::{
     AddedRemovedInfo,
     IOErrorInfo,
     Notification,
+    NotificationKind,
     PropertyChangedInfo,
 };
-pub use object::ObjectType;
+pub use object::{AnyObject, ObjectType};
+pub use setup::{DeviceSnapshot, EndpointSnapshot, EntitySnapshot, PropertyMap, Setup, SetupSnapshot};
 
 /// Unschedules previously-sent packets for all the endpoints.
 /// See [MIDIFlushOutput](https://developer.apple.com/reference/coremidi/1495312-midiflushoutput).
 ///
-pub fn flush() -> Result<(), OSStatus> {
-    let status = unsafe { MIDIFlushOutput(0) };
-    unit_result_from_status(status)
+pub fn flush() -> Result<(), Error> {
+    Current::flush_output(0).map_err(Error::from)
 }
 
 /// Stops and restarts MIDI I/O.
 /// See [MIDIRestart](https://developer.apple.com/reference/coremidi/1495146-midirestart).
 ///
-pub fn restart() -> Result<(), OSStatus> {
-    let status = unsafe { MIDIRestart() };
-    unit_result_from_status(status)
-}
-
-/// Convert an OSStatus into a Result<T, OSStatus> given a mapping closure
-fn result_from_status<T, F: FnOnce() -> T>(status: OSStatus, f: F) -> Result<T, OSStatus> {
-    match status {
-        0 => Ok(f()),
-        _ => Err(status),
-    }
-}
-
-/// Convert an OSSStatus into a Result<(), OSStatus>
-fn unit_result_from_status(status: OSStatus) -> Result<(), OSStatus> {
-    result_from_status(status, || ())
+pub fn restart() -> Result<(), Error> {
+    Current::restart().map_err(Error::from)
 }
\ No newline at end of file
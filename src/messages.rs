@@ -0,0 +1,689 @@
+use std::error;
+use std::fmt;
+
+use packets::{Packet, Timestamp};
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const POLY_PRESSURE: u8 = 0xA0;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const CHANNEL_PRESSURE: u8 = 0xD0;
+const PITCH_BEND: u8 = 0xE0;
+const SYSEX_START: u8 = 0xF0;
+const TIME_CODE_QUARTER_FRAME: u8 = 0xF1;
+const SONG_POSITION: u8 = 0xF2;
+const SONG_SELECT: u8 = 0xF3;
+const TUNE_REQUEST: u8 = 0xF6;
+const SYSEX_END: u8 = 0xF7;
+const TIMING_CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+const ACTIVE_SENSING: u8 = 0xFE;
+const SYSTEM_RESET: u8 = 0xFF;
+
+/// A MIDI 1.0 message.
+///
+/// Channels go from 0 to 15, and data values are 7 bits long, except for the ones
+/// that are explicitly 14 bits long (pitch bend and song position).
+/// Note that a `NoteOn` with a velocity of 0 is kept as it is, even if it is commonly used as a `NoteOff`.
+///
+/// A message can be decoded from raw MIDI bytes and encoded back:
+///
+/// ```
+/// use coremidi::MidiMessage;
+/// let message = MidiMessage::NoteOn { channel: 0, note: 0x40, velocity: 0x7f };
+/// assert_eq!(message.to_bytes(), vec![0x90, 0x40, 0x7f]);
+/// assert_eq!(MidiMessage::decode(&[0x90, 0x40, 0x7f]), Some(Ok((message, 3))));
+/// ```
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// The value goes from 0 to 16383, where 8192 means no bend.
+    PitchBend { channel: u8, value: u16 },
+    /// The bytes between the start (0xF0) and the end (0xF7) of a System Exclusive message.
+    SysEx(Vec<u8>),
+    TimeCodeQuarterFrame { message_type: u8, value: u8 },
+    /// The number of MIDI beats (sixteenth notes) since the start of the song.
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
+}
+
+/// The reasons why some MIDI bytes could not be decoded into a `MidiMessage`.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MessageError {
+    /// A number of data bytes without a status byte before them.
+    /// This is what running status or the continuation of a SysEx split across packets look like.
+    MissingStatus(usize),
+    /// A status byte that is not defined in MIDI 1.0 (0xF4, 0xF5, 0xF9, 0xFD),
+    /// or the end of a SysEx (0xF7) without its start.
+    UndefinedStatus(u8),
+    /// The message with this status byte ended before all its data bytes were found.
+    Truncated(u8),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageError::MissingStatus(len) => write!(f, "{} data bytes without a status byte", len),
+            MessageError::UndefinedStatus(status) => write!(f, "undefined status byte {:02x}", status),
+            MessageError::Truncated(status) => write!(f, "truncated message with status {:02x}", status),
+        }
+    }
+}
+
+impl error::Error for MessageError {}
+
+impl MidiMessage {
+    /// Decode the message at the beginning of `data`.
+    ///
+    /// On success it returns the message together with the number of bytes that it took,
+    /// and on failure the error together with the number of bytes that should be skipped
+    /// to continue decoding. It returns `None` when `data` is empty.
+    ///
+    /// Running status is not supported, see `MessageError::MissingStatus`.
+    ///
+    /// ```
+    /// use coremidi::{MidiMessage, MessageError};
+    /// assert_eq!(MidiMessage::decode(&[0xF8, 0x90]), Some(Ok((MidiMessage::TimingClock, 1))));
+    /// assert_eq!(MidiMessage::decode(&[0x40, 0x7f, 0x90]), Some(Err((MessageError::MissingStatus(2), 2))));
+    /// assert_eq!(MidiMessage::decode(&[]), None);
+    /// ```
+    pub fn decode(data: &[u8]) -> Option<Result<(MidiMessage, usize), (MessageError, usize)>> {
+        let status = match data.first() {
+            Some(&status) => status,
+            None => return None,
+        };
+
+        if !is_status(status) {
+            let len = data.iter().take_while(|&&b| !is_status(b)).count();
+            return Some(Err((MessageError::MissingStatus(len), len)));
+        }
+
+        if status == SYSEX_START {
+            return Some(Self::decode_sysex(data));
+        }
+
+        let len = match Self::expected_len(status) {
+            Some(len) => len,
+            None => return Some(Err((MessageError::UndefinedStatus(status), 1))),
+        };
+
+        let available = data[1..].iter().take(len - 1).take_while(|&&b| !is_status(b)).count();
+        if available < len - 1 {
+            return Some(Err((MessageError::Truncated(status), 1 + available)));
+        }
+
+        Some(Ok((Self::from_parts(status, &data[1..len]), len)))
+    }
+
+    /// Encode the message into raw MIDI bytes.
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut bytes);
+        bytes
+    }
+
+    /// Append the raw MIDI bytes for this message into `bytes`.
+    ///
+    pub fn write_to(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            MidiMessage::SysEx(ref data) => {
+                bytes.push(SYSEX_START);
+                bytes.extend(data.iter().map(|b| b & 0x7f));
+                bytes.push(SYSEX_END);
+            },
+            _ => {
+                let (short, len) = self.short_bytes();
+                bytes.extend_from_slice(&short[..len]);
+            }
+        }
+    }
+
+    /// Write the raw MIDI bytes for any message other than SysEx into `bytes`, without allocating.
+    /// It returns the number of bytes written, which is at most 3.
+    pub(crate) fn write_to_slice(&self, bytes: &mut [u8; 3]) -> usize {
+        let (short, len) = self.short_bytes();
+        *bytes = short;
+        len
+    }
+
+    /// Get the number of raw MIDI bytes needed to encode this message.
+    ///
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            MidiMessage::SysEx(ref data) => data.len() + 2,
+            _ => self.short_bytes().1,
+        }
+    }
+
+    /// Get the channel for channel messages, or `None` for system messages.
+    ///
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOff { channel, .. } |
+            MidiMessage::NoteOn { channel, .. } |
+            MidiMessage::PolyPressure { channel, .. } |
+            MidiMessage::ControlChange { channel, .. } |
+            MidiMessage::ProgramChange { channel, .. } |
+            MidiMessage::ChannelPressure { channel, .. } |
+            MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// Check whether this is a system real-time message, which can appear in the middle of other messages.
+    ///
+    pub fn is_realtime(&self) -> bool {
+        match *self {
+            MidiMessage::TimingClock |
+            MidiMessage::Start |
+            MidiMessage::Continue |
+            MidiMessage::Stop |
+            MidiMessage::ActiveSensing |
+            MidiMessage::SystemReset => true,
+            _ => false,
+        }
+    }
+
+    /// Build a message from a status byte, other than SysEx, and exactly the number of data bytes it needs.
+    fn from_parts(status: u8, data: &[u8]) -> MidiMessage {
+        let channel = status & 0x0f;
+        match status & 0xf0 {
+            NOTE_OFF => MidiMessage::NoteOff { channel, note: data[0], velocity: data[1] },
+            NOTE_ON => MidiMessage::NoteOn { channel, note: data[0], velocity: data[1] },
+            POLY_PRESSURE => MidiMessage::PolyPressure { channel, note: data[0], pressure: data[1] },
+            CONTROL_CHANGE => MidiMessage::ControlChange { channel, control: data[0], value: data[1] },
+            PROGRAM_CHANGE => MidiMessage::ProgramChange { channel, program: data[0] },
+            CHANNEL_PRESSURE => MidiMessage::ChannelPressure { channel, pressure: data[0] },
+            PITCH_BEND => MidiMessage::PitchBend { channel, value: from_14_bits(data[0], data[1]) },
+            _ => match status {
+                TIME_CODE_QUARTER_FRAME => MidiMessage::TimeCodeQuarterFrame {
+                    message_type: (data[0] >> 4) & 0x07,
+                    value: data[0] & 0x0f,
+                },
+                SONG_POSITION => MidiMessage::SongPosition(from_14_bits(data[0], data[1])),
+                SONG_SELECT => MidiMessage::SongSelect(data[0]),
+                TUNE_REQUEST => MidiMessage::TuneRequest,
+                TIMING_CLOCK => MidiMessage::TimingClock,
+                START => MidiMessage::Start,
+                CONTINUE => MidiMessage::Continue,
+                STOP => MidiMessage::Stop,
+                ACTIVE_SENSING => MidiMessage::ActiveSensing,
+                SYSTEM_RESET => MidiMessage::SystemReset,
+                _ => unreachable!("status without a known length"),
+            }
+        }
+    }
+
+    fn decode_sysex(data: &[u8]) -> Result<(MidiMessage, usize), (MessageError, usize)> {
+        let payload_len = data[1..].iter().take_while(|&&b| !is_status(b)).count();
+        match data.get(1 + payload_len) {
+            Some(&SYSEX_END) => {
+                let payload = data[1..=payload_len].to_vec();
+                Ok((MidiMessage::SysEx(payload), payload_len + 2))
+            },
+            _ => Err((MessageError::Truncated(SYSEX_START), 1 + payload_len)),
+        }
+    }
+
+    /// The number of bytes, including the status, for messages other than SysEx.
+    /// It returns `None` for undefined status bytes.
+    fn expected_len(status: u8) -> Option<usize> {
+        match status & 0xf0 {
+            NOTE_OFF | NOTE_ON | POLY_PRESSURE | CONTROL_CHANGE | PITCH_BEND => Some(3),
+            PROGRAM_CHANGE | CHANNEL_PRESSURE => Some(2),
+            _ => match status {
+                SONG_POSITION => Some(3),
+                TIME_CODE_QUARTER_FRAME | SONG_SELECT => Some(2),
+                TUNE_REQUEST | TIMING_CLOCK | START | CONTINUE | STOP | ACTIVE_SENSING | SYSTEM_RESET => Some(1),
+                _ => None,
+            }
+        }
+    }
+
+    /// Encode any message other than SysEx without allocating.
+    fn short_bytes(&self) -> ([u8; 3], usize) {
+        match *self {
+            MidiMessage::NoteOff { channel, note, velocity } =>
+                ([NOTE_OFF | (channel & 0x0f), note & 0x7f, velocity & 0x7f], 3),
+            MidiMessage::NoteOn { channel, note, velocity } =>
+                ([NOTE_ON | (channel & 0x0f), note & 0x7f, velocity & 0x7f], 3),
+            MidiMessage::PolyPressure { channel, note, pressure } =>
+                ([POLY_PRESSURE | (channel & 0x0f), note & 0x7f, pressure & 0x7f], 3),
+            MidiMessage::ControlChange { channel, control, value } =>
+                ([CONTROL_CHANGE | (channel & 0x0f), control & 0x7f, value & 0x7f], 3),
+            MidiMessage::ProgramChange { channel, program } =>
+                ([PROGRAM_CHANGE | (channel & 0x0f), program & 0x7f, 0], 2),
+            MidiMessage::ChannelPressure { channel, pressure } =>
+                ([CHANNEL_PRESSURE | (channel & 0x0f), pressure & 0x7f, 0], 2),
+            MidiMessage::PitchBend { channel, value } => {
+                let (lsb, msb) = to_14_bits(value);
+                ([PITCH_BEND | (channel & 0x0f), lsb, msb], 3)
+            },
+            MidiMessage::TimeCodeQuarterFrame { message_type, value } =>
+                ([TIME_CODE_QUARTER_FRAME, ((message_type & 0x07) << 4) | (value & 0x0f), 0], 2),
+            MidiMessage::SongPosition(position) => {
+                let (lsb, msb) = to_14_bits(position);
+                ([SONG_POSITION, lsb, msb], 3)
+            },
+            MidiMessage::SongSelect(song) => ([SONG_SELECT, song & 0x7f, 0], 2),
+            MidiMessage::TuneRequest => ([TUNE_REQUEST, 0, 0], 1),
+            MidiMessage::TimingClock => ([TIMING_CLOCK, 0, 0], 1),
+            MidiMessage::Start => ([START, 0, 0], 1),
+            MidiMessage::Continue => ([CONTINUE, 0, 0], 1),
+            MidiMessage::Stop => ([STOP, 0, 0], 1),
+            MidiMessage::ActiveSensing => ([ACTIVE_SENSING, 0, 0], 1),
+            MidiMessage::SystemReset => ([SYSTEM_RESET, 0, 0], 1),
+            MidiMessage::SysEx(_) => unreachable!("SysEx messages don't have a fixed length"),
+        }
+    }
+}
+
+/// A message decoded by a `MessageParser`, together with the timestamp of the packet where it started.
+pub type ParsedMessage = (Timestamp, Result<MidiMessage, MessageError>);
+
+/// A stateful MIDI 1.0 parser for the packets received from a single source.
+///
+/// Unlike `Packet::messages`, it keeps its state between packets, so it can deal with:
+///
+/// - running status, where the status byte is omitted when it is the same as the previous one,
+/// - real-time messages interleaved in the middle of other messages (even SysEx),
+/// - messages split across several packets, like long SysEx dumps.
+///
+/// Messages that are interrupted by another status byte are reported as `MessageError::Truncated`,
+/// and bytes that can't be part of any message are reported too, instead of being skipped silently.
+///
+/// ```
+/// use coremidi::{MessageParser, MidiMessage, PacketBuffer};
+/// let mut parser = MessageParser::new();
+/// let packet_list = &PacketBuffer::new(42, &[0x90, 0x40, 0x7f, 0x41, 0xf8, 0x7f]);
+/// for packet in packet_list.iter() {
+///     let messages: Vec<_> = parser.parse(packet).collect();
+///     assert_eq!(messages, vec![
+///         (42, Ok(MidiMessage::NoteOn { channel: 0, note: 0x40, velocity: 0x7f })),
+///         (42, Ok(MidiMessage::TimingClock)),
+///         (42, Ok(MidiMessage::NoteOn { channel: 0, note: 0x41, velocity: 0x7f })),
+///     ]);
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct MessageParser {
+    running_status: Option<u8>,
+    status: Option<u8>,
+    data: [u8; 2],
+    data_len: usize,
+    sysex: Option<Vec<u8>>,
+    timestamp: Timestamp,
+    missing_status: usize,
+}
+
+impl MessageParser {
+    pub fn new() -> MessageParser {
+        MessageParser::default()
+    }
+
+    /// Parse the data of the next packet received from the source.
+    ///
+    pub fn parse<'a>(&'a mut self, packet: &'a Packet) -> ParsedMessageIterator<'a> {
+        self.parse_data(packet.timestamp(), packet.data())
+    }
+
+    /// Parse the next chunk of raw MIDI bytes received from the source at `timestamp`.
+    ///
+    pub fn parse_data<'a>(&'a mut self, timestamp: Timestamp, data: &'a [u8]) -> ParsedMessageIterator<'a> {
+        ParsedMessageIterator {
+            parser: self,
+            timestamp,
+            data,
+            pending: None,
+        }
+    }
+
+    /// Forget the running status and discard the message being parsed, if any.
+    ///
+    /// It returns the error for the discarded message, so it can be reported as truncated.
+    pub fn reset(&mut self) -> Option<(Timestamp, MessageError)> {
+        self.running_status = None;
+        self.take_incomplete().map(|(timestamp, result)| (timestamp, result.unwrap_err()))
+    }
+
+    fn push_byte(&mut self, timestamp: Timestamp, byte: u8) -> (Option<ParsedMessage>, Option<ParsedMessage>) {
+        if !is_status(byte) {
+            return (self.push_data_byte(timestamp, byte), None);
+        }
+
+        let missing_status = self.take_missing_status();
+
+        if is_realtime_status(byte) {
+            let result = match MidiMessage::expected_len(byte) {
+                Some(_) => Ok(MidiMessage::from_parts(byte, &[])),
+                None => Err(MessageError::UndefinedStatus(byte)),
+            };
+            return (missing_status, Some((timestamp, result)));
+        }
+
+        if byte == SYSEX_END {
+            if let Some(sysex) = self.sysex.take() {
+                return (None, Some((self.timestamp, Ok(MidiMessage::SysEx(sysex)))));
+            }
+        }
+
+        // Any other status byte interrupts the message being parsed
+        let interrupted = missing_status.or_else(|| self.take_incomplete());
+
+        self.timestamp = timestamp;
+        self.running_status = None;
+        let parsed = match byte {
+            SYSEX_START => {
+                self.sysex = Some(Vec::new());
+                None
+            },
+            _ if byte < SYSEX_START => {
+                self.running_status = Some(byte);
+                self.status = Some(byte);
+                None
+            },
+            _ => match MidiMessage::expected_len(byte) {
+                None => Some((timestamp, Err(MessageError::UndefinedStatus(byte)))),
+                Some(1) => Some((timestamp, Ok(MidiMessage::from_parts(byte, &[])))),
+                Some(_) => {
+                    self.status = Some(byte);
+                    None
+                }
+            }
+        };
+
+        match interrupted {
+            Some(interrupted) => (Some(interrupted), parsed),
+            None => (parsed, None),
+        }
+    }
+
+    fn push_data_byte(&mut self, timestamp: Timestamp, byte: u8) -> Option<ParsedMessage> {
+        if let Some(ref mut sysex) = self.sysex {
+            sysex.push(byte);
+            return None;
+        }
+
+        let status = match self.status.or(self.running_status) {
+            Some(status) => status,
+            None => {
+                if self.missing_status == 0 {
+                    self.timestamp = timestamp;
+                }
+                self.missing_status += 1;
+                return None;
+            }
+        };
+
+        if self.status.is_none() {
+            self.status = Some(status);
+            self.timestamp = timestamp;
+        }
+
+        self.data[self.data_len] = byte;
+        self.data_len += 1;
+
+        if Some(self.data_len + 1) == MidiMessage::expected_len(status) {
+            let message = MidiMessage::from_parts(status, &self.data[..self.data_len]);
+            self.status = None;
+            self.data_len = 0;
+            Some((self.timestamp, Ok(message)))
+        } else {
+            None
+        }
+    }
+
+    fn take_incomplete(&mut self) -> Option<ParsedMessage> {
+        let timestamp = self.timestamp;
+        self.data_len = 0;
+        if let Some(status) = self.status.take() {
+            Some((timestamp, Err(MessageError::Truncated(status))))
+        } else if self.sysex.take().is_some() {
+            Some((timestamp, Err(MessageError::Truncated(SYSEX_START))))
+        } else {
+            None
+        }
+    }
+
+    fn take_missing_status(&mut self) -> Option<ParsedMessage> {
+        if self.missing_status > 0 {
+            let len = self.missing_status;
+            self.missing_status = 0;
+            Some((self.timestamp, Err(MessageError::MissingStatus(len))))
+        } else {
+            None
+        }
+    }
+}
+
+/// The messages parsed by a `MessageParser` from a chunk of data.
+///
+/// The data is only consumed as the iterator advances, so it should be run to completion.
+pub struct ParsedMessageIterator<'a> {
+    parser: &'a mut MessageParser,
+    timestamp: Timestamp,
+    data: &'a [u8],
+    pending: Option<ParsedMessage>,
+}
+
+impl<'a> Iterator for ParsedMessageIterator<'a> {
+    type Item = ParsedMessage;
+
+    fn next(&mut self) -> Option<ParsedMessage> {
+        if let Some(parsed) = self.pending.take() {
+            return Some(parsed);
+        }
+
+        while let Some((&byte, rest)) = self.data.split_first() {
+            self.data = rest;
+            match self.parser.push_byte(self.timestamp, byte) {
+                (Some(parsed), pending) => {
+                    self.pending = pending;
+                    return Some(parsed);
+                },
+                (None, Some(parsed)) => return Some(parsed),
+                (None, None) => {}
+            }
+        }
+
+        // Data bytes without status are reported per chunk, as they won't be part of any later message
+        self.parser.take_missing_status()
+    }
+}
+
+#[inline]
+fn is_status(byte: u8) -> bool {
+    byte & 0b1000_0000 != 0
+}
+
+#[inline]
+fn is_realtime_status(byte: u8) -> bool {
+    byte >= TIMING_CLOCK
+}
+
+#[inline]
+fn from_14_bits(lsb: u8, msb: u8) -> u16 {
+    (u16::from(msb & 0x7f) << 7) | u16::from(lsb & 0x7f)
+}
+
+#[inline]
+fn to_14_bits(value: u16) -> (u8, u8) {
+    ((value & 0x7f) as u8, ((value >> 7) & 0x7f) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MidiMessage, MessageError, MessageParser, ParsedMessage};
+    use packets::Timestamp;
+
+    fn round_trip(bytes: &[u8], message: MidiMessage) {
+        assert_eq!(MidiMessage::decode(bytes), Some(Ok((message.clone(), bytes.len()))));
+        assert_eq!(message.to_bytes(), bytes);
+        assert_eq!(message.encoded_len(), bytes.len());
+    }
+
+    #[test]
+    fn channel_messages() {
+        round_trip(&[0x81, 0x3c, 0x40], MidiMessage::NoteOff { channel: 1, note: 0x3c, velocity: 0x40 });
+        round_trip(&[0x9f, 0x3c, 0x00], MidiMessage::NoteOn { channel: 15, note: 0x3c, velocity: 0 });
+        round_trip(&[0xa2, 0x3c, 0x10], MidiMessage::PolyPressure { channel: 2, note: 0x3c, pressure: 0x10 });
+        round_trip(&[0xb3, 0x07, 0x64], MidiMessage::ControlChange { channel: 3, control: 7, value: 100 });
+        round_trip(&[0xc4, 0x05], MidiMessage::ProgramChange { channel: 4, program: 5 });
+        round_trip(&[0xd5, 0x20], MidiMessage::ChannelPressure { channel: 5, pressure: 0x20 });
+        round_trip(&[0xe6, 0x00, 0x40], MidiMessage::PitchBend { channel: 6, value: 8192 });
+        round_trip(&[0xe6, 0x7f, 0x7f], MidiMessage::PitchBend { channel: 6, value: 16383 });
+    }
+
+    #[test]
+    fn system_messages() {
+        round_trip(&[0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7], MidiMessage::SysEx(vec![0x7e, 0x7f, 0x06, 0x01]));
+        round_trip(&[0xf0, 0xf7], MidiMessage::SysEx(vec![]));
+        round_trip(&[0xf1, 0x35], MidiMessage::TimeCodeQuarterFrame { message_type: 3, value: 5 });
+        round_trip(&[0xf2, 0x01, 0x02], MidiMessage::SongPosition(0x101));
+        round_trip(&[0xf3, 0x09], MidiMessage::SongSelect(9));
+        round_trip(&[0xf6], MidiMessage::TuneRequest);
+        round_trip(&[0xf8], MidiMessage::TimingClock);
+        round_trip(&[0xfa], MidiMessage::Start);
+        round_trip(&[0xfb], MidiMessage::Continue);
+        round_trip(&[0xfc], MidiMessage::Stop);
+        round_trip(&[0xfe], MidiMessage::ActiveSensing);
+        round_trip(&[0xff], MidiMessage::SystemReset);
+    }
+
+    #[test]
+    fn encode_masks_out_of_range_values() {
+        let message = MidiMessage::NoteOn { channel: 0x13, note: 0xbc, velocity: 0xff };
+        assert_eq!(message.to_bytes(), vec![0x93, 0x3c, 0x7f]);
+        assert_eq!(MidiMessage::PitchBend { channel: 0, value: 0xffff }.to_bytes(), vec![0xe0, 0x7f, 0x7f]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(MidiMessage::decode(&[]), None);
+        assert_eq!(MidiMessage::decode(&[0x3c, 0x7f]), Some(Err((MessageError::MissingStatus(2), 2))));
+        assert_eq!(MidiMessage::decode(&[0xf4, 0x00]), Some(Err((MessageError::UndefinedStatus(0xf4), 1))));
+        assert_eq!(MidiMessage::decode(&[0xf7]), Some(Err((MessageError::UndefinedStatus(0xf7), 1))));
+        assert_eq!(MidiMessage::decode(&[0x90, 0x3c]), Some(Err((MessageError::Truncated(0x90), 2))));
+        assert_eq!(MidiMessage::decode(&[0x90, 0x3c, 0x80, 0x3c, 0x00]), Some(Err((MessageError::Truncated(0x90), 2))));
+        assert_eq!(MidiMessage::decode(&[0xf0, 0x01, 0x02]), Some(Err((MessageError::Truncated(0xf0), 3))));
+    }
+
+    #[test]
+    fn channel_and_realtime() {
+        assert_eq!(MidiMessage::ProgramChange { channel: 9, program: 0 }.channel(), Some(9));
+        assert_eq!(MidiMessage::TimingClock.channel(), None);
+        assert!(MidiMessage::TimingClock.is_realtime());
+        assert!(!MidiMessage::TuneRequest.is_realtime());
+    }
+
+    fn parse_all(parser: &mut MessageParser, chunks: &[(Timestamp, &[u8])]) -> Vec<ParsedMessage> {
+        chunks.iter().flat_map(|&(timestamp, data)| parser.parse_data(timestamp, data).collect::<Vec<_>>()).collect()
+    }
+
+    #[test]
+    fn parser_running_status() {
+        let mut parser = MessageParser::new();
+        let parsed = parse_all(&mut parser, &[
+            (1, &[0x90, 0x40, 0x7f, 0x41, 0x7f]),
+            (2, &[0x42, 0x00, 0xc0, 0x01, 0x02]),
+        ]);
+        assert_eq!(parsed, vec![
+            (1, Ok(MidiMessage::NoteOn { channel: 0, note: 0x40, velocity: 0x7f })),
+            (1, Ok(MidiMessage::NoteOn { channel: 0, note: 0x41, velocity: 0x7f })),
+            (2, Ok(MidiMessage::NoteOn { channel: 0, note: 0x42, velocity: 0x00 })),
+            (2, Ok(MidiMessage::ProgramChange { channel: 0, program: 1 })),
+            (2, Ok(MidiMessage::ProgramChange { channel: 0, program: 2 })),
+        ]);
+    }
+
+    #[test]
+    fn parser_system_messages_cancel_running_status() {
+        let mut parser = MessageParser::new();
+        let parsed = parse_all(&mut parser, &[(1, &[0x90, 0x40, 0x7f, 0xf3, 0x01, 0x41, 0x7f])]);
+        assert_eq!(parsed, vec![
+            (1, Ok(MidiMessage::NoteOn { channel: 0, note: 0x40, velocity: 0x7f })),
+            (1, Ok(MidiMessage::SongSelect(1))),
+            (1, Err(MessageError::MissingStatus(2))),
+        ]);
+    }
+
+    #[test]
+    fn parser_interleaved_realtime() {
+        let mut parser = MessageParser::new();
+        let parsed = parse_all(&mut parser, &[
+            (1, &[0xb0, 0xf8, 0x07, 0xfa, 0x64]),
+            (2, &[0xf0, 0x01, 0xf8, 0x02]),
+            (3, &[0xfe, 0x03, 0xf7]),
+        ]);
+        assert_eq!(parsed, vec![
+            (1, Ok(MidiMessage::TimingClock)),
+            (1, Ok(MidiMessage::Start)),
+            (1, Ok(MidiMessage::ControlChange { channel: 0, control: 7, value: 100 })),
+            (2, Ok(MidiMessage::TimingClock)),
+            (3, Ok(MidiMessage::ActiveSensing)),
+            (2, Ok(MidiMessage::SysEx(vec![0x01, 0x02, 0x03]))),
+        ]);
+    }
+
+    #[test]
+    fn parser_truncated_messages() {
+        let mut parser = MessageParser::new();
+        let parsed = parse_all(&mut parser, &[
+            (1, &[0x90, 0x40, 0xf0, 0x01]),
+            (2, &[0x80, 0x40, 0x00]),
+            (3, &[0xe0, 0x01, 0xf6]),
+        ]);
+        assert_eq!(parsed, vec![
+            (1, Err(MessageError::Truncated(0x90))),
+            (1, Err(MessageError::Truncated(0xf0))),
+            (2, Ok(MidiMessage::NoteOff { channel: 0, note: 0x40, velocity: 0x00 })),
+            (3, Err(MessageError::Truncated(0xe0))),
+            (3, Ok(MidiMessage::TuneRequest)),
+        ]);
+    }
+
+    #[test]
+    fn parser_malformed_bytes() {
+        let mut parser = MessageParser::new();
+        let parsed = parse_all(&mut parser, &[
+            (1, &[0x01, 0x02, 0xf9, 0x03]),
+            (2, &[0xf7, 0xf4, 0x90, 0x40, 0x7f]),
+        ]);
+        assert_eq!(parsed, vec![
+            (1, Err(MessageError::MissingStatus(2))),
+            (1, Err(MessageError::UndefinedStatus(0xf9))),
+            (1, Err(MessageError::MissingStatus(1))),
+            (2, Err(MessageError::UndefinedStatus(0xf7))),
+            (2, Err(MessageError::UndefinedStatus(0xf4))),
+            (2, Ok(MidiMessage::NoteOn { channel: 0, note: 0x40, velocity: 0x7f })),
+        ]);
+    }
+
+    #[test]
+    fn parser_reset() {
+        let mut parser = MessageParser::new();
+        assert_eq!(parse_all(&mut parser, &[(1, &[0x90, 0x40, 0x7f, 0x41])]).len(), 1);
+        assert_eq!(parser.reset(), Some((1, MessageError::Truncated(0x90))));
+        assert_eq!(parser.reset(), None);
+        assert_eq!(parse_all(&mut parser, &[(2, &[0x41, 0x7f])]), vec![(2, Err(MessageError::MissingStatus(2)))]);
+    }
+}
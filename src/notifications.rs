@@ -1,9 +1,21 @@
 #![allow(non_upper_case_globals)]
 
-use core_foundation::string::{CFString, CFStringRef};
-use core_foundation::base::{TCFType, OSStatus};
+#[cfg(target_os = "macos")]
+use core_foundation::string::CFString;
+#[cfg(target_os = "macos")]
+use core_foundation::base::TCFType;
 
-use coremidi_sys::{
+use std::collections::HashMap;
+
+use Object;
+use object::ObjectType;
+use Device;
+use Entity;
+use {Destinations, Devices, ExternalDevices, PropertyKey, PropertyValue, Sources};
+use backend::sys::{
+    CFStringRef,
+    MIDIObjectRef,
+    OSStatus,
     MIDINotification,
     MIDIObjectAddRemoveNotification,
     MIDIObjectPropertyChangeNotification,
@@ -17,12 +29,9 @@ use coremidi_sys::{
     kMIDIMsgIOError
 };
 
-use Object;
-use object::ObjectType;
-use Device;
-
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct AddedRemovedInfo {
     pub parent: Object,
     pub parent_type: ObjectType,
@@ -30,16 +39,32 @@ pub struct AddedRemovedInfo {
     pub child_type: ObjectType
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct PropertyChangedInfo {
     pub object: Object,
     pub object_type: ObjectType,
-    pub property_name: String
+    pub property_name: String,
+    /// The value before the change, only known for the properties watched by the client
+    /// (see `Client::new_with_watched_properties`).
+    pub old_value: Option<PropertyValue>,
+    /// The value after the change, only known for the properties watched by the client,
+    /// and `None` when the property was removed.
+    pub new_value: Option<PropertyValue>,
+}
+
+impl PropertyChangedInfo {
+    /// Get the key of the property that changed.
+    ///
+    pub fn property(&self) -> PropertyKey {
+        PropertyKey::from_name(&self.property_name)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct IOErrorInfo {
     pub driver_device: Device,
     pub error_code: OSStatus
@@ -48,8 +73,9 @@ pub struct IOErrorInfo {
 /// A message describing a system state change.
 /// See [MIDINotification](https://developer.apple.com/reference/coremidi/midinotification).
 ///
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum Notification {
     SetupChanged,
     ObjectAdded(AddedRemovedInfo),
@@ -60,6 +86,49 @@ pub enum Notification {
     IOError(IOErrorInfo)
 }
 
+/// The kind of a `Notification`, without the information it carries.
+///
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NotificationKind {
+    SetupChanged,
+    ObjectAdded,
+    ObjectRemoved,
+    PropertyChanged,
+    ThruConnectionsChanged,
+    SerialPortOwnerChanged,
+    IOError,
+}
+
+impl Notification {
+    /// Get the kind of the notification.
+    ///
+    pub fn kind(&self) -> NotificationKind {
+        match *self {
+            Notification::SetupChanged => NotificationKind::SetupChanged,
+            Notification::ObjectAdded(_) => NotificationKind::ObjectAdded,
+            Notification::ObjectRemoved(_) => NotificationKind::ObjectRemoved,
+            Notification::PropertyChanged(_) => NotificationKind::PropertyChanged,
+            Notification::ThruConnectionsChanged => NotificationKind::ThruConnectionsChanged,
+            Notification::SerialPortOwnerChanged => NotificationKind::SerialPortOwnerChanged,
+            Notification::IOError(_) => NotificationKind::IOError,
+        }
+    }
+
+    /// Get the type of the object the notification is about: the child for the added and removed objects,
+    /// the object which property changed, or the device for the I/O errors.
+    /// The notifications about the whole setup return `None`.
+    ///
+    pub fn object_type(&self) -> Option<ObjectType> {
+        match *self {
+            Notification::ObjectAdded(ref info) | Notification::ObjectRemoved(ref info) => Some(info.child_type),
+            Notification::PropertyChanged(ref info) => Some(info.object_type),
+            Notification::IOError(_) => Some(ObjectType::Device),
+            _ => None,
+        }
+    }
+}
+
 impl Notification {
     pub fn from(notification: &MIDINotification) -> Result<Notification, i32> {
         match notification.messageID as ::std::os::raw::c_uint {
@@ -97,15 +166,13 @@ impl Notification {
         let property_changed_notification = unsafe { &*(notification as *const _ as *const MIDIObjectPropertyChangeNotification) };
         match ObjectType::from(property_changed_notification.objectType) {
             Ok(object_type) => {
-                let property_name = {
-                    let name_ref: CFStringRef = property_changed_notification.propertyName;
-                    let name: CFString = unsafe { TCFType::wrap_under_get_rule(name_ref) };
-                    name.to_string()
-                };
+                let property_name = property_name(property_changed_notification.propertyName);
                 let property_changed_info = PropertyChangedInfo {
                     object: Object(property_changed_notification.object),
                     object_type,
-                    property_name
+                    property_name,
+                    old_value: None,
+                    new_value: None,
                 };
                 Ok(Notification::PropertyChanged(property_changed_info))
             },
@@ -124,19 +191,152 @@ impl Notification {
     }
 }
 
+#[cfg(target_os = "macos")]
+fn property_name(name_ref: CFStringRef) -> String {
+    let name: CFString = unsafe { TCFType::wrap_under_get_rule(name_ref) };
+    name.to_string()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn property_name(name: CFStringRef) -> String {
+    name.to_string()
+}
+
+/// The last known values of the watched properties of every object,
+/// used to tell the previous value in the `PropertyChanged` notifications.
+///
+pub(crate) struct PropertyCache {
+    watched: Vec<PropertyKey>,
+    values: HashMap<(MIDIObjectRef, PropertyKey), PropertyValue>,
+    // The entities of the cached devices and the endpoints of the cached entities, to forget
+    // about them too when their parent is removed, as they can't be looked up anymore by then.
+    children: HashMap<MIDIObjectRef, Vec<MIDIObjectRef>>,
+}
+
+impl PropertyCache {
+    /// Create a cache with the current values of the watched properties for all the objects in the system.
+    pub fn new(watched: &[PropertyKey]) -> PropertyCache {
+        let mut cache = PropertyCache { watched: watched.to_vec(), values: HashMap::new(), children: HashMap::new() };
+        for device in Devices.into_iter().chain(ExternalDevices) {
+            cache.add_device(&device);
+        }
+        for source in Sources.into_iter().filter(|source| source.entity().is_none()) {
+            cache.add(&source);
+        }
+        for destination in Destinations.into_iter().filter(|destination| destination.entity().is_none()) {
+            cache.add(&destination);
+        }
+        cache
+    }
+
+    /// Keep the cache up to date with a notification, and for the watched properties
+    /// return the `PropertyChanged` notification with the old and new values filled in.
+    pub fn update(&mut self, notification: &Notification) -> Option<Notification> {
+        match *notification {
+            Notification::ObjectAdded(ref info) => {
+                match info.child_type {
+                    ObjectType::Device | ObjectType::ExternalDevice => {
+                        self.add_device(&Device { object: Object(info.child.0) })
+                    },
+                    ObjectType::Entity | ObjectType::ExternalEntity => {
+                        self.add_entity(&Entity { object: Object(info.child.0) })
+                    },
+                    _ => self.add(&info.child),
+                }
+                if let Some(children) = self.children.get_mut(&info.parent.0) {
+                    if !children.contains(&info.child.0) {
+                        children.push(info.child.0);
+                    }
+                }
+                None
+            },
+            Notification::ObjectRemoved(ref info) => {
+                if let Some(children) = self.children.get_mut(&info.parent.0) {
+                    children.retain(|&child| child != info.child.0);
+                }
+                self.remove(info.child.0);
+                None
+            },
+            Notification::PropertyChanged(ref info) => {
+                let key = info.property();
+                if !self.watched.contains(&key) {
+                    return None;
+                }
+                let new_value = key.value_from(&info.object).ok();
+                let cache_key = (info.object.0, key);
+                let old_value = match new_value {
+                    Some(ref value) => self.values.insert(cache_key, value.clone()),
+                    None => self.values.remove(&cache_key),
+                };
+                Some(Notification::PropertyChanged(PropertyChangedInfo {
+                    object: Object(info.object.0),
+                    object_type: info.object_type,
+                    property_name: info.property_name.clone(),
+                    old_value,
+                    new_value,
+                }))
+            },
+            _ => None,
+        }
+    }
+
+    fn add_device(&mut self, device: &Device) {
+        self.add(device);
+        let mut children = Vec::new();
+        for entity in device.entities() {
+            self.add_entity(&entity);
+            children.push(entity.object.0);
+        }
+        self.children.insert(device.object.0, children);
+    }
+
+    fn add_entity(&mut self, entity: &Entity) {
+        self.add(entity);
+        let mut children = Vec::new();
+        for source in entity.sources() {
+            self.add(&source);
+            children.push(source.endpoint.object.0);
+        }
+        for destination in entity.destinations() {
+            self.add(&destination);
+            children.push(destination.endpoint.object.0);
+        }
+        self.children.insert(entity.object.0, children);
+    }
+
+    fn remove(&mut self, removed: MIDIObjectRef) {
+        self.values.retain(|&(object_ref, _), _| object_ref != removed);
+        for child in self.children.remove(&removed).unwrap_or_default() {
+            self.remove(child);
+        }
+    }
+
+    fn add(&mut self, object: &Object) {
+        for key in &self.watched {
+            if let Ok(value) = key.value_from(object) {
+                self.values.insert((object.0, key.clone()), value);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
+    #[cfg(target_os = "macos")]
     use core_foundation::string::CFString;
-    use core_foundation::base::{TCFType, OSStatus};
+    #[cfg(target_os = "macos")]
+    use core_foundation::base::TCFType;
 
-    use coremidi_sys::{
+    use backend::sys::{
+        CFStringRef,
         MIDIObjectRef,
         MIDINotification,
         MIDINotificationMessageID,
         MIDIObjectAddRemoveNotification,
         MIDIObjectPropertyChangeNotification,
         MIDIIOErrorNotification,
+        OSStatus,
         kMIDIMsgSetupChanged,
         kMIDIMsgObjectAdded,
         kMIDIMsgObjectRemoved,
@@ -152,6 +352,17 @@ mod tests {
     use object::ObjectType;
     use notifications::{Notification, AddedRemovedInfo, PropertyChangedInfo, IOErrorInfo};
 
+    #[cfg(target_os = "macos")]
+    fn with_property_name<F: FnOnce(CFStringRef)>(name: &'static str, f: F) {
+        let name = CFString::new(name);
+        f(name.as_concrete_TypeRef())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn with_property_name<F: FnOnce(CFStringRef)>(name: &'static str, f: F) {
+        f(name)
+    }
+
     #[test]
     fn notification_from_error() {
         let notification_raw = MIDINotification {
@@ -261,45 +472,49 @@ mod tests {
 
     #[test]
     fn notification_from_property_changed() {
-        let name = CFString::new("name");
-        let notification_raw = MIDIObjectPropertyChangeNotification {
-            messageID: kMIDIMsgPropertyChanged as MIDINotificationMessageID,
-            messageSize: 24,
-            object: 1 as MIDIObjectRef,
-            objectType: kMIDIObjectType_Device,
-            propertyName: name.as_concrete_TypeRef()
-        };
+        with_property_name("name", |name| {
+            let notification_raw = MIDIObjectPropertyChangeNotification {
+                messageID: kMIDIMsgPropertyChanged as MIDINotificationMessageID,
+                messageSize: 24,
+                object: 1 as MIDIObjectRef,
+                objectType: kMIDIObjectType_Device,
+                propertyName: name
+            };
 
-        let notification = Notification::from(
-            unsafe { &*(&notification_raw as *const _ as *const MIDINotification) });
+            let notification = Notification::from(
+                unsafe { &*(&notification_raw as *const _ as *const MIDINotification) });
 
-        assert!(notification.is_ok());
+            assert!(notification.is_ok());
 
-        let info = PropertyChangedInfo {
-            object: Object(1),
-            object_type: ObjectType::Device,
-            property_name: "name".to_string()
-        };
+            let info = PropertyChangedInfo {
+                object: Object(1),
+                object_type: ObjectType::Device,
+                property_name: "name".to_string(),
+                old_value: None,
+                new_value: None,
+            };
 
-        assert_eq!(notification.unwrap(), Notification::PropertyChanged(info));
+            assert_eq!(notification.unwrap(), Notification::PropertyChanged(info));
+        });
     }
 
     #[test]
     fn notification_from_property_changed_error() {
-        let name = CFString::new("name");
-        let notification_raw = MIDIObjectPropertyChangeNotification {
-            messageID: kMIDIMsgPropertyChanged as MIDINotificationMessageID,
-            messageSize: 24,
-            object: 1 as MIDIObjectRef,
-            objectType: 0xffff,
-            propertyName: name.as_concrete_TypeRef()
-        };
+        with_property_name("name", |name| {
+            let notification_raw = MIDIObjectPropertyChangeNotification {
+                messageID: kMIDIMsgPropertyChanged as MIDINotificationMessageID,
+                messageSize: 24,
+                object: 1 as MIDIObjectRef,
+                objectType: 0xffff,
+                propertyName: name
+            };
 
-        let notification = Notification::from(
-            unsafe { &*(&notification_raw as *const _ as *const MIDINotification) });
+            let notification = Notification::from(
+                unsafe { &*(&notification_raw as *const _ as *const MIDINotification) });
 
-        assert!(notification.is_err());
-        assert_eq!(notification.err().unwrap(), kMIDIMsgPropertyChanged as i32);
+            assert!(notification.is_err());
+            assert_eq!(notification.err().unwrap(), kMIDIMsgPropertyChanged as i32);
+        });
     }
 
     #[test]
@@ -346,3 +561,40 @@ mod tests {
         assert_eq!(notification.unwrap(), Notification::IOError(info));
     }
 }
+
+#[cfg(all(test, not(target_os = "macos")))]
+mod cache_tests {
+    use backend::{add_device, remove_device};
+    use {Device, Object, PropertyKey};
+    use object::ObjectType;
+    use notifications::{AddedRemovedInfo, Notification, PropertyCache};
+
+    #[test]
+    fn removing_a_device_forgets_its_entities_and_endpoints() {
+        let device_ref = add_device("cache-device", false, &[("cache-port", 1, 1)]);
+        let device = Device { object: Object(device_ref) };
+        let entity = device.entities().next().unwrap();
+        let source = entity.sources().next().unwrap();
+        let destination = entity.destinations().next().unwrap();
+        let refs = [device_ref, entity.object.0, source.endpoint.object.0, destination.endpoint.object.0];
+
+        let mut cache = PropertyCache::new(&[PropertyKey::Name]);
+        for object_ref in &refs {
+            assert!(cache.values.contains_key(&(*object_ref, PropertyKey::Name)));
+        }
+
+        let removed = Notification::ObjectRemoved(AddedRemovedInfo {
+            parent: Object(0),
+            parent_type: ObjectType::Other,
+            child: Object(device_ref),
+            child_type: ObjectType::Device,
+        });
+        assert_eq!(cache.update(&removed), None);
+        for object_ref in &refs {
+            assert!(!cache.values.contains_key(&(*object_ref, PropertyKey::Name)));
+        }
+        assert!(!cache.children.contains_key(&device_ref));
+        assert!(!cache.children.contains_key(&entity.object.0));
+        remove_device(device_ref);
+    }
+}
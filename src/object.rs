@@ -1,8 +1,8 @@
 #![allow(non_upper_case_globals)]
 
-use core_foundation_sys::base::OSStatus;
-
-use coremidi_sys::{
+use backend::{Backend, Current};
+use backend::sys::{
+    MIDIObjectType,
     SInt32,
     kMIDIObjectType_Other,
     kMIDIObjectType_Device,
@@ -15,15 +15,29 @@ use coremidi_sys::{
     kMIDIObjectType_ExternalDestination
 };
 
+use std::collections::BTreeMap;
 use std::fmt;
+use std::ops::Deref;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+#[cfg(feature = "serde")]
+use serde::ser::SerializeStruct;
 
+use Destination;
+use Device;
+use Endpoint;
+use Entity;
+use Error;
 use Object;
+use Source;
 use properties::{
-    PropertyGetter, PropertySetter, Properties,
-    StringProperty, IntegerProperty, BooleanProperty
+    PropertyGetter, PropertySetter, Properties, PropertyValue,
+    StringProperty, IntegerProperty, BooleanProperty, DataProperty, DictionaryProperty
 };
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ObjectType {
     Other,
     Device,
@@ -51,9 +65,111 @@ impl ObjectType {
             unknown => Err(unknown)
         }
     }
+
+    /// Get the raw `MIDIObjectType` for this type of object.
+    ///
+    pub fn to_raw(self) -> MIDIObjectType {
+        match self {
+            ObjectType::Other => kMIDIObjectType_Other,
+            ObjectType::Device => kMIDIObjectType_Device,
+            ObjectType::Entity => kMIDIObjectType_Entity,
+            ObjectType::Source => kMIDIObjectType_Source,
+            ObjectType::Destination => kMIDIObjectType_Destination,
+            ObjectType::ExternalDevice => kMIDIObjectType_ExternalDevice,
+            ObjectType::ExternalEntity => kMIDIObjectType_ExternalEntity,
+            ObjectType::ExternalSource => kMIDIObjectType_ExternalSource,
+            ObjectType::ExternalDestination => kMIDIObjectType_ExternalDestination,
+        }
+    }
+}
+
+/// A MIDI object of any type, as found by `Object::from_unique_id`.
+///
+/// The endpoints of external devices are not sources or destinations that can be connected to or
+/// sent to, so they are given as plain endpoints.
+///
+#[derive(Debug, PartialEq)]
+pub enum AnyObject {
+    Device(Device),
+    ExternalDevice(Device),
+    Entity(Entity),
+    ExternalEntity(Entity),
+    Source(Source),
+    Destination(Destination),
+    ExternalSource(Endpoint),
+    ExternalDestination(Endpoint),
+    Other(Object),
+}
+
+impl AnyObject {
+    fn new(object_ref: ::backend::sys::MIDIObjectRef, object_type: ObjectType) -> AnyObject {
+        let object = Object(object_ref);
+        match object_type {
+            ObjectType::Device => AnyObject::Device(Device { object }),
+            ObjectType::ExternalDevice => AnyObject::ExternalDevice(Device { object }),
+            ObjectType::Entity => AnyObject::Entity(Entity { object }),
+            ObjectType::ExternalEntity => AnyObject::ExternalEntity(Entity { object }),
+            ObjectType::Source => AnyObject::Source(Source { endpoint: Endpoint { object } }),
+            ObjectType::Destination => AnyObject::Destination(Destination { endpoint: Endpoint { object } }),
+            ObjectType::ExternalSource => AnyObject::ExternalSource(Endpoint { object }),
+            ObjectType::ExternalDestination => AnyObject::ExternalDestination(Endpoint { object }),
+            ObjectType::Other => AnyObject::Other(object),
+        }
+    }
+
+    /// Get the type of the object.
+    ///
+    pub fn object_type(&self) -> ObjectType {
+        match *self {
+            AnyObject::Device(_) => ObjectType::Device,
+            AnyObject::ExternalDevice(_) => ObjectType::ExternalDevice,
+            AnyObject::Entity(_) => ObjectType::Entity,
+            AnyObject::ExternalEntity(_) => ObjectType::ExternalEntity,
+            AnyObject::Source(_) => ObjectType::Source,
+            AnyObject::Destination(_) => ObjectType::Destination,
+            AnyObject::ExternalSource(_) => ObjectType::ExternalSource,
+            AnyObject::ExternalDestination(_) => ObjectType::ExternalDestination,
+            AnyObject::Other(_) => ObjectType::Other,
+        }
+    }
+}
+
+impl Deref for AnyObject {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        match *self {
+            AnyObject::Device(ref device) | AnyObject::ExternalDevice(ref device) => device,
+            AnyObject::Entity(ref entity) | AnyObject::ExternalEntity(ref entity) => entity,
+            AnyObject::Source(ref source) => source,
+            AnyObject::Destination(ref destination) => destination,
+            AnyObject::ExternalSource(ref endpoint) | AnyObject::ExternalDestination(ref endpoint) => endpoint,
+            AnyObject::Other(ref object) => object,
+        }
+    }
 }
 
 impl Object {
+    /// Find the object with the given unique ID, whatever its type.
+    /// See `MIDIObjectFindByUniqueID`.
+    ///
+    /// The unique IDs are kept by CoreMIDI across restarts, so they are the way to refer
+    /// to the devices and endpoints saved in a project:
+    ///
+    /// ```rust,no_run
+    /// use coremidi::{AnyObject, Object};
+    /// match Object::from_unique_id(0x1234_5678) {
+    ///     Ok(AnyObject::Source(source)) => println!("Found {:?}", source.display_name()),
+    ///     Ok(other) => println!("Found a {:?}", other.object_type()),
+    ///     Err(error) => println!("Not found: {}", error),
+    /// }
+    /// ```
+    pub fn from_unique_id(unique_id: u32) -> Result<AnyObject, Error> {
+        let (object_ref, object_type) = Current::object_find_by_unique_id(unique_id as SInt32)?;
+        let object_type = ObjectType::from(object_type).unwrap_or(ObjectType::Other);
+        Ok(AnyObject::new(object_ref, object_type))
+    }
+
     /// Get the name for the object.
     ///
     pub fn name(&self) -> Option<String> {
@@ -74,25 +190,25 @@ impl Object {
 
     /// Sets an object's string-type property.
     ///
-    pub fn set_property_string(&self, name: &str, value: &str) -> Result<(), OSStatus> {
+    pub fn set_property_string(&self, name: &str, value: &str) -> Result<(), Error> {
         StringProperty::new(name).set_value(self, value)
     }
 
     /// Gets an object's string-type property.
     ///
-    pub fn get_property_string(&self, name: &str) -> Result<String, OSStatus> {
+    pub fn get_property_string(&self, name: &str) -> Result<String, Error> {
         StringProperty::new(name).value_from(self)
     }
 
     /// Sets an object's integer-type property.
     ///
-    pub fn set_property_integer(&self, name: &str, value: i32) -> Result<(), OSStatus> {
+    pub fn set_property_integer(&self, name: &str, value: i32) -> Result<(), Error> {
         IntegerProperty::new(name).set_value(self, value)
     }
 
     /// Gets an object's integer-type property.
     ///
-    pub fn get_property_integer(&self, name: &str) -> Result<i32, OSStatus> {
+    pub fn get_property_integer(&self, name: &str) -> Result<i32, Error> {
         IntegerProperty::new(name).value_from(self)
     }
 
@@ -100,7 +216,7 @@ impl Object {
     ///
     /// CoreMIDI treats booleans as integers (0/1) but this API uses native bool types
     ///
-    pub fn set_property_boolean(&self, name: &str, value: bool) -> Result<(), OSStatus> {
+    pub fn set_property_boolean(&self, name: &str, value: bool) -> Result<(), Error> {
         BooleanProperty::new(name).set_value(self, value)
     }
 
@@ -108,9 +224,44 @@ impl Object {
     ///
     /// CoreMIDI treats booleans as integers (0/1) but this API uses native bool types
     ///
-    pub fn get_property_boolean(&self, name: &str) -> Result<bool, OSStatus> {
+    pub fn get_property_boolean(&self, name: &str) -> Result<bool, Error> {
         BooleanProperty::new(name).value_from(self)
     }
+
+    /// Sets an object's data-type property.
+    ///
+    pub fn set_property_data(&self, name: &str, value: &[u8]) -> Result<(), Error> {
+        DataProperty::new(name).set_value(self, value)
+    }
+
+    /// Gets an object's data-type property.
+    ///
+    pub fn get_property_data(&self, name: &str) -> Result<Vec<u8>, Error> {
+        DataProperty::new(name).value_from(self)
+    }
+
+    /// Sets an object's dictionary-type property.
+    ///
+    pub fn set_property_dictionary(&self, name: &str, value: &BTreeMap<String, PropertyValue>) -> Result<(), Error> {
+        DictionaryProperty::new(name).set_value(self, value.clone())
+    }
+
+    /// Gets an object's dictionary-type property.
+    ///
+    pub fn get_property_dictionary(&self, name: &str) -> Result<BTreeMap<String, PropertyValue>, Error> {
+        DictionaryProperty::new(name).value_from(self)
+    }
+
+    /// Gets all the properties of the object, keyed by their names.
+    ///
+    /// When `deep` is true, the properties of its children are included too: the entities of a device
+    /// are listed under the "entities" key, and the endpoints of an entity under "sources" and "destinations".
+    /// Note that CoreMIDI stores booleans as integers.
+    ///
+    /// See `MIDIObjectGetProperties`.
+    pub fn properties(&self, deep: bool) -> Result<BTreeMap<String, PropertyValue>, Error> {
+        Current::object_get_properties(self.0, deep).map_err(Error::from)
+    }
 }
 
 impl fmt::Debug for Object {
@@ -119,11 +270,25 @@ impl fmt::Debug for Object {
     }
 }
 
+/// The reference of an object is only valid inside the process that got it, so an object is
+/// serialized as its unique id and its name instead, which are `None` once it has been removed.
+///
+#[cfg(feature = "serde")]
+impl Serialize for Object {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Object", 2)?;
+        state.serialize_field("unique_id", &self.unique_id())?;
+        state.serialize_field("name", &self.name())?;
+        state.end()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use object::ObjectType;
+    use object::{AnyObject, ObjectType};
+    use {Client, Destination, Error, Object, PropertyValue, Source};
 
-    use coremidi_sys::{
+    use backend::sys::{
         kMIDIObjectType_Other,
         kMIDIObjectType_Device,
         kMIDIObjectType_Entity,
@@ -148,8 +313,60 @@ mod tests {
         assert_eq!(ObjectType::from(kMIDIObjectType_ExternalDestination), Ok(ObjectType::ExternalDestination));
     }
 
+    #[test]
+    fn objecttype_to_raw() {
+        for raw in &[-1, 0, 1, 2, 3, 16, 17, 18, 19] {
+            assert_eq!(ObjectType::from(*raw).map(ObjectType::to_raw), Ok(*raw));
+        }
+    }
+
     #[test]
     fn objecttype_from_error() {
         assert_eq!(ObjectType::from(0xffff as i32), Err(0xffff));
     }
+
+    #[test]
+    fn properties_of_virtual_destination() {
+        let client = Client::new("Object Test Client").unwrap();
+        let destination = client.virtual_destination("Object Test Destination", |_| ()).unwrap();
+        destination.set_property_data("coremidi-test-data", &[1, 2, 3]).unwrap();
+
+        let properties = destination.properties(false).unwrap();
+
+        assert_eq!(properties.get("name"), Some(&PropertyValue::String("Object Test Destination".to_string())));
+        assert_eq!(properties.get("coremidi-test-data"), Some(&PropertyValue::Data(vec![1, 2, 3])));
+        assert_eq!(properties.get("uniqueID"), destination.unique_id().map(|id| PropertyValue::Integer(i64::from(id as i32))).as_ref());
+    }
+
+    #[test]
+    fn find_endpoints() {
+        let client = Client::new("Object Find Client").unwrap();
+        let source = client.virtual_source("Object Find Source").unwrap();
+        let destination = client.virtual_destination("Object Find Destination", |_| ()).unwrap();
+        let source_id = source.unique_id().unwrap();
+        let destination_id = destination.unique_id().unwrap();
+
+        match Object::from_unique_id(source_id) {
+            Ok(AnyObject::Source(found)) => assert_eq!(found.endpoint, source.endpoint),
+            other => panic!("Unexpected {:?}", other),
+        }
+        let found = Object::from_unique_id(destination_id).unwrap();
+        assert_eq!(found.object_type(), ObjectType::Destination);
+        assert_eq!(found.name(), Some("Object Find Destination".to_string()));
+
+        assert_eq!(Source::find_by_unique_id(source_id).map(|found| found.endpoint.object.0), Some(source.endpoint.object.0));
+        assert_eq!(Source::find_by_unique_id(destination_id), None);
+        assert_eq!(Destination::find_by_unique_id(destination_id).map(|found| found.endpoint.object.0), Some(destination.endpoint.object.0));
+        assert_eq!(Destination::find_by_unique_id(source_id), None);
+
+        assert_eq!(Source::find_by_name("Object Find Source").map(|found| found.endpoint.object.0), Some(source.endpoint.object.0));
+        assert_eq!(Source::find_by_display_name("Object Find Source").map(|found| found.endpoint.object.0), Some(source.endpoint.object.0));
+        assert_eq!(Source::find_by_name("Object Find Destination"), None);
+        assert_eq!(Destination::find_by_name("Object Find Destination").map(|found| found.endpoint.object.0), Some(destination.endpoint.object.0));
+        assert_eq!(Destination::find_by_display_name("Object Find Destination").map(|found| found.endpoint.object.0), Some(destination.endpoint.object.0));
+
+        drop(source);
+        assert_eq!(Object::from_unique_id(source_id), Err(Error::ObjectNotFound));
+        assert_eq!(Source::find_by_unique_id(source_id), None);
+    }
 }
@@ -0,0 +1,782 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use {Clock, Destination, Error, HostClock, HostTime, MessageParser, MidiMessage, OutputPort, PacketBuffer, PacketList};
+use lock;
+use smf::{Smf, TempoMap};
+
+// How long before they play the events are sent by default
+const DEFAULT_LOOKAHEAD: Duration = Duration::from_millis(100);
+
+/// MIDI messages placed in ticks, together with the tempo map to play them.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeline {
+    tempo_map: TempoMap,
+    events: Vec<(u64, MidiMessage)>,
+    len_ticks: u64,
+}
+
+impl Timeline {
+    /// Create a timeline from messages with their position in ticks, which don't need to be sorted.
+    ///
+    pub fn new<I>(tempo_map: TempoMap, events: I) -> Timeline
+        where I: IntoIterator<Item = (u64, MidiMessage)>
+    {
+        let mut events: Vec<_> = events.into_iter().collect();
+        events.sort_by_key(|&(ticks, _)| ticks);
+        let len_ticks = events.last().map_or(0, |&(ticks, _)| ticks);
+        Timeline { tempo_map, events, len_ticks }
+    }
+
+    /// Create a timeline with the messages in all the tracks of a Standard MIDI File, and its tempo map.
+    /// The timeline lasts until the end of the longest track.
+    ///
+    pub fn from_smf(smf: &Smf) -> Timeline {
+        let mut timeline = Timeline::new(smf.tempo_map(), smf.tracks.iter().flat_map(|track| track.messages()));
+        timeline.len_ticks = smf.tracks.iter().map(|track| track.len_ticks()).fold(timeline.len_ticks, cmp::max);
+        timeline
+    }
+
+    /// Get the tempo map.
+    ///
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    /// Get the messages, sorted by their position in ticks.
+    ///
+    pub fn events(&self) -> &[(u64, MidiMessage)] {
+        &self.events
+    }
+
+    /// Get the position in ticks where the timeline ends.
+    ///
+    pub fn len_ticks(&self) -> u64 {
+        self.len_ticks
+    }
+
+    /// Get the messages that set the controllers, programs, channel pressure and pitch bend
+    /// to the values they have at a position, as sent when seeking there.
+    ///
+    /// The data entry and the RPN and NRPN selection controllers are not chased, as their meaning
+    /// depends on the order they are sent in, and neither are the channel mode messages.
+    ///
+    pub fn chase(&self, ticks: u64) -> Vec<MidiMessage> {
+        // Sorted by channel, and with the bank select before the program change
+        let mut state = BTreeMap::new();
+        for &(_, ref message) in self.events.iter().take_while(|&&(event_ticks, _)| event_ticks < ticks) {
+            let key = match *message {
+                MidiMessage::ControlChange { channel, control, .. } => match control {
+                    0 | 32 => (channel, 0, control),
+                    6 | 38 | 96..=101 | 120..=127 => continue,
+                    _ => (channel, 2, control),
+                },
+                MidiMessage::ProgramChange { channel, .. } => (channel, 1, 0),
+                MidiMessage::ChannelPressure { channel, .. } => (channel, 3, 0),
+                MidiMessage::PitchBend { channel, .. } => (channel, 4, 0),
+                _ => continue,
+            };
+            state.insert(key, message.clone());
+        }
+        state.into_iter().map(|(_, message)| message).collect()
+    }
+
+    fn index_at(&self, ticks: u64) -> usize {
+        self.events.iter().position(|&(event_ticks, _)| event_ticks >= ticks).unwrap_or_else(|| self.events.len())
+    }
+}
+
+// A pass over the timeline, playing from a position at a host time, which ends at the end of the loop
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Pass {
+    start_nanos: u64,
+    start_ticks: u64,
+}
+
+/// Schedules the events of a timeline ahead of time, into packets with the host time they have to play at.
+///
+/// It does the timing of a `Player`, without sending anything, and it reads the time from a `Clock`,
+/// so it can be driven with a simulated one.
+///
+/// ```
+/// use std::time::Duration;
+/// use coremidi::{Clock, HostTime, MidiMessage, Sequencer, Timeline};
+/// use coremidi::smf::{Division, TempoMap};
+///
+/// struct FixedClock;
+/// impl Clock for FixedClock {
+///     fn now(&self) -> HostTime { HostTime::from_nanos(1_000_000_000) }
+/// }
+///
+/// let timeline = Timeline::new(TempoMap::new(Division::TicksPerQuarterNote(480)), vec![
+///     (480, MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 }),
+/// ]);
+/// let mut sequencer = Sequencer::with_clock(timeline, FixedClock);
+/// sequencer.set_lookahead(Duration::from_secs(1));
+/// sequencer.play();
+/// let packets = sequencer.schedule();
+/// let packet = packets.iter().next().unwrap();
+/// assert_eq!(HostTime::from_timestamp(packet.timestamp()).as_nanos(), 1_500_000_000);
+/// ```
+#[derive(Debug)]
+pub struct Sequencer<C = HostClock> {
+    timeline: Timeline,
+    clock: C,
+    lookahead: Duration,
+    loop_range: Option<(u64, u64)>,
+    // Where the playback is while paused
+    position: u64,
+    // The next event to schedule
+    next: usize,
+    // The passes that are playing or scheduled, or none while paused
+    passes: Vec<Pass>,
+    // The messages to send when the playback starts, after a seek
+    chase: Vec<MidiMessage>,
+}
+
+impl Sequencer<HostClock> {
+    /// Create a sequencer following the host clock, paused at the start of the timeline.
+    ///
+    pub fn new(timeline: Timeline) -> Sequencer<HostClock> {
+        Sequencer::with_clock(timeline, HostClock)
+    }
+}
+
+impl<C: Clock> Sequencer<C> {
+    /// Create a sequencer following a clock, paused at the start of the timeline.
+    ///
+    pub fn with_clock(timeline: Timeline, clock: C) -> Sequencer<C> {
+        Sequencer {
+            timeline,
+            clock,
+            lookahead: DEFAULT_LOOKAHEAD,
+            loop_range: None,
+            position: 0,
+            next: 0,
+            passes: Vec::new(),
+            chase: Vec::new(),
+        }
+    }
+
+    /// Get the timeline.
+    ///
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    /// Get how long before they play the events are scheduled.
+    ///
+    pub fn lookahead(&self) -> Duration {
+        self.lookahead
+    }
+
+    /// Set how long before they play the events are scheduled, which is 100 milliseconds by default.
+    /// It has to be longer than the time between calls to `schedule`.
+    ///
+    pub fn set_lookahead(&mut self, lookahead: Duration) {
+        self.lookahead = lookahead;
+    }
+
+    /// Play a range of ticks over and over, or stop looping with `None`. Empty ranges are ignored.
+    /// When playing before the end of the loop, but past it, the playback seeks to the start of the loop,
+    /// and the events already scheduled must be flushed from the destinations.
+    ///
+    pub fn set_loop(&mut self, range: Option<Range<u64>>) {
+        self.set_loop_range(range);
+    }
+
+    // Set the loop, and tell whether the playback had to seek to its start
+    fn set_loop_range(&mut self, range: Option<Range<u64>>) -> bool {
+        let range = range.filter(|range| range.start < range.end).map(|range| (range.start, range.end));
+        let now = self.now_nanos();
+        let passed = match (range, self.current_pass(now)) {
+            (Some((_, end)), Some(pass)) => pass.start_ticks < end && self.position() >= end,
+            _ => false,
+        };
+        self.loop_range = range;
+        if let (true, Some((start, _))) = (passed, range) {
+            self.seek(start);
+        }
+        passed
+    }
+
+    /// Get the range of ticks played over and over.
+    ///
+    pub fn loop_range(&self) -> Option<Range<u64>> {
+        self.loop_range.map(|(start, end)| start..end)
+    }
+
+    /// Whether the timeline is playing.
+    ///
+    pub fn is_playing(&self) -> bool {
+        !self.passes.is_empty()
+    }
+
+    /// Get the position in ticks that is playing, or where the playback is paused.
+    ///
+    pub fn position(&self) -> u64 {
+        let now = self.now_nanos();
+        match self.current_pass(now) {
+            Some(pass) => {
+                let ticks = self.pass_ticks(pass, now);
+                self.pass_end(pass).map_or(ticks, |end| cmp::min(ticks, end))
+            },
+            None => self.position,
+        }
+    }
+
+    /// Start playing from the current position.
+    ///
+    pub fn play(&mut self) {
+        if !self.is_playing() {
+            self.passes.push(Pass { start_nanos: self.now_nanos(), start_ticks: self.position });
+        }
+    }
+
+    /// Stop playing, keeping the position to resume from.
+    /// The events already scheduled after the current time must be flushed from the destinations,
+    /// as they are scheduled again when resuming.
+    ///
+    pub fn pause(&mut self) {
+        let now = self.now_nanos();
+        if let Some(pass) = self.current_pass(now) {
+            let end = self.pass_end(pass);
+            self.position = self.position();
+            self.next = (self.timeline.index_at(pass.start_ticks)..self.timeline.events.len())
+                .find(|&index| self.pass_nanos(pass, self.timeline.events[index].0) > now)
+                .unwrap_or_else(|| self.timeline.events.len());
+            if let (Some(end), Some((start, _))) = (end, self.loop_range) {
+                if self.position >= end {
+                    self.position = start;
+                    self.next = self.timeline.index_at(start);
+                }
+            }
+        }
+        self.passes.clear();
+    }
+
+    /// Move to a position in ticks, and chase the controllers, programs, channel pressure and pitch bend
+    /// there, which are sent when the playback starts.
+    /// When playing, the events already scheduled must be flushed from the destinations.
+    ///
+    pub fn seek(&mut self, ticks: u64) {
+        self.chase = self.timeline.chase(ticks);
+        self.position = ticks;
+        self.next = self.timeline.index_at(ticks);
+        if self.is_playing() {
+            self.passes = vec![Pass { start_nanos: self.now_nanos(), start_ticks: ticks }];
+        }
+    }
+
+    /// Get the events to send, which are the ones that play before the current time plus the lookahead
+    /// and were not scheduled yet, with the timestamps they play at. The events that were due before
+    /// the current time, when it is called late, keep their timestamps in the past.
+    /// When the end of the timeline is reached without looping, the playback stops.
+    ///
+    pub fn schedule(&mut self) -> PacketBuffer {
+        let mut buffer = PacketBuffer::with_capacity(256);
+        if !self.is_playing() {
+            return buffer;
+        }
+        let now = self.now_nanos();
+        let horizon = now.saturating_add(duration_to_nanos(self.lookahead));
+
+        for message in self.chase.drain(..) {
+            buffer.push_message(HostTime::from_nanos(now).timestamp(), &message);
+        }
+        while self.passes.len() > 1 && self.passes[1].start_nanos <= now {
+            self.passes.remove(0);
+        }
+
+        loop {
+            let pass = self.passes[self.passes.len() - 1];
+            let end = self.pass_end(pass);
+            while self.next < self.timeline.events.len() {
+                let (ticks, ref message) = self.timeline.events[self.next];
+                if end.map_or(false, |end| ticks >= end) {
+                    break;
+                }
+                let nanos = self.pass_nanos(pass, ticks);
+                if nanos >= horizon {
+                    return buffer;
+                }
+                buffer.push_message(HostTime::from_nanos(nanos).timestamp(), message);
+                self.next += 1;
+            }
+            match (end, self.loop_range) {
+                (Some(end), Some((start, _))) => {
+                    let end_nanos = self.pass_nanos(pass, end);
+                    if end_nanos >= horizon || end_nanos <= pass.start_nanos {
+                        break;
+                    }
+                    self.passes.push(Pass { start_nanos: end_nanos, start_ticks: start });
+                    self.next = self.timeline.index_at(start);
+                },
+                _ => {
+                    let len_ticks = cmp::max(self.timeline.len_ticks, pass.start_ticks);
+                    if self.pass_nanos(pass, len_ticks) <= now {
+                        self.position = len_ticks;
+                        self.passes.clear();
+                    }
+                    break;
+                },
+            }
+        }
+        buffer
+    }
+
+    fn now_nanos(&self) -> u64 {
+        self.clock.now().as_nanos()
+    }
+
+    // The last pass that started before a time, or the first one when none did
+    fn current_pass(&self, nanos: u64) -> Option<Pass> {
+        self.passes.iter().rev().find(|pass| pass.start_nanos <= nanos).or_else(|| self.passes.first()).cloned()
+    }
+
+    fn pass_end(&self, pass: Pass) -> Option<u64> {
+        self.loop_range.map(|(_, end)| end).filter(|&end| pass.start_ticks < end)
+    }
+
+    fn pass_nanos(&self, pass: Pass, ticks: u64) -> u64 {
+        let tempo_map = &self.timeline.tempo_map;
+        pass.start_nanos + tempo_map.ticks_to_nanos(ticks) - tempo_map.ticks_to_nanos(pass.start_ticks)
+    }
+
+    fn pass_ticks(&self, pass: Pass, nanos: u64) -> u64 {
+        let tempo_map = &self.timeline.tempo_map;
+        tempo_map.nanos_to_ticks(tempo_map.ticks_to_nanos(pass.start_ticks) + nanos.saturating_sub(pass.start_nanos))
+    }
+}
+
+fn duration_to_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+#[derive(Debug)]
+struct PlayerState<C> {
+    sequencer: Sequencer<C>,
+    output_port: OutputPort,
+    destinations: Vec<Destination>,
+    // The notes sent to each destination, by its index, channel and note,
+    // with the time they are released at once their Note Off is sent
+    notes: HashMap<(usize, u8, u8), Option<u64>>,
+}
+
+impl<C: Clock> PlayerState<C> {
+    fn update(&mut self) -> Result<(), Error> {
+        let packets = self.sequencer.schedule();
+        let now = self.sequencer.now_nanos();
+        self.notes.retain(|_, released| released.map_or(true, |released| released > now));
+        if !packets.is_empty() {
+            for (index, destination) in self.destinations.iter().enumerate() {
+                self.output_port.send(destination, &packets)?;
+                Self::track_notes(&mut self.notes, index, &packets);
+            }
+        }
+        Ok(())
+    }
+
+    fn track_notes(notes: &mut HashMap<(usize, u8, u8), Option<u64>>, index: usize, packets: &PacketList) {
+        let mut parser = MessageParser::new();
+        for packet in packets.iter() {
+            for (timestamp, message) in parser.parse(packet) {
+                match message {
+                    Ok(MidiMessage::NoteOn { channel, note, velocity }) if velocity > 0 => {
+                        notes.insert((index, channel, note), None);
+                    },
+                    Ok(MidiMessage::NoteOn { channel, note, .. }) | Ok(MidiMessage::NoteOff { channel, note, .. }) => {
+                        if let Some(released) = notes.get_mut(&(index, channel, note)) {
+                            *released = Some(HostTime::from_timestamp(timestamp).as_nanos());
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    // Flushing drops the Note Offs already scheduled, so the notes that are still sounding are released right away
+    fn flush(&mut self) -> Result<(), Error> {
+        let now = self.sequencer.now_nanos();
+        for (index, destination) in self.destinations.iter().enumerate() {
+            destination.flush()?;
+            let mut note_offs = PacketBuffer::with_capacity(64);
+            for (&(_, channel, note), _) in self.notes.iter()
+                .filter(|&(&(notes_index, _, _), released)| notes_index == index && released.map_or(true, |released| released > now))
+            {
+                note_offs.push_message(0, &MidiMessage::NoteOff { channel, note, velocity: 0 });
+            }
+            self.notes.retain(|&(notes_index, _, _), _| notes_index != index);
+            if !note_offs.is_empty() {
+                self.output_port.send(destination, &note_offs)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Plays a timeline into some destinations.
+///
+/// Instead of sending every event when it is due, a worker thread sends them a bit ahead of time,
+/// with the timestamps they have to play at, so CoreMIDI can deliver them on time.
+/// The scheduling is done by a `Sequencer`, which sets how long ahead the events are sent,
+/// and the clock followed.
+///
+/// Pausing, or seeking while playing, flushes the events already scheduled in the destinations.
+/// So does setting a loop that ends before the position that is playing. The notes left sounding
+/// by the flushed Note Offs are then released with a Note Off sent right away.
+///
+/// ```rust,no_run
+/// use std::fs::File;
+/// use coremidi::{Client, Destination};
+/// use coremidi::{Player, Sequencer, Timeline};
+/// use coremidi::smf::Smf;
+///
+/// let smf = Smf::read(&mut File::open("song.mid").unwrap()).unwrap();
+/// let client = Client::new("example-client").unwrap();
+/// let output_port = client.output_port("example-port").unwrap();
+/// let destination = Destination::from_index(0).unwrap();
+/// let player = Player::new(Sequencer::new(Timeline::from_smf(&smf)), output_port, vec![destination]);
+/// player.play().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Player<C: Clock + Send + 'static = HostClock> {
+    state: Arc<Mutex<PlayerState<C>>>,
+    stop: Option<Sender<()>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<C: Clock + Send + 'static> Player<C> {
+    /// Create a player sending to some destinations through an output port, paused where the sequencer is,
+    /// and start its worker thread.
+    ///
+    pub fn new(sequencer: Sequencer<C>, output_port: OutputPort, destinations: Vec<Destination>) -> Player<C> {
+        // Wake up often enough to always have the next events scheduled
+        let interval = cmp::max(sequencer.lookahead() / 4, Duration::from_millis(1));
+        let state = Arc::new(Mutex::new(PlayerState { sequencer, output_port, destinations, notes: HashMap::new() }));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let worker_state = state.clone();
+        let worker = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // There is nobody to report the errors to, the next update tries again
+                let _ = lock(&worker_state).update();
+            }
+        });
+        Player { state, stop: Some(stop), worker: Some(worker) }
+    }
+
+    /// Start playing from the current position.
+    ///
+    pub fn play(&self) -> Result<(), Error> {
+        let mut state = lock(&self.state);
+        state.sequencer.play();
+        state.update()
+    }
+
+    /// Stop playing, and flush the events already scheduled in the destinations.
+    ///
+    pub fn pause(&self) -> Result<(), Error> {
+        let mut state = lock(&self.state);
+        state.sequencer.pause();
+        state.flush()
+    }
+
+    /// Move to a position in ticks, chasing the controllers and programs there.
+    ///
+    pub fn seek(&self, ticks: u64) -> Result<(), Error> {
+        let mut state = lock(&self.state);
+        if state.sequencer.is_playing() {
+            state.flush()?;
+        }
+        state.sequencer.seek(ticks);
+        state.update()
+    }
+
+    /// Play a range of ticks over and over, or stop looping with `None`.
+    /// When playing past the end of the new loop, it seeks to its start and flushes the events
+    /// already scheduled in the destinations.
+    ///
+    pub fn set_loop(&self, range: Option<Range<u64>>) -> Result<(), Error> {
+        let mut state = lock(&self.state);
+        if state.sequencer.set_loop_range(range) {
+            state.flush()?;
+        }
+        state.update()
+    }
+
+    /// Whether the timeline is playing.
+    ///
+    pub fn is_playing(&self) -> bool {
+        lock(&self.state).sequencer.is_playing()
+    }
+
+    /// Get the position in ticks that is playing, or where the playback is paused.
+    ///
+    pub fn position(&self) -> u64 {
+        lock(&self.state).sequencer.position()
+    }
+}
+
+impl<C: Clock + Send + 'static> Drop for Player<C> {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    use {Clock, HostTime, MidiMessage, PacketList};
+    use smf::{Division, TempoMap};
+    use super::{Sequencer, Timeline};
+
+    #[derive(Clone, Debug, Default)]
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl FakeClock {
+        fn set(&self, millis: u64) {
+            self.0.store(millis * 1_000_000, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> HostTime {
+            HostTime::from_nanos(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    fn timeline() -> Timeline {
+        Timeline::new(TempoMap::new(Division::TicksPerQuarterNote(480)), vec![
+            (1440, MidiMessage::NoteOn { channel: 0, note: 64, velocity: 100 }),
+            (0, MidiMessage::ControlChange { channel: 0, control: 0, value: 1 }),
+            (0, MidiMessage::ProgramChange { channel: 0, program: 5 }),
+            (0, MidiMessage::ControlChange { channel: 0, control: 7, value: 100 }),
+            (240, MidiMessage::PitchBend { channel: 0, value: 0x2000 }),
+            (480, MidiMessage::ControlChange { channel: 0, control: 7, value: 50 }),
+            (480, MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 }),
+            (960, MidiMessage::NoteOn { channel: 0, note: 62, velocity: 100 }),
+        ])
+    }
+
+    fn sequencer(clock: &FakeClock) -> Sequencer<FakeClock> {
+        let mut sequencer = Sequencer::with_clock(timeline(), clock.clone());
+        sequencer.set_lookahead(Duration::from_millis(600));
+        sequencer
+    }
+
+    // The packets as the milliseconds they play at and their data
+    fn sent(packet_list: &PacketList) -> Vec<(u64, Vec<u8>)> {
+        packet_list.iter()
+            .map(|packet| (HostTime::from_timestamp(packet.timestamp()).as_nanos() / 1_000_000, packet.data().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn schedules_ahead_with_future_timestamps() {
+        let clock = FakeClock::default();
+        let mut sequencer = sequencer(&clock);
+        clock.set(1000);
+        sequencer.play();
+        assert_eq!(sent(&sequencer.schedule()), vec![
+            (1000, vec![0xB0, 0, 1, 0xC0, 5, 0xB0, 7, 100]),
+            (1250, vec![0xE0, 0x00, 0x40]),
+            (1500, vec![0xB0, 7, 50, 0x90, 60, 100]),
+        ]);
+        assert_eq!(sent(&sequencer.schedule()), vec![]);
+
+        clock.set(1500);
+        assert_eq!(sequencer.position(), 480);
+        assert_eq!(sent(&sequencer.schedule()), vec![(2000, vec![0x90, 62, 100])]);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let clock = FakeClock::default();
+        let mut sequencer = sequencer(&clock);
+        clock.set(1000);
+        sequencer.play();
+        sequencer.schedule();
+        clock.set(1500);
+        sequencer.schedule();
+
+        clock.set(1600);
+        sequencer.pause();
+        assert!(!sequencer.is_playing());
+        assert_eq!(sequencer.position(), 576);
+        assert_eq!(sent(&sequencer.schedule()), vec![]);
+
+        clock.set(5000);
+        assert_eq!(sequencer.position(), 576);
+        sequencer.play();
+        assert_eq!(sent(&sequencer.schedule()), vec![(5400, vec![0x90, 62, 100])]);
+    }
+
+    #[test]
+    fn seek_chases_controllers_and_programs() {
+        let clock = FakeClock::default();
+        let mut sequencer = sequencer(&clock);
+        clock.set(1000);
+        sequencer.seek(720);
+        sequencer.play();
+        assert_eq!(sent(&sequencer.schedule()), vec![
+            (1000, vec![0xB0, 0, 1, 0xC0, 5, 0xB0, 7, 50, 0xE0, 0x00, 0x40]),
+            (1250, vec![0x90, 62, 100]),
+        ]);
+
+        clock.set(1100);
+        sequencer.seek(0);
+        assert_eq!(sent(&sequencer.schedule()), vec![
+            (1100, vec![0xB0, 0, 1, 0xC0, 5, 0xB0, 7, 100]),
+            (1350, vec![0xE0, 0x00, 0x40]),
+            (1600, vec![0xB0, 7, 50, 0x90, 60, 100]),
+        ]);
+    }
+
+    #[test]
+    fn loops_over_a_range() {
+        let clock = FakeClock::default();
+        let mut sequencer = sequencer(&clock);
+        sequencer.set_loop(Some(0..960));
+        clock.set(10_000);
+        sequencer.play();
+        assert_eq!(sent(&sequencer.schedule()).len(), 3);
+
+        clock.set(10_900);
+        assert_eq!(sequencer.position(), 864);
+        assert_eq!(sent(&sequencer.schedule()), vec![
+            (11_000, vec![0xB0, 0, 1, 0xC0, 5, 0xB0, 7, 100]),
+            (11_250, vec![0xE0, 0x00, 0x40]),
+        ]);
+        assert_eq!(sequencer.position(), 864);
+
+        clock.set(11_200);
+        assert_eq!(sequencer.position(), 192);
+        sequencer.pause();
+        clock.set(20_000);
+        sequencer.play();
+        assert_eq!(sent(&sequencer.schedule()), vec![
+            (20_050, vec![0xE0, 0x00, 0x40]),
+            (20_300, vec![0xB0, 7, 50, 0x90, 60, 100]),
+        ]);
+    }
+
+    #[test]
+    fn loop_ending_before_the_position_seeks_to_its_start() {
+        let clock = FakeClock::default();
+        let mut sequencer = sequencer(&clock);
+        clock.set(1000);
+        sequencer.play();
+        sequencer.schedule();
+
+        clock.set(1800);
+        sequencer.set_loop(Some(240..480));
+        assert_eq!(sequencer.position(), 240);
+        assert_eq!(sent(&sequencer.schedule()), vec![
+            (1800, vec![0xB0, 0, 1, 0xC0, 5, 0xB0, 7, 100, 0xE0, 0x00, 0x40]),
+            (2050, vec![0xE0, 0x00, 0x40]),
+            (2300, vec![0xE0, 0x00, 0x40]),
+        ]);
+    }
+
+    #[test]
+    fn stops_at_the_end() {
+        let clock = FakeClock::default();
+        let mut sequencer = sequencer(&clock);
+        clock.set(1000);
+        sequencer.play();
+        sequencer.schedule();
+
+        clock.set(3000);
+        assert_eq!(sent(&sequencer.schedule()), vec![(2000, vec![0x90, 62, 100]), (2500, vec![0x90, 64, 100])]);
+        assert!(!sequencer.is_playing());
+        assert_eq!(sequencer.position(), 1440);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn player_sends_to_destinations() {
+        use std::sync::mpsc;
+        use {Client, Destination};
+        use super::Player;
+
+        let client = Client::new("player-client").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let _virtual_destination = client.virtual_destination("player-destination", move |packet_list| {
+            let _ = sender.send(sent(packet_list));
+        }).unwrap();
+
+        let clock = FakeClock::default();
+        clock.set(1000);
+        let mut sequencer = sequencer(&clock);
+        sequencer.set_lookahead(Duration::from_millis(300));
+        let destination = Destination::find_by_name("player-destination").unwrap();
+        let player = Player::new(sequencer, client.output_port("player-port").unwrap(), vec![destination]);
+
+        player.play().unwrap();
+        assert!(player.is_playing());
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)).unwrap(), vec![
+            (1000, vec![0xB0, 0, 1, 0xC0, 5, 0xB0, 7, 100]),
+            (1250, vec![0xE0, 0x00, 0x40]),
+        ]);
+
+        clock.set(1100);
+        player.pause().unwrap();
+        assert!(!player.is_playing());
+        assert_eq!(player.position(), 96);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn player_releases_the_notes_sounding_when_pausing() {
+        use std::sync::mpsc;
+        use {Client, Destination};
+        use super::Player;
+
+        let client = Client::new("player-client").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let _virtual_destination = client.virtual_destination("player-notes-destination", move |packet_list| {
+            let _ = sender.send(sent(packet_list));
+        }).unwrap();
+
+        let clock = FakeClock::default();
+        clock.set(1000);
+        let timeline = Timeline::new(TempoMap::new(Division::TicksPerQuarterNote(480)), vec![
+            (0, MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }),
+            (480, MidiMessage::NoteOff { channel: 1, note: 60, velocity: 64 }),
+        ]);
+        let mut sequencer = Sequencer::with_clock(timeline, clock.clone());
+        sequencer.set_lookahead(Duration::from_millis(600));
+        let destination = Destination::find_by_name("player-notes-destination").unwrap();
+        let player = Player::new(sequencer, client.output_port("player-port").unwrap(), vec![destination]);
+
+        player.play().unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)).unwrap(), vec![
+            (1000, vec![0x91, 60, 100]),
+            (1500, vec![0x81, 60, 64]),
+        ]);
+
+        clock.set(1200);
+        player.pause().unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)).unwrap(), vec![(0, vec![0x81, 60, 0])]);
+
+        clock.set(2000);
+        player.play().unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)).unwrap(), vec![(2300, vec![0x81, 60, 64])]);
+        clock.set(2400);
+        player.pause().unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -1,19 +1,19 @@
-use core_foundation::base::OSStatus;
-
-use coremidi_sys::{
-    MIDIPortConnectSource, MIDIPortDisconnectSource, MIDIPortDispose, MIDISend
-};
-
+use std::os::raw::c_void;
 use std::ptr;
 use std::ops::Deref;
 
+use Error;
 use Object;
 use Port;
 use OutputPort;
 use InputPort;
+use InputPortWithContext;
 use Destination;
 use Source;
 use PacketList;
+use EventList;
+use backend::{Backend, Current};
+use lock;
 
 impl Deref for Port {
     type Target = Object;
@@ -25,7 +25,7 @@ impl Deref for Port {
 
 impl Drop for Port {
     fn drop(&mut self) {
-        unsafe { MIDIPortDispose(self.object.0) };
+        let _ = Current::port_dispose(self.object.0);
     }
 }
 
@@ -33,13 +33,22 @@ impl OutputPort {
     /// Send a list of packets to a destination.
     /// See [MIDISend](https://developer.apple.com/reference/coremidi/1495289-midisend).
     ///
-    pub fn send(&self, destination: &Destination, packet_list: &PacketList) -> Result<(), OSStatus> {
-        let status = unsafe { MIDISend(
+    pub fn send(&self, destination: &Destination, packet_list: &PacketList) -> Result<(), Error> {
+        Current::send(
             self.port.object.0,
             destination.endpoint.object.0,
-            packet_list.as_ptr())
-        };
-        if status == 0 { Ok(()) } else { Err(status) }
+            packet_list).map_err(Error::from)
+    }
+
+    /// Send a list of Universal MIDI Packets to a destination.
+    /// It fails with `Error::UnknownError` before macOS 11, where it is not available.
+    /// See `MIDISendEventList`.
+    ///
+    pub fn send_event_list(&self, destination: &Destination, event_list: &EventList) -> Result<(), Error> {
+        Current::send_event_list(
+            self.port.object.0,
+            destination.endpoint.object.0,
+            event_list).map_err(Error::from)
     }
 }
 
@@ -53,21 +62,17 @@ impl Deref for OutputPort {
 
 impl InputPort {
 
-    pub fn connect_source(&self, source: &Source) -> Result<(), OSStatus> {
-        let status = unsafe { MIDIPortConnectSource(
+    pub fn connect_source(&self, source: &Source) -> Result<(), Error> {
+        Current::port_connect_source(
             self.object.0,
             source.object.0,
-            ptr::null_mut())
-        };
-        if status == 0 { Ok(()) } else { Err(status) }
+            ptr::null_mut()).map_err(Error::from)
     }
 
-    pub fn disconnect_source(&self, source: &Source) -> Result<(), OSStatus> {
-        let status = unsafe { MIDIPortDisconnectSource(
+    pub fn disconnect_source(&self, source: &Source) -> Result<(), Error> {
+        Current::port_disconnect_source(
             self.object.0,
-            source.object.0)
-        };
-        if status == 0 { Ok(()) } else { Err(status) }
+            source.object.0).map_err(Error::from)
     }
 }
 
@@ -78,3 +83,272 @@ impl Deref for InputPort {
         &self.port
     }
 }
+
+impl<T> InputPortWithContext<T> {
+    /// Connect a source to the port, with the context handed to the callback for the packets it sends.
+    /// Connecting a source again replaces its context, dropping the previous one.
+    /// See [MIDIPortConnectSource](https://developer.apple.com/reference/coremidi/1495347-midiportconnectsource).
+    ///
+    pub fn connect_source(&mut self, source: &Source, context: T) -> Result<(), Error> {
+        Current::port_connect_source(
+            self.object.0,
+            source.object.0,
+            source.object.0 as usize as *mut c_void).map_err(Error::from)?;
+        lock(&self.contexts).insert(source.object.0, context);
+        Ok(())
+    }
+
+    /// Disconnect a source from the port, dropping its context.
+    /// See [MIDIPortDisconnectSource](https://developer.apple.com/reference/coremidi/1495303-midiportdisconnectsource).
+    ///
+    pub fn disconnect_source(&mut self, source: &Source) -> Result<(), Error> {
+        Current::port_disconnect_source(
+            self.object.0,
+            source.object.0).map_err(Error::from)?;
+        lock(&self.contexts).remove(&source.object.0);
+        Ok(())
+    }
+
+    /// Check whether a source is connected to the port.
+    ///
+    pub fn is_connected(&self, source: &Source) -> bool {
+        lock(&self.contexts).contains_key(&source.object.0)
+    }
+}
+
+impl<T> Deref for InputPortWithContext<T> {
+    type Target = Port;
+
+    fn deref(&self) -> &Port {
+        &self.port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use {
+        Client,
+        Destinations,
+        EventBuffer,
+        MidiMessage,
+        OverflowPolicy,
+        PacketBuffer,
+        PacketList,
+        Protocol,
+        ReceivedPacket,
+        RecvError,
+        Sources,
+        UmpMessage,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn output_port_send_to_virtual_destination() {
+        let client = Client::new("Ports Test Client").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let virtual_destination = client.virtual_destination("Ports Test Destination", move |packet_list| {
+            for packet in packet_list.iter() {
+                tx.send(packet.data().to_vec()).unwrap();
+            }
+        }).unwrap();
+        let output_port = client.output_port("Ports Test Output").unwrap();
+
+        let destination = Destinations.into_iter()
+            .find(|destination| destination.unique_id() == virtual_destination.unique_id())
+            .unwrap();
+        output_port.send(&destination, &PacketBuffer::new(0, &[0x90, 0x40, 0x7f])).unwrap();
+
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), vec![0x90, 0x40, 0x7f]);
+    }
+
+    #[test]
+    fn input_port_receives_from_connected_source() {
+        let client = Client::new("Ports Test Client").unwrap();
+        let virtual_source = client.virtual_source("Ports Test Source").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let input_port = client.input_port("Ports Test Input", move |packet_list| {
+            for packet in packet_list.iter() {
+                tx.send(packet.data().to_vec()).unwrap();
+            }
+        }).unwrap();
+
+        let source = Sources.into_iter()
+            .find(|source| source.unique_id() == virtual_source.unique_id())
+            .unwrap();
+        input_port.connect_source(&source).unwrap();
+        virtual_source.received(&PacketBuffer::new(0, &[0x80, 0x40, 0x00])).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), vec![0x80, 0x40, 0x00]);
+
+        input_port.disconnect_source(&source).unwrap();
+        virtual_source.received(&PacketBuffer::new(0, &[0x90, 0x40, 0x7f])).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn input_port_with_context_tells_sources_apart() {
+        let client = Client::new("Ports Test Client").unwrap();
+        let first_source = client.virtual_source("Ports Test First Source").unwrap();
+        let second_source = client.virtual_source("Ports Test Second Source").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let mut input_port = client.input_port_with_context("Ports Test Input", move |packet_list, context: &mut (&'static str, usize)| {
+            context.1 += 1;
+            for packet in packet_list.iter() {
+                tx.send((context.0, context.1, packet.data().to_vec())).unwrap();
+            }
+        }).unwrap();
+
+        let find_source = |unique_id| Sources.into_iter()
+            .find(|source| source.unique_id() == unique_id)
+            .unwrap();
+        let first = find_source(first_source.unique_id());
+        let second = find_source(second_source.unique_id());
+        input_port.connect_source(&first, ("first", 0)).unwrap();
+        input_port.connect_source(&second, ("second", 0)).unwrap();
+
+        first_source.received(&PacketBuffer::new(0, &[0x90, 0x40, 0x7f])).unwrap();
+        second_source.received(&PacketBuffer::new(0, &[0x91, 0x40, 0x7f])).unwrap();
+        first_source.received(&PacketBuffer::new(0, &[0x80, 0x40, 0x00])).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), ("first", 1, vec![0x90, 0x40, 0x7f]));
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), ("second", 1, vec![0x91, 0x40, 0x7f]));
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), ("first", 2, vec![0x80, 0x40, 0x00]));
+        assert!(input_port.is_connected(&first));
+
+        input_port.disconnect_source(&first).unwrap();
+        assert!(!input_port.is_connected(&first));
+        first_source.received(&PacketBuffer::new(0, &[0x90, 0x41, 0x7f])).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        input_port.connect_source(&second, ("replaced", 0)).unwrap();
+        second_source.received(&PacketBuffer::new(0, &[0x81, 0x40, 0x00])).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), ("replaced", 1, vec![0x81, 0x40, 0x00]));
+    }
+
+    #[test]
+    fn input_port_with_context_drops_replaced_contexts() {
+        struct Counted(Arc<AtomicUsize>);
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let client = Client::new("Ports Test Client").unwrap();
+        let virtual_source = client.virtual_source("Ports Test Reconnected Source").unwrap();
+        let source = Sources.into_iter()
+            .find(|source| source.unique_id() == virtual_source.unique_id())
+            .unwrap();
+        let mut input_port = client.input_port_with_context("Ports Test Input", |_, _: &mut Counted| ()).unwrap();
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        for _ in 0..1000 {
+            input_port.connect_source(&source, Counted(dropped.clone())).unwrap();
+        }
+        assert_eq!(dropped.load(Ordering::SeqCst), 999);
+        assert_eq!(Arc::strong_count(&dropped), 2);
+
+        input_port.disconnect_source(&source).unwrap();
+        assert_eq!(Arc::strong_count(&dropped), 1);
+    }
+
+    #[test]
+    fn output_port_send_event_list_to_virtual_destination() {
+        let client = Client::new("Ports Test Client").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let virtual_destination = client.virtual_destination("Ports Test Destination", move |packet_list| {
+            for packet in packet_list.iter() {
+                tx.send((packet.timestamp(), packet.data().to_vec())).unwrap();
+            }
+        }).unwrap();
+        let output_port = client.output_port("Ports Test Output").unwrap();
+
+        let destination = Destinations.into_iter()
+            .find(|destination| destination.unique_id() == virtual_destination.unique_id())
+            .unwrap();
+        let mut event_list = EventBuffer::new(Protocol::Midi10);
+        event_list.push_message(5, &UmpMessage::Midi1ChannelVoice {
+            group: 0,
+            message: MidiMessage::NoteOn { channel: 0, note: 0x40, velocity: 0x7f },
+        });
+        for message in UmpMessage::sysex7(0, &[1, 2, 3, 4, 5, 6, 7]) {
+            event_list.push_message(6, &message);
+        }
+        output_port.send_event_list(&destination, &event_list).unwrap();
+
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), (5, vec![0x90, 0x40, 0x7f]));
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), (6, vec![0xf0, 1, 2, 3, 4, 5, 6, 7, 0xf7]));
+    }
+
+    #[test]
+    fn input_port_with_protocol_receives_event_lists() {
+        let client = Client::new("Ports Test Client").unwrap();
+        let virtual_source = client.virtual_source("Ports Test Source").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let input_port = client.input_port_with_protocol("Ports Test Input", Protocol::Midi10, move |event_list| {
+            for packet in event_list.iter() {
+                tx.send((event_list.protocol(), packet.timestamp(), packet.data().to_vec())).unwrap();
+            }
+        }).unwrap();
+
+        let source = Sources.into_iter()
+            .find(|source| source.unique_id() == virtual_source.unique_id())
+            .unwrap();
+        input_port.connect_source(&source).unwrap();
+        // running status, and a SysEx that needs two UMPs
+        let mut packets = PacketBuffer::new(7, &[0x90, 0x40, 0x7f, 0x41, 0x7f]);
+        packets.push_data(8, &[0xf0, 1, 2, 3, 4, 5, 6, 7, 0xf7]);
+        virtual_source.received(&packets).unwrap();
+
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), (Protocol::Midi10, 7, vec![0x2090_407f, 0x2090_417f]));
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), (Protocol::Midi10, 8, vec![0x3016_0102, 0x0304_0506, 0x3031_0700, 0x0000_0000]));
+    }
+
+    #[test]
+    fn input_port_channel_receives_from_connected_source() {
+        let client = Client::new("Ports Test Client").unwrap();
+        let virtual_source = client.virtual_source("Ports Test Source").unwrap();
+        let (input_port, receiver) = client.input_port_channel("Ports Test Input", 16, OverflowPolicy::DropNewest).unwrap();
+
+        let source = Sources.into_iter()
+            .find(|source| source.unique_id() == virtual_source.unique_id())
+            .unwrap();
+        input_port.connect_source(&source).unwrap();
+        virtual_source.received(&PacketBuffer::new(42, &[0x90, 0x40, 0x7f])).unwrap();
+        assert_eq!(receiver.recv_timeout(TIMEOUT), Ok(ReceivedPacket { timestamp: 42, data: vec![0x90, 0x40, 0x7f] }));
+
+        drop(input_port);
+        assert_eq!(receiver.recv_timeout(TIMEOUT), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn forward_received_packet_list_to_output_port() {
+        let client = Client::new("Ports Test Client").unwrap();
+        let (forward_tx, forward_rx) = mpsc::channel();
+        let forwarder = client.virtual_destination("Ports Test Forwarder", move |packet_list| {
+            forward_tx.send(packet_list.to_owned()).unwrap();
+        }).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let receiver = client.virtual_destination("Ports Test Receiver", move |packet_list| {
+            tx.send(PacketBuffer::from(packet_list)).unwrap();
+        }).unwrap();
+        let output_port = client.output_port("Ports Test Output").unwrap();
+
+        let find_destination = |unique_id| Destinations.into_iter()
+            .find(|destination| destination.unique_id() == unique_id)
+            .unwrap();
+        let mut packets = PacketBuffer::new(1, &[0x90, 0x40, 0x7f]);
+        packets.push_data(2, &[0x80, 0x40, 0x00]);
+        output_port.send(&find_destination(forwarder.unique_id()), &packets).unwrap();
+
+        let forwarded = forward_rx.recv_timeout(TIMEOUT).unwrap();
+        output_port.send(&find_destination(receiver.unique_id()), &forwarded).unwrap();
+
+        let received = rx.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(format!("{}", &received as &PacketList), format!("{}", &packets as &PacketList));
+    }
+}
@@ -1,144 +1,176 @@
-use core_foundation::{
-    string::{
-        CFString, 
-        CFStringRef,
-    },
-    base::{
-        CFGetRetainCount,
-        CFTypeRef,
-        CFIndex, 
-        OSStatus,
-        TCFType,
-    }
-};
-
-use coremidi_sys::*;
+use std::collections::BTreeMap;
 
-use std::mem::MaybeUninit;
-
-use {
-    Object,
-    result_from_status,
-    unit_result_from_status,
+use backend::{
+    Backend,
+    Current,
+    PropertyKeyStorage,
 };
+use backend::sys::*;
+
+use Error;
+use Object;
+use object::ObjectType;
 
 pub trait PropertyGetter<T> {
-    fn value_from(&self, object: &Object) -> Result<T, OSStatus>;
+    fn value_from(&self, object: &Object) -> Result<T, Error>;
 }
 
 pub trait PropertySetter<T> {
-    fn set_value(&self, object: &Object, value: T) -> Result<(), OSStatus>;
+    fn set_value(&self, object: &Object, value: T) -> Result<(), Error>;
 }
 
-/// Because Property structs can be constructed from strings that have been
-/// passed in from the user or are constants CFStringRefs from CoreMidi, we 
-/// need to abstract over how we store their keys.
-enum PropertyKeyStorage {
-    Owned(CFString),
-    Constant(CFStringRef)
+/// The value of a property, or of an item of a dictionary property.
+///
+/// It mirrors the property list types that CoreMIDI stores: numbers are kept as integers
+/// unless they were stored as floating point numbers.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PropertyValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Data(Vec<u8>),
+    Array(Vec<PropertyValue>),
+    Dictionary(BTreeMap<String, PropertyValue>),
 }
 
-impl PropertyKeyStorage {
-    /// Return a raw CFStringRef pointing to this property key
-    fn as_string_ref(&self) -> CFStringRef {
-        match self {
-            PropertyKeyStorage::Owned(owned) => owned.as_concrete_TypeRef(),
-            PropertyKeyStorage::Constant(constant) => *constant,
-        }
-    }
-
-    /// For checking the retain count when debugging
-    #[allow(dead_code)]
-    fn retain_count(&self) -> CFIndex {
-        match self {
-            PropertyKeyStorage::Owned(owned) => owned.retain_count(),
-            PropertyKeyStorage::Constant(constant) => unsafe { CFGetRetainCount(*constant as CFTypeRef) },
-        }
-    }
-}
+const ALL_OBJECTS: &[ObjectType] = &[
+    ObjectType::Device,
+    ObjectType::Entity,
+    ObjectType::Source,
+    ObjectType::Destination,
+    ObjectType::ExternalDevice,
+    ObjectType::ExternalEntity,
+    ObjectType::ExternalSource,
+    ObjectType::ExternalDestination,
+];
+
+const DEVICES: &[ObjectType] = &[
+    ObjectType::Device,
+    ObjectType::ExternalDevice,
+];
+
+const DEVICES_AND_ENTITIES: &[ObjectType] = &[
+    ObjectType::Device,
+    ObjectType::Entity,
+    ObjectType::ExternalDevice,
+    ObjectType::ExternalEntity,
+];
+
+const DEVICES_AND_ENDPOINTS: &[ObjectType] = &[
+    ObjectType::Device,
+    ObjectType::Source,
+    ObjectType::Destination,
+    ObjectType::ExternalDevice,
+    ObjectType::ExternalSource,
+    ObjectType::ExternalDestination,
+];
+
+const ENTITIES_AND_ENDPOINTS: &[ObjectType] = &[
+    ObjectType::Entity,
+    ObjectType::Source,
+    ObjectType::Destination,
+    ObjectType::ExternalEntity,
+    ObjectType::ExternalSource,
+    ObjectType::ExternalDestination,
+];
+
+const ENDPOINTS: &[ObjectType] = &[
+    ObjectType::Source,
+    ObjectType::Destination,
+    ObjectType::ExternalSource,
+    ObjectType::ExternalDestination,
+];
 
 /// A MIDI object property which value is an String
 ///
-pub struct StringProperty(PropertyKeyStorage);
+pub struct StringProperty(PropertyKeyStorage, &'static [ObjectType]);
 
 impl StringProperty {
     pub fn new(name: &str) -> Self {
-        StringProperty(PropertyKeyStorage::Owned(CFString::new(name)))
+        StringProperty(PropertyKeyStorage::new(name), ALL_OBJECTS)
     }
 
     /// Note: Should only be used internally with predefined CoreMidi constants,
     /// since it does not bump the retain count of the CFStringRef.
-    fn from_constant_string_ref(string_ref: CFStringRef) -> Self {
-        StringProperty(PropertyKeyStorage::Constant(string_ref))
+    fn from_constant_string_ref(string_ref: CFStringRef, applies_to: &'static [ObjectType]) -> Self {
+        StringProperty(PropertyKeyStorage::constant(string_ref), applies_to)
+    }
+
+    /// The types of the objects this property is defined for.
+    /// Properties created with `new` might be set on any object.
+    ///
+    /// Objects also inherit the properties of the entity and the device they belong to.
+    pub fn applies_to(&self) -> &'static [ObjectType] {
+        self.1
+    }
+
+    /// The name of the property, as found in the `PropertyChanged` notifications.
+    ///
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn property_value_from(&self, object: &Object) -> Result<PropertyValue, Error> {
+        self.value_from(object).map(PropertyValue::String)
     }
 }
 
 impl<T> PropertyGetter<T> for StringProperty where T: From<String> {
-    fn value_from(&self, object: &Object) -> Result<T, OSStatus> {
-        let property_key = self.0.as_string_ref();
-        let mut string_ref = MaybeUninit::uninit();
-        let status = unsafe {
-            MIDIObjectGetStringProperty(object.0, property_key, string_ref.as_mut_ptr())
-        };
-        result_from_status(status, || {
-            let string_ref = unsafe { string_ref.assume_init() };
-            if string_ref.is_null() { return "".to_string().into() };
-            let cf_string: CFString = unsafe { TCFType::wrap_under_create_rule(string_ref) };
-            cf_string.to_string().into()
-        })
+    fn value_from(&self, object: &Object) -> Result<T, Error> {
+        Current::object_get_string_property(object.0, &self.0).map(|value| value.into()).map_err(Error::from)
     }
 }
 
 impl<'a, T> PropertySetter<T> for StringProperty where T: Into<String> {
-    fn set_value(&self, object: &Object, value: T) -> Result<(), OSStatus> {
-        let property_key = self.0.as_string_ref();
+    fn set_value(&self, object: &Object, value: T) -> Result<(), Error> {
         let value: String = value.into();
-        let string = CFString::new(&value);
-        let string_ref = string.as_concrete_TypeRef();
-        let status = unsafe {
-            MIDIObjectSetStringProperty(object.0, property_key, string_ref)
-        };
-        unit_result_from_status(status)
+        Current::object_set_string_property(object.0, &self.0, &value).map_err(Error::from)
     }
 }
 
 /// A MIDI object property which value is an Integer
 ///
-pub struct IntegerProperty(PropertyKeyStorage);
+pub struct IntegerProperty(PropertyKeyStorage, &'static [ObjectType]);
 
 impl IntegerProperty {
     pub fn new(name: &str) -> Self {
-        IntegerProperty(PropertyKeyStorage::Owned(CFString::new(name)))
+        IntegerProperty(PropertyKeyStorage::new(name), ALL_OBJECTS)
     }
 
     /// Note: Should only be used internally with predefined CoreMidi constants,
     /// since it does not bump the retain count of the CFStringRef.
-    fn from_constant_string_ref(string_ref: CFStringRef) -> Self {
-        IntegerProperty(PropertyKeyStorage::Constant(string_ref))
+    fn from_constant_string_ref(string_ref: CFStringRef, applies_to: &'static [ObjectType]) -> Self {
+        IntegerProperty(PropertyKeyStorage::constant(string_ref), applies_to)
+    }
+
+    /// The types of the objects this property is defined for, as in `StringProperty::applies_to`.
+    ///
+    pub fn applies_to(&self) -> &'static [ObjectType] {
+        self.1
+    }
+
+    /// The name of the property.
+    ///
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn property_value_from(&self, object: &Object) -> Result<PropertyValue, Error> {
+        self.value_from(object).map(|value: SInt32| PropertyValue::Integer(i64::from(value)))
     }
 }
 
 impl<T> PropertyGetter<T> for IntegerProperty where T: From<SInt32> {
-    fn value_from(&self, object: &Object) -> Result<T, OSStatus> {
-        let property_key = self.0.as_string_ref();
-        let mut value = MaybeUninit::uninit();
-        let status = unsafe {
-            MIDIObjectGetIntegerProperty(object.0, property_key, value.as_mut_ptr())
-        };
-        result_from_status(status, || {
-            let value = unsafe { value.assume_init() };
-            value.into()
-        })
+    fn value_from(&self, object: &Object) -> Result<T, Error> {
+        Current::object_get_integer_property(object.0, &self.0).map(|value| value.into()).map_err(Error::from)
     }
 }
 
 impl <T> PropertySetter<T> for IntegerProperty where T: Into<SInt32> {
-    fn set_value(&self, object: &Object, value: T) -> Result<(), OSStatus> {
-        let property_key = self.0.as_string_ref();
-        let status = unsafe {
-            MIDIObjectSetIntegerProperty(object.0, property_key, value.into())
-        };
-        unit_result_from_status(status)
+    fn set_value(&self, object: &Object, value: T) -> Result<(), Error> {
+        Current::object_set_integer_property(object.0, &self.0, value.into()).map_err(Error::from)
     }
 }
 
@@ -153,24 +185,131 @@ impl BooleanProperty {
 
     /// Note: Should only be used internally with predefined CoreMidi constants,
     /// since it does not bump the retain count of the CFStringRef.
-    fn from_constant_string_ref(string_ref: CFStringRef) -> Self {
-        BooleanProperty(IntegerProperty::from_constant_string_ref(string_ref))
+    fn from_constant_string_ref(string_ref: CFStringRef, applies_to: &'static [ObjectType]) -> Self {
+        BooleanProperty(IntegerProperty::from_constant_string_ref(string_ref, applies_to))
+    }
+
+    /// The types of the objects this property is defined for, as in `StringProperty::applies_to`.
+    ///
+    pub fn applies_to(&self) -> &'static [ObjectType] {
+        self.0.applies_to()
+    }
+
+    /// The name of the property.
+    ///
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn property_value_from(&self, object: &Object) -> Result<PropertyValue, Error> {
+        self.value_from(object).map(PropertyValue::Boolean)
     }
 }
 
 impl<T> PropertyGetter<T> for BooleanProperty where T: From<bool> {
-    fn value_from(&self, object: &Object) -> Result<T, OSStatus> {
+    fn value_from(&self, object: &Object) -> Result<T, Error> {
         self.0.value_from(object).map(|value: SInt32| (value == 1).into())
     }
 }
 
 impl<T> PropertySetter<T> for BooleanProperty where T: Into<bool> {
-    fn set_value(&self, object: &Object, value: T) -> Result<(), OSStatus> {
+    fn set_value(&self, object: &Object, value: T) -> Result<(), Error> {
         let value: SInt32 = if value.into() { 1 } else { 0 };
         self.0.set_value(object, value)
     }
 }
 
+/// A MIDI object property which value is a block of bytes (CFData)
+///
+pub struct DataProperty(PropertyKeyStorage, &'static [ObjectType]);
+
+impl DataProperty {
+    pub fn new(name: &str) -> Self {
+        DataProperty(PropertyKeyStorage::new(name), ALL_OBJECTS)
+    }
+
+    /// Note: Should only be used internally with predefined CoreMidi constants,
+    /// since it does not bump the retain count of the CFStringRef.
+    fn from_constant_string_ref(string_ref: CFStringRef, applies_to: &'static [ObjectType]) -> Self {
+        DataProperty(PropertyKeyStorage::constant(string_ref), applies_to)
+    }
+
+    /// The types of the objects this property is defined for, as in `StringProperty::applies_to`.
+    ///
+    pub fn applies_to(&self) -> &'static [ObjectType] {
+        self.1
+    }
+
+    /// The name of the property.
+    ///
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn property_value_from(&self, object: &Object) -> Result<PropertyValue, Error> {
+        self.value_from(object).map(PropertyValue::Data)
+    }
+}
+
+impl<T> PropertyGetter<T> for DataProperty where T: From<Vec<u8>> {
+    fn value_from(&self, object: &Object) -> Result<T, Error> {
+        Current::object_get_data_property(object.0, &self.0).map(|value| value.into()).map_err(Error::from)
+    }
+}
+
+impl<T> PropertySetter<T> for DataProperty where T: Into<Vec<u8>> {
+    fn set_value(&self, object: &Object, value: T) -> Result<(), Error> {
+        let value: Vec<u8> = value.into();
+        Current::object_set_data_property(object.0, &self.0, &value).map_err(Error::from)
+    }
+}
+
+/// A MIDI object property which value is a dictionary (CFDictionary)
+///
+/// Only the items with string keys and property list values are kept.
+pub struct DictionaryProperty(PropertyKeyStorage, &'static [ObjectType]);
+
+impl DictionaryProperty {
+    pub fn new(name: &str) -> Self {
+        DictionaryProperty(PropertyKeyStorage::new(name), ALL_OBJECTS)
+    }
+
+    /// Note: Should only be used internally with predefined CoreMidi constants,
+    /// since it does not bump the retain count of the CFStringRef.
+    fn from_constant_string_ref(string_ref: CFStringRef, applies_to: &'static [ObjectType]) -> Self {
+        DictionaryProperty(PropertyKeyStorage::constant(string_ref), applies_to)
+    }
+
+    /// The types of the objects this property is defined for, as in `StringProperty::applies_to`.
+    ///
+    pub fn applies_to(&self) -> &'static [ObjectType] {
+        self.1
+    }
+
+    /// The name of the property.
+    ///
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn property_value_from(&self, object: &Object) -> Result<PropertyValue, Error> {
+        self.value_from(object).map(PropertyValue::Dictionary)
+    }
+}
+
+impl<T> PropertyGetter<T> for DictionaryProperty where T: From<BTreeMap<String, PropertyValue>> {
+    fn value_from(&self, object: &Object) -> Result<T, Error> {
+        Current::object_get_dictionary_property(object.0, &self.0).map(|value| value.into()).map_err(Error::from)
+    }
+}
+
+impl<T> PropertySetter<T> for DictionaryProperty where T: Into<BTreeMap<String, PropertyValue>> {
+    fn set_value(&self, object: &Object, value: T) -> Result<(), Error> {
+        let value: BTreeMap<String, PropertyValue> = value.into();
+        Current::object_set_dictionary_property(object.0, &self.0, &value).map_err(Error::from)
+    }
+}
+
 /// The set of properties that might be available for MIDI objects.
 ///
 pub struct Properties;
@@ -178,221 +317,385 @@ pub struct Properties;
 impl Properties {
     /// See [kMIDIPropertyName](https://developer.apple.com/reference/coremidi/kmidipropertyname)
     pub fn name() -> StringProperty {
-        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyName })
+        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyName }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertyManufacturer](https://developer.apple.com/reference/coremidi/kmidipropertymanufacturer)
     pub fn manufacturer() -> StringProperty {
-        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyManufacturer }) 
+        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyManufacturer }, DEVICES_AND_ENDPOINTS) 
     }
     
     /// See [kMIDIPropertyModel](https://developer.apple.com/reference/coremidi/kmidipropertymodel)
     pub fn model() -> StringProperty {
-        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyModel })
+        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyModel }, DEVICES_AND_ENDPOINTS)
     }
     
     /// See [kMIDIPropertyUniqueID](https://developer.apple.com/reference/coremidi/kmidipropertyuniqueid)
     pub fn unique_id() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyUniqueID })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyUniqueID }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertyDeviceID](https://developer.apple.com/reference/coremidi/kmidipropertydeviceid)
     pub fn device_id() -> IntegerProperty { 
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyDeviceID })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyDeviceID }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertyReceiveChannels](https://developer.apple.com/reference/coremidi/kmidipropertyreceivechannels)
     pub fn receive_channels() -> IntegerProperty { 
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceiveChannels })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceiveChannels }, ENDPOINTS)
     }
     
     /// See [kMIDIPropertyTransmitChannels](https://developer.apple.com/reference/coremidi/kmidipropertytransmitchannels)
     pub fn transmit_channels() -> IntegerProperty { 
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitChannels })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitChannels }, ENDPOINTS)
     }
     
     /// See [kMIDIPropertyMaxSysExSpeed](https://developer.apple.com/reference/coremidi/kmidipropertymaxsysexspeed)
     pub fn max_sysex_speed() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyMaxSysExSpeed })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyMaxSysExSpeed }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertyAdvanceScheduleTimeMuSec](https://developer.apple.com/reference/coremidi/kMIDIPropertyAdvanceScheduleTimeMuSec)
     pub fn advance_schedule_time_musec() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyAdvanceScheduleTimeMuSec })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyAdvanceScheduleTimeMuSec }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertyIsEmbeddedEntity](https://developer.apple.com/reference/coremidi/kMIDIPropertyIsEmbeddedEntity)
     pub fn is_embedded_entity() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsEmbeddedEntity })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsEmbeddedEntity }, ENTITIES_AND_ENDPOINTS)
     }
     
     /// See [kMIDIPropertyIsBroadcast](https://developer.apple.com/reference/coremidi/kMIDIPropertyIsBroadcast)
     pub fn is_broadcast() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsBroadcast })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsBroadcast }, ENTITIES_AND_ENDPOINTS)
     }
     
     /// See [kMIDIPropertySingleRealtimeEntity](https://developer.apple.com/reference/coremidi/kMIDIPropertySingleRealtimeEntity)
     pub fn single_realtime_entity() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertySingleRealtimeEntity })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertySingleRealtimeEntity }, DEVICES)
     }
     
     /// See [kMIDIPropertyConnectionUniqueID](https://developer.apple.com/reference/coremidi/kMIDIPropertyConnectionUniqueID)
     pub fn connection_unique_id() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyConnectionUniqueID })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyConnectionUniqueID }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertyOffline](https://developer.apple.com/reference/coremidi/kMIDIPropertyOffline)
     pub fn offline() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyOffline })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyOffline }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertyPrivate](https://developer.apple.com/reference/coremidi/kMIDIPropertyPrivate)
     pub fn private() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyPrivate })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyPrivate }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertyDriverOwner](https://developer.apple.com/reference/coremidi/kMIDIPropertyDriverOwner)
     pub fn driver_owner() -> StringProperty {
-        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyDriverOwner })
+        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyDriverOwner }, ALL_OBJECTS)
     }
     
-    // /// See [kMIDIPropertyNameConfiguration](https://developer.apple.com/reference/coremidi/kMIDIPropertyNameConfiguration)
-    // pub fn name_configuration() -> Property { unsafe { Property(kMIDIPropertyNameConfiguration) } }
-    
-    // /// See [kMIDIPropertyImage](https://developer.apple.com/reference/coremidi/kMIDIPropertyImage)
-    // pub fn image() -> Property { unsafe { Property(kMIDIPropertyImage) } }
-    
+    /// See [kMIDIPropertyFactoryPatchNameFile](https://developer.apple.com/reference/coremidi/kMIDIPropertyFactoryPatchNameFile)
+    ///
+    /// Deprecated by CoreMIDI in favour of `name_configuration_dictionary`.
+    pub fn factory_patch_name_file() -> DataProperty {
+        DataProperty::from_constant_string_ref(unsafe { kMIDIPropertyFactoryPatchNameFile }, ALL_OBJECTS)
+    }
+
+    /// See [kMIDIPropertyUserPatchNameFile](https://developer.apple.com/reference/coremidi/kMIDIPropertyUserPatchNameFile)
+    ///
+    /// Deprecated by CoreMIDI in favour of `name_configuration_dictionary`.
+    pub fn user_patch_name_file() -> DataProperty {
+        DataProperty::from_constant_string_ref(unsafe { kMIDIPropertyUserPatchNameFile }, ALL_OBJECTS)
+    }
+
+    /// See [kMIDIPropertyNameConfiguration](https://developer.apple.com/reference/coremidi/kMIDIPropertyNameConfiguration)
+    ///
+    /// Deprecated by CoreMIDI in favour of `name_configuration_dictionary`.
+    pub fn name_configuration() -> DictionaryProperty {
+        DictionaryProperty::from_constant_string_ref(unsafe { kMIDIPropertyNameConfiguration }, ALL_OBJECTS)
+    }
+
+    /// See [kMIDIPropertyNameConfigurationDictionary](https://developer.apple.com/reference/coremidi/kMIDIPropertyNameConfigurationDictionary)
+    pub fn name_configuration_dictionary() -> DictionaryProperty {
+        // Added in macOS 15, so the key is created from its value instead of the constant
+        DictionaryProperty(PropertyKeyStorage::new("nameConfigurationDictionary"), ALL_OBJECTS)
+    }
+
+    /// See [kMIDIPropertyImage](https://developer.apple.com/reference/coremidi/kMIDIPropertyImage)
+    ///
+    /// The value is the POSIX path of the icon of the device.
+    pub fn image() -> StringProperty {
+        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyImage }, DEVICES)
+    }
+
     /// See [kMIDIPropertyDriverVersion](https://developer.apple.com/reference/coremidi/kMIDIPropertyDriverVersion)
     pub fn driver_version() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyDriverVersion })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyDriverVersion }, ALL_OBJECTS)
     }
     
     /// See [kMIDIPropertySupportsGeneralMIDI](https://developer.apple.com/reference/coremidi/kMIDIPropertySupportsGeneralMIDI)
     pub fn supports_general_midi() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertySupportsGeneralMIDI })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertySupportsGeneralMIDI }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertySupportsMMC](https://developer.apple.com/reference/coremidi/kMIDIPropertySupportsMMC)
     pub fn supports_mmc() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertySupportsMMC })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertySupportsMMC }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertyCanRoute](https://developer.apple.com/reference/coremidi/kMIDIPropertyCanRoute)
     pub fn can_route() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyCanRoute })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyCanRoute }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertyReceivesClock](https://developer.apple.com/reference/coremidi/kMIDIPropertyReceivesClock)
     pub fn receives_clock() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesClock })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesClock }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertyReceivesMTC](https://developer.apple.com/reference/coremidi/kMIDIPropertyReceivesMTC)
     pub fn receives_mtc() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesMTC })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesMTC }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertyReceivesNotes](https://developer.apple.com/reference/coremidi/kMIDIPropertyReceivesNotes)
     pub fn receives_notes() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesNotes })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesNotes }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertyReceivesProgramChanges](https://developer.apple.com/reference/coremidi/kMIDIPropertyReceivesProgramChanges)
     pub fn receives_program_changes() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesProgramChanges })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesProgramChanges }, DEVICES_AND_ENTITIES)
     }
     
     /// See [kMIDIPropertyReceivesBankSelectMSB](https://developer.apple.com/reference/coremidi/kMIDIPropertyReceivesBankSelectMSB)
     pub fn receives_bank_select_msb() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesBankSelectMSB })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesBankSelectMSB }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyReceivesBankSelectLSB](https://developer.apple.com/reference/coremidi/kMIDIPropertyReceivesBankSelectLSB)
     pub fn receives_bank_select_lsb() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesBankSelectLSB })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyReceivesBankSelectLSB }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyTransmitsBankSelectMSB](https://developer.apple.com/reference/coremidi/kMIDIPropertyTransmitsBankSelectMSB)
     pub fn transmits_bank_select_msb() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsBankSelectMSB })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsBankSelectMSB }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyTransmitsBankSelectLSB](https://developer.apple.com/reference/coremidi/kMIDIPropertyTransmitsBankSelectLSB)
     pub fn transmits_bank_select_lsb() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsBankSelectLSB })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsBankSelectLSB }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyTransmitsClock](https://developer.apple.com/reference/coremidi/kMIDIPropertyTransmitsClock)
     pub fn transmits_clock() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsClock })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsClock }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyTransmitsMTC](https://developer.apple.com/reference/coremidi/kMIDIPropertyTransmitsMTC)
     pub fn transmits_mtc() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsMTC })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsMTC }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyTransmitsNotes](https://developer.apple.com/reference/coremidi/kMIDIPropertyTransmitsNotes)
     pub fn transmits_notes() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsNotes })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsNotes }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyTransmitsProgramChanges](https://developer.apple.com/reference/coremidi/kMIDIPropertyTransmitsProgramChanges)
     pub fn transmits_program_changes() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsProgramChanges })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyTransmitsProgramChanges }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyPanDisruptsStereo](https://developer.apple.com/reference/coremidi/kMIDIPropertyPanDisruptsStereo)
     pub fn pan_disrupts_stereo() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyPanDisruptsStereo })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyPanDisruptsStereo }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyIsSampler](https://developer.apple.com/reference/coremidi/kMIDIPropertyIsSampler)
     pub fn is_sampler() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsSampler })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsSampler }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyIsDrumMachine](https://developer.apple.com/reference/coremidi/kMIDIPropertyIsDrumMachine)
     pub fn is_drum_machine() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsDrumMachine })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsDrumMachine }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyIsMixer](https://developer.apple.com/reference/coremidi/kMIDIPropertyIsMixer)
     pub fn is_mixer() -> BooleanProperty { 
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsMixer })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsMixer }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyIsEffectUnit](https://developer.apple.com/reference/coremidi/kMIDIPropertyIsEffectUnit)
     pub fn is_effect_unit() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsEffectUnit })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertyIsEffectUnit }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyMaxReceiveChannels](https://developer.apple.com/reference/coremidi/kMIDIPropertyMaxReceiveChannels)
     pub fn max_receive_channels() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyMaxReceiveChannels })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyMaxReceiveChannels }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyMaxTransmitChannels](https://developer.apple.com/reference/coremidi/kMIDIPropertyMaxTransmitChannels)
     pub fn max_transmit_channels() -> IntegerProperty {
-        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyMaxTransmitChannels })
+        IntegerProperty::from_constant_string_ref(unsafe { kMIDIPropertyMaxTransmitChannels }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyDriverDeviceEditorApp](https://developer.apple.com/reference/coremidi/kMIDIPropertyDriverDeviceEditorApp)
     pub fn driver_device_editor_app() -> StringProperty {
-        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyDriverDeviceEditorApp })
+        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyDriverDeviceEditorApp }, DEVICES)
     }
 
     /// See [kMIDIPropertySupportsShowControl](https://developer.apple.com/reference/coremidi/kMIDIPropertySupportsShowControl)
     pub fn supports_show_control() -> BooleanProperty {
-        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertySupportsShowControl })
+        BooleanProperty::from_constant_string_ref(unsafe { kMIDIPropertySupportsShowControl }, DEVICES_AND_ENTITIES)
     }
 
     /// See [kMIDIPropertyDisplayName](https://developer.apple.com/reference/coremidi/kMIDIPropertyDisplayName)
     pub fn display_name() -> StringProperty {
-        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyDisplayName })
+        StringProperty::from_constant_string_ref(unsafe { kMIDIPropertyDisplayName }, ALL_OBJECTS)
+    }
+
+    /// See [kMIDIPropertyProtocolID](https://developer.apple.com/reference/coremidi/kMIDIPropertyProtocolID)
+    ///
+    /// The value is a `MIDIProtocolID`, which can be read with `Protocol::from_raw`.
+    pub fn protocol_id() -> IntegerProperty {
+        // Added in macOS 11, like the UMP properties below, so the key is created from its value instead of the constant
+        IntegerProperty(PropertyKeyStorage::new("protocolID"), ENDPOINTS)
+    }
+
+    /// See [kMIDIPropertyUMPActiveGroupBitmap](https://developer.apple.com/reference/coremidi/kMIDIPropertyUMPActiveGroupBitmap)
+    ///
+    /// Bit `n` is set when the group `n` is in use.
+    pub fn ump_active_group_bitmap() -> IntegerProperty {
+        IntegerProperty(PropertyKeyStorage::new("UMPActiveGroupBitmap"), ENDPOINTS)
+    }
+
+    /// See [kMIDIPropertyUMPCanTransmitGroupless](https://developer.apple.com/reference/coremidi/kMIDIPropertyUMPCanTransmitGroupless)
+    pub fn ump_can_transmit_groupless() -> BooleanProperty {
+        BooleanProperty(IntegerProperty(PropertyKeyStorage::new("UMPCanTransmitGroupless"), ENDPOINTS))
+    }
+
+    /// See [kMIDIPropertySupportsMIDI2](https://developer.apple.com/reference/coremidi/kMIDIPropertySupportsMIDI2)
+    pub fn supports_midi2() -> BooleanProperty {
+        // Not exported by every version of CoreMIDI, so the key is created from its value instead of the constant
+        BooleanProperty(IntegerProperty(PropertyKeyStorage::new("supportsMIDI2"), DEVICES_AND_ENTITIES))
     }
 }
 
+macro_rules! property_keys {
+    ($($key:ident => $property:ident, $name:literal,)*) => {
+        /// The key of a property, as a typed version of its name.
+        ///
+        /// There is one key for each of the properties in `Properties`, and any other name
+        /// (like the custom properties set by applications) is kept in `Other`.
+        ///
+        /// ```
+        /// use coremidi::{Properties, PropertyKey};
+        /// assert_eq!(PropertyKey::from_name(&Properties::offline().name()), PropertyKey::Offline);
+        /// assert_eq!(PropertyKey::from_name("my-property"), PropertyKey::Other("my-property".to_string()));
+        /// ```
+        #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+        pub enum PropertyKey {
+            $($key,)*
+            Other(String),
+        }
+
+        impl PropertyKey {
+            /// Get the key for a property name.
+            ///
+            pub fn from_name(name: &str) -> PropertyKey {
+                match name {
+                    $($name => PropertyKey::$key,)*
+                    _ => PropertyKey::Other(name.to_string()),
+                }
+            }
+
+            /// Get the name of the property.
+            ///
+            pub fn name(&self) -> String {
+                match *self {
+                    $(PropertyKey::$key => $name.to_string(),)*
+                    PropertyKey::Other(ref name) => name.clone(),
+                }
+            }
+
+            /// Get the value of the property for an object, with the type of the property in `Properties`.
+            /// The value of the `Other` properties is given as CoreMIDI stores it, like booleans as integers.
+            ///
+            pub fn value_from(&self, object: &Object) -> Result<PropertyValue, Error> {
+                match *self {
+                    $(PropertyKey::$key => Properties::$property().property_value_from(object),)*
+                    PropertyKey::Other(ref name) => object.properties(false)?.remove(name).ok_or(Error::UnknownProperty),
+                }
+            }
+
+            #[cfg(test)]
+            fn all_with_property_names() -> Vec<(PropertyKey, String)> {
+                vec![$((PropertyKey::$key, Properties::$property().name()),)*]
+            }
+        }
+    }
+}
+
+property_keys! {
+    Name => name, "name",
+    Manufacturer => manufacturer, "manufacturer",
+    Model => model, "model",
+    UniqueID => unique_id, "uniqueID",
+    DeviceID => device_id, "deviceID",
+    ReceiveChannels => receive_channels, "receiveChannels",
+    TransmitChannels => transmit_channels, "transmitChannels",
+    MaxSysExSpeed => max_sysex_speed, "maxSysExSpeed",
+    AdvanceScheduleTimeMuSec => advance_schedule_time_musec, "scheduleAheadMuSec",
+    IsEmbeddedEntity => is_embedded_entity, "embedded",
+    IsBroadcast => is_broadcast, "broadcast",
+    SingleRealtimeEntity => single_realtime_entity, "singleRealtimeEntity",
+    ConnectionUniqueID => connection_unique_id, "connUniqueID",
+    Offline => offline, "offline",
+    Private => private, "private",
+    DriverOwner => driver_owner, "driver",
+    FactoryPatchNameFile => factory_patch_name_file, "factoryPatchNameFile",
+    UserPatchNameFile => user_patch_name_file, "userPatchNameFile",
+    NameConfiguration => name_configuration, "nameConfiguration",
+    NameConfigurationDictionary => name_configuration_dictionary, "nameConfigurationDictionary",
+    Image => image, "image",
+    DriverVersion => driver_version, "driverVersion",
+    SupportsGeneralMIDI => supports_general_midi, "supportsGeneralMIDI",
+    SupportsMMC => supports_mmc, "supportsMMC",
+    CanRoute => can_route, "canRoute",
+    ReceivesClock => receives_clock, "receivesClock",
+    ReceivesMTC => receives_mtc, "receivesMTC",
+    ReceivesNotes => receives_notes, "receivesNotes",
+    ReceivesProgramChanges => receives_program_changes, "receivesProgramChanges",
+    ReceivesBankSelectMSB => receives_bank_select_msb, "receivesBankSelectMSB",
+    ReceivesBankSelectLSB => receives_bank_select_lsb, "receivesBankSelectLSB",
+    TransmitsBankSelectMSB => transmits_bank_select_msb, "transmitsBankSelectMSB",
+    TransmitsBankSelectLSB => transmits_bank_select_lsb, "transmitsBankSelectLSB",
+    TransmitsClock => transmits_clock, "transmitsClock",
+    TransmitsMTC => transmits_mtc, "transmitsMTC",
+    TransmitsNotes => transmits_notes, "transmitsNotes",
+    TransmitsProgramChanges => transmits_program_changes, "transmitsProgramChanges",
+    PanDisruptsStereo => pan_disrupts_stereo, "panDisruptsStereo",
+    IsSampler => is_sampler, "isSampler",
+    IsDrumMachine => is_drum_machine, "isDrumMachine",
+    IsMixer => is_mixer, "isMixer",
+    IsEffectUnit => is_effect_unit, "isEffectUnit",
+    MaxReceiveChannels => max_receive_channels, "maxReceiveChannels",
+    MaxTransmitChannels => max_transmit_channels, "maxTransmitChannels",
+    DriverDeviceEditorApp => driver_device_editor_app, "driverDeviceEditorApp",
+    SupportsShowControl => supports_show_control, "supportsShowControl",
+    DisplayName => display_name, "displayName",
+    ProtocolID => protocol_id, "protocolID",
+    UMPActiveGroupBitmap => ump_active_group_bitmap, "UMPActiveGroupBitmap",
+    UMPCanTransmitGroupless => ump_can_transmit_groupless, "UMPCanTransmitGroupless",
+    SupportsMIDI2 => supports_midi2, "supportsMIDI2",
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -503,4 +806,90 @@ mod tests {
             assert!(value, true)
         }
     }
+
+    mod data {
+        use super::*;
+
+        #[test]
+        fn test_not_set() {
+            let (_client, dest) = setup();
+            let property = Properties::user_patch_name_file();
+
+            let value: Result<Vec<u8>, _> = property.value_from(&dest);
+
+            assert!(value.is_err())
+        }
+
+        #[test]
+        fn test_roundtrip() {
+            let (_client, dest) = setup();
+            let property = DataProperty::new("coremidi-test-data");
+
+            property.set_value(&dest, &[0x00u8, 0x7f, 0xff][..]).unwrap();
+            let value: Vec<u8> = property.value_from(&dest).unwrap();
+
+            assert_eq!(value, vec![0x00, 0x7f, 0xff]);
+        }
+    }
+
+    mod dictionary {
+        use super::*;
+
+        #[test]
+        fn test_roundtrip() {
+            let (_client, dest) = setup();
+            let property = Properties::name_configuration_dictionary();
+            let mut bank = BTreeMap::new();
+            bank.insert("name".to_string(), PropertyValue::String("Pianos".to_string()));
+            bank.insert("programs".to_string(), PropertyValue::Array(vec![PropertyValue::Integer(0), PropertyValue::Integer(1)]));
+            let mut configuration = BTreeMap::new();
+            configuration.insert("bank".to_string(), PropertyValue::Dictionary(bank));
+            configuration.insert("gm".to_string(), PropertyValue::Boolean(true));
+            configuration.insert("tuning".to_string(), PropertyValue::Float(440.0));
+            configuration.insert("sysex".to_string(), PropertyValue::Data(vec![0xf0, 0xf7]));
+
+            property.set_value(&dest, configuration.clone()).unwrap();
+            let value: BTreeMap<String, PropertyValue> = property.value_from(&dest).unwrap();
+
+            assert_eq!(value, configuration);
+        }
+
+        #[test]
+        fn test_wrong_type() {
+            let (_client, dest) = setup();
+
+            // "name" is a string property
+            let value: Result<BTreeMap<String, PropertyValue>, _> = DictionaryProperty::new("name").value_from(&dest);
+
+            assert!(value.is_err())
+        }
+    }
+
+    #[test]
+    fn test_applies_to() {
+        assert!(Properties::name().applies_to().contains(&ObjectType::Destination));
+        assert_eq!(Properties::single_realtime_entity().applies_to(), &[ObjectType::Device, ObjectType::ExternalDevice]);
+        assert!(!Properties::protocol_id().applies_to().contains(&ObjectType::Device));
+        assert!(Properties::ump_can_transmit_groupless().applies_to().contains(&ObjectType::Source));
+        assert!(StringProperty::new("custom").applies_to().contains(&ObjectType::Entity));
+    }
+
+    #[test]
+    fn test_property_keys() {
+        assert_eq!(PropertyKey::from_name(&Properties::unique_id().name()), PropertyKey::UniqueID);
+        assert_eq!(PropertyKey::UMPCanTransmitGroupless.name(), Properties::ump_can_transmit_groupless().name());
+        assert_eq!(PropertyKey::from_name("custom"), PropertyKey::Other("custom".to_string()));
+        assert_eq!(PropertyKey::Other("custom".to_string()).name(), "custom");
+        for (key, name) in PropertyKey::all_with_property_names() {
+            assert_eq!(key.name(), name);
+            assert_eq!(PropertyKey::from_name(&name), key);
+        }
+
+        let (_client, dest) = setup();
+        Properties::private().set_value(&dest, true).unwrap();
+        assert_eq!(PropertyKey::Name.value_from(&dest), Ok(PropertyValue::String(NAME_ORIG.to_string())));
+        assert_eq!(PropertyKey::Private.value_from(&dest), Ok(PropertyValue::Boolean(true)));
+        assert_eq!(PropertyKey::from_name("private").value_from(&dest), Ok(PropertyValue::Boolean(true)));
+        assert_eq!(PropertyKey::Other("undefined".to_string()).value_from(&dest), Err(Error::UnknownProperty));
+    }
 }
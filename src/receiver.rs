@@ -0,0 +1,599 @@
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "stream")]
+use std::pin::Pin;
+#[cfg(feature = "stream")]
+use std::task::{Context, Poll, Waker};
+
+#[cfg(feature = "stream")]
+use futures_core::Stream;
+
+use PacketList;
+use packets::Timestamp;
+
+/// What to do with a packet that arrives when the receiver queue is full.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued packet to make room for the new one.
+    /// The new packet is discarded instead if the receiver is reading the oldest one at that moment.
+    DropOldest,
+    /// Discard the packet that just arrived.
+    DropNewest,
+    /// Discard the packet that just arrived, and report how many packets were lost
+    /// as a `RecvError::Overflowed` at the point of the stream where they are missing.
+    Report,
+}
+
+/// A packet received through a `PacketReceiver`, copied out of the CoreMIDI packet list.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedPacket {
+    pub timestamp: Timestamp,
+    pub data: Vec<u8>,
+}
+
+/// The errors returned when receiving from a `PacketReceiver`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// There are no packets queued (only returned by `try_recv`).
+    Empty,
+    /// No packet arrived before the timeout (only returned by `recv_timeout`).
+    Timeout,
+    /// The number of packets that were lost because the queue was full, when using `OverflowPolicy::Report`.
+    /// The packets received afterwards are still available.
+    Overflowed(usize),
+    /// The input port has been dropped and there are no more packets queued.
+    Disconnected,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvError::Empty => write!(f, "no packets available"),
+            RecvError::Timeout => write!(f, "timed out waiting for packets"),
+            RecvError::Overflowed(lost) => write!(f, "{} packets lost because the queue was full", lost),
+            RecvError::Disconnected => write!(f, "the input port has been disposed"),
+        }
+    }
+}
+
+impl error::Error for RecvError {}
+
+// The size of a slot of the queue, the same as the data of a CoreMIDI `MIDIPacket`
+const SLOT_SIZE: usize = 256;
+
+struct SlotData {
+    timestamp: Timestamp,
+    // The number of packets dropped just before this one, to be reported first
+    lost_before: usize,
+    len: usize,
+    bytes: [u8; SLOT_SIZE],
+}
+
+struct Slot {
+    // Twice the position that can be written into the slot while it is free,
+    // and one more than that once the packet at that position has been written
+    stamp: AtomicUsize,
+    data: UnsafeCell<SlotData>,
+}
+
+/// A bounded queue with preallocated slots, which never locks nor allocates.
+///
+/// There is a single writer, and the slots are claimed for reading with a compare and swap,
+/// so that the writer can also discard the oldest packets when the queue is full.
+struct Ring {
+    slots: Box<[Slot]>,
+    // The position of the next slot to read
+    head: AtomicUsize,
+    // The position of the next slot to write
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new(capacity: usize) -> Ring {
+        let slots: Vec<_> = (0..capacity).map(|position| Slot {
+            stamp: AtomicUsize::new(2 * position),
+            data: UnsafeCell::new(SlotData { timestamp: 0, lost_before: 0, len: 0, bytes: [0; SLOT_SIZE] }),
+        }).collect();
+        Ring { slots: slots.into_boxed_slice(), head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, position: usize) -> &Slot {
+        &self.slots[position % self.slots.len()]
+    }
+
+    /// Looks for the first of the next `count` slots to write that is not free yet.
+    fn first_busy(&self, count: usize) -> Option<usize> {
+        let tail = self.tail.load(Ordering::SeqCst);
+        (tail..tail + count).find(|&position| self.slot(position).stamp.load(Ordering::SeqCst) != 2 * position)
+    }
+
+    /// Writes the next slot, which must be free. It can only be called by the single writer.
+    unsafe fn write(&self, timestamp: Timestamp, lost_before: usize, bytes: &[u8]) {
+        let position = self.tail.load(Ordering::SeqCst);
+        let slot = self.slot(position);
+        let data = &mut *slot.data.get();
+        data.timestamp = timestamp;
+        data.lost_before = lost_before;
+        data.len = bytes.len();
+        data.bytes[..bytes.len()].copy_from_slice(bytes);
+        slot.stamp.store(2 * position + 1, Ordering::SeqCst);
+        self.tail.store(position + 1, Ordering::SeqCst);
+    }
+
+    /// Claims the oldest slot and reads it, returning `None` when the queue is empty.
+    fn pop<F, R>(&self, read: F) -> Option<R> where F: FnOnce(&SlotData) -> R {
+        let mut position = self.head.load(Ordering::SeqCst);
+        loop {
+            let slot = self.slot(position);
+            let stamp = slot.stamp.load(Ordering::SeqCst);
+            if stamp < 2 * position + 1 {
+                return None;
+            }
+            if stamp == 2 * position + 1 {
+                match self.head.compare_exchange(position, position + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                    Ok(_) => {
+                        let result = read(unsafe { &*slot.data.get() });
+                        slot.stamp.store(2 * (position + self.slots.len()), Ordering::SeqCst);
+                        return Some(result);
+                    },
+                    Err(head) => position = head,
+                }
+            } else {
+                position = self.head.load(Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReceiverState {
+    // A packet that was read after reporting the packets lost before it
+    pending: Option<ReceivedPacket>,
+    #[cfg(feature = "stream")]
+    waker: Option<Waker>,
+}
+
+struct Shared {
+    ring: Ring,
+    lost: AtomicUsize,
+    dropped: AtomicUsize,
+    disconnected: AtomicBool,
+    // Set by the receivers before waiting, so that the sender only locks `state` to wake them up
+    waiting: AtomicBool,
+    state: Mutex<ReceiverState>,
+    available: Condvar,
+    policy: OverflowPolicy,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<ReceiverState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shared")
+            .field("capacity", &self.ring.capacity())
+            .field("policy", &self.policy)
+            .field("dropped", &self.dropped.load(Ordering::SeqCst))
+            .field("disconnected", &self.disconnected.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+/// The sending half of the queue, owned by the input port callback.
+///
+/// It runs on the CoreMIDI thread, so it only locks to wake up a receiver that is waiting.
+pub(crate) struct PacketSender {
+    shared: Arc<Shared>,
+}
+
+impl PacketSender {
+    pub(crate) fn send(&self, packet_list: &PacketList) {
+        for packet in packet_list.iter() {
+            self.push(packet.timestamp(), packet.data());
+        }
+        self.notify();
+    }
+
+    fn push(&self, timestamp: Timestamp, data: &[u8]) {
+        let ring = &self.shared.ring;
+        let chunks = cmp::max(1, (data.len() + SLOT_SIZE - 1) / SLOT_SIZE);
+        if !self.make_room(chunks) {
+            self.shared.dropped.fetch_add(1, Ordering::SeqCst);
+            if self.shared.policy == OverflowPolicy::Report {
+                self.shared.lost.fetch_add(1, Ordering::SeqCst);
+            }
+            return;
+        }
+        let mut lost_before = self.shared.lost.swap(0, Ordering::SeqCst);
+        for chunk in data.chunks(SLOT_SIZE) {
+            unsafe { ring.write(timestamp, lost_before, chunk) };
+            lost_before = 0;
+        }
+        if data.is_empty() {
+            unsafe { ring.write(timestamp, lost_before, data) };
+        }
+    }
+
+    /// Makes sure the next `chunks` slots are free, discarding the oldest packets if the policy allows it.
+    fn make_room(&self, chunks: usize) -> bool {
+        let ring = &self.shared.ring;
+        if chunks > ring.capacity() {
+            return false;
+        }
+        while let Some(position) = ring.first_busy(chunks) {
+            let being_read = ring.head.load(Ordering::SeqCst) > position - ring.capacity();
+            if being_read || self.shared.policy != OverflowPolicy::DropOldest {
+                return false;
+            }
+            if ring.pop(|_| ()).is_some() {
+                self.shared.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        true
+    }
+
+    fn notify(&self) {
+        if self.shared.waiting.swap(false, Ordering::SeqCst) {
+            self.wake(self.shared.state());
+        }
+    }
+
+    #[cfg(not(feature = "stream"))]
+    fn wake(&self, state: MutexGuard<ReceiverState>) {
+        drop(state);
+        self.shared.available.notify_all();
+    }
+
+    #[cfg(feature = "stream")]
+    fn wake(&self, mut state: MutexGuard<ReceiverState>) {
+        let waker = state.waker.take();
+        drop(state);
+        self.shared.available.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for PacketSender {
+    fn drop(&mut self) {
+        self.shared.disconnected.store(true, Ordering::SeqCst);
+        self.notify();
+    }
+}
+
+/// The receiving half of an input port created with `Client::input_port_channel`.
+///
+/// Packets are copied out of the CoreMIDI callback into a bounded queue, so they can be
+/// consumed from any thread. The queue is allocated upfront, so the callback neither allocates
+/// nor waits for the receiver. Packets longer than 256 bytes are split into consecutive packets
+/// with the same timestamp, as CoreMIDI does with long SysEx messages. When the queue is full,
+/// the `OverflowPolicy` given when creating the port decides which packets are dropped.
+///
+/// With the `stream` feature enabled, it also implements `futures_core::Stream`, which yields
+/// the same items as `recv` and finishes once the input port has been dropped.
+///
+/// ```rust,no_run
+/// use coremidi::{Client, OverflowPolicy, Sources};
+/// let client = Client::new("example-client").unwrap();
+/// let (input_port, receiver) = client.input_port_channel("example-port", 1024, OverflowPolicy::Report).unwrap();
+/// let source = Sources.into_iter().next().unwrap();
+/// input_port.connect_source(&source).unwrap();
+/// while let Ok(packet) = receiver.recv() {
+///     println!("{}: {:?}", packet.timestamp, packet.data);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PacketReceiver {
+    shared: Arc<Shared>,
+}
+
+impl PacketReceiver {
+    /// Receive the next packet, blocking until one is available.
+    ///
+    pub fn recv(&self) -> Result<ReceivedPacket, RecvError> {
+        let mut state = self.shared.state();
+        loop {
+            match self.next(&mut state) {
+                Err(RecvError::Empty) if self.shared.waiting.swap(true, Ordering::SeqCst) => {
+                    state = self.shared.available.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner())
+                },
+                // Check again, now that the sender knows it has to wake us up
+                Err(RecvError::Empty) => {},
+                result => return result
+            }
+        }
+    }
+
+    /// Receive the next packet if there is any queued, without blocking.
+    ///
+    pub fn try_recv(&self) -> Result<ReceivedPacket, RecvError> {
+        self.next(&mut self.shared.state())
+    }
+
+    /// Receive the next packet, blocking until one is available or the timeout expires.
+    ///
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<ReceivedPacket, RecvError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state();
+        loop {
+            match self.next(&mut state) {
+                Err(RecvError::Empty) if self.shared.waiting.swap(true, Ordering::SeqCst) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvError::Timeout);
+                    }
+                    state = self.shared.available.wait_timeout(state, deadline - now)
+                        .map(|(state, _)| state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner().0);
+                },
+                Err(RecvError::Empty) => {},
+                result => return result
+            }
+        }
+    }
+
+    /// Returns an iterator over the packets that are currently queued, without blocking.
+    ///
+    pub fn try_iter(&self) -> TryIter {
+        TryIter { receiver: self }
+    }
+
+    /// The maximum number of packets that can be queued.
+    /// Packets longer than 256 bytes take as many places as 256-byte pieces they are received in.
+    ///
+    pub fn capacity(&self) -> usize {
+        self.shared.ring.capacity()
+    }
+
+    /// The policy applied when a packet arrives and the queue is full.
+    ///
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.shared.policy
+    }
+
+    /// The total number of packets dropped because the queue was full, whatever the overflow policy.
+    ///
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::SeqCst)
+    }
+
+    fn next(&self, state: &mut ReceiverState) -> Result<ReceivedPacket, RecvError> {
+        if let Some(packet) = state.pending.take() {
+            return Ok(packet);
+        }
+        // Read before the queue, so that the packets sent before disconnecting are not missed
+        let disconnected = self.shared.disconnected.load(Ordering::SeqCst);
+        let popped = self.shared.ring.pop(|data| {
+            let packet = ReceivedPacket { timestamp: data.timestamp, data: data.bytes[..data.len].to_vec() };
+            (data.lost_before, packet)
+        });
+        match popped {
+            Some((0, packet)) => Ok(packet),
+            Some((lost, packet)) => {
+                state.pending = Some(packet);
+                Err(RecvError::Overflowed(lost))
+            },
+            None => match self.shared.lost.swap(0, Ordering::SeqCst) {
+                0 if disconnected => Err(RecvError::Disconnected),
+                0 => Err(RecvError::Empty),
+                lost => Err(RecvError::Overflowed(lost)),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "stream")]
+impl Stream for PacketReceiver {
+    type Item = Result<ReceivedPacket, RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut state = self.shared.state();
+        loop {
+            match self.next(&mut state) {
+                Err(RecvError::Empty) => {
+                    state.waker = Some(cx.waker().clone());
+                    if self.shared.waiting.swap(true, Ordering::SeqCst) {
+                        return Poll::Pending;
+                    }
+                },
+                Err(RecvError::Disconnected) => return Poll::Ready(None),
+                result => return Poll::Ready(Some(result)),
+            }
+        }
+    }
+}
+
+/// An iterator over the packets queued in a `PacketReceiver`.
+///
+/// It finishes when there are no more packets queued. Lost packets are reported as errors when
+/// using `OverflowPolicy::Report`.
+///
+pub struct TryIter<'a> {
+    receiver: &'a PacketReceiver,
+}
+
+impl<'a> Iterator for TryIter<'a> {
+    type Item = Result<ReceivedPacket, RecvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.receiver.try_recv() {
+            Err(RecvError::Empty) | Err(RecvError::Disconnected) => None,
+            result => Some(result),
+        }
+    }
+}
+
+/// Creates the two halves of a bounded packet queue.
+pub(crate) fn packet_channel(capacity: usize, policy: OverflowPolicy) -> (PacketSender, PacketReceiver) {
+    assert!(capacity > 0, "The capacity of the queue must be greater than zero");
+    let shared = Arc::new(Shared {
+        ring: Ring::new(capacity),
+        lost: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+        disconnected: AtomicBool::new(false),
+        waiting: AtomicBool::new(false),
+        state: Mutex::new(ReceiverState::default()),
+        available: Condvar::new(),
+        policy,
+    });
+    (PacketSender { shared: shared.clone() }, PacketReceiver { shared })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use PacketBuffer;
+    use super::{packet_channel, OverflowPolicy, ReceivedPacket, RecvError};
+
+    fn packet(timestamp: u64, data: &[u8]) -> ReceivedPacket {
+        ReceivedPacket { timestamp, data: data.to_vec() }
+    }
+
+    fn packets(count: u8) -> PacketBuffer {
+        let mut buffer = PacketBuffer::new(0, &[0x90, 0, 0x7f]);
+        for note in 1..count {
+            buffer.push_data(u64::from(note), &[0x90, note, 0x7f]);
+        }
+        buffer
+    }
+
+    #[test]
+    fn receive_packets_in_order() {
+        let (sender, receiver) = packet_channel(4, OverflowPolicy::DropNewest);
+        let mut buffer = PacketBuffer::new(10, &[0x90, 0x40, 0x7f]);
+        buffer.push_data(20, &[0xf8]);
+        sender.send(&buffer);
+
+        assert_eq!(receiver.try_recv(), Ok(packet(10, &[0x90, 0x40, 0x7f])));
+        assert_eq!(receiver.try_recv(), Ok(packet(20, &[0xf8])));
+        assert_eq!(receiver.try_recv(), Err(RecvError::Empty));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvError::Timeout));
+
+        drop(sender);
+        assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn overflow_drop_oldest() {
+        let (sender, receiver) = packet_channel(2, OverflowPolicy::DropOldest);
+        sender.send(&packets(4));
+
+        let timestamps: Vec<_> = receiver.try_iter().map(|packet| packet.unwrap().timestamp).collect();
+        assert_eq!(timestamps, vec![2, 3]);
+        assert_eq!(receiver.dropped(), 2);
+    }
+
+    #[test]
+    fn overflow_drop_newest() {
+        let (sender, receiver) = packet_channel(2, OverflowPolicy::DropNewest);
+        sender.send(&packets(4));
+
+        let timestamps: Vec<_> = receiver.try_iter().map(|packet| packet.unwrap().timestamp).collect();
+        assert_eq!(timestamps, vec![0, 1]);
+        assert_eq!(receiver.dropped(), 2);
+    }
+
+    #[test]
+    fn overflow_report() {
+        let (sender, receiver) = packet_channel(2, OverflowPolicy::Report);
+        sender.send(&packets(5));
+        assert_eq!(receiver.try_recv().map(|packet| packet.timestamp), Ok(0));
+        sender.send(&PacketBuffer::new(7, &[0xfe]));
+
+        let received: Vec<_> = receiver.try_iter().map(|result| result.map(|packet| packet.timestamp)).collect();
+        assert_eq!(received, vec![Ok(1), Err(RecvError::Overflowed(3)), Ok(7)]);
+        assert_eq!(receiver.dropped(), 3);
+
+        sender.send(&packets(3));
+        let received: Vec<_> = receiver.try_iter().map(|result| result.map(|packet| packet.timestamp)).collect();
+        assert_eq!(received, vec![Ok(0), Ok(1), Err(RecvError::Overflowed(1))]);
+        assert_eq!(receiver.try_recv(), Err(RecvError::Empty));
+    }
+
+    #[test]
+    fn long_packets_are_split() {
+        let (sender, receiver) = packet_channel(4, OverflowPolicy::DropNewest);
+        let mut sysex = vec![0x01; 300];
+        sysex[0] = 0xf0;
+        sysex[299] = 0xf7;
+        sender.send(&PacketBuffer::new(9, &sysex));
+        sender.send(&PacketBuffer::new(10, &[0x01; 1000]));
+
+        assert_eq!(receiver.try_recv(), Ok(packet(9, &sysex[..256])));
+        assert_eq!(receiver.try_recv(), Ok(packet(9, &sysex[256..])));
+        assert_eq!(receiver.try_recv(), Err(RecvError::Empty));
+        assert_eq!(receiver.dropped(), 1);
+    }
+
+    #[test]
+    fn drop_oldest_drops_new_packets_while_the_oldest_is_read() {
+        let (sender, receiver) = packet_channel(2, OverflowPolicy::DropOldest);
+        sender.send(&packets(2));
+        let shared = receiver.shared.clone();
+        let read_while_sending = shared.ring.pop(|data| {
+            sender.send(&PacketBuffer::new(5, &[0xfe]));
+            data.timestamp
+        });
+
+        assert_eq!(read_while_sending, Some(0));
+        let timestamps: Vec<_> = receiver.try_iter().map(|packet| packet.unwrap().timestamp).collect();
+        assert_eq!(timestamps, vec![1]);
+        assert_eq!(receiver.dropped(), 1);
+    }
+
+    #[test]
+    fn recv_blocks_until_a_packet_arrives() {
+        let (sender, receiver) = packet_channel(1, OverflowPolicy::DropNewest);
+        let handle = thread::spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(10));
+        sender.send(&PacketBuffer::new(5, &[0xfa]));
+        assert_eq!(handle.join().unwrap(), Ok(packet(5, &[0xfa])));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn stream_yields_packets_until_disconnected() {
+        use std::pin::Pin;
+        use std::ptr;
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+        use futures_core::Stream;
+
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker { noop_raw_waker() }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut context = Context::from_waker(&waker);
+        let (sender, mut receiver) = packet_channel(2, OverflowPolicy::DropNewest);
+
+        assert_eq!(Pin::new(&mut receiver).poll_next(&mut context), Poll::Pending);
+        sender.send(&PacketBuffer::new(3, &[0xfc]));
+        assert_eq!(Pin::new(&mut receiver).poll_next(&mut context), Poll::Ready(Some(Ok(packet(3, &[0xfc])))));
+        drop(sender);
+        assert_eq!(Pin::new(&mut receiver).poll_next(&mut context), Poll::Ready(None));
+    }
+}
@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use {
+    Client,
+    Error,
+    HostTime,
+    InputPortWithContext,
+    MessageParser,
+    MidiMessage,
+    PacketList,
+    Port,
+    Source,
+    VirtualDestination,
+};
+use backend::sys::MIDIEndpointRef;
+use packets::Timestamp;
+use smf::{self, EventKind, Format, MetaEvent, Smf, TempoMap, Track, TrackEvent};
+
+/// A message recorded by a `Recorder`, with the timestamp it arrived with and its position in ticks.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordedEvent {
+    pub timestamp: Timestamp,
+    pub ticks: u64,
+    pub message: MidiMessage,
+}
+
+/// The messages recorded from a source, sorted by their position in ticks.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordedTrack {
+    pub name: String,
+    pub events: Vec<RecordedEvent>,
+}
+
+/// What a `Recorder` has recorded, with a track for each source.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    pub tempo_map: TempoMap,
+    pub tracks: Vec<RecordedTrack>,
+}
+
+impl Recording {
+    /// Convert the recording into a format 1 Standard MIDI File, with a first track with the tempo changes,
+    /// followed by a track for each source named after it.
+    ///
+    pub fn to_smf(&self) -> Smf {
+        let mut smf = Smf::new(Format::Parallel, self.tempo_map.division());
+
+        let mut tempo_track = Track::new();
+        let mut last_ticks = 0;
+        for (ticks, tempo) in self.tempo_map.tempo_changes() {
+            tempo_track.events.push(TrackEvent { delta: smf::delta(last_ticks, ticks), kind: EventKind::Meta(MetaEvent::Tempo(tempo)) });
+            last_ticks = ticks;
+        }
+        tempo_track.events.push(TrackEvent { delta: 0, kind: EventKind::Meta(MetaEvent::EndOfTrack) });
+        smf.tracks.push(tempo_track);
+
+        for recorded in &self.tracks {
+            let mut track = Track::from_messages(recorded.events.iter().map(|event| (event.ticks, event.message.clone())));
+            track.events.insert(0, TrackEvent { delta: 0, kind: EventKind::Meta(MetaEvent::TrackName(recorded.name.clone().into_bytes())) });
+            smf.tracks.push(track);
+        }
+        smf
+    }
+}
+
+enum Command {
+    AddTrack(usize, String),
+    Packets(usize, Vec<(Timestamp, Vec<u8>)>),
+    Start(Timestamp),
+    Rewind(Timestamp),
+    PunchIn(Timestamp),
+    Stop(Timestamp),
+    Recording(Sender<Recording>),
+    Shutdown,
+}
+
+/// Records the MIDI messages sent to it from an input callback into a track of a `Recorder`.
+///
+/// It only copies the packets and hands them to the recorder worker thread,
+/// so it can be used from the realtime threads running the callbacks.
+///
+#[derive(Clone, Debug)]
+pub struct TrackInput {
+    index: usize,
+    sender: Sender<Command>,
+}
+
+impl TrackInput {
+    /// Record the packets received from the source of the track.
+    /// The packets without a timestamp are recorded at the current host time.
+    ///
+    pub fn record(&self, packet_list: &PacketList) {
+        let mut now = None;
+        let packets = packet_list.iter().map(|packet| {
+            let timestamp = match packet.timestamp() {
+                0 => *now.get_or_insert_with(|| HostTime::now().timestamp()),
+                timestamp => timestamp,
+            };
+            (timestamp, packet.data().to_vec())
+        }).collect();
+        // Nothing is recorded once the recorder is gone
+        let _ = self.sender.send(Command::Packets(self.index, packets));
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TrackFactory {
+    sender: Sender<Command>,
+    next_index: Arc<AtomicUsize>,
+}
+
+impl TrackFactory {
+    fn track_input(&self, name: &str) -> TrackInput {
+        let index = self.next_index.fetch_add(1, Ordering::SeqCst);
+        let _ = self.sender.send(Command::AddTrack(index, name.to_string()));
+        TrackInput { index, sender: self.sender.clone() }
+    }
+}
+
+/// Records the MIDI messages arriving at input ports and virtual destinations, with a track for each source.
+///
+/// The messages keep the timestamps they arrived with, and are placed in ticks from the start of the
+/// recording with a tempo map. The callbacks only copy the packets, and a worker thread parses them
+/// and converts their timestamps, so recording doesn't hold the realtime threads up.
+///
+/// Only the messages with a timestamp between a `start` or `punch_in` and the following `stop` are recorded.
+/// A punch in replaces what was recorded before from there until the punch out, usually in another pass
+/// over the recording started with `rewind`:
+///
+/// ```rust,no_run
+/// use coremidi::{Client, Recorder, Sources};
+/// use coremidi::smf::{Division, TempoMap};
+///
+/// let client = Client::new("example-client").unwrap();
+/// let recorder = Recorder::new(TempoMap::new(Division::TicksPerQuarterNote(480)));
+/// let mut input_port = recorder.input_port(&client, "example-port").unwrap();
+/// for source in Sources {
+///     input_port.connect_source(&source).unwrap();
+/// }
+/// recorder.start();
+/// // ...
+/// recorder.stop();
+/// let smf = recorder.recording().to_smf();
+/// ```
+#[derive(Debug)]
+pub struct Recorder {
+    tracks: TrackFactory,
+    tempo_map: TempoMap,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    /// Create a recorder placing the messages in ticks with the tempo map, and start its worker thread.
+    ///
+    pub fn new(tempo_map: TempoMap) -> Recorder {
+        let (sender, receiver) = mpsc::channel();
+        let mut state = RecorderState::new(tempo_map.clone());
+        let worker = thread::spawn(move || {
+            for command in receiver {
+                match command {
+                    Command::AddTrack(index, name) => state.add_track(index, name),
+                    Command::Packets(index, packets) => state.record(index, &packets),
+                    Command::Start(timestamp) => state.start(timestamp),
+                    Command::Rewind(timestamp) => state.rewind(timestamp),
+                    Command::PunchIn(timestamp) => state.punch_in(timestamp),
+                    Command::Stop(timestamp) => state.stop(timestamp),
+                    Command::Recording(reply) => { let _ = reply.send(state.recording()); },
+                    Command::Shutdown => break,
+                }
+            }
+        });
+        Recorder {
+            tracks: TrackFactory { sender, next_index: Arc::new(AtomicUsize::new(0)) },
+            tempo_map,
+            worker: Some(worker),
+        }
+    }
+
+    /// Add a track, and get the input to record into it from any callback.
+    ///
+    pub fn track_input(&self, name: &str) -> TrackInput {
+        self.tracks.track_input(name)
+    }
+
+    /// Create an input port recording each source connected to it into its own track.
+    ///
+    pub fn input_port(&self, client: &Client, name: &str) -> Result<RecorderPort, Error> {
+        let port = client.input_port_with_context(name, |packet_list, input: &mut TrackInput| input.record(packet_list))?;
+        Ok(RecorderPort { port, tracks: self.tracks.clone(), inputs: HashMap::new() })
+    }
+
+    /// Create a virtual destination recording what is sent to it into a track with its name.
+    ///
+    pub fn virtual_destination(&self, client: &Client, name: &str) -> Result<VirtualDestination, Error> {
+        let input = self.track_input(name);
+        client.virtual_destination(name, move |packet_list| input.record(packet_list))
+    }
+
+    /// Start a new recording now, discarding what was recorded before.
+    ///
+    pub fn start(&self) {
+        self.start_at(HostTime::now().timestamp());
+    }
+
+    /// Start a new recording at a host time, which is placed at tick 0, discarding what was recorded before.
+    ///
+    pub fn start_at(&self, timestamp: Timestamp) {
+        self.send(Command::Start(timestamp));
+    }
+
+    /// Start another pass over the recording now, without recording until the punch in.
+    ///
+    pub fn rewind(&self) {
+        self.rewind_at(HostTime::now().timestamp());
+    }
+
+    /// Start another pass over the recording, placing tick 0 at a host time, without recording until the punch in.
+    ///
+    pub fn rewind_at(&self, timestamp: Timestamp) {
+        self.send(Command::Rewind(timestamp));
+    }
+
+    /// Resume recording now, replacing what was recorded before until the next `stop`.
+    ///
+    pub fn punch_in(&self) {
+        self.punch_in_at(HostTime::now().timestamp());
+    }
+
+    /// Resume recording at a host time, replacing what was recorded before until the next `stop`.
+    /// It starts a new recording when there is none.
+    ///
+    pub fn punch_in_at(&self, timestamp: Timestamp) {
+        self.send(Command::PunchIn(timestamp));
+    }
+
+    /// Stop recording now.
+    ///
+    pub fn stop(&self) {
+        self.stop_at(HostTime::now().timestamp());
+    }
+
+    /// Stop recording the messages arriving from a host time on.
+    ///
+    pub fn stop_at(&self, timestamp: Timestamp) {
+        self.send(Command::Stop(timestamp));
+    }
+
+    /// Get what has been recorded, once the worker has handled the packets received so far.
+    ///
+    pub fn recording(&self) -> Recording {
+        let (reply, receiver) = mpsc::channel();
+        self.send(Command::Recording(reply));
+        receiver.recv().unwrap_or_else(|_| Recording { tempo_map: self.tempo_map.clone(), tracks: Vec::new() })
+    }
+
+    fn send(&self, command: Command) {
+        let _ = self.tracks.sender.send(command);
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.send(Command::Shutdown);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// An input port created by a `Recorder`, which records each source connected to it into its own track.
+///
+/// A source connected again keeps recording into the same track.
+///
+#[derive(Debug)]
+pub struct RecorderPort {
+    port: InputPortWithContext<TrackInput>,
+    tracks: TrackFactory,
+    inputs: HashMap<MIDIEndpointRef, TrackInput>,
+}
+
+impl RecorderPort {
+    /// Connect a source, recording it into a track named after its display name.
+    /// Connecting a source that is already connected does nothing.
+    ///
+    pub fn connect_source(&mut self, source: &Source) -> Result<(), Error> {
+        if self.port.is_connected(source) {
+            return Ok(());
+        }
+        let tracks = &self.tracks;
+        let input = self.inputs.entry(source.object.0).or_insert_with(|| {
+            let name = source.display_name().or_else(|| source.name()).unwrap_or_default();
+            tracks.track_input(&name)
+        }).clone();
+        self.port.connect_source(source, input)
+    }
+
+    /// Disconnect a source, which track is kept in the recording.
+    ///
+    pub fn disconnect_source(&mut self, source: &Source) -> Result<(), Error> {
+        self.port.disconnect_source(source)
+    }
+}
+
+impl Deref for RecorderPort {
+    type Target = Port;
+
+    fn deref(&self) -> &Port {
+        &self.port
+    }
+}
+
+// A period of time when the messages are recorded, with the host time of tick 0 for that pass
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    origin: Timestamp,
+    start: Timestamp,
+    end: Option<Timestamp>,
+}
+
+impl Window {
+    fn contains(&self, timestamp: Timestamp) -> bool {
+        self.start <= timestamp && self.end.map_or(true, |end| timestamp < end)
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrackState {
+    name: String,
+    parser: MessageParser,
+    // The recorded events, with the index of the window they were recorded in
+    events: Vec<(usize, RecordedEvent)>,
+}
+
+// What the worker thread knows about the recording
+#[derive(Debug)]
+struct RecorderState {
+    tempo_map: TempoMap,
+    origin: Option<Timestamp>,
+    windows: Vec<Window>,
+    tracks: Vec<TrackState>,
+}
+
+impl RecorderState {
+    fn new(tempo_map: TempoMap) -> RecorderState {
+        RecorderState { tempo_map, origin: None, windows: Vec::new(), tracks: Vec::new() }
+    }
+
+    fn add_track(&mut self, index: usize, name: String) {
+        if self.tracks.len() <= index {
+            self.tracks.resize_with(index + 1, TrackState::default);
+        }
+        self.tracks[index].name = name;
+    }
+
+    fn start(&mut self, timestamp: Timestamp) {
+        for track in &mut self.tracks {
+            track.events.clear();
+        }
+        self.origin = Some(timestamp);
+        self.windows = vec![Window { origin: timestamp, start: timestamp, end: None }];
+    }
+
+    fn rewind(&mut self, timestamp: Timestamp) {
+        self.stop(timestamp);
+        self.origin = Some(timestamp);
+    }
+
+    fn punch_in(&mut self, timestamp: Timestamp) {
+        match self.origin {
+            Some(origin) => {
+                self.stop(timestamp);
+                self.windows.push(Window { origin, start: timestamp, end: None });
+            },
+            None => self.start(timestamp),
+        }
+    }
+
+    fn stop(&mut self, timestamp: Timestamp) {
+        if let Some(window) = self.windows.last_mut() {
+            if window.end.is_none() {
+                window.end = Some(timestamp.max(window.start));
+            }
+        }
+    }
+
+    fn record(&mut self, index: usize, packets: &[(Timestamp, Vec<u8>)]) {
+        if self.tracks.len() <= index {
+            self.tracks.resize_with(index + 1, TrackState::default);
+        }
+        let track = &mut self.tracks[index];
+        for &(timestamp, ref data) in packets {
+            // Every packet goes through the parser, to keep track of running status and split messages
+            for (timestamp, message) in track.parser.parse_data(timestamp, data) {
+                let window = self.windows.iter().rposition(|window| window.contains(timestamp));
+                if let (Ok(message), Some(window)) = (message, window) {
+                    let ticks = ticks_at(&self.tempo_map, self.windows[window].origin, timestamp);
+                    track.events.push((window, RecordedEvent { timestamp, ticks, message }));
+                }
+            }
+        }
+    }
+
+    fn recording(&self) -> Recording {
+        // The ticks recorded by each window, which replace what the previous ones recorded
+        let ranges: Vec<(u64, Option<u64>)> = self.windows.iter().map(|window| {
+            (ticks_at(&self.tempo_map, window.origin, window.start),
+             window.end.map(|end| ticks_at(&self.tempo_map, window.origin, end)))
+        }).collect();
+        let replaced = |window: usize, ticks: u64| {
+            ranges[window + 1..].iter().any(|&(start, end)| start <= ticks && end.map_or(true, |end| ticks < end))
+        };
+
+        let tracks = self.tracks.iter().map(|track| {
+            let mut events: Vec<_> = track.events.iter()
+                .filter(|&&(window, ref event)| !replaced(window, event.ticks))
+                .map(|&(_, ref event)| event.clone())
+                .collect();
+            events.sort_by_key(|event| event.ticks);
+            RecordedTrack { name: track.name.clone(), events }
+        }).collect();
+        Recording { tempo_map: self.tempo_map.clone(), tracks }
+    }
+}
+
+fn ticks_at(tempo_map: &TempoMap, origin: Timestamp, timestamp: Timestamp) -> u64 {
+    let nanos = HostTime::from_timestamp(timestamp).as_nanos().saturating_sub(HostTime::from_timestamp(origin).as_nanos());
+    tempo_map.nanos_to_ticks(nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use {HostTime, MidiMessage, PacketBuffer};
+    use packets::Timestamp;
+    use smf::{Division, EventKind, MetaEvent, Smf, TempoMap};
+    use super::{RecordedEvent, Recorder};
+
+    fn at(millis: u64) -> Timestamp {
+        HostTime::from_nanos(millis * 1_000_000).timestamp()
+    }
+
+    fn note_on(timestamp: Timestamp, ticks: u64, channel: u8, note: u8) -> RecordedEvent {
+        RecordedEvent { timestamp, ticks, message: MidiMessage::NoteOn { channel, note, velocity: 100 } }
+    }
+
+    fn recorder() -> Recorder {
+        Recorder::new(TempoMap::new(Division::TicksPerQuarterNote(480)))
+    }
+
+    #[test]
+    fn records_a_track_per_input_in_ticks() {
+        let recorder = recorder();
+        let keys = recorder.track_input("keys");
+        let drums = recorder.track_input("drums");
+
+        keys.record(&PacketBuffer::new(at(900), &[0x90, 60, 100]));
+        recorder.start_at(at(1000));
+        keys.record(&PacketBuffer::new(at(1000), &[0x90, 60, 100]));
+        let mut buffer = PacketBuffer::new(at(1500), &[0x90, 62, 100]);
+        buffer.push_data(at(1750), &[64, 100]);
+        keys.record(&buffer);
+        drums.record(&PacketBuffer::new(at(1250), &[0x99, 36, 100]));
+        recorder.stop_at(at(2000));
+        keys.record(&PacketBuffer::new(at(2000), &[0x90, 65, 100]));
+
+        let recording = recorder.recording();
+        assert_eq!(recording.tracks.len(), 2);
+        assert_eq!(recording.tracks[0].name, "keys");
+        assert_eq!(recording.tracks[0].events, vec![
+            note_on(at(1000), 0, 0, 60),
+            note_on(at(1500), 480, 0, 62),
+            note_on(at(1750), 720, 0, 64),
+        ]);
+        assert_eq!(recording.tracks[1].name, "drums");
+        assert_eq!(recording.tracks[1].events, vec![note_on(at(1250), 240, 9, 36)]);
+    }
+
+    #[test]
+    fn punch_in_replaces_the_recorded_messages() {
+        let recorder = recorder();
+        let keys = recorder.track_input("keys");
+
+        recorder.start_at(at(1000));
+        keys.record(&PacketBuffer::new(at(1000), &[0x90, 60, 100]));
+        keys.record(&PacketBuffer::new(at(1500), &[0x90, 62, 100]));
+        keys.record(&PacketBuffer::new(at(2000), &[0x90, 64, 100]));
+        recorder.stop_at(at(2250));
+
+        recorder.rewind_at(at(5000));
+        keys.record(&PacketBuffer::new(at(5100), &[0x90, 70, 100]));
+        recorder.punch_in_at(at(5400));
+        keys.record(&PacketBuffer::new(at(5500), &[0x90, 72, 100]));
+        recorder.stop_at(at(5800));
+        keys.record(&PacketBuffer::new(at(5900), &[0x90, 74, 100]));
+
+        assert_eq!(recorder.recording().tracks[0].events, vec![
+            note_on(at(1000), 0, 0, 60),
+            note_on(at(5500), 480, 0, 72),
+            note_on(at(2000), 960, 0, 64),
+        ]);
+
+        recorder.start_at(at(8000));
+        assert_eq!(recorder.recording().tracks[0].events, vec![]);
+    }
+
+    #[test]
+    fn recording_to_smf() {
+        let recorder = Recorder::new({
+            let mut tempo_map = TempoMap::new(Division::TicksPerQuarterNote(480));
+            tempo_map.set_tempo(960, 250_000);
+            tempo_map
+        });
+        let keys = recorder.track_input("keys");
+        recorder.start_at(at(1000));
+        keys.record(&PacketBuffer::new(at(1500), &[0x90, 60, 100]));
+        keys.record(&PacketBuffer::new(at(2250), &[0xF0, 0x7E, 0x7F, 0xF7]));
+        recorder.stop_at(at(3000));
+
+        let smf = recorder.recording().to_smf();
+        assert_eq!(smf.tracks.len(), 2);
+        assert_eq!(smf.tracks[0].timed_events().collect::<Vec<_>>(), vec![
+            (0, &EventKind::Meta(MetaEvent::Tempo(500_000))),
+            (960, &EventKind::Meta(MetaEvent::Tempo(250_000))),
+            (960, &EventKind::Meta(MetaEvent::EndOfTrack)),
+        ]);
+        assert_eq!(smf.tracks[1].events[0].kind, EventKind::Meta(MetaEvent::TrackName(b"keys".to_vec())));
+        assert_eq!(smf.tracks[1].messages(), vec![
+            (480, MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 }),
+            (1440, MidiMessage::SysEx(vec![0x7E, 0x7F])),
+        ]);
+        assert_eq!(Smf::from_bytes(&smf.to_bytes().unwrap()), Ok(smf));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn records_sources_and_virtual_destinations() {
+        use {Client, Destination, Source};
+
+        let client = Client::new("recorder-client").unwrap();
+        let recorder = recorder();
+        let source = client.virtual_source("recorder-source").unwrap();
+        let mut input_port = recorder.input_port(&client, "recorder-port").unwrap();
+        let found = Source::find_by_name("recorder-source").unwrap();
+        input_port.connect_source(&found).unwrap();
+        input_port.connect_source(&found).unwrap();
+        let _destination = recorder.virtual_destination(&client, "recorder-destination").unwrap();
+        let output_port = client.output_port("recorder-output").unwrap();
+
+        recorder.start_at(at(1000));
+        source.received(&PacketBuffer::new(at(1500), &[0x90, 60, 100])).unwrap();
+        let destination = Destination::find_by_name("recorder-destination").unwrap();
+        output_port.send(&destination, &PacketBuffer::new(at(2000), &[0x90, 62, 100])).unwrap();
+
+        let recording = recorder.recording();
+        let names: Vec<_> = recording.tracks.iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, vec!["recorder-source", "recorder-destination"]);
+        assert_eq!(recording.tracks[0].events, vec![note_on(at(1500), 480, 0, 60)]);
+        assert_eq!(recording.tracks[1].events, vec![note_on(at(2000), 960, 0, 62)]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use {Destinations, Devices, Entity, Error, ExternalDevices, Object, PropertyValue, Sources};
+
+/// The properties of a MIDI object, keyed by their names.
+///
+pub type PropertyMap = BTreeMap<String, PropertyValue>;
+
+/// The MIDI setup of the system, made of devices, entities and endpoints.
+///
+/// It can be captured into a `SetupSnapshot`, which is a plain copy of every object and its
+/// properties that can be inspected offline. With the `serde` feature enabled the snapshot can
+/// be serialized, for example to attach a JSON dump of the setup to a bug report:
+///
+/// ```rust,no_run
+/// let snapshot = coremidi::Setup::snapshot().unwrap();
+/// for device in &snapshot.devices {
+///     println!("{:?} has {} entities", device.properties.get("name"), device.entities.len());
+/// }
+/// ```
+///
+pub struct Setup;
+
+impl Setup {
+    /// Capture the devices, the external devices and the virtual endpoints in the system,
+    /// together with all their properties.
+    /// Objects whose properties can't be read, like the ones removed while the snapshot is being
+    /// taken, are left out of it.
+    /// See `MIDIObjectGetProperties`.
+    ///
+    pub fn snapshot() -> Result<SetupSnapshot, Error> {
+        Ok(SetupSnapshot {
+            devices: Devices.into_iter()
+                .filter_map(|device| DeviceSnapshot::new(&device, device.entities()).ok())
+                .collect(),
+            external_devices: ExternalDevices.into_iter()
+                .filter_map(|device| DeviceSnapshot::new(&device, device.entities()).ok())
+                .collect(),
+            virtual_sources: Sources.into_iter()
+                .filter(|source| source.entity().is_none())
+                .filter_map(|source| EndpointSnapshot::new(&source).ok())
+                .collect(),
+            virtual_destinations: Destinations.into_iter()
+                .filter(|destination| destination.entity().is_none())
+                .filter_map(|destination| EndpointSnapshot::new(&destination).ok())
+                .collect(),
+        })
+    }
+}
+
+/// A copy of the MIDI setup taken with `Setup::snapshot`.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SetupSnapshot {
+    pub devices: Vec<DeviceSnapshot>,
+    pub external_devices: Vec<DeviceSnapshot>,
+    /// The sources that don't belong to any entity, like the ones created by applications.
+    pub virtual_sources: Vec<EndpointSnapshot>,
+    /// The destinations that don't belong to any entity, like the ones created by applications.
+    pub virtual_destinations: Vec<EndpointSnapshot>,
+}
+
+/// A copy of a device, or an external device, and its entities.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceSnapshot {
+    pub properties: PropertyMap,
+    pub entities: Vec<EntitySnapshot>,
+}
+
+impl DeviceSnapshot {
+    fn new<I: Iterator<Item = Entity>>(device: &Object, entities: I) -> Result<DeviceSnapshot, Error> {
+        Ok(DeviceSnapshot {
+            properties: device.properties(false)?,
+            entities: entities.filter_map(|entity| EntitySnapshot::new(&entity).ok()).collect(),
+        })
+    }
+}
+
+/// A copy of an entity and its endpoints.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EntitySnapshot {
+    pub properties: PropertyMap,
+    pub sources: Vec<EndpointSnapshot>,
+    pub destinations: Vec<EndpointSnapshot>,
+}
+
+impl EntitySnapshot {
+    fn new(entity: &Entity) -> Result<EntitySnapshot, Error> {
+        Ok(EntitySnapshot {
+            properties: entity.properties(false)?,
+            sources: entity.sources().filter_map(|source| EndpointSnapshot::new(&source).ok()).collect(),
+            destinations: entity.destinations().filter_map(|destination| EndpointSnapshot::new(&destination).ok()).collect(),
+        })
+    }
+}
+
+/// A copy of a source or a destination.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EndpointSnapshot {
+    pub properties: PropertyMap,
+}
+
+impl EndpointSnapshot {
+    fn new(endpoint: &Object) -> Result<EndpointSnapshot, Error> {
+        Ok(EndpointSnapshot { properties: endpoint.properties(false)? })
+    }
+}
+
+#[cfg(all(test, not(target_os = "macos")))]
+mod tests {
+    use backend::{add_device, remove_device};
+    use {Client, PropertyValue, Setup};
+
+    fn name(value: &str) -> PropertyValue {
+        PropertyValue::String(value.to_string())
+    }
+
+    #[test]
+    fn snapshot_captures_devices_and_virtual_endpoints() {
+        let device_ref = add_device("snapshot-device", false, &[("snapshot-port", 1, 2)]);
+        let client = Client::new("snapshot-client").unwrap();
+        let _source = client.virtual_source("snapshot-source").unwrap();
+
+        let snapshot = Setup::snapshot().unwrap();
+        let device = snapshot.devices.iter()
+            .find(|device| device.properties.get("name") == Some(&name("snapshot-device")))
+            .unwrap();
+        assert_eq!(device.entities.len(), 1);
+        assert_eq!(device.entities[0].properties.get("name"), Some(&name("snapshot-port")));
+        assert_eq!(device.entities[0].sources.len(), 1);
+        assert_eq!(device.entities[0].destinations.len(), 2);
+
+        let source_names: Vec<_> = snapshot.virtual_sources.iter().map(|source| source.properties.get("name")).collect();
+        assert!(source_names.contains(&Some(&name("snapshot-source"))));
+        assert!(!source_names.contains(&Some(&name("snapshot-port"))));
+        remove_device(device_ref);
+    }
+}
@@ -0,0 +1,1113 @@
+//! Reading and writing Standard MIDI Files, and converting their tracks to and from
+//! the MIDI messages and packet lists of this crate.
+//!
+//! A file is loaded into a `Smf`, made of `Track`s with the `TrackEvent`s as they are stored
+//! in the file, so it can be written back without losing anything. The tracks can then be
+//! converted into MIDI messages with their position in ticks, or into a `PacketBuffer` with
+//! the timestamps computed from the tempo changes in the file:
+//!
+//! ```rust,no_run
+//! use std::fs::File;
+//! use coremidi::{Client, Destination, HostTime};
+//! use coremidi::smf::Smf;
+//!
+//! let smf = Smf::read(&mut File::open("song.mid").unwrap()).unwrap();
+//! let client = Client::new("example-client").unwrap();
+//! let output_port = client.output_port("example-port").unwrap();
+//! let destination = Destination::from_index(0).unwrap();
+//! output_port.send(&destination, &smf.to_packets(HostTime::now().timestamp())).unwrap();
+//! ```
+
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use {HostTime, MessageParser, MidiMessage, PacketBuffer, PacketList};
+use packets::Timestamp;
+
+const HEADER_CHUNK: &[u8] = b"MThd";
+const TRACK_CHUNK: &[u8] = b"MTrk";
+
+const SYSEX: u8 = 0xF0;
+const ESCAPE: u8 = 0xF7;
+const META: u8 = 0xFF;
+
+const META_SEQUENCE_NUMBER: u8 = 0x00;
+const META_TEXT: u8 = 0x01;
+const META_COPYRIGHT: u8 = 0x02;
+const META_TRACK_NAME: u8 = 0x03;
+const META_INSTRUMENT_NAME: u8 = 0x04;
+const META_LYRIC: u8 = 0x05;
+const META_MARKER: u8 = 0x06;
+const META_CUE_POINT: u8 = 0x07;
+const META_CHANNEL_PREFIX: u8 = 0x20;
+const META_PORT: u8 = 0x21;
+const META_END_OF_TRACK: u8 = 0x2F;
+const META_TEMPO: u8 = 0x51;
+const META_SMPTE_OFFSET: u8 = 0x54;
+const META_TIME_SIGNATURE: u8 = 0x58;
+const META_KEY_SIGNATURE: u8 = 0x59;
+const META_SEQUENCER_SPECIFIC: u8 = 0x7F;
+
+/// The largest value that fits in a variable length quantity.
+const MAX_VARIABLE_LENGTH: u32 = 0x0FFF_FFFF;
+
+/// The tempo used until the first tempo change, in microseconds per quarter note (120 beats per minute).
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+/// The errors found when reading or writing a Standard MIDI File.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SmfError {
+    /// The data doesn't start with a header chunk.
+    MissingHeader,
+    /// The header chunk is too short, or the format is not 0, 1 or 2.
+    InvalidHeader,
+    /// The number of frames per second of an SMPTE division is not 24, 25, 29 or 30.
+    InvalidDivision,
+    /// The data ends in the middle of a chunk or an event, or there are less tracks than declared.
+    UnexpectedEnd,
+    /// A variable length quantity is longer than 4 bytes, or a value is too large to be written as one.
+    InvalidLength,
+    /// A data byte was found where an event was expected, without a previous channel message to use its status.
+    MissingStatus,
+    /// An event with this status byte is not allowed in a track, or has data bytes out of range.
+    /// System common and real-time messages must be stored as escape events.
+    InvalidEvent(u8),
+}
+
+impl fmt::Display for SmfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SmfError::MissingHeader => write!(f, "not a standard MIDI file, the header chunk is missing"),
+            SmfError::InvalidHeader => write!(f, "invalid header chunk"),
+            SmfError::InvalidDivision => write!(f, "invalid SMPTE division"),
+            SmfError::UnexpectedEnd => write!(f, "unexpected end of the file"),
+            SmfError::InvalidLength => write!(f, "invalid variable length quantity"),
+            SmfError::MissingStatus => write!(f, "running status without a previous channel message"),
+            SmfError::InvalidEvent(status) => write!(f, "invalid event with status {:02x}", status),
+        }
+    }
+}
+
+impl error::Error for SmfError {}
+
+/// How the tracks of a file are organised.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Format {
+    /// Format 0: a single track with the events of every channel.
+    SingleTrack,
+    /// Format 1: several tracks played at the same time, where the first one usually has the tempo map.
+    Parallel,
+    /// Format 2: several independent sequences, like patterns, each one with its own tempo map.
+    Sequential,
+}
+
+impl Format {
+    fn from_raw(format: u16) -> Result<Format, SmfError> {
+        match format {
+            0 => Ok(Format::SingleTrack),
+            1 => Ok(Format::Parallel),
+            2 => Ok(Format::Sequential),
+            _ => Err(SmfError::InvalidHeader),
+        }
+    }
+
+    fn to_raw(self) -> u16 {
+        match self {
+            Format::SingleTrack => 0,
+            Format::Parallel => 1,
+            Format::Sequential => 2,
+        }
+    }
+}
+
+/// The meaning of the ticks that measure the time between events.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Division {
+    /// Musical time, with this number of ticks per quarter note. The duration of a tick depends on the tempo.
+    TicksPerQuarterNote(u16),
+    /// Absolute time, with a number of ticks per SMPTE frame, where the frames per second are
+    /// 24, 25, 29 (for 29.97 drop frame) or 30.
+    Smpte { frames_per_second: u8, ticks_per_frame: u8 },
+}
+
+impl Division {
+    fn from_raw(division: u16) -> Result<Division, SmfError> {
+        if division & 0x8000 == 0 {
+            return Ok(Division::TicksPerQuarterNote(division));
+        }
+        let frames_per_second = ((division >> 8) as u8 as i8).wrapping_neg() as u8;
+        match frames_per_second {
+            24 | 25 | 29 | 30 => Ok(Division::Smpte { frames_per_second, ticks_per_frame: division as u8 }),
+            _ => Err(SmfError::InvalidDivision),
+        }
+    }
+
+    fn to_raw(self) -> Result<u16, SmfError> {
+        match self {
+            Division::TicksPerQuarterNote(ticks) if ticks < 0x8000 => Ok(ticks),
+            Division::TicksPerQuarterNote(_) => Err(SmfError::InvalidDivision),
+            Division::Smpte { frames_per_second, ticks_per_frame } => match frames_per_second {
+                24 | 25 | 29 | 30 => Ok(u16::from(-(frames_per_second as i8) as u8) << 8 | u16::from(ticks_per_frame)),
+                _ => Err(SmfError::InvalidDivision),
+            },
+        }
+    }
+}
+
+/// A meta event, which describes the sequence but is not sent to MIDI devices.
+///
+/// The texts are kept as they are stored, as the files don't say which encoding they use,
+/// see `MetaEvent::text` to read them as a string.
+///
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MetaEvent {
+    SequenceNumber(u16),
+    Text(Vec<u8>),
+    Copyright(Vec<u8>),
+    TrackName(Vec<u8>),
+    InstrumentName(Vec<u8>),
+    Lyric(Vec<u8>),
+    Marker(Vec<u8>),
+    CuePoint(Vec<u8>),
+    /// The MIDI channel of the following meta and SysEx events.
+    ChannelPrefix(u8),
+    /// The MIDI port, or cable, of the following events.
+    Port(u8),
+    EndOfTrack,
+    /// The new tempo, in microseconds per quarter note.
+    Tempo(u32),
+    SmpteOffset { hours: u8, minutes: u8, seconds: u8, frames: u8, subframes: u8 },
+    /// The `denominator` is a power of two, so 2 means quarter notes, and 3 eighth notes.
+    /// The `clocks_per_click` are the MIDI clocks (24 per quarter note) between metronome clicks.
+    TimeSignature { numerator: u8, denominator: u8, clocks_per_click: u8, thirty_seconds_per_quarter: u8 },
+    /// The number of sharps, or flats when negative, and whether the key is minor.
+    KeySignature { sharps: i8, minor: bool },
+    SequencerSpecific(Vec<u8>),
+    /// Any other meta event, or a known one with the wrong length.
+    Unknown { meta_type: u8, data: Vec<u8> },
+}
+
+impl MetaEvent {
+    /// Get the text of the text events, like track names, markers and lyrics.
+    /// Bytes that are not valid UTF-8 are replaced.
+    ///
+    pub fn text(&self) -> Option<String> {
+        match *self {
+            MetaEvent::Text(ref text) |
+            MetaEvent::Copyright(ref text) |
+            MetaEvent::TrackName(ref text) |
+            MetaEvent::InstrumentName(ref text) |
+            MetaEvent::Lyric(ref text) |
+            MetaEvent::Marker(ref text) |
+            MetaEvent::CuePoint(ref text) => Some(String::from_utf8_lossy(text).into_owned()),
+            _ => None,
+        }
+    }
+
+    fn from_raw(meta_type: u8, data: &[u8]) -> MetaEvent {
+        match (meta_type, data.len()) {
+            (META_SEQUENCE_NUMBER, 2) => MetaEvent::SequenceNumber(u16::from(data[0]) << 8 | u16::from(data[1])),
+            (META_TEXT, _) => MetaEvent::Text(data.to_vec()),
+            (META_COPYRIGHT, _) => MetaEvent::Copyright(data.to_vec()),
+            (META_TRACK_NAME, _) => MetaEvent::TrackName(data.to_vec()),
+            (META_INSTRUMENT_NAME, _) => MetaEvent::InstrumentName(data.to_vec()),
+            (META_LYRIC, _) => MetaEvent::Lyric(data.to_vec()),
+            (META_MARKER, _) => MetaEvent::Marker(data.to_vec()),
+            (META_CUE_POINT, _) => MetaEvent::CuePoint(data.to_vec()),
+            (META_CHANNEL_PREFIX, 1) => MetaEvent::ChannelPrefix(data[0]),
+            (META_PORT, 1) => MetaEvent::Port(data[0]),
+            (META_END_OF_TRACK, 0) => MetaEvent::EndOfTrack,
+            (META_TEMPO, 3) => MetaEvent::Tempo(u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2])),
+            (META_SMPTE_OFFSET, 5) => MetaEvent::SmpteOffset {
+                hours: data[0], minutes: data[1], seconds: data[2], frames: data[3], subframes: data[4],
+            },
+            (META_TIME_SIGNATURE, 4) => MetaEvent::TimeSignature {
+                numerator: data[0], denominator: data[1], clocks_per_click: data[2], thirty_seconds_per_quarter: data[3],
+            },
+            (META_KEY_SIGNATURE, 2) => MetaEvent::KeySignature { sharps: data[0] as i8, minor: data[1] != 0 },
+            (META_SEQUENCER_SPECIFIC, _) => MetaEvent::SequencerSpecific(data.to_vec()),
+            _ => MetaEvent::Unknown { meta_type, data: data.to_vec() },
+        }
+    }
+
+    fn to_raw(&self) -> (u8, Vec<u8>) {
+        match *self {
+            MetaEvent::SequenceNumber(number) => (META_SEQUENCE_NUMBER, vec![(number >> 8) as u8, number as u8]),
+            MetaEvent::Text(ref text) => (META_TEXT, text.clone()),
+            MetaEvent::Copyright(ref text) => (META_COPYRIGHT, text.clone()),
+            MetaEvent::TrackName(ref text) => (META_TRACK_NAME, text.clone()),
+            MetaEvent::InstrumentName(ref text) => (META_INSTRUMENT_NAME, text.clone()),
+            MetaEvent::Lyric(ref text) => (META_LYRIC, text.clone()),
+            MetaEvent::Marker(ref text) => (META_MARKER, text.clone()),
+            MetaEvent::CuePoint(ref text) => (META_CUE_POINT, text.clone()),
+            MetaEvent::ChannelPrefix(channel) => (META_CHANNEL_PREFIX, vec![channel]),
+            MetaEvent::Port(port) => (META_PORT, vec![port]),
+            MetaEvent::EndOfTrack => (META_END_OF_TRACK, Vec::new()),
+            MetaEvent::Tempo(tempo) => (META_TEMPO, vec![(tempo >> 16) as u8, (tempo >> 8) as u8, tempo as u8]),
+            MetaEvent::SmpteOffset { hours, minutes, seconds, frames, subframes } => {
+                (META_SMPTE_OFFSET, vec![hours, minutes, seconds, frames, subframes])
+            },
+            MetaEvent::TimeSignature { numerator, denominator, clocks_per_click, thirty_seconds_per_quarter } => {
+                (META_TIME_SIGNATURE, vec![numerator, denominator, clocks_per_click, thirty_seconds_per_quarter])
+            },
+            MetaEvent::KeySignature { sharps, minor } => (META_KEY_SIGNATURE, vec![sharps as u8, minor as u8]),
+            MetaEvent::SequencerSpecific(ref data) => (META_SEQUENCER_SPECIFIC, data.clone()),
+            MetaEvent::Unknown { meta_type, ref data } => (meta_type, data.clone()),
+        }
+    }
+}
+
+/// The contents of an event in a track.
+///
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum EventKind {
+    /// A channel message. Other messages are stored as SysEx or escape events.
+    Midi(MidiMessage),
+    /// The bytes after the start of a System Exclusive message (0xF0), which end with 0xF7
+    /// unless the message continues in the following escape events.
+    SysEx(Vec<u8>),
+    /// Bytes sent as they are, either the continuation of a SysEx, or any other message.
+    Escape(Vec<u8>),
+    Meta(MetaEvent),
+}
+
+impl EventKind {
+    fn is_end_of_track(&self) -> bool {
+        match *self {
+            EventKind::Meta(MetaEvent::EndOfTrack) => true,
+            _ => false,
+        }
+    }
+}
+
+/// An event in a track, with the number of ticks since the previous one.
+///
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TrackEvent {
+    pub delta: u32,
+    pub kind: EventKind,
+}
+
+/// A track of a Standard MIDI File.
+///
+/// It can be built from MIDI messages with their position in ticks:
+///
+/// ```
+/// use coremidi::MidiMessage;
+/// use coremidi::smf::Track;
+/// let track = Track::from_messages(vec![
+///     (0, MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 }),
+///     (480, MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 }),
+/// ]);
+/// assert_eq!(track.events.len(), 3); // including the end of track
+/// assert_eq!(track.messages()[1], (480, MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 }));
+/// ```
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Track {
+    pub events: Vec<TrackEvent>,
+}
+
+impl Track {
+    /// Create an empty track.
+    ///
+    pub fn new() -> Track {
+        Track::default()
+    }
+
+    /// Build a track from MIDI messages with their position in ticks, which don't need to be sorted.
+    /// The SysEx, system common and real-time messages are stored as SysEx and escape events,
+    /// and the track is closed with an end of track event after the last message.
+    ///
+    pub fn from_messages<I>(messages: I) -> Track
+        where I: IntoIterator<Item = (u64, MidiMessage)>
+    {
+        let mut messages: Vec<_> = messages.into_iter().collect();
+        messages.sort_by_key(|&(ticks, _)| ticks);
+        let mut events = Vec::with_capacity(messages.len() + 1);
+        let mut last_ticks = 0;
+        for (ticks, message) in messages {
+            let kind = match message {
+                MidiMessage::SysEx(mut data) => {
+                    data.push(ESCAPE);
+                    EventKind::SysEx(data)
+                },
+                message => match message.channel() {
+                    Some(_) => EventKind::Midi(message),
+                    None => EventKind::Escape(message.to_bytes()),
+                },
+            };
+            events.push(TrackEvent { delta: delta(last_ticks, ticks), kind });
+            last_ticks = ticks;
+        }
+        events.push(TrackEvent { delta: 0, kind: EventKind::Meta(MetaEvent::EndOfTrack) });
+        Track { events }
+    }
+
+    /// Build a track from the messages in a packet list, which timestamps are converted into ticks
+    /// since `start` with the tempo map.
+    /// Messages split across packets, and running status, are supported.
+    ///
+    pub fn from_packets(packet_list: &PacketList, start: Timestamp, tempo_map: &TempoMap) -> Track {
+        let start_nanos = HostTime::from_timestamp(start).as_nanos();
+        let mut parser = MessageParser::new();
+        let mut messages = Vec::new();
+        for packet in packet_list.iter() {
+            for (timestamp, message) in parser.parse(packet) {
+                if let Ok(message) = message {
+                    let nanos = HostTime::from_timestamp(timestamp).as_nanos().saturating_sub(start_nanos);
+                    messages.push((tempo_map.nanos_to_ticks(nanos), message));
+                }
+            }
+        }
+        Track::from_messages(messages)
+    }
+
+    /// Get the MIDI messages in the track, with their position in ticks.
+    /// A SysEx continued in escape events is put together and placed where it starts,
+    /// and the meta events are left out.
+    ///
+    pub fn messages(&self) -> Vec<(u64, MidiMessage)> {
+        let mut messages = Vec::new();
+        let mut sysex: Option<(u64, Vec<u8>)> = None;
+        for (ticks, kind) in self.timed_events() {
+            match *kind {
+                EventKind::Midi(ref message) => messages.push((ticks, message.clone())),
+                EventKind::SysEx(ref data) => {
+                    sysex = Some((ticks, Vec::new()));
+                    Self::continue_sysex(&mut sysex, data, &mut messages);
+                },
+                EventKind::Escape(ref data) if sysex.is_some() => Self::continue_sysex(&mut sysex, data, &mut messages),
+                EventKind::Escape(ref data) => {
+                    let mut offset = 0;
+                    while let Some(result) = MidiMessage::decode(&data[offset..]) {
+                        offset += match result {
+                            Ok((message, len)) => {
+                                messages.push((ticks, message));
+                                len
+                            },
+                            Err((_, len)) => len,
+                        };
+                    }
+                },
+                EventKind::Meta(_) => {},
+            }
+        }
+        messages
+    }
+
+    fn continue_sysex(sysex: &mut Option<(u64, Vec<u8>)>, data: &[u8], messages: &mut Vec<(u64, MidiMessage)>) {
+        let complete = data.last() == Some(&ESCAPE);
+        if let Some((_, ref mut buffer)) = *sysex {
+            buffer.extend_from_slice(if complete { &data[..data.len() - 1] } else { data });
+        }
+        if complete {
+            if let Some((ticks, buffer)) = sysex.take() {
+                messages.push((ticks, MidiMessage::SysEx(buffer)));
+            }
+        }
+    }
+
+    /// Convert the track into a packet list, which timestamps are computed from `start` with the tempo map.
+    /// The meta events are left out.
+    ///
+    pub fn to_packets(&self, start: Timestamp, tempo_map: &TempoMap) -> PacketBuffer {
+        packets_from(self.raw_messages(), start, tempo_map)
+    }
+
+    /// Get the position in ticks of the end of the track.
+    ///
+    pub fn len_ticks(&self) -> u64 {
+        self.timed_events().last().map_or(0, |(ticks, _)| ticks)
+    }
+
+    /// Iterate over the events of the track, with their position in ticks.
+    ///
+    pub fn timed_events(&self) -> TimedEvents {
+        TimedEvents { events: self.events.iter(), ticks: 0 }
+    }
+
+    // The events that are sent to devices, with their position in ticks and their raw bytes
+    fn raw_messages(&self) -> Vec<(u64, Vec<u8>)> {
+        self.timed_events().filter_map(|(ticks, kind)| match *kind {
+            EventKind::Midi(ref message) => Some((ticks, message.to_bytes())),
+            EventKind::SysEx(ref data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 1);
+                bytes.push(SYSEX);
+                bytes.extend_from_slice(data);
+                Some((ticks, bytes))
+            },
+            EventKind::Escape(ref data) => Some((ticks, data.clone())),
+            EventKind::Meta(_) => None,
+        }).collect()
+    }
+
+    fn read(data: &[u8]) -> Result<Track, SmfError> {
+        let mut reader = Reader { data, offset: 0 };
+        let mut events = Vec::new();
+        let mut running_status = None;
+        while !reader.is_empty() {
+            let delta = reader.variable_length()?;
+            let kind = match reader.byte()? {
+                META => {
+                    let meta_type = reader.byte()?;
+                    let len = reader.variable_length()?;
+                    EventKind::Meta(MetaEvent::from_raw(meta_type, reader.bytes(len as usize)?))
+                },
+                SYSEX => {
+                    let len = reader.variable_length()?;
+                    EventKind::SysEx(reader.bytes(len as usize)?.to_vec())
+                },
+                ESCAPE => {
+                    let len = reader.variable_length()?;
+                    EventKind::Escape(reader.bytes(len as usize)?.to_vec())
+                },
+                status @ 0x80..=0xEF => {
+                    running_status = Some(status);
+                    reader.channel_message(status)?
+                },
+                0x00..=0x7F => {
+                    let status = running_status.ok_or(SmfError::MissingStatus)?;
+                    reader.offset -= 1;
+                    reader.channel_message(status)?
+                },
+                status => return Err(SmfError::InvalidEvent(status)),
+            };
+            let end_of_track = kind.is_end_of_track();
+            events.push(TrackEvent { delta, kind });
+            if end_of_track {
+                break;
+            }
+        }
+        Ok(Track { events })
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) -> Result<(), SmfError> {
+        let mut data = Vec::new();
+        let mut running_status = None;
+        let mut end_of_track = false;
+        for event in &self.events {
+            write_variable_length(event.delta, &mut data)?;
+            match event.kind {
+                EventKind::Midi(MidiMessage::SysEx(ref sysex)) => {
+                    running_status = None;
+                    data.push(SYSEX);
+                    write_variable_length(sysex.len() as u32 + 1, &mut data)?;
+                    data.extend(sysex.iter().map(|b| b & 0x7f));
+                    data.push(ESCAPE);
+                },
+                EventKind::Midi(ref message) if message.channel().is_some() => {
+                    let message = message.to_bytes();
+                    if running_status == Some(message[0]) {
+                        data.extend_from_slice(&message[1..]);
+                    } else {
+                        running_status = Some(message[0]);
+                        data.extend_from_slice(&message);
+                    }
+                },
+                EventKind::Midi(ref message) => {
+                    running_status = None;
+                    write_data(ESCAPE, &message.to_bytes(), &mut data)?;
+                },
+                EventKind::SysEx(ref sysex) => {
+                    running_status = None;
+                    write_data(SYSEX, sysex, &mut data)?;
+                },
+                EventKind::Escape(ref escaped) => {
+                    running_status = None;
+                    write_data(ESCAPE, escaped, &mut data)?;
+                },
+                EventKind::Meta(ref meta) => {
+                    running_status = None;
+                    let (meta_type, meta_data) = meta.to_raw();
+                    data.push(META);
+                    write_data(meta_type, &meta_data, &mut data)?;
+                },
+            }
+            if event.kind.is_end_of_track() {
+                end_of_track = true;
+                break;
+            }
+        }
+        if !end_of_track {
+            data.extend_from_slice(&[0, META, META_END_OF_TRACK, 0]);
+        }
+        write_chunk(TRACK_CHUNK, &data, bytes)
+    }
+}
+
+/// An iterator over the events of a track, with their position in ticks.
+///
+pub struct TimedEvents<'a> {
+    events: ::std::slice::Iter<'a, TrackEvent>,
+    ticks: u64,
+}
+
+impl<'a> Iterator for TimedEvents<'a> {
+    type Item = (u64, &'a EventKind);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.next().map(|event| {
+            self.ticks += u64::from(event.delta);
+            (self.ticks, &event.kind)
+        })
+    }
+}
+
+/// A Standard MIDI File.
+///
+/// ```
+/// use coremidi::MidiMessage;
+/// use coremidi::smf::{Division, Format, Smf, Track};
+/// let mut smf = Smf::new(Format::SingleTrack, Division::TicksPerQuarterNote(96));
+/// smf.tracks.push(Track::from_messages(vec![(0, MidiMessage::ProgramChange { channel: 0, program: 5 })]));
+/// let bytes = smf.to_bytes().unwrap();
+/// assert_eq!(Smf::from_bytes(&bytes), Ok(smf));
+/// ```
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Smf {
+    pub format: Format,
+    pub division: Division,
+    pub tracks: Vec<Track>,
+}
+
+impl Smf {
+    /// Create a file without tracks.
+    ///
+    pub fn new(format: Format, division: Division) -> Smf {
+        Smf { format, division, tracks: Vec::new() }
+    }
+
+    /// Parse the contents of a file.
+    /// Chunks other than the header and the tracks are skipped.
+    ///
+    pub fn from_bytes(data: &[u8]) -> Result<Smf, SmfError> {
+        let mut reader = Reader { data, offset: 0 };
+        let (chunk_type, header) = reader.chunk().map_err(|_| SmfError::MissingHeader)?;
+        if chunk_type != HEADER_CHUNK {
+            return Err(SmfError::MissingHeader);
+        }
+        if header.len() < 6 {
+            return Err(SmfError::InvalidHeader);
+        }
+        let format = Format::from_raw(u16::from(header[0]) << 8 | u16::from(header[1]))?;
+        let number_of_tracks = (u16::from(header[2]) << 8 | u16::from(header[3])) as usize;
+        let division = Division::from_raw(u16::from(header[4]) << 8 | u16::from(header[5]))?;
+
+        let mut tracks = Vec::with_capacity(number_of_tracks);
+        while tracks.len() < number_of_tracks {
+            let (chunk_type, chunk) = reader.chunk()?;
+            if chunk_type == TRACK_CHUNK {
+                tracks.push(Track::read(chunk)?);
+            }
+        }
+        Ok(Smf { format, division, tracks })
+    }
+
+    /// Read a file.
+    /// The errors found in its contents are returned as `io::ErrorKind::InvalidData` errors.
+    ///
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Smf> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Smf::from_bytes(&data).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    /// Encode the file, using running status for the channel messages,
+    /// and adding an end of track event to the tracks without it.
+    ///
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SmfError> {
+        let mut header = Vec::with_capacity(6);
+        header.extend_from_slice(&u16_bytes(self.format.to_raw()));
+        header.extend_from_slice(&u16_bytes(self.tracks.len() as u16));
+        header.extend_from_slice(&u16_bytes(self.division.to_raw()?));
+
+        let mut bytes = Vec::new();
+        write_chunk(HEADER_CHUNK, &header, &mut bytes)?;
+        for track in &self.tracks {
+            track.write(&mut bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Write the file.
+    /// The errors found when encoding it are returned as `io::ErrorKind::InvalidInput` errors.
+    ///
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let bytes = self.to_bytes().map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        writer.write_all(&bytes)
+    }
+
+    /// Get the tempo map of the file, from the tempo changes in all its tracks,
+    /// or only in the first one for sequential files.
+    ///
+    pub fn tempo_map(&self) -> TempoMap {
+        let tracks = match self.format {
+            Format::Sequential => &self.tracks[..cmp::min(1, self.tracks.len())],
+            _ => &self.tracks[..],
+        };
+        TempoMap::from_tracks(self.division, tracks)
+    }
+
+    /// Convert the tracks into a single packet list, which timestamps are computed from `start`
+    /// with the tempo map of the file.
+    ///
+    /// The tracks of sequential files are independent sequences, so they should be converted
+    /// one by one with `Track::to_packets` instead.
+    ///
+    pub fn to_packets(&self, start: Timestamp) -> PacketBuffer {
+        let mut messages: Vec<(u64, Vec<u8>)> = self.tracks.iter().flat_map(|track| track.raw_messages()).collect();
+        messages.sort_by_key(|&(ticks, _)| ticks);
+        packets_from(messages, start, &self.tempo_map())
+    }
+}
+
+/// Converts positions in ticks into nanoseconds and back, following the tempo changes.
+///
+/// ```
+/// use coremidi::smf::{Division, TempoMap};
+/// let mut tempo_map = TempoMap::new(Division::TicksPerQuarterNote(480));
+/// tempo_map.set_tempo(960, 250_000);
+/// assert_eq!(tempo_map.ticks_to_nanos(480), 500_000_000);
+/// assert_eq!(tempo_map.ticks_to_nanos(1440), 1_250_000_000);
+/// assert_eq!(tempo_map.nanos_to_ticks(1_250_000_000), 1440);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct TempoMap {
+    division: Division,
+    // The tempo changes sorted by their position, with the time when they happen, always starting at tick 0
+    changes: Vec<TempoChange>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TempoChange {
+    ticks: u64,
+    nanos: u64,
+    tempo: u32,
+}
+
+impl TempoMap {
+    /// Create a tempo map with the `DEFAULT_TEMPO`.
+    ///
+    pub fn new(division: Division) -> TempoMap {
+        TempoMap { division, changes: vec![TempoChange { ticks: 0, nanos: 0, tempo: DEFAULT_TEMPO }] }
+    }
+
+    /// Create a tempo map from the tempo events in some tracks.
+    ///
+    pub fn from_tracks(division: Division, tracks: &[Track]) -> TempoMap {
+        let mut tempo_map = TempoMap::new(division);
+        for track in tracks {
+            for (ticks, kind) in track.timed_events() {
+                if let EventKind::Meta(MetaEvent::Tempo(tempo)) = *kind {
+                    tempo_map.set_tempo(ticks, tempo);
+                }
+            }
+        }
+        tempo_map
+    }
+
+    /// Get the division the ticks are measured in.
+    ///
+    pub fn division(&self) -> Division {
+        self.division
+    }
+
+    /// Change the tempo, in microseconds per quarter note, from a position in ticks on.
+    /// The tempo doesn't change the duration of the ticks with SMPTE divisions.
+    ///
+    pub fn set_tempo(&mut self, ticks: u64, tempo: u32) {
+        let tempo = cmp::max(tempo, 1);
+        let index = match self.changes.binary_search_by_key(&ticks, |change| change.ticks) {
+            Ok(index) => {
+                self.changes[index].tempo = tempo;
+                index
+            },
+            Err(index) => {
+                self.changes.insert(index, TempoChange { ticks, nanos: 0, tempo });
+                index
+            }
+        };
+        for index in cmp::max(index, 1)..self.changes.len() {
+            let previous = self.changes[index - 1];
+            let elapsed = self.segment_nanos(previous.tempo, self.changes[index].ticks - previous.ticks);
+            self.changes[index].nanos = previous.nanos + elapsed;
+        }
+    }
+
+    /// Get the tempo, in microseconds per quarter note, at a position in ticks.
+    ///
+    pub fn tempo_at(&self, ticks: u64) -> u32 {
+        self.change_at_ticks(ticks).tempo
+    }
+
+    /// Get the tempo changes, as their position in ticks and the new tempo in microseconds per quarter note.
+    ///
+    pub fn tempo_changes(&self) -> Vec<(u64, u32)> {
+        self.changes.iter().map(|change| (change.ticks, change.tempo)).collect()
+    }
+
+    /// Get the time, in nanoseconds since the start, of a position in ticks.
+    ///
+    pub fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+        let change = self.change_at_ticks(ticks);
+        change.nanos + self.segment_nanos(change.tempo, ticks - change.ticks)
+    }
+
+    /// Get the position in ticks, rounded to the nearest one, of a time in nanoseconds since the start.
+    ///
+    pub fn nanos_to_ticks(&self, nanos: u64) -> u64 {
+        let index = match self.changes.binary_search_by_key(&nanos, |change| change.nanos) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+        let change = self.changes[index];
+        change.ticks + self.segment_ticks(change.tempo, nanos - change.nanos)
+    }
+
+    fn change_at_ticks(&self, ticks: u64) -> TempoChange {
+        match self.changes.binary_search_by_key(&ticks, |change| change.ticks) {
+            Ok(index) => self.changes[index],
+            Err(index) => self.changes[index - 1],
+        }
+    }
+
+    // The nanoseconds per tick as a fraction
+    fn tick_duration(&self, tempo: u32) -> (u128, u128) {
+        match self.division {
+            Division::TicksPerQuarterNote(ticks) => (u128::from(tempo) * 1000, u128::from(cmp::max(ticks, 1))),
+            Division::Smpte { frames_per_second: 29, ticks_per_frame } => {
+                (1_000_000_000 * 1001, 30_000 * u128::from(cmp::max(ticks_per_frame, 1)))
+            },
+            Division::Smpte { frames_per_second, ticks_per_frame } => {
+                (1_000_000_000, u128::from(frames_per_second) * u128::from(cmp::max(ticks_per_frame, 1)))
+            },
+        }
+    }
+
+    fn segment_nanos(&self, tempo: u32, ticks: u64) -> u64 {
+        let (numerator, denominator) = self.tick_duration(tempo);
+        (u128::from(ticks) * numerator / denominator) as u64
+    }
+
+    fn segment_ticks(&self, tempo: u32, nanos: u64) -> u64 {
+        let (numerator, denominator) = self.tick_duration(tempo);
+        ((u128::from(nanos) * denominator * 2 + numerator) / (numerator * 2)) as u64
+    }
+}
+
+pub(crate) fn delta(last_ticks: u64, ticks: u64) -> u32 {
+    cmp::min(ticks - last_ticks, u64::from(u32::max_value())) as u32
+}
+
+fn packets_from(messages: Vec<(u64, Vec<u8>)>, start: Timestamp, tempo_map: &TempoMap) -> PacketBuffer {
+    let start_nanos = HostTime::from_timestamp(start).as_nanos();
+    let capacity = messages.iter().map(|&(_, ref bytes)| bytes.len() + 16).sum();
+    let mut buffer = PacketBuffer::with_capacity(capacity);
+    for (ticks, bytes) in messages {
+        let timestamp = HostTime::from_nanos(start_nanos + tempo_map.ticks_to_nanos(ticks)).timestamp();
+        buffer.push_data(timestamp, &bytes);
+    }
+    buffer
+}
+
+fn u16_bytes(value: u16) -> [u8; 2] {
+    [(value >> 8) as u8, value as u8]
+}
+
+fn write_variable_length(value: u32, bytes: &mut Vec<u8>) -> Result<(), SmfError> {
+    if value > MAX_VARIABLE_LENGTH {
+        return Err(SmfError::InvalidLength);
+    }
+    let mut shift = 21;
+    while shift > 0 && value >> shift == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        bytes.push(0x80 | (value >> shift) as u8 & 0x7f);
+        shift -= 7;
+    }
+    bytes.push(value as u8 & 0x7f);
+    Ok(())
+}
+
+// A byte followed by the length of the data and the data itself, as in SysEx, escape and meta events
+fn write_data(first: u8, data: &[u8], bytes: &mut Vec<u8>) -> Result<(), SmfError> {
+    bytes.push(first);
+    write_variable_length(data.len() as u32, bytes)?;
+    bytes.extend_from_slice(data);
+    Ok(())
+}
+
+fn write_chunk(chunk_type: &[u8], data: &[u8], bytes: &mut Vec<u8>) -> Result<(), SmfError> {
+    if data.len() > u32::max_value() as usize {
+        return Err(SmfError::InvalidLength);
+    }
+    let len = data.len() as u32;
+    bytes.extend_from_slice(chunk_type);
+    bytes.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    bytes.extend_from_slice(data);
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, SmfError> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SmfError> {
+        if self.data.len() - self.offset < len {
+            return Err(SmfError::UnexpectedEnd);
+        }
+        let bytes = &self.data[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn variable_length(&mut self) -> Result<u32, SmfError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = value << 7 | u32::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SmfError::InvalidLength)
+    }
+
+    fn chunk(&mut self) -> Result<(&'a [u8], &'a [u8]), SmfError> {
+        let chunk_type = self.bytes(4)?;
+        let len = self.bytes(4)?;
+        let len = u32::from(len[0]) << 24 | u32::from(len[1]) << 16 | u32::from(len[2]) << 8 | u32::from(len[3]);
+        Ok((chunk_type, self.bytes(len as usize)?))
+    }
+
+    fn channel_message(&mut self, status: u8) -> Result<EventKind, SmfError> {
+        let len = match status & 0xf0 {
+            0xC0 | 0xD0 => 1,
+            _ => 2,
+        };
+        let mut message = [status, 0, 0];
+        message[1..=len].copy_from_slice(self.bytes(len)?);
+        match MidiMessage::decode(&message[..=len]) {
+            Some(Ok((message, _))) => Ok(EventKind::Midi(message)),
+            _ => Err(SmfError::InvalidEvent(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {HostTime, MidiMessage, PacketBuffer, PacketList};
+    use super::{Division, EventKind, Format, MetaEvent, Smf, SmfError, TempoMap, Track, TrackEvent};
+
+    // A format 0 file with meta events, running status, SysEx split into escape events, and an escaped message
+    const FILE: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 79,
+        0x00, 0xFF, 0x03, 0x04, b'S', b'o', b'n', b'g',
+        0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+        0x00, 0xFF, 0x58, 0x04, 0x06, 0x03, 0x24, 0x08,
+        0x00, 0xFF, 0x59, 0x02, 0xFD, 0x01,
+        0x00, 0x90, 0x3C, 0x40,
+        0x60, 0x3C, 0x00,
+        0x00, 0xFF, 0x06, 0x05, b'V', b'e', b'r', b's', b'e',
+        0x00, 0xFF, 0x05, 0x02, b'l', b'a',
+        0x81, 0x00, 0xF0, 0x03, 0x43, 0x12, 0xF7,
+        0x00, 0xF0, 0x02, 0x43, 0x12,
+        0x10, 0xF7, 0x02, 0x00, 0xF7,
+        0x00, 0xF7, 0x01, 0xF8,
+        0x00, 0xC0, 0x05,
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+
+    fn event(delta: u32, kind: EventKind) -> TrackEvent {
+        TrackEvent { delta, kind }
+    }
+
+    #[test]
+    fn read_file() {
+        let smf = Smf::from_bytes(FILE).unwrap();
+        assert_eq!(smf.format, Format::SingleTrack);
+        assert_eq!(smf.division, Division::TicksPerQuarterNote(96));
+        assert_eq!(smf.tracks.len(), 1);
+        assert_eq!(smf.tracks[0].events, vec![
+            event(0, EventKind::Meta(MetaEvent::TrackName(b"Song".to_vec()))),
+            event(0, EventKind::Meta(MetaEvent::Tempo(500_000))),
+            event(0, EventKind::Meta(MetaEvent::TimeSignature {
+                numerator: 6, denominator: 3, clocks_per_click: 36, thirty_seconds_per_quarter: 8,
+            })),
+            event(0, EventKind::Meta(MetaEvent::KeySignature { sharps: -3, minor: true })),
+            event(0, EventKind::Midi(MidiMessage::NoteOn { channel: 0, note: 0x3C, velocity: 0x40 })),
+            event(96, EventKind::Midi(MidiMessage::NoteOn { channel: 0, note: 0x3C, velocity: 0 })),
+            event(0, EventKind::Meta(MetaEvent::Marker(b"Verse".to_vec()))),
+            event(0, EventKind::Meta(MetaEvent::Lyric(b"la".to_vec()))),
+            event(128, EventKind::SysEx(vec![0x43, 0x12, 0xF7])),
+            event(0, EventKind::SysEx(vec![0x43, 0x12])),
+            event(16, EventKind::Escape(vec![0x00, 0xF7])),
+            event(0, EventKind::Escape(vec![0xF8])),
+            event(0, EventKind::Midi(MidiMessage::ProgramChange { channel: 0, program: 5 })),
+            event(0, EventKind::Meta(MetaEvent::EndOfTrack)),
+        ]);
+        assert_eq!(smf.tracks[0].len_ticks(), 240);
+        assert_eq!(smf.tracks[0].events[6].kind, EventKind::Meta(MetaEvent::Marker(b"Verse".to_vec())));
+        assert_eq!(MetaEvent::Lyric(b"la".to_vec()).text(), Some("la".to_string()));
+    }
+
+    #[test]
+    fn write_file() {
+        let smf = Smf::from_bytes(FILE).unwrap();
+        assert_eq!(smf.to_bytes().unwrap(), FILE.to_vec());
+
+        let mut written = Vec::new();
+        smf.write(&mut written).unwrap();
+        assert_eq!(Smf::read(&mut &written[..]).unwrap(), smf);
+    }
+
+    #[test]
+    fn track_messages() {
+        let smf = Smf::from_bytes(FILE).unwrap();
+        assert_eq!(smf.tracks[0].messages(), vec![
+            (0, MidiMessage::NoteOn { channel: 0, note: 0x3C, velocity: 0x40 }),
+            (96, MidiMessage::NoteOn { channel: 0, note: 0x3C, velocity: 0 }),
+            (224, MidiMessage::SysEx(vec![0x43, 0x12])),
+            (224, MidiMessage::SysEx(vec![0x43, 0x12, 0x00])),
+            (240, MidiMessage::TimingClock),
+            (240, MidiMessage::ProgramChange { channel: 0, program: 5 }),
+        ]);
+    }
+
+    #[test]
+    fn track_from_messages() {
+        let track = Track::from_messages(vec![
+            (10, MidiMessage::Start),
+            (0, MidiMessage::ControlChange { channel: 1, control: 7, value: 100 }),
+            (10, MidiMessage::SysEx(vec![0x7E, 0x09])),
+        ]);
+        assert_eq!(track.events, vec![
+            event(0, EventKind::Midi(MidiMessage::ControlChange { channel: 1, control: 7, value: 100 })),
+            event(10, EventKind::Escape(vec![0xFA])),
+            event(0, EventKind::SysEx(vec![0x7E, 0x09, 0xF7])),
+            event(0, EventKind::Meta(MetaEvent::EndOfTrack)),
+        ]);
+        assert_eq!(track.messages(), vec![
+            (0, MidiMessage::ControlChange { channel: 1, control: 7, value: 100 }),
+            (10, MidiMessage::Start),
+            (10, MidiMessage::SysEx(vec![0x7E, 0x09])),
+        ]);
+    }
+
+    #[test]
+    fn write_multiple_tracks_with_smpte_division() {
+        let mut smf = Smf::new(Format::Parallel, Division::Smpte { frames_per_second: 29, ticks_per_frame: 40 });
+        smf.tracks.push(Track { events: vec![
+            event(0, EventKind::Meta(MetaEvent::SmpteOffset { hours: 1, minutes: 2, seconds: 3, frames: 4, subframes: 5 })),
+            event(0, EventKind::Meta(MetaEvent::Unknown { meta_type: 0x60, data: vec![1, 2] })),
+        ]});
+        smf.tracks.push(Track::from_messages(vec![(0x0FFF_FFFF, MidiMessage::TuneRequest)]));
+
+        let bytes = smf.to_bytes().unwrap();
+        assert_eq!(&bytes[8..14], &[0, 1, 0, 2, 0xE3, 40]);
+        let read = Smf::from_bytes(&bytes).unwrap();
+        assert_eq!(read.division, smf.division);
+        // the missing end of track is added when writing
+        assert_eq!(read.tracks[0].events.last(), Some(&event(0, EventKind::Meta(MetaEvent::EndOfTrack))));
+        assert_eq!(read.tracks[1], smf.tracks[1]);
+
+        smf.tracks[1].events[0].delta = 0x1000_0000;
+        assert_eq!(smf.to_bytes(), Err(SmfError::InvalidLength));
+    }
+
+    #[test]
+    fn read_errors() {
+        assert_eq!(Smf::from_bytes(b"RIFF"), Err(SmfError::MissingHeader));
+        assert_eq!(Smf::from_bytes(b"MThd\0\0\0\x06\0\x03\0\x01\0\x60"), Err(SmfError::InvalidHeader));
+        assert_eq!(Smf::from_bytes(b"MThd\0\0\0\x06\0\0\0\x01\xE6\x28"), Err(SmfError::InvalidDivision));
+        assert_eq!(Smf::from_bytes(b"MThd\0\0\0\x06\0\0\0\x01\0\x60"), Err(SmfError::UnexpectedEnd));
+        assert_eq!(Smf::from_bytes(b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x03\0\x3C\x40"), Err(SmfError::MissingStatus));
+        assert_eq!(Smf::from_bytes(b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x02\0\xF8"), Err(SmfError::InvalidEvent(0xF8)));
+        assert_eq!(Smf::from_bytes(b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x03\0\x90\x3C"), Err(SmfError::UnexpectedEnd));
+        assert_eq!(Smf::from_bytes(b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x05\xFF\xFF\xFF\xFF\x7F"), Err(SmfError::InvalidLength));
+        // other chunks are skipped
+        assert!(Smf::from_bytes(b"MThd\0\0\0\x06\0\0\0\x01\0\x60XFIH\0\0\0\x01\0MTrk\0\0\0\x04\0\xFF\x2F\0").is_ok());
+    }
+
+    #[test]
+    fn tempo_map_with_ticks_per_quarter_note() {
+        let mut tempo_map = TempoMap::new(Division::TicksPerQuarterNote(96));
+        assert_eq!(tempo_map.ticks_to_nanos(96), 500_000_000);
+        tempo_map.set_tempo(192, 1_000_000);
+        tempo_map.set_tempo(96, 250_000);
+        assert_eq!(tempo_map.tempo_changes(), vec![(0, 500_000), (96, 250_000), (192, 1_000_000)]);
+        assert_eq!(tempo_map.tempo_at(95), 500_000);
+        assert_eq!(tempo_map.tempo_at(96), 250_000);
+        assert_eq!(tempo_map.ticks_to_nanos(144), 625_000_000);
+        assert_eq!(tempo_map.ticks_to_nanos(240), 1_250_000_000);
+        assert_eq!(tempo_map.nanos_to_ticks(1_250_000_000), 240);
+        assert_eq!(tempo_map.nanos_to_ticks(625_000_000 + 1_302_083), 144);
+        assert_eq!(tempo_map.nanos_to_ticks(625_000_000 + 1_302_084), 145);
+        for ticks in 0..300 {
+            assert_eq!(tempo_map.nanos_to_ticks(tempo_map.ticks_to_nanos(ticks)), ticks);
+        }
+    }
+
+    #[test]
+    fn tempo_map_with_smpte_division() {
+        let mut tempo_map = TempoMap::new(Division::Smpte { frames_per_second: 25, ticks_per_frame: 40 });
+        tempo_map.set_tempo(0, 1_000_000);
+        assert_eq!(tempo_map.ticks_to_nanos(1000), 1_000_000_000);
+        let drop_frame = TempoMap::new(Division::Smpte { frames_per_second: 29, ticks_per_frame: 1 });
+        assert_eq!(drop_frame.ticks_to_nanos(30_000), 1_001_000_000_000);
+        assert_eq!(drop_frame.nanos_to_ticks(1_001_000_000_000), 30_000);
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let smf = Smf::from_bytes(FILE).unwrap();
+        let start = HostTime::from_nanos(1_000_000_000).timestamp();
+        let packets = smf.to_packets(start);
+        let timestamp = |nanos: u64| HostTime::from_nanos(1_000_000_000 + nanos).timestamp();
+        let received: Vec<_> = packets.iter().map(|packet| (packet.timestamp(), packet.data().to_vec())).collect();
+        assert_eq!(received, vec![
+            (timestamp(0), vec![0x90, 0x3C, 0x40]),
+            (timestamp(500_000_000), vec![0x90, 0x3C, 0x00]),
+            (timestamp(1_166_666_666), vec![0xF0, 0x43, 0x12, 0xF7]),
+            (timestamp(1_166_666_666), vec![0xF0, 0x43, 0x12]),
+            (timestamp(1_250_000_000), vec![0x00, 0xF7]),
+            (timestamp(1_250_000_000), vec![0xF8, 0xC0, 0x05]),
+        ]);
+
+        let track = Track::from_packets(&packets, start, &smf.tempo_map());
+        assert_eq!(track.messages(), smf.tracks[0].messages());
+
+        let mut buffer = PacketBuffer::new(timestamp(0), &[0x90, 0x40, 0x7f]);
+        buffer.push_data(timestamp(250_000_000), &[0x41, 0x7f]);
+        let track = Track::from_packets(&buffer as &PacketList, start, &TempoMap::new(Division::TicksPerQuarterNote(480)));
+        assert_eq!(track.messages(), vec![
+            (0, MidiMessage::NoteOn { channel: 0, note: 0x40, velocity: 0x7f }),
+            (240, MidiMessage::NoteOn { channel: 0, note: 0x41, velocity: 0x7f }),
+        ]);
+    }
+}
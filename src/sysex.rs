@@ -0,0 +1,481 @@
+use std::cell::UnsafeCell;
+use std::os::raw::c_void;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use Destination;
+use Error;
+use Packet;
+use PacketList;
+use backend::{Backend, Current};
+use backend::sys::MIDISysexSendRequest;
+use packets::Timestamp;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const FIRST_REALTIME: u8 = 0xF8;
+
+const DEFAULT_MAX_SYSEX_SIZE: usize = 1024 * 1024;
+
+/// Something that happened to a System Exclusive message while being reassembled by a `SysExAssembler`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SysExEvent {
+    /// A complete message, from the start (0xF0) to the end (0xF7) bytes, both included.
+    /// The timestamp is the one of the packet where the message started.
+    Complete { timestamp: Timestamp, data: Vec<u8> },
+    /// A message that was discarded before being complete, after `received` bytes.
+    /// The timestamp is the one of the packet where the message started.
+    Aborted { timestamp: Timestamp, received: usize, reason: SysExAbortReason },
+}
+
+/// The reasons why a System Exclusive message can be discarded by a `SysExAssembler`.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SysExAbortReason {
+    /// A status byte other than the end of the message (0xF7) or real-time came before the end of the message.
+    Interrupted(u8),
+    /// The message would be bigger than the maximum size.
+    TooLong,
+    /// No more data was received for the message during the timeout.
+    TimedOut,
+}
+
+/// Reassembles System Exclusive messages split across several packets and packet lists.
+///
+/// It is meant to be moved into the callback of an `InputPort` or a `VirtualDestination`,
+/// and be given every packet list received. Real-time messages in the middle of a System Exclusive
+/// message are skipped, as well as any other data outside of them.
+///
+/// Timeouts are checked whenever new packets are processed, or explicitly with `check_timeout`.
+///
+/// ```rust,no_run
+/// use coremidi::{Client, SysExAssembler, SysExEvent};
+/// use std::time::Duration;
+/// let client = Client::new("example-client").unwrap();
+/// let mut assembler = SysExAssembler::new(|event| match event {
+///     SysExEvent::Complete { timestamp, data } => println!("{}: {} bytes", timestamp, data.len()),
+///     SysExEvent::Aborted { reason, .. } => println!("aborted: {:?}", reason),
+/// }).max_size(64 * 1024).timeout(Duration::from_secs(2));
+/// let input_port = client.input_port("example-port", move |packet_list| assembler.process(packet_list)).unwrap();
+/// ```
+pub struct SysExAssembler<F> {
+    callback: F,
+    max_size: usize,
+    timeout: Option<Duration>,
+    buffer: Vec<u8>,
+    in_progress: bool,
+    timestamp: Timestamp,
+    last_received: Option<Instant>,
+}
+
+impl<F: FnMut(SysExEvent)> SysExAssembler<F> {
+    /// Create an assembler that calls `callback` for every complete or aborted message.
+    ///
+    /// By default messages can be up to 1 MiB long, and incomplete messages never time out.
+    pub fn new(callback: F) -> SysExAssembler<F> {
+        SysExAssembler {
+            callback,
+            max_size: DEFAULT_MAX_SYSEX_SIZE,
+            timeout: None,
+            buffer: Vec::new(),
+            in_progress: false,
+            timestamp: 0,
+            last_received: None,
+        }
+    }
+
+    /// Set the maximum size for a message, including the start and end bytes.
+    ///
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Set how long to wait for more data before discarding an incomplete message.
+    ///
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Check whether there is a message being reassembled.
+    ///
+    pub fn is_in_progress(&self) -> bool {
+        self.in_progress
+    }
+
+    /// Process all the packets in a list.
+    ///
+    pub fn process(&mut self, packet_list: &PacketList) {
+        let now = Instant::now();
+        for packet in packet_list.iter() {
+            self.process_at(now, packet.timestamp(), packet.data());
+        }
+    }
+
+    /// Process a single packet.
+    ///
+    pub fn process_packet(&mut self, packet: &Packet) {
+        self.process_at(Instant::now(), packet.timestamp(), packet.data());
+    }
+
+    /// Discard the message being reassembled if it timed out.
+    ///
+    pub fn check_timeout(&mut self) {
+        self.check_timeout_at(Instant::now());
+    }
+
+    fn process_at(&mut self, now: Instant, timestamp: Timestamp, data: &[u8]) {
+        self.check_timeout_at(now);
+
+        for &byte in data {
+            match byte {
+                SYSEX_START => {
+                    self.abort(SysExAbortReason::Interrupted(byte));
+                    self.in_progress = true;
+                    self.timestamp = timestamp;
+                    self.buffer.clear();
+                    self.push(byte);
+                },
+                SYSEX_END if self.in_progress => {
+                    self.buffer.push(byte);
+                    self.in_progress = false;
+                    let data = self.buffer.split_off(0);
+                    (self.callback)(SysExEvent::Complete { timestamp: self.timestamp, data });
+                },
+                _ if byte >= FIRST_REALTIME => {},
+                _ if byte & 0x80 != 0 => self.abort(SysExAbortReason::Interrupted(byte)),
+                _ if self.in_progress => self.push(byte),
+                _ => {}
+            }
+        }
+
+        if self.in_progress {
+            self.last_received = Some(now);
+        }
+    }
+
+    fn check_timeout_at(&mut self, now: Instant) {
+        if let (Some(timeout), Some(last_received)) = (self.timeout, self.last_received) {
+            if now.duration_since(last_received) > timeout {
+                self.abort(SysExAbortReason::TimedOut);
+            }
+        }
+    }
+
+    /// Push a byte into the message being reassembled, leaving room for the end byte.
+    fn push(&mut self, byte: u8) {
+        if self.buffer.len() + 2 > self.max_size {
+            self.abort(SysExAbortReason::TooLong);
+        } else {
+            self.buffer.push(byte);
+        }
+    }
+
+    fn abort(&mut self, reason: SysExAbortReason) {
+        if self.in_progress {
+            self.in_progress = false;
+            self.last_received = None;
+            let received = self.buffer.len();
+            self.buffer.clear();
+            (self.callback)(SysExEvent::Aborted { timestamp: self.timestamp, received, reason });
+        }
+    }
+}
+
+/// The outcome of sending a System Exclusive message with a `SysExSender`.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SysExSendResult {
+    /// All the data was sent.
+    Completed,
+    /// The request was cancelled, or the destination went away, after sending `bytes_sent` bytes.
+    Cancelled { bytes_sent: usize },
+}
+
+type SysExSendCallback = Box<FnOnce(SysExSendResult) + Send>;
+
+struct SysExSendState {
+    // Note: `request.data` points into `data`, and both are only freed when the completion proc has been called
+    request: UnsafeCell<MIDISysexSendRequest>,
+    data: Vec<u8>,
+    result: Mutex<Option<SysExSendResult>>,
+    finished: Condvar,
+    callback: Mutex<Option<SysExSendCallback>>,
+}
+
+// The request is only accessed through volatile reads and writes once it has been sent
+unsafe impl Send for SysExSendState {}
+unsafe impl Sync for SysExSendState {}
+
+/// Sends a System Exclusive message of any size to a destination in the background.
+/// See [MIDISendSysex](https://developer.apple.com/reference/coremidi/1495356-midisendsysex).
+///
+/// The data is split into chunks and paced by the MIDI server, so there is no need to fit it
+/// into a `PacketBuffer`. The sender allows to follow the progress, cancel it or wait for it to finish.
+/// Dropping the sender does not cancel the request.
+///
+/// ```rust,no_run
+/// use coremidi::{Destination, SysExSender};
+/// let destination = Destination::from_index(0).unwrap();
+/// let dump = vec![0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+/// let sender = SysExSender::send_with_callback(&destination, dump, |result| println!("{:?}", result)).unwrap();
+/// println!("{} of {} bytes sent", sender.bytes_sent(), sender.bytes_total());
+/// sender.wait();
+/// ```
+pub struct SysExSender {
+    state: Arc<SysExSendState>,
+}
+
+impl SysExSender {
+    /// Start sending `data` to the destination.
+    ///
+    pub fn send(destination: &Destination, data: Vec<u8>) -> Result<SysExSender, Error> {
+        Self::send_with_callback(destination, data, |_| ())
+    }
+
+    /// Start sending `data` to the destination, and call `callback` once it is finished.
+    ///
+    /// The callback is called from the MIDI server thread.
+    pub fn send_with_callback<F>(destination: &Destination, data: Vec<u8>, callback: F) -> Result<SysExSender, Error>
+            where F: FnOnce(SysExSendResult) + Send + 'static {
+
+        assert!(data.len() <= u32::max_value() as usize, "sysex data too long");
+
+        let state = Arc::new(SysExSendState {
+            request: UnsafeCell::new(MIDISysexSendRequest {
+                destination: destination.endpoint.object.0,
+                data: ptr::null(),
+                bytesToSend: data.len() as _,
+                complete: 0,
+                reserved: [0; 3],
+                completionProc: Some(Self::completion_proc as extern "C" fn(_)),
+                completionRefCon: ptr::null_mut(),
+            }),
+            data,
+            result: Mutex::new(None),
+            finished: Condvar::new(),
+            callback: Mutex::new(Some(Box::new(callback))),
+        });
+
+        unsafe {
+            let request = state.request.get();
+            (*request).data = state.data.as_ptr();
+            let ref_con = Arc::into_raw(state.clone());
+            (*request).completionRefCon = ref_con as *mut c_void;
+            if let Err(status) = Current::send_sysex(request) {
+                drop(Arc::from_raw(ref_con));
+                return Err(Error::from(status));
+            }
+        }
+
+        Ok(SysExSender { state })
+    }
+
+    /// Get the total number of bytes to send.
+    ///
+    pub fn bytes_total(&self) -> usize {
+        self.state.data.len()
+    }
+
+    /// Get the number of bytes sent so far.
+    ///
+    pub fn bytes_sent(&self) -> usize {
+        match self.result() {
+            Some(SysExSendResult::Completed) => self.bytes_total(),
+            Some(SysExSendResult::Cancelled { bytes_sent }) => bytes_sent,
+            None => {
+                let bytes_to_send = unsafe { ptr::read_volatile(&(*self.state.request.get()).bytesToSend) };
+                self.bytes_total() - bytes_to_send as usize
+            }
+        }
+    }
+
+    /// Get the result, or `None` if it is not finished yet.
+    ///
+    pub fn result(&self) -> Option<SysExSendResult> {
+        *self.state.result.lock().unwrap()
+    }
+
+    /// Ask the MIDI server to stop sending data.
+    ///
+    /// The request finishes asynchronously, as `SysExSendResult::Cancelled` unless all the data was already sent.
+    pub fn cancel(&self) {
+        unsafe { ptr::write_volatile(&mut (*self.state.request.get()).complete, 1) };
+    }
+
+    /// Block the current thread until all the data is sent or the request is cancelled.
+    ///
+    pub fn wait(&self) -> SysExSendResult {
+        let mut result = self.state.result.lock().unwrap();
+        loop {
+            if let Some(result) = *result {
+                return result;
+            }
+            result = self.state.finished.wait(result).unwrap();
+        }
+    }
+
+    /// Block the current thread until the request finishes or the timeout expires.
+    ///
+    /// It returns `None` when the timeout expired before the request finished.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<SysExSendResult> {
+        let deadline = Instant::now() + timeout;
+        let mut result = self.state.result.lock().unwrap();
+        while result.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            result = self.state.finished.wait_timeout(result, deadline - now).unwrap().0;
+        }
+        *result
+    }
+
+    extern "C" fn completion_proc(request: *mut MIDISysexSendRequest) {
+        let state = unsafe { Arc::from_raw((*request).completionRefCon as *const SysExSendState) };
+        let bytes_to_send = unsafe { ptr::read_volatile(&(*request).bytesToSend) } as usize;
+        let result = match bytes_to_send {
+            0 => SysExSendResult::Completed,
+            _ => SysExSendResult::Cancelled { bytes_sent: state.data.len() - bytes_to_send },
+        };
+
+        *state.result.lock().unwrap() = Some(result);
+        state.finished.notify_all();
+
+        let callback = state.callback.lock().unwrap().take();
+        if let Some(callback) = callback {
+            let _ = catch_unwind(AssertUnwindSafe(|| callback(result)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use std::sync::mpsc;
+
+    use {Client, Destinations, PacketBuffer};
+    use super::{SysExAssembler, SysExEvent, SysExAbortReason, SysExSender, SysExSendResult};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn assembler(max_size: usize) -> (SysExAssembler<impl FnMut(SysExEvent)>, mpsc::Receiver<SysExEvent>) {
+        let (tx, rx) = mpsc::channel();
+        let assembler = SysExAssembler::new(move |event| tx.send(event).unwrap())
+            .max_size(max_size)
+            .timeout(Duration::from_millis(100));
+        (assembler, rx)
+    }
+
+    #[test]
+    fn complete_across_packet_lists() {
+        let (mut assembler, rx) = assembler(16);
+        let mut packet_buf = PacketBuffer::new(42, &[0x90, 0x40, 0x7f]);
+        packet_buf.push_data(43, &[0xf0, 0x01, 0x02]);
+        packet_buf.push_data(44, &[0x03, 0xf8, 0x04]);
+        assembler.process(&packet_buf);
+        assert!(assembler.is_in_progress());
+        assembler.process(&PacketBuffer::new(45, &[0x05, 0xf7, 0x80, 0x40, 0x00]));
+        assert!(!assembler.is_in_progress());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![
+            SysExEvent::Complete { timestamp: 43, data: vec![0xf0, 0x01, 0x02, 0x03, 0x04, 0x05, 0xf7] },
+        ]);
+    }
+
+    #[test]
+    fn aborted_by_status_byte() {
+        let (mut assembler, rx) = assembler(16);
+        assembler.process(&PacketBuffer::new(42, &[0xf0, 0x01, 0x02]));
+        assembler.process(&PacketBuffer::new(43, &[0x90, 0x40, 0x7f]));
+        assembler.process(&PacketBuffer::new(44, &[0xf0, 0x01]));
+        assembler.process(&PacketBuffer::new(45, &[0xf0, 0x02, 0xf7]));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![
+            SysExEvent::Aborted { timestamp: 42, received: 3, reason: SysExAbortReason::Interrupted(0x90) },
+            SysExEvent::Aborted { timestamp: 44, received: 2, reason: SysExAbortReason::Interrupted(0xf0) },
+            SysExEvent::Complete { timestamp: 45, data: vec![0xf0, 0x02, 0xf7] },
+        ]);
+    }
+
+    #[test]
+    fn aborted_when_too_long() {
+        let (mut assembler, rx) = assembler(4);
+        assembler.process(&PacketBuffer::new(42, &[0xf0, 0x01, 0x02, 0xf7]));
+        assembler.process(&PacketBuffer::new(43, &[0xf0, 0x01, 0x02, 0x03, 0x04, 0xf7]));
+        assert!(!assembler.is_in_progress());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![
+            SysExEvent::Complete { timestamp: 42, data: vec![0xf0, 0x01, 0x02, 0xf7] },
+            SysExEvent::Aborted { timestamp: 43, received: 3, reason: SysExAbortReason::TooLong },
+        ]);
+    }
+
+    #[test]
+    fn aborted_when_timed_out() {
+        let (mut assembler, rx) = assembler(16);
+        let start = Instant::now();
+        assembler.process_at(start, 42, &[0xf0, 0x01]);
+        assembler.process_at(start + Duration::from_millis(50), 43, &[0x02]);
+        assembler.check_timeout_at(start + Duration::from_millis(100));
+        assert!(assembler.is_in_progress());
+        assembler.process_at(start + Duration::from_millis(200), 44, &[0x03, 0xf7]);
+        assert!(!assembler.is_in_progress());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![
+            SysExEvent::Aborted { timestamp: 42, received: 3, reason: SysExAbortReason::TimedOut },
+        ]);
+    }
+
+    fn sysex_destination(client: &Client, name: &str) -> (::VirtualDestination, ::Destination, mpsc::Receiver<u8>) {
+        let (tx, rx) = mpsc::channel();
+        let virtual_destination = client.virtual_destination(name, move |packet_list| {
+            for packet in packet_list.iter() {
+                for &byte in packet.data() {
+                    tx.send(byte).unwrap();
+                }
+            }
+        }).unwrap();
+        let unique_id = virtual_destination.unique_id();
+        let destination = Destinations.into_iter().find(|d| d.unique_id() == unique_id).unwrap();
+        (virtual_destination, destination, rx)
+    }
+
+    #[test]
+    fn sender_completes() {
+        let client = Client::new("SysEx Client").unwrap();
+        let (_virtual_destination, destination, rx) = sysex_destination(&client, "SysEx Destination");
+        let mut data = vec![0xf0];
+        data.extend((0..598).map(|i| (i % 128) as u8));
+        data.push(0xf7);
+
+        let (tx, callback_rx) = mpsc::channel();
+        let sender = SysExSender::send_with_callback(&destination, data.clone(), move |result| tx.send(result).unwrap()).unwrap();
+        assert_eq!(sender.bytes_total(), 600);
+
+        assert_eq!(sender.wait_timeout(TIMEOUT), Some(SysExSendResult::Completed));
+        assert_eq!(callback_rx.recv_timeout(TIMEOUT), Ok(SysExSendResult::Completed));
+        assert_eq!(sender.bytes_sent(), 600);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), data);
+    }
+
+    #[test]
+    fn sender_cancelled() {
+        let client = Client::new("SysEx Client").unwrap();
+        let (_virtual_destination, destination, rx) = sysex_destination(&client, "SysEx Destination");
+        let mut data = vec![0xf0];
+        data.extend((0..4094).map(|i| (i % 128) as u8));
+        data.push(0xf7);
+
+        let sender = SysExSender::send(&destination, data.clone()).unwrap();
+        sender.cancel();
+
+        let bytes_sent = match sender.wait_timeout(TIMEOUT) {
+            Some(SysExSendResult::Cancelled { bytes_sent }) => bytes_sent,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert!(bytes_sent < data.len());
+        assert_eq!(sender.bytes_sent(), bytes_sent);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), &data[..bytes_sent]);
+    }
+}
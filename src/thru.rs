@@ -0,0 +1,772 @@
+use std::fmt;
+use std::ops::Deref;
+
+use Error;
+use Object;
+use Source;
+use Destination;
+use ThruConnection;
+use backend::{Backend, Current};
+use backend::sys::MIDIObjectRef;
+
+/// The maximum number of sources or destinations in a thru connection.
+pub const THRU_CONNECTION_MAX_ENDPOINTS: usize = 8;
+
+// Offsets into the binary layout of MIDIThruConnectionParams
+const PARAMS_SIZE: usize = 204;
+const ENDPOINT_SIZE: usize = 8;
+const SOURCES_OFFSET: usize = 8;
+const NUM_DESTINATIONS_OFFSET: usize = 72;
+const DESTINATIONS_OFFSET: usize = 76;
+const CHANNEL_MAP_OFFSET: usize = 140;
+const VELOCITY_RANGE_OFFSET: usize = 156;
+const NOTE_RANGE_OFFSET: usize = 158;
+const TRANSFORMS_OFFSET: usize = 160;
+const FILTERS_OFFSET: usize = 184;
+const FILTER_OUT_ALL_CONTROLS_OFFSET: usize = 191;
+const NUM_CONTROL_TRANSFORMS_OFFSET: usize = 192;
+const NUM_MAPS_OFFSET: usize = 194;
+const CONTROL_TRANSFORM_SIZE: usize = 8;
+const VALUE_MAP_SIZE: usize = 128;
+
+const CHANNEL_FILTERED_OUT: u8 = 0xFF;
+
+/// A source or destination of a thru connection.
+///
+/// The unique id is used to find the endpoint again when a persistent connection is restored.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ThruConnectionEndpoint {
+    endpoint_ref: MIDIObjectRef,
+    unique_id: i32,
+}
+
+impl ThruConnectionEndpoint {
+    fn from_object(object: &Object) -> ThruConnectionEndpoint {
+        ThruConnectionEndpoint {
+            endpoint_ref: object.0,
+            unique_id: object.unique_id().unwrap_or(0) as i32,
+        }
+    }
+
+    /// Get the unique id of the endpoint.
+    ///
+    pub fn unique_id(self) -> u32 {
+        self.unique_id as u32
+    }
+}
+
+/// A transformation applied to the events going through a thru connection.
+/// See [MIDITransformType](https://developer.apple.com/reference/coremidi/miditransformtype).
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Transform {
+    None,
+    FilterOut,
+    /// Remap the control to another control number (only for control transforms).
+    MapControl(i16),
+    Add(i16),
+    /// Scale by a fixed point number with 8 bits for the fractional part (256 is 1.0).
+    Scale(i16),
+    MinValue(i16),
+    MaxValue(i16),
+    /// Map the values with the value map at this index.
+    MapValue(i16),
+}
+
+impl Transform {
+    fn to_raw(self) -> (u16, i16) {
+        match self {
+            Transform::None => (0, 0),
+            Transform::FilterOut => (1, 0),
+            Transform::MapControl(param) => (2, param),
+            Transform::Add(param) => (8, param),
+            Transform::Scale(param) => (9, param),
+            Transform::MinValue(param) => (10, param),
+            Transform::MaxValue(param) => (11, param),
+            Transform::MapValue(param) => (12, param),
+        }
+    }
+
+    fn from_raw(transform_type: u16, param: i16) -> Result<Transform, ThruParamsError> {
+        match transform_type {
+            0 => Ok(Transform::None),
+            1 => Ok(Transform::FilterOut),
+            2 => Ok(Transform::MapControl(param)),
+            8 => Ok(Transform::Add(param)),
+            9 => Ok(Transform::Scale(param)),
+            10 => Ok(Transform::MinValue(param)),
+            11 => Ok(Transform::MaxValue(param)),
+            12 => Ok(Transform::MapValue(param)),
+            unknown => Err(ThruParamsError::UnknownTransform(unknown)),
+        }
+    }
+}
+
+/// The kind of controller messages that a control transform applies to.
+/// See [MIDITransformControlType](https://developer.apple.com/reference/coremidi/miditransformcontroltype).
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ControlType {
+    SevenBit,
+    FourteenBit,
+    SevenBitRpn,
+    FourteenBitRpn,
+    SevenBitNrpn,
+    FourteenBitNrpn,
+}
+
+impl ControlType {
+    fn from_raw(control_type: u8) -> Result<ControlType, ThruParamsError> {
+        match control_type {
+            0 => Ok(ControlType::SevenBit),
+            1 => Ok(ControlType::FourteenBit),
+            2 => Ok(ControlType::SevenBitRpn),
+            3 => Ok(ControlType::FourteenBitRpn),
+            4 => Ok(ControlType::SevenBitNrpn),
+            5 => Ok(ControlType::FourteenBitNrpn),
+            unknown => Err(ThruParamsError::UnknownControlType(unknown)),
+        }
+    }
+}
+
+/// A transformation for the controller messages with a given control number.
+/// See [MIDIControlTransform](https://developer.apple.com/reference/coremidi/midicontroltransform).
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ControlTransform {
+    pub control_type: ControlType,
+    pub remapped_control_type: ControlType,
+    pub control_number: u16,
+    pub transform: Transform,
+}
+
+/// The value that each value from 0 to 127 is mapped to, for `Transform::MapValue`.
+/// See [MIDIValueMap](https://developer.apple.com/reference/coremidi/midivaluemap).
+///
+#[derive(Clone, Copy)]
+pub struct ValueMap(pub [u8; VALUE_MAP_SIZE]);
+
+impl PartialEq for ValueMap {
+    fn eq(&self, other: &ValueMap) -> bool {
+        self.0[..] == other.0[..]
+    }
+}
+
+impl Eq for ValueMap {}
+
+impl fmt::Debug for ValueMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ValueMap({:?})", &self.0[..])
+    }
+}
+
+/// The reasons why some data can't be read as `ThruConnectionParams`.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum ThruParamsError {
+    /// The data is shorter than the size it declares.
+    TooShort(usize),
+    /// There are more than `THRU_CONNECTION_MAX_ENDPOINTS` sources or destinations.
+    TooManyEndpoints(u32),
+    UnknownTransform(u16),
+    UnknownControlType(u8),
+}
+
+/// The settings of a thru connection.
+/// See [MIDIThruConnectionParams](https://developer.apple.com/reference/coremidi/midithruconnectionparams).
+///
+/// By default there are no sources or destinations, every channel is mapped to itself,
+/// and nothing is filtered or transformed.
+///
+/// ```
+/// use coremidi::{ThruConnectionParams, Transform};
+/// let params = ThruConnectionParams::new()
+///     .map_channel(0, 9)
+///     .note_range(36, 84)
+///     .velocity_transform(Transform::Scale(128))
+///     .filter_out_beat_clock(true);
+/// assert_eq!(params.channel_map()[0], Some(9));
+/// assert_eq!(ThruConnectionParams::from_bytes(&params.to_bytes()), Ok(params));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThruConnectionParams {
+    sources: Vec<ThruConnectionEndpoint>,
+    destinations: Vec<ThruConnectionEndpoint>,
+    channel_map: [u8; 16],
+    low_velocity: u8,
+    high_velocity: u8,
+    low_note: u8,
+    high_note: u8,
+    note_number: Transform,
+    velocity: Transform,
+    key_pressure: Transform,
+    channel_pressure: Transform,
+    program_change: Transform,
+    pitch_bend: Transform,
+    filter_out_sysex: bool,
+    filter_out_mtc: bool,
+    filter_out_beat_clock: bool,
+    filter_out_tune_request: bool,
+    filter_out_all_controls: bool,
+    controls: Vec<ControlTransform>,
+    maps: Vec<ValueMap>,
+}
+
+impl Default for ThruConnectionParams {
+    fn default() -> Self {
+        let mut channel_map = [0u8; 16];
+        for (channel, mapped) in channel_map.iter_mut().enumerate() {
+            *mapped = channel as u8;
+        }
+        ThruConnectionParams {
+            sources: Vec::new(),
+            destinations: Vec::new(),
+            channel_map,
+            low_velocity: 0,
+            high_velocity: 0,
+            low_note: 0,
+            high_note: 127,
+            note_number: Transform::None,
+            velocity: Transform::None,
+            key_pressure: Transform::None,
+            channel_pressure: Transform::None,
+            program_change: Transform::None,
+            pitch_bend: Transform::None,
+            filter_out_sysex: false,
+            filter_out_mtc: false,
+            filter_out_beat_clock: false,
+            filter_out_tune_request: false,
+            filter_out_all_controls: false,
+            controls: Vec::new(),
+            maps: Vec::new(),
+        }
+    }
+}
+
+impl ThruConnectionParams {
+    /// Create the default params, without sources or destinations.
+    ///
+    pub fn new() -> ThruConnectionParams {
+        ThruConnectionParams::default()
+    }
+
+    /// Add a source, or fail with `ThruParamsError::TooManyEndpoints` when there are
+    /// already `THRU_CONNECTION_MAX_ENDPOINTS`.
+    ///
+    pub fn source(mut self, source: &Source) -> Result<Self, ThruParamsError> {
+        if self.sources.len() >= THRU_CONNECTION_MAX_ENDPOINTS {
+            return Err(ThruParamsError::TooManyEndpoints(self.sources.len() as u32 + 1));
+        }
+        self.sources.push(ThruConnectionEndpoint::from_object(source));
+        Ok(self)
+    }
+
+    /// Add a destination, or fail with `ThruParamsError::TooManyEndpoints` when there are
+    /// already `THRU_CONNECTION_MAX_ENDPOINTS`.
+    ///
+    pub fn destination(mut self, destination: &Destination) -> Result<Self, ThruParamsError> {
+        if self.destinations.len() >= THRU_CONNECTION_MAX_ENDPOINTS {
+            return Err(ThruParamsError::TooManyEndpoints(self.destinations.len() as u32 + 1));
+        }
+        self.destinations.push(ThruConnectionEndpoint::from_object(destination));
+        Ok(self)
+    }
+
+    /// Send the events from the channel `from` to the channel `to` (both from 0 to 15).
+    ///
+    pub fn map_channel(mut self, from: u8, to: u8) -> Self {
+        self.channel_map[(from & 0x0f) as usize] = to & 0x0f;
+        self
+    }
+
+    /// Filter out the events from a channel (from 0 to 15).
+    ///
+    pub fn filter_out_channel(mut self, channel: u8) -> Self {
+        self.channel_map[(channel & 0x0f) as usize] = CHANNEL_FILTERED_OUT;
+        self
+    }
+
+    /// Filter out the note events with velocities out of the range, where a `high` of 0 means no upper limit.
+    ///
+    pub fn velocity_range(mut self, low: u8, high: u8) -> Self {
+        self.low_velocity = low;
+        self.high_velocity = high;
+        self
+    }
+
+    /// Filter out the note and key pressure events with notes out of the range when `low <= high`,
+    /// or the ones within the range otherwise.
+    ///
+    pub fn note_range(mut self, low: u8, high: u8) -> Self {
+        self.low_note = low;
+        self.high_note = high;
+        self
+    }
+
+    /// Set the transformation for note numbers.
+    ///
+    pub fn note_number_transform(mut self, transform: Transform) -> Self {
+        self.note_number = transform;
+        self
+    }
+
+    /// Set the transformation for note velocities.
+    ///
+    pub fn velocity_transform(mut self, transform: Transform) -> Self {
+        self.velocity = transform;
+        self
+    }
+
+    /// Set the transformation for key pressure.
+    ///
+    pub fn key_pressure_transform(mut self, transform: Transform) -> Self {
+        self.key_pressure = transform;
+        self
+    }
+
+    /// Set the transformation for channel pressure.
+    ///
+    pub fn channel_pressure_transform(mut self, transform: Transform) -> Self {
+        self.channel_pressure = transform;
+        self
+    }
+
+    /// Set the transformation for program changes.
+    ///
+    pub fn program_change_transform(mut self, transform: Transform) -> Self {
+        self.program_change = transform;
+        self
+    }
+
+    /// Set the transformation for pitch bends.
+    ///
+    pub fn pitch_bend_transform(mut self, transform: Transform) -> Self {
+        self.pitch_bend = transform;
+        self
+    }
+
+    /// Add a transformation for controller messages.
+    ///
+    pub fn control_transform(mut self, control_transform: ControlTransform) -> Self {
+        self.controls.push(control_transform);
+        self
+    }
+
+    /// Add a value map, which can be used from `Transform::MapValue` with the index it is added at.
+    ///
+    pub fn value_map(mut self, map: ValueMap) -> Self {
+        self.maps.push(map);
+        self
+    }
+
+    /// Filter out the System Exclusive messages.
+    ///
+    pub fn filter_out_sysex(mut self, filter_out: bool) -> Self {
+        self.filter_out_sysex = filter_out;
+        self
+    }
+
+    /// Filter out the MIDI Time Code messages.
+    ///
+    pub fn filter_out_mtc(mut self, filter_out: bool) -> Self {
+        self.filter_out_mtc = filter_out;
+        self
+    }
+
+    /// Filter out the beat clock messages.
+    ///
+    pub fn filter_out_beat_clock(mut self, filter_out: bool) -> Self {
+        self.filter_out_beat_clock = filter_out;
+        self
+    }
+
+    /// Filter out the Tune Request messages.
+    ///
+    pub fn filter_out_tune_request(mut self, filter_out: bool) -> Self {
+        self.filter_out_tune_request = filter_out;
+        self
+    }
+
+    /// Filter out the controller messages.
+    ///
+    pub fn filter_out_all_controls(mut self, filter_out: bool) -> Self {
+        self.filter_out_all_controls = filter_out;
+        self
+    }
+
+    /// Get the sources the events come from.
+    ///
+    pub fn sources(&self) -> &[ThruConnectionEndpoint] {
+        &self.sources
+    }
+
+    /// Get the destinations the events go to.
+    ///
+    pub fn destinations(&self) -> &[ThruConnectionEndpoint] {
+        &self.destinations
+    }
+
+    /// Get the channel that every channel is mapped to, or `None` for the filtered out ones.
+    ///
+    pub fn channel_map(&self) -> [Option<u8>; 16] {
+        let mut channel_map = [None; 16];
+        for (mapped, &raw) in channel_map.iter_mut().zip(self.channel_map.iter()) {
+            if raw != CHANNEL_FILTERED_OUT {
+                *mapped = Some(raw);
+            }
+        }
+        channel_map
+    }
+
+    /// Get the transformations for controller messages.
+    ///
+    pub fn controls(&self) -> &[ControlTransform] {
+        &self.controls
+    }
+
+    /// Get the value maps, in the order they were added.
+    ///
+    pub fn value_maps(&self) -> &[ValueMap] {
+        &self.maps
+    }
+
+    /// Serialize the params into the binary layout of MIDIThruConnectionParams,
+    /// followed by the control transforms and the value maps.
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; PARAMS_SIZE];
+
+        // version stays 0
+        write_u32(&mut bytes, SOURCES_OFFSET - 4, self.sources.len() as u32);
+        write_endpoints(&mut bytes, SOURCES_OFFSET, &self.sources);
+        write_u32(&mut bytes, NUM_DESTINATIONS_OFFSET, self.destinations.len() as u32);
+        write_endpoints(&mut bytes, DESTINATIONS_OFFSET, &self.destinations);
+
+        bytes[CHANNEL_MAP_OFFSET..(CHANNEL_MAP_OFFSET + 16)].copy_from_slice(&self.channel_map);
+        bytes[VELOCITY_RANGE_OFFSET] = self.low_velocity;
+        bytes[VELOCITY_RANGE_OFFSET + 1] = self.high_velocity;
+        bytes[NOTE_RANGE_OFFSET] = self.low_note;
+        bytes[NOTE_RANGE_OFFSET + 1] = self.high_note;
+
+        for (i, transform) in self.transforms().iter().enumerate() {
+            write_transform(&mut bytes, TRANSFORMS_OFFSET + i * 4, *transform);
+        }
+
+        bytes[FILTERS_OFFSET] = self.filter_out_sysex as u8;
+        bytes[FILTERS_OFFSET + 1] = self.filter_out_mtc as u8;
+        bytes[FILTERS_OFFSET + 2] = self.filter_out_beat_clock as u8;
+        bytes[FILTERS_OFFSET + 3] = self.filter_out_tune_request as u8;
+        bytes[FILTER_OUT_ALL_CONTROLS_OFFSET] = self.filter_out_all_controls as u8;
+        write_u16(&mut bytes, NUM_CONTROL_TRANSFORMS_OFFSET, self.controls.len() as u16);
+        write_u16(&mut bytes, NUM_MAPS_OFFSET, self.maps.len() as u16);
+
+        for control in &self.controls {
+            let mut control_bytes = [0u8; CONTROL_TRANSFORM_SIZE];
+            control_bytes[0] = control.control_type as u8;
+            control_bytes[1] = control.remapped_control_type as u8;
+            write_u16(&mut control_bytes, 2, control.control_number);
+            write_transform(&mut control_bytes, 4, control.transform);
+            bytes.extend_from_slice(&control_bytes);
+        }
+
+        for map in &self.maps {
+            bytes.extend_from_slice(&map.0);
+        }
+
+        bytes
+    }
+
+    /// Deserialize the params from the binary layout of MIDIThruConnectionParams,
+    /// followed by the control transforms and the value maps.
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> Result<ThruConnectionParams, ThruParamsError> {
+        if bytes.len() < PARAMS_SIZE {
+            return Err(ThruParamsError::TooShort(bytes.len()));
+        }
+
+        let num_controls = read_u16(bytes, NUM_CONTROL_TRANSFORMS_OFFSET) as usize;
+        let num_maps = read_u16(bytes, NUM_MAPS_OFFSET) as usize;
+        let controls_offset = PARAMS_SIZE;
+        let maps_offset = controls_offset + num_controls * CONTROL_TRANSFORM_SIZE;
+        if bytes.len() < maps_offset + num_maps * VALUE_MAP_SIZE {
+            return Err(ThruParamsError::TooShort(bytes.len()));
+        }
+
+        let mut channel_map = [0u8; 16];
+        channel_map.copy_from_slice(&bytes[CHANNEL_MAP_OFFSET..(CHANNEL_MAP_OFFSET + 16)]);
+
+        let transform = |index: usize| read_transform(bytes, TRANSFORMS_OFFSET + index * 4);
+
+        let mut controls = Vec::with_capacity(num_controls);
+        for index in 0..num_controls {
+            let offset = controls_offset + index * CONTROL_TRANSFORM_SIZE;
+            controls.push(ControlTransform {
+                control_type: ControlType::from_raw(bytes[offset])?,
+                remapped_control_type: ControlType::from_raw(bytes[offset + 1])?,
+                control_number: read_u16(bytes, offset + 2),
+                transform: read_transform(bytes, offset + 4)?,
+            });
+        }
+
+        let mut maps = Vec::with_capacity(num_maps);
+        for index in 0..num_maps {
+            let offset = maps_offset + index * VALUE_MAP_SIZE;
+            let mut map = [0u8; VALUE_MAP_SIZE];
+            map.copy_from_slice(&bytes[offset..(offset + VALUE_MAP_SIZE)]);
+            maps.push(ValueMap(map));
+        }
+
+        Ok(ThruConnectionParams {
+            sources: read_endpoints(bytes, SOURCES_OFFSET - 4)?,
+            destinations: read_endpoints(bytes, NUM_DESTINATIONS_OFFSET)?,
+            channel_map,
+            low_velocity: bytes[VELOCITY_RANGE_OFFSET],
+            high_velocity: bytes[VELOCITY_RANGE_OFFSET + 1],
+            low_note: bytes[NOTE_RANGE_OFFSET],
+            high_note: bytes[NOTE_RANGE_OFFSET + 1],
+            note_number: transform(0)?,
+            velocity: transform(1)?,
+            key_pressure: transform(2)?,
+            channel_pressure: transform(3)?,
+            program_change: transform(4)?,
+            pitch_bend: transform(5)?,
+            filter_out_sysex: bytes[FILTERS_OFFSET] != 0,
+            filter_out_mtc: bytes[FILTERS_OFFSET + 1] != 0,
+            filter_out_beat_clock: bytes[FILTERS_OFFSET + 2] != 0,
+            filter_out_tune_request: bytes[FILTERS_OFFSET + 3] != 0,
+            filter_out_all_controls: bytes[FILTER_OUT_ALL_CONTROLS_OFFSET] != 0,
+            controls,
+            maps,
+        })
+    }
+
+    fn transforms(&self) -> [Transform; 6] {
+        [self.note_number, self.velocity, self.key_pressure, self.channel_pressure, self.program_change, self.pitch_bend]
+    }
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..(offset + 2)].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..(offset + 4)].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn write_transform(bytes: &mut [u8], offset: usize, transform: Transform) {
+    let (transform_type, param) = transform.to_raw();
+    write_u16(bytes, offset, transform_type);
+    write_u16(bytes, offset + 2, param as u16);
+}
+
+fn read_transform(bytes: &[u8], offset: usize) -> Result<Transform, ThruParamsError> {
+    Transform::from_raw(read_u16(bytes, offset), read_u16(bytes, offset + 2) as i16)
+}
+
+fn write_endpoints(bytes: &mut [u8], offset: usize, endpoints: &[ThruConnectionEndpoint]) {
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        write_u32(bytes, offset + i * ENDPOINT_SIZE, endpoint.endpoint_ref);
+        write_u32(bytes, offset + i * ENDPOINT_SIZE + 4, endpoint.unique_id as u32);
+    }
+}
+
+/// Reads the number of endpoints at `offset` followed by the endpoints.
+fn read_endpoints(bytes: &[u8], offset: usize) -> Result<Vec<ThruConnectionEndpoint>, ThruParamsError> {
+    let count = read_u32(bytes, offset);
+    if count as usize > THRU_CONNECTION_MAX_ENDPOINTS {
+        return Err(ThruParamsError::TooManyEndpoints(count));
+    }
+    Ok((0..(count as usize)).map(|i| {
+        let endpoint_offset = offset + 4 + i * ENDPOINT_SIZE;
+        ThruConnectionEndpoint {
+            endpoint_ref: read_u32(bytes, endpoint_offset),
+            unique_id: read_u32(bytes, endpoint_offset + 4) as i32,
+        }
+    }).collect())
+}
+
+impl ThruConnection {
+    /// Creates a thru connection that will be disposed when dropped, or when the process exits.
+    /// See [MIDIThruConnectionCreate](https://developer.apple.com/reference/coremidi/1495370-midithruconnectioncreate).
+    ///
+    pub fn create(params: &ThruConnectionParams) -> Result<ThruConnection, Error> {
+        Current::thru_connection_create(None, &params.to_bytes()).map(|connection_ref| {
+            ThruConnection { object: Object(connection_ref), persistent: false }
+        }).map_err(Error::from)
+    }
+
+    /// Creates a thru connection that persists until it is explicitly disposed, even after the process exits.
+    /// The owner id is usually the bundle id of the application, and can be used to find its connections later.
+    /// See [MIDIThruConnectionCreate](https://developer.apple.com/reference/coremidi/1495370-midithruconnectioncreate).
+    ///
+    pub fn create_persistent(owner_id: &str, params: &ThruConnectionParams) -> Result<ThruConnection, Error> {
+        Current::thru_connection_create(Some(owner_id), &params.to_bytes()).map(|connection_ref| {
+            ThruConnection { object: Object(connection_ref), persistent: true }
+        }).map_err(Error::from)
+    }
+
+    /// Finds the persistent thru connections created with an owner id.
+    /// See [MIDIThruConnectionFind](https://developer.apple.com/reference/coremidi/1495101-midithruconnectionfind).
+    ///
+    pub fn find(owner_id: &str) -> Result<Vec<ThruConnection>, Error> {
+        Current::thru_connection_find(owner_id).map(|connection_refs| {
+            connection_refs.into_iter().map(|connection_ref| {
+                ThruConnection { object: Object(connection_ref), persistent: true }
+            }).collect()
+        }).map_err(Error::from)
+    }
+
+    /// Gets the current params of the connection.
+    /// See [MIDIThruConnectionGetParams](https://developer.apple.com/reference/coremidi/1495263-midithruconnectiongetparams).
+    ///
+    pub fn params(&self) -> Result<ThruConnectionParams, Error> {
+        let bytes = Current::thru_connection_get_params(self.object.0)?;
+        // The MIDI server is expected to always return well formed params
+        ThruConnectionParams::from_bytes(&bytes).map_err(|_| Error::UnknownError)
+    }
+
+    /// Changes the params of the connection.
+    /// See [MIDIThruConnectionSetParams](https://developer.apple.com/reference/coremidi/1495161-midithruconnectionsetparams).
+    ///
+    pub fn set_params(&self, params: &ThruConnectionParams) -> Result<(), Error> {
+        Current::thru_connection_set_params(self.object.0, &params.to_bytes()).map_err(Error::from)
+    }
+
+    /// Check whether the connection outlives this value and the process.
+    ///
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    /// Disposes the connection, even if it is persistent.
+    /// See [MIDIThruConnectionDispose](https://developer.apple.com/reference/coremidi/1495301-midithruconnectiondispose).
+    ///
+    pub fn dispose(mut self) -> Result<(), Error> {
+        // Avoid disposing it twice when dropped
+        self.persistent = true;
+        Current::thru_connection_dispose(self.object.0).map_err(Error::from)
+    }
+}
+
+impl Deref for ThruConnection {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        &self.object
+    }
+}
+
+impl Drop for ThruConnection {
+    fn drop(&mut self) {
+        if !self.persistent {
+            let _ = Current::thru_connection_dispose(self.object.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Client, Destinations, Sources, ThruConnection};
+    use super::{
+        ControlTransform, ControlType, ThruConnectionParams, ThruParamsError, Transform, ValueMap,
+        PARAMS_SIZE, CHANNEL_MAP_OFFSET, NOTE_RANGE_OFFSET, TRANSFORMS_OFFSET, FILTERS_OFFSET, THRU_CONNECTION_MAX_ENDPOINTS,
+    };
+
+    #[test]
+    fn default_params_layout() {
+        let bytes = ThruConnectionParams::new().to_bytes();
+        assert_eq!(bytes.len(), PARAMS_SIZE);
+        assert_eq!(&bytes[CHANNEL_MAP_OFFSET..(CHANNEL_MAP_OFFSET + 16)], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(&bytes[NOTE_RANGE_OFFSET..(NOTE_RANGE_OFFSET + 2)], &[0, 127]);
+        assert!(bytes[..CHANNEL_MAP_OFFSET].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn params_layout() {
+        let params = ThruConnectionParams::new()
+            .filter_out_channel(1)
+            .velocity_transform(Transform::Add(-10))
+            .filter_out_mtc(true)
+            .control_transform(ControlTransform {
+                control_type: ControlType::SevenBit,
+                remapped_control_type: ControlType::FourteenBit,
+                control_number: 7,
+                transform: Transform::MapControl(11),
+            })
+            .value_map(ValueMap([0x7f; 128]));
+        let bytes = params.to_bytes();
+        assert_eq!(bytes.len(), PARAMS_SIZE + 8 + 128);
+        assert_eq!(bytes[CHANNEL_MAP_OFFSET + 1], 0xff);
+        assert_eq!(&bytes[(TRANSFORMS_OFFSET + 4)..(TRANSFORMS_OFFSET + 8)], &[8, 0, 0xf6, 0xff]);
+        assert_eq!(&bytes[FILTERS_OFFSET..(FILTERS_OFFSET + 4)], &[0, 1, 0, 0]);
+        assert_eq!(&bytes[192..196], &[1, 0, 1, 0]);
+        assert_eq!(&bytes[PARAMS_SIZE..(PARAMS_SIZE + 8)], &[0, 1, 7, 0, 2, 0, 11, 0]);
+        assert_eq!(bytes[PARAMS_SIZE + 8], 0x7f);
+        assert_eq!(ThruConnectionParams::from_bytes(&bytes), Ok(params));
+    }
+
+    #[test]
+    fn params_round_trip_with_endpoints() {
+        let client = Client::new("Thru Client").unwrap();
+        let virtual_source = client.virtual_source("Thru Source").unwrap();
+        let virtual_destination = client.virtual_destination("Thru Destination", |_| ()).unwrap();
+        let source = Sources.into_iter().find(|s| s.unique_id() == virtual_source.unique_id()).unwrap();
+        let destination = Destinations.into_iter().find(|d| d.unique_id() == virtual_destination.unique_id()).unwrap();
+
+        let params = ThruConnectionParams::new()
+            .source(&source).unwrap()
+            .destination(&destination).unwrap()
+            .map_channel(0, 9);
+        let decoded = ThruConnectionParams::from_bytes(&params.to_bytes()).unwrap();
+        assert_eq!(decoded.sources()[0].unique_id(), source.unique_id().unwrap());
+        assert_eq!(decoded.destinations()[0].unique_id(), destination.unique_id().unwrap());
+        assert_eq!(decoded.channel_map()[0], Some(9));
+        assert_eq!(decoded, params);
+
+        let full = (0..THRU_CONNECTION_MAX_ENDPOINTS)
+            .try_fold(ThruConnectionParams::new(), |params, _| params.destination(&destination))
+            .unwrap();
+        assert_eq!(full.destinations().len(), THRU_CONNECTION_MAX_ENDPOINTS);
+        assert_eq!(full.destination(&destination), Err(ThruParamsError::TooManyEndpoints(9)));
+    }
+
+    #[test]
+    fn params_errors() {
+        let mut bytes = ThruConnectionParams::new().to_bytes();
+        assert_eq!(ThruConnectionParams::from_bytes(&bytes[..100]), Err(ThruParamsError::TooShort(100)));
+        bytes[4] = 9;
+        assert_eq!(ThruConnectionParams::from_bytes(&bytes), Err(ThruParamsError::TooManyEndpoints(9)));
+        bytes[4] = 0;
+        bytes[TRANSFORMS_OFFSET] = 3;
+        assert_eq!(ThruConnectionParams::from_bytes(&bytes), Err(ThruParamsError::UnknownTransform(3)));
+        bytes[TRANSFORMS_OFFSET] = 0;
+        bytes[193] = 1;
+        assert_eq!(ThruConnectionParams::from_bytes(&bytes), Err(ThruParamsError::TooShort(PARAMS_SIZE)));
+    }
+
+    #[test]
+    fn persistent_connections() {
+        let owner_id = "coremidi.tests.persistent_connections";
+        let params = ThruConnectionParams::new().filter_out_sysex(true);
+        let connection = ThruConnection::create_persistent(owner_id, &params).unwrap();
+        assert!(connection.is_persistent());
+        assert_eq!(connection.params(), Ok(params));
+
+        let found = ThruConnection::find(owner_id).unwrap();
+        assert_eq!(found.len(), 1);
+        let new_params = ThruConnectionParams::new().filter_out_beat_clock(true);
+        found[0].set_params(&new_params).unwrap();
+        assert_eq!(connection.params(), Ok(new_params));
+
+        connection.dispose().unwrap();
+        assert_eq!(ThruConnection::find(owner_id).unwrap().len(), 0);
+    }
+}
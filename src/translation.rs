@@ -0,0 +1,557 @@
+use events::{EventBuffer, EventList, Protocol};
+use messages::{MessageParser, MidiMessage};
+use packets::PacketBuffer;
+use ump::{Midi2ChannelVoice, UmpMessage};
+use PacketList;
+
+const BANK_SELECT_MSB: u8 = 0;
+const BANK_SELECT_LSB: u8 = 32;
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+const NRPN_LSB: u8 = 98;
+const NRPN_MSB: u8 = 99;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+const NULL_PARAMETER: (u8, u8) = (127, 127);
+
+/// The change of a Relative Controller for a Data Increment, which is one step of the 14-bit Data Entry value.
+const DATA_INCREMENT_STEP: i32 = 1 << 18;
+
+/// The MIDI 1.0 velocity that a Note On with velocity 0 stands for.
+const DEFAULT_NOTE_OFF_VELOCITY: u8 = 64;
+
+/// Scale a value of `src_bits` up to `dst_bits` with the min-center-max algorithm of the MIDI 2.0 specification.
+///
+/// The minimum, the center and the maximum values are kept, and scaling the result back down
+/// with `scale_down` gives the original value.
+pub(crate) fn scale_up(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    let scale_bits = dst_bits - src_bits;
+    let shifted = value << scale_bits;
+    if value <= 1 << (src_bits - 1) {
+        return shifted;
+    }
+    // Values above the center fill the new low bits by repeating the bits below the most significant one
+    let repeat_bits = src_bits - 1;
+    let mut repeat = value & ((1 << repeat_bits) - 1);
+    repeat = if scale_bits > repeat_bits {
+        repeat << (scale_bits - repeat_bits)
+    } else {
+        repeat >> (repeat_bits - scale_bits)
+    };
+    let mut result = shifted;
+    while repeat != 0 {
+        result |= repeat;
+        repeat >>= repeat_bits;
+    }
+    result
+}
+
+/// Scale a value of `src_bits` down to `dst_bits` by dropping the least significant bits.
+///
+pub(crate) fn scale_down(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    value >> (src_bits - dst_bits)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParameterKind {
+    Registered,
+    Assignable,
+}
+
+/// What a MIDI 1.0 receiver remembers about a channel between messages.
+#[derive(Clone, Copy, Debug, Default)]
+struct ChannelState {
+    bank: (u8, u8),
+    bank_pending: bool,
+    parameter_kind: Option<ParameterKind>,
+    parameter: (u8, u8),
+    data_entry_msb: u8,
+}
+
+impl ChannelState {
+    fn control_change(&mut self, channel: u8, control: u8, value: u8) -> Option<Midi2ChannelVoice> {
+        match control {
+            BANK_SELECT_MSB | BANK_SELECT_LSB => {
+                if control == BANK_SELECT_MSB { self.bank.0 = value } else { self.bank.1 = value }
+                self.bank_pending = true;
+                None
+            },
+            RPN_MSB | RPN_LSB | NRPN_MSB | NRPN_LSB => {
+                let kind = if control == RPN_MSB || control == RPN_LSB {
+                    ParameterKind::Registered
+                } else {
+                    ParameterKind::Assignable
+                };
+                if self.parameter_kind != Some(kind) {
+                    self.parameter_kind = Some(kind);
+                    self.parameter = (0, 0);
+                }
+                if control == RPN_MSB || control == NRPN_MSB { self.parameter.0 = value } else { self.parameter.1 = value }
+                self.data_entry_msb = 0;
+                None
+            },
+            DATA_ENTRY_MSB | DATA_ENTRY_LSB if self.parameter_kind.is_some() && self.parameter != NULL_PARAMETER => {
+                let lsb = if control == DATA_ENTRY_MSB {
+                    self.data_entry_msb = value;
+                    0
+                } else {
+                    value
+                };
+                let data = scale_up((u32::from(self.data_entry_msb) << 7) | u32::from(lsb), 14, 32);
+                let (bank, index) = self.parameter;
+                Some(match self.parameter_kind {
+                    Some(ParameterKind::Registered) => Midi2ChannelVoice::RegisteredController { channel, bank, index, data },
+                    _ => Midi2ChannelVoice::AssignableController { channel, bank, index, data },
+                })
+            },
+            DATA_INCREMENT | DATA_DECREMENT if self.parameter_kind.is_some() && self.parameter != NULL_PARAMETER => {
+                let data = if control == DATA_INCREMENT { DATA_INCREMENT_STEP } else { -DATA_INCREMENT_STEP };
+                let (bank, index) = self.parameter;
+                Some(match self.parameter_kind {
+                    Some(ParameterKind::Registered) => Midi2ChannelVoice::RelativeRegisteredController { channel, bank, index, data },
+                    _ => Midi2ChannelVoice::RelativeAssignableController { channel, bank, index, data },
+                })
+            },
+            _ => Some(Midi2ChannelVoice::ControlChange { channel, index: control, data: scale_up(u32::from(value), 7, 32) }),
+        }
+    }
+}
+
+/// Translates MIDI 1.0 byte streams into MIDI 2.0 Universal MIDI Packets, following the
+/// translation rules of the MIDI 2.0 specification.
+///
+/// Values are scaled up with the min-center-max algorithm, so translating them back with
+/// `Midi2ToMidi1` gives the original values. The translator keeps the state of a MIDI 1.0 receiver:
+///
+/// - A Note On with velocity 0 becomes a Note Off with the default velocity (64).
+/// - Bank Select (CC 0 and 32) is not sent on its own, but as the bank of the next Program Change.
+/// - Data Entry (CC 6 and 38) after selecting a RPN (CC 101 and 100) or a NRPN (CC 99 and 98)
+///   becomes a Registered or an Assignable Controller. The MSB is sent as soon as it's received,
+///   and the LSB sends the controller again with the complete value.
+/// - Data Increment and Decrement (CC 96 and 97) after selecting a RPN or a NRPN become a Relative
+///   Registered or a Relative Assignable Controller that changes the value by one step of the 14-bit Data Entry.
+/// - SysEx messages are split in SysEx7 chunks.
+///
+/// Running status and SysEx messages split across packets are supported, and malformed bytes are skipped.
+/// All the messages are translated into the same group, as MIDI 1.0 streams have no groups.
+///
+/// ```
+/// use coremidi::{Midi1ToMidi2, PacketBuffer};
+/// let mut translator = Midi1ToMidi2::new(0);
+/// let event_list = translator.translate_packet_list(&PacketBuffer::new(0, &[0x90, 0x3c, 0x40]));
+/// assert_eq!(event_list.iter().next().unwrap().data(), &[0x4090_3c00, 0x8000_0000]);
+/// ```
+#[derive(Debug)]
+pub struct Midi1ToMidi2 {
+    group: u8,
+    parser: MessageParser,
+    channels: [ChannelState; 16],
+}
+
+impl Midi1ToMidi2 {
+    /// Create a translator that writes the messages into `group`.
+    ///
+    pub fn new(group: u8) -> Midi1ToMidi2 {
+        Midi1ToMidi2 {
+            group: group & 0xf,
+            parser: MessageParser::new(),
+            channels: [ChannelState::default(); 16],
+        }
+    }
+
+    /// Forget the state of the stream, like the running status, the selected parameters and the pending banks.
+    ///
+    pub fn reset(&mut self) {
+        self.parser.reset();
+        self.channels = [ChannelState::default(); 16];
+    }
+
+    /// Translate a single MIDI 1.0 message, appending the resulting messages into `output`.
+    ///
+    /// Some messages, like Bank Select or the RPN and NRPN selection, only update the state
+    /// of the translator, so nothing is appended for them.
+    pub fn translate(&mut self, message: &MidiMessage, output: &mut Vec<UmpMessage>) {
+        let group = self.group;
+        if let MidiMessage::SysEx(ref payload) = *message {
+            output.extend(UmpMessage::sysex7(group, payload));
+        } else if message.channel().is_some() {
+            if let Some(message) = self.translate_channel_voice(message) {
+                output.push(UmpMessage::Midi2ChannelVoice { group, message });
+            }
+        } else {
+            output.push(UmpMessage::System { group, message: message.clone() });
+        }
+    }
+
+    /// Translate all the packets in a packet list into an event list of the MIDI 2.0 protocol,
+    /// keeping the timestamps of the packets.
+    ///
+    pub fn translate_packet_list(&mut self, packet_list: &PacketList) -> EventBuffer {
+        let mut event_list = EventBuffer::new(Protocol::Midi20);
+        let mut messages = Vec::new();
+        for packet in packet_list.iter() {
+            let parsed: Vec<_> = self.parser.parse(packet).collect();
+            for (timestamp, message) in parsed {
+                if let Ok(message) = message {
+                    messages.clear();
+                    self.translate(&message, &mut messages);
+                    for ump in &messages {
+                        event_list.push_message(timestamp, ump);
+                    }
+                }
+            }
+        }
+        event_list
+    }
+
+    fn translate_channel_voice(&mut self, message: &MidiMessage) -> Option<Midi2ChannelVoice> {
+        let velocity = |velocity: u8| scale_up(u32::from(velocity), 7, 16) as u16;
+        let data = |value: u8| scale_up(u32::from(value), 7, 32);
+        match *message {
+            MidiMessage::NoteOn { channel, note, velocity: 0 } => Some(Midi2ChannelVoice::NoteOff {
+                channel: channel & 0xf,
+                note,
+                velocity: velocity(DEFAULT_NOTE_OFF_VELOCITY),
+                attribute_type: 0,
+                attribute: 0,
+            }),
+            MidiMessage::NoteOn { channel, note, velocity: value } => Some(Midi2ChannelVoice::NoteOn {
+                channel: channel & 0xf,
+                note,
+                velocity: velocity(value),
+                attribute_type: 0,
+                attribute: 0,
+            }),
+            MidiMessage::NoteOff { channel, note, velocity: value } => Some(Midi2ChannelVoice::NoteOff {
+                channel: channel & 0xf,
+                note,
+                velocity: velocity(value),
+                attribute_type: 0,
+                attribute: 0,
+            }),
+            MidiMessage::PolyPressure { channel, note, pressure } => {
+                Some(Midi2ChannelVoice::PolyPressure { channel: channel & 0xf, note, data: data(pressure) })
+            },
+            MidiMessage::ControlChange { channel, control, value } => {
+                self.channels[usize::from(channel & 0xf)].control_change(channel & 0xf, control, value)
+            },
+            MidiMessage::ProgramChange { channel, program } => {
+                let state = &mut self.channels[usize::from(channel & 0xf)];
+                let bank = if state.bank_pending { Some(state.bank) } else { None };
+                state.bank_pending = false;
+                Some(Midi2ChannelVoice::ProgramChange { channel: channel & 0xf, program, bank })
+            },
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                Some(Midi2ChannelVoice::ChannelPressure { channel: channel & 0xf, data: data(pressure) })
+            },
+            MidiMessage::PitchBend { channel, value } => {
+                Some(Midi2ChannelVoice::PitchBend { channel: channel & 0xf, data: scale_up(u32::from(value), 14, 32) })
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Translates MIDI 2.0 Universal MIDI Packets into MIDI 1.0 byte streams, following the
+/// translation rules of the MIDI 2.0 specification.
+///
+/// Values are scaled down by dropping their least significant bits:
+///
+/// - A Note On whose velocity becomes 0 is sent with velocity 1, so it's not taken as a Note Off.
+/// - A Program Change with a bank is preceded by Bank Select (CC 0 and 32).
+/// - Registered and Assignable Controllers are sent as a RPN or a NRPN selection followed by Data Entry (CC 6 and 38).
+/// - Relative Registered and Assignable Controllers are sent as the selection followed by a Data Increment or
+///   a Data Decrement (CC 96 and 97) for each step of the 14-bit Data Entry value, so changes smaller than a step are skipped.
+///
+/// System messages, MIDI 1.0 Channel Voice messages and SysEx7 chunks are passed through.
+/// The messages without a MIDI 1.0 equivalent, like the per-note controllers,
+/// per-note pitch bend, SysEx8 or the utility messages, are skipped. Groups are ignored.
+///
+#[derive(Clone, Debug, Default)]
+pub struct Midi2ToMidi1;
+
+impl Midi2ToMidi1 {
+    pub fn new() -> Midi2ToMidi1 {
+        Midi2ToMidi1
+    }
+
+    /// Translate a single message, appending the MIDI 1.0 bytes into `bytes`.
+    ///
+    /// It returns false, without writing anything, for messages that can't be expressed in MIDI 1.0.
+    pub fn translate(&self, message: &UmpMessage, bytes: &mut Vec<u8>) -> bool {
+        match *message {
+            UmpMessage::Midi2ChannelVoice { message, .. } => {
+                let messages = Self::translate_channel_voice(message);
+                for message in &messages {
+                    message.write_to(bytes);
+                }
+                !messages.is_empty()
+            },
+            _ => message.write_midi1_bytes(bytes),
+        }
+    }
+
+    /// Translate all the packets in an event list of any protocol into a packet list,
+    /// keeping a packet with the same timestamp per event packet.
+    ///
+    /// Malformed words are skipped.
+    pub fn translate_event_list(&self, event_list: &EventList) -> PacketBuffer {
+        let mut packet_list = PacketBuffer::with_capacity(0);
+        let mut bytes = Vec::new();
+        for packet in event_list.iter() {
+            bytes.clear();
+            for message in packet.messages().filter_map(Result::ok) {
+                self.translate(&message, &mut bytes);
+            }
+            if !bytes.is_empty() {
+                packet_list.push_data(packet.timestamp(), &bytes);
+            }
+        }
+        packet_list
+    }
+
+    fn translate_channel_voice(message: Midi2ChannelVoice) -> Vec<MidiMessage> {
+        let velocity = |velocity: u16| scale_down(u32::from(velocity), 16, 7) as u8;
+        let value = |data: u32| scale_down(data, 32, 7) as u8;
+        let control = |channel: u8, control: u8, value: u8| MidiMessage::ControlChange { channel, control, value };
+        let parameter = |channel: u8, selection: (u8, u8), parameter: (u8, u8), data: u32| {
+            let data = scale_down(data, 32, 14);
+            vec![
+                control(channel, selection.0, parameter.0 & 0x7f),
+                control(channel, selection.1, parameter.1 & 0x7f),
+                control(channel, DATA_ENTRY_MSB, (data >> 7) as u8),
+                control(channel, DATA_ENTRY_LSB, (data & 0x7f) as u8),
+            ]
+        };
+        let relative = |channel: u8, selection: (u8, u8), parameter: (u8, u8), data: i32| {
+            let steps = (data / DATA_INCREMENT_STEP).abs() as usize;
+            if steps == 0 {
+                return Vec::new();
+            }
+            let step = if data > 0 { DATA_INCREMENT } else { DATA_DECREMENT };
+            let mut messages = vec![
+                control(channel, selection.0, parameter.0 & 0x7f),
+                control(channel, selection.1, parameter.1 & 0x7f),
+            ];
+            messages.extend((0..steps).map(|_| control(channel, step, 0)));
+            messages
+        };
+        match message {
+            Midi2ChannelVoice::NoteOff { channel, note, velocity: v, .. } => {
+                vec![MidiMessage::NoteOff { channel, note, velocity: velocity(v) }]
+            },
+            Midi2ChannelVoice::NoteOn { channel, note, velocity: v, .. } => {
+                vec![MidiMessage::NoteOn { channel, note, velocity: velocity(v).max(1) }]
+            },
+            Midi2ChannelVoice::PolyPressure { channel, note, data } => {
+                vec![MidiMessage::PolyPressure { channel, note, pressure: value(data) }]
+            },
+            Midi2ChannelVoice::ControlChange { channel, index, data } => vec![control(channel, index, value(data))],
+            Midi2ChannelVoice::RegisteredController { channel, bank, index, data } => {
+                parameter(channel, (RPN_MSB, RPN_LSB), (bank, index), data)
+            },
+            Midi2ChannelVoice::AssignableController { channel, bank, index, data } => {
+                parameter(channel, (NRPN_MSB, NRPN_LSB), (bank, index), data)
+            },
+            Midi2ChannelVoice::RelativeRegisteredController { channel, bank, index, data } => {
+                relative(channel, (RPN_MSB, RPN_LSB), (bank, index), data)
+            },
+            Midi2ChannelVoice::RelativeAssignableController { channel, bank, index, data } => {
+                relative(channel, (NRPN_MSB, NRPN_LSB), (bank, index), data)
+            },
+            Midi2ChannelVoice::ProgramChange { channel, program, bank } => {
+                let mut messages = Vec::new();
+                if let Some((msb, lsb)) = bank {
+                    messages.push(control(channel, BANK_SELECT_MSB, msb & 0x7f));
+                    messages.push(control(channel, BANK_SELECT_LSB, lsb & 0x7f));
+                }
+                messages.push(MidiMessage::ProgramChange { channel, program });
+                messages
+            },
+            Midi2ChannelVoice::ChannelPressure { channel, data } => {
+                vec![MidiMessage::ChannelPressure { channel, pressure: value(data) }]
+            },
+            Midi2ChannelVoice::PitchBend { channel, data } => {
+                vec![MidiMessage::PitchBend { channel, value: scale_down(data, 32, 14) as u16 }]
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use events::Protocol;
+    use packets::PacketBuffer;
+    use ump::{ChunkStatus, UmpMessage};
+    use EventBuffer;
+    use MidiMessage;
+    use super::*;
+
+    fn to_midi2(translator: &mut Midi1ToMidi2, bytes: &[u8]) -> Vec<u32> {
+        let event_list = translator.translate_packet_list(&PacketBuffer::new(0, bytes));
+        event_list.iter().flat_map(|packet| packet.data().to_vec()).collect()
+    }
+
+    fn to_midi1(words: &[u32]) -> Vec<u8> {
+        let mut event_list = EventBuffer::new(Protocol::Midi20);
+        event_list.push_data(0, words);
+        let packet_list = Midi2ToMidi1::new().translate_event_list(&event_list);
+        packet_list.iter().flat_map(|packet| packet.data().to_vec()).collect()
+    }
+
+    #[test]
+    fn scale_up_keeps_min_center_and_max() {
+        let cases: &[(u32, u32, u32, u32)] = &[
+            (0, 7, 16, 0x0000),
+            (1, 7, 16, 0x0200),
+            (64, 7, 16, 0x8000),
+            (100, 7, 16, 0xc924),
+            (127, 7, 16, 0xffff),
+            (0, 7, 32, 0x0000_0000),
+            (64, 7, 32, 0x8000_0000),
+            (100, 7, 32, 0xc924_9249),
+            (127, 7, 32, 0xffff_ffff),
+            (0, 14, 32, 0x0000_0000),
+            (0x2000, 14, 32, 0x8000_0000),
+            (0x3f80, 14, 32, 0xfe03_f01f),
+            (0x3fff, 14, 32, 0xffff_ffff),
+        ];
+        for &(value, src_bits, dst_bits, expected) in cases {
+            assert_eq!(scale_up(value, src_bits, dst_bits), expected, "{} from {} to {} bits", value, src_bits, dst_bits);
+        }
+    }
+
+    #[test]
+    fn scale_down_reverts_scale_up() {
+        for &(src_bits, dst_bits) in &[(7, 16), (7, 32), (14, 32)] {
+            for value in 0..(1 << src_bits) {
+                assert_eq!(scale_down(scale_up(value, src_bits, dst_bits), dst_bits, src_bits), value);
+            }
+        }
+    }
+
+    #[test]
+    fn midi1_to_midi2() {
+        let cases: &[(&[u8], &[u32])] = &[
+            (&[0x90, 0x3c, 0x7f], &[0x4090_3c00, 0xffff_0000]),
+            (&[0x90, 0x3c, 0x00], &[0x4080_3c00, 0x8000_0000]),
+            (&[0x81, 0x3c, 0x40], &[0x4081_3c00, 0x8000_0000]),
+            (&[0x90, 0x3c, 0x7f, 0x3e, 0x64], &[0x4090_3c00, 0xffff_0000, 0x4090_3e00, 0xc924_0000]),
+            (&[0xa2, 0x3c, 0x40], &[0x40a2_3c00, 0x8000_0000]),
+            (&[0xb0, 0x07, 0x7f], &[0x40b0_0700, 0xffff_ffff]),
+            (&[0xc0, 0x05], &[0x40c0_0000, 0x0500_0000]),
+            (&[0xb0, 0x00, 0x01, 0xb0, 0x20, 0x02, 0xc0, 0x05], &[0x40c0_0001, 0x0500_0102]),
+            (&[0xb0, 0x00, 0x01, 0xc0, 0x05, 0xc0, 0x06], &[0x40c0_0001, 0x0500_0100, 0x40c0_0000, 0x0600_0000]),
+            (&[0xd3, 0x40], &[0x40d3_0000, 0x8000_0000]),
+            (&[0xe0, 0x00, 0x40], &[0x40e0_0000, 0x8000_0000]),
+            (&[0xe0, 0x7f, 0x7f], &[0x40e0_0000, 0xffff_ffff]),
+            (&[0xb0, 0x65, 0x00, 0xb0, 0x64, 0x00, 0xb0, 0x06, 0x02],
+             &[0x4020_0000, 0x0400_0000]),
+            (&[0xb0, 0x65, 0x00, 0x64, 0x00, 0x06, 0x7f, 0x26, 0x7f],
+             &[0x4020_0000, 0xfe03_f01f, 0x4020_0000, 0xffff_ffff]),
+            (&[0xb1, 0x63, 0x01, 0xb1, 0x62, 0x02, 0xb1, 0x06, 0x40],
+             &[0x4031_0102, 0x8000_0000]),
+            (&[0xb0, 0x65, 0x7f, 0xb0, 0x64, 0x7f, 0xb0, 0x06, 0x40], &[0x40b0_0600, 0x8000_0000]),
+            (&[0xb0, 0x06, 0x40, 0xb0, 0x26, 0x00], &[0x40b0_0600, 0x8000_0000, 0x40b0_2600, 0x0000_0000]),
+            (&[0xb0, 0x65, 0x00, 0xb0, 0x64, 0x00, 0xb0, 0x60, 0x00], &[0x4040_0000, 0x0004_0000]),
+            (&[0xb1, 0x63, 0x01, 0xb1, 0x62, 0x02, 0xb1, 0x61, 0x7f], &[0x4051_0102, 0xfffc_0000]),
+            (&[0xb0, 0x60, 0x00], &[0x40b0_6000, 0x0000_0000]),
+            (&[0xf8], &[0x10f8_0000]),
+            (&[0xf2, 0x10, 0x20], &[0x10f2_1020]),
+            (&[0xf0, 0x7e, 0x7f, 0xf7], &[0x3002_7e7f, 0x0000_0000]),
+            (&[0xf0, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xf7],
+             &[0x3016_0102, 0x0304_0506, 0x3031_0700, 0x0000_0000]),
+            (&[0x3c, 0x7f], &[]),
+        ];
+        for &(bytes, words) in cases {
+            assert_eq!(to_midi2(&mut Midi1ToMidi2::new(0), bytes), words, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn midi2_to_midi1() {
+        let cases: &[(&[u32], &[u8])] = &[
+            (&[0x4090_3c00, 0xffff_0000], &[0x90, 0x3c, 0x7f]),
+            (&[0x4090_3c00, 0x0100_0000], &[0x90, 0x3c, 0x01]),
+            (&[0x4081_3c00, 0x8000_0000], &[0x81, 0x3c, 0x40]),
+            (&[0x40a2_3c00, 0x8000_0000], &[0xa2, 0x3c, 0x40]),
+            (&[0x40b0_0700, 0xffff_ffff], &[0xb0, 0x07, 0x7f]),
+            (&[0x40c0_0000, 0x0500_0000], &[0xc0, 0x05]),
+            (&[0x40c0_0001, 0x0500_0102], &[0xb0, 0x00, 0x01, 0xb0, 0x20, 0x02, 0xc0, 0x05]),
+            (&[0x40d3_0000, 0x8000_0000], &[0xd3, 0x40]),
+            (&[0x40e0_0000, 0x8000_0000], &[0xe0, 0x00, 0x40]),
+            (&[0x40e0_0000, 0xffff_ffff], &[0xe0, 0x7f, 0x7f]),
+            (&[0x4020_0000, 0x0400_0000], &[0xb0, 0x65, 0x00, 0xb0, 0x64, 0x00, 0xb0, 0x06, 0x02, 0xb0, 0x26, 0x00]),
+            (&[0x4031_0102, 0xffff_ffff], &[0xb1, 0x63, 0x01, 0xb1, 0x62, 0x02, 0xb1, 0x06, 0x7f, 0xb1, 0x26, 0x7f]),
+            (&[0x10f8_0000], &[0xf8]),
+            (&[0x2090_3c7f], &[0x90, 0x3c, 0x7f]),
+            (&[0x3016_0102, 0x0304_0506, 0x3031_0700, 0x0000_0000],
+             &[0xf0, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xf7]),
+            (&[0x4060_3c00, 0x8000_0000], &[]),
+            (&[0x4040_0000, 0x0004_0000], &[0xb0, 0x65, 0x00, 0xb0, 0x64, 0x00, 0xb0, 0x60, 0x00]),
+            (&[0x4051_0102, 0xfff8_0000], &[0xb1, 0x63, 0x01, 0xb1, 0x62, 0x02, 0xb1, 0x61, 0x00, 0xb1, 0x61, 0x00]),
+            (&[0x4040_0000, 0x0000_0001], &[]),
+            (&[0x0010_0000], &[]),
+        ];
+        for &(words, bytes) in cases {
+            assert_eq!(to_midi1(words), bytes, "{:08x?}", words);
+        }
+    }
+
+    #[test]
+    fn channel_voice_round_trip() {
+        let messages = [
+            MidiMessage::NoteOn { channel: 1, note: 0x3c, velocity: 0x01 },
+            MidiMessage::NoteOff { channel: 2, note: 0x3c, velocity: 0x7f },
+            MidiMessage::PolyPressure { channel: 3, note: 0x3c, pressure: 0x33 },
+            MidiMessage::ControlChange { channel: 4, control: 0x07, value: 0x65 },
+            MidiMessage::ProgramChange { channel: 5, program: 0x10 },
+            MidiMessage::ChannelPressure { channel: 6, pressure: 0x41 },
+            MidiMessage::PitchBend { channel: 7, value: 0x1234 },
+        ];
+        for message in &messages {
+            let mut translated = Vec::new();
+            Midi1ToMidi2::new(0).translate(message, &mut translated);
+            let mut bytes = Vec::new();
+            assert!(Midi2ToMidi1::new().translate(&translated[0], &mut bytes));
+            assert_eq!(&bytes, &message.to_bytes());
+        }
+    }
+
+    #[test]
+    fn midi1_to_midi2_keeps_state_across_packet_lists() {
+        let mut translator = Midi1ToMidi2::new(0);
+        assert_eq!(to_midi2(&mut translator, &[0xb0, 0x65, 0x00, 0xb0, 0x64, 0x00]), &[]);
+        assert_eq!(to_midi2(&mut translator, &[0xb0, 0x06, 0x02]), &[0x4020_0000, 0x0400_0000]);
+        assert_eq!(to_midi2(&mut translator, &[0xf0, 0x01, 0x02]), &[]);
+        assert_eq!(to_midi2(&mut translator, &[0x03, 0xf7]), &[0x3003_0102, 0x0300_0000]);
+
+        translator.reset();
+        assert_eq!(to_midi2(&mut translator, &[0xb0, 0x06, 0x02]), &[0x40b0_0600, 0x0400_0000]);
+    }
+
+    #[test]
+    fn midi1_to_midi2_uses_the_group() {
+        let mut translated = Vec::new();
+        Midi1ToMidi2::new(3).translate(&MidiMessage::TimingClock, &mut translated);
+        Midi1ToMidi2::new(3).translate(&MidiMessage::SysEx(vec![0x01]), &mut translated);
+        assert_eq!(translated, vec![
+            UmpMessage::System { group: 3, message: MidiMessage::TimingClock },
+            UmpMessage::SysEx7 { group: 3, status: ChunkStatus::Complete, data: vec![0x01] },
+        ]);
+    }
+
+    #[test]
+    fn midi2_to_midi1_keeps_timestamps() {
+        let mut event_list = EventBuffer::new(Protocol::Midi20);
+        event_list.push_data(10, &[0x4090_3c00, 0xffff_0000]);
+        event_list.push_data(20, &[0x0010_0000]);
+        event_list.push_data(30, &[0x4080_3c00, 0x0000_0000]);
+        let packet_list = Midi2ToMidi1::new().translate_event_list(&event_list);
+        let packets: Vec<_> = packet_list.iter().map(|packet| (packet.timestamp(), packet.data().to_vec())).collect();
+        assert_eq!(packets, vec![(10, vec![0x90, 0x3c, 0x7f]), (30, vec![0x80, 0x3c, 0x00])]);
+    }
+}
@@ -0,0 +1,878 @@
+use std::error;
+use std::fmt;
+
+use messages::MidiMessage;
+
+const UTILITY: u8 = 0x0;
+const SYSTEM: u8 = 0x1;
+const MIDI1_CHANNEL_VOICE: u8 = 0x2;
+const DATA_64: u8 = 0x3;
+const MIDI2_CHANNEL_VOICE: u8 = 0x4;
+const DATA_128: u8 = 0x5;
+const FLEX_DATA: u8 = 0xD;
+
+const SYSEX7_MAX_BYTES: usize = 6;
+const SYSEX8_MAX_BYTES: usize = 13;
+const MIXED_DATA_SET_BYTES: usize = 14;
+
+/// The position of a UMP within a message that is split across several of them, like SysEx.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ChunkStatus {
+    /// The whole message fits in a single UMP.
+    Complete,
+    Start,
+    Continue,
+    End,
+}
+
+impl ChunkStatus {
+    fn from_bits(bits: u32) -> ChunkStatus {
+        match bits & 0x3 {
+            0 => ChunkStatus::Complete,
+            1 => ChunkStatus::Start,
+            2 => ChunkStatus::Continue,
+            _ => ChunkStatus::End,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        match self {
+            ChunkStatus::Complete => 0,
+            ChunkStatus::Start => 1,
+            ChunkStatus::Continue => 2,
+            ChunkStatus::End => 3,
+        }
+    }
+
+    /// The status of each of the `count` chunks of a message, in order.
+    fn for_chunk(index: usize, count: usize) -> ChunkStatus {
+        match (index, count) {
+            (_, 1) => ChunkStatus::Complete,
+            (0, _) => ChunkStatus::Start,
+            (i, n) if i + 1 == n => ChunkStatus::End,
+            _ => ChunkStatus::Continue,
+        }
+    }
+}
+
+/// A Utility message (message type 0x0), which doesn't belong to any group.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UtilityMessage {
+    NoOp,
+    /// The sender clock time, in units of 1/31250 seconds.
+    JitterReductionClock(u16),
+    /// The time at which the following message was sent, in units of 1/31250 seconds.
+    JitterReductionTimestamp(u16),
+    DeltaClockstampTicksPerQuarterNote(u16),
+    /// The number of ticks since the last event, up to 20 bits long.
+    DeltaClockstamp(u32),
+}
+
+/// A MIDI 2.0 Channel Voice message (message type 0x4).
+///
+/// Channels go from 0 to 15, notes, banks and indexes are 7 bits long,
+/// and the rest of the values use the full range of their type.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Midi2ChannelVoice {
+    RegisteredPerNoteController { channel: u8, note: u8, index: u8, data: u32 },
+    AssignablePerNoteController { channel: u8, note: u8, index: u8, data: u32 },
+    RegisteredController { channel: u8, bank: u8, index: u8, data: u32 },
+    AssignableController { channel: u8, bank: u8, index: u8, data: u32 },
+    RelativeRegisteredController { channel: u8, bank: u8, index: u8, data: i32 },
+    RelativeAssignableController { channel: u8, bank: u8, index: u8, data: i32 },
+    /// The data goes from 0 to 0xFFFF_FFFF, where 0x8000_0000 means no bend.
+    PerNotePitchBend { channel: u8, note: u8, data: u32 },
+    NoteOff { channel: u8, note: u8, velocity: u16, attribute_type: u8, attribute: u16 },
+    NoteOn { channel: u8, note: u8, velocity: u16, attribute_type: u8, attribute: u16 },
+    PolyPressure { channel: u8, note: u8, data: u32 },
+    ControlChange { channel: u8, index: u8, data: u32 },
+    /// The bank, when present, is given as its (MSB, LSB) pair.
+    ProgramChange { channel: u8, program: u8, bank: Option<(u8, u8)> },
+    ChannelPressure { channel: u8, data: u32 },
+    /// The data goes from 0 to 0xFFFF_FFFF, where 0x8000_0000 means no bend.
+    PitchBend { channel: u8, data: u32 },
+    PerNoteManagement { channel: u8, note: u8, detach: bool, reset: bool },
+}
+
+/// The destination of a Flex Data message.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FlexDataAddress {
+    Channel(u8),
+    Group,
+}
+
+/// A message carried in Universal MIDI Packets (UMP), as used by MIDI 2.0.
+///
+/// Groups go from 0 to 15. The MIDI 1.0 messages carried in UMPs reuse `MidiMessage`,
+/// and SysEx messages are split into chunks that must be put back together by the receiver.
+///
+/// A message can be decoded from UMP words and encoded back:
+///
+/// ```
+/// use coremidi::{Midi2ChannelVoice, UmpMessage};
+/// let message = UmpMessage::Midi2ChannelVoice {
+///     group: 0,
+///     message: Midi2ChannelVoice::NoteOn { channel: 0, note: 0x3c, velocity: 0xffff, attribute_type: 0, attribute: 0 },
+/// };
+/// assert_eq!(message.to_words(), vec![0x4090_3c00, 0xffff_0000]);
+/// assert_eq!(UmpMessage::decode(&[0x4090_3c00, 0xffff_0000]), Some(Ok((message, 2))));
+/// ```
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UmpMessage {
+    Utility(UtilityMessage),
+    /// A MIDI 1.0 System Common or System Real Time message.
+    System { group: u8, message: MidiMessage },
+    /// A MIDI 1.0 Channel Voice message.
+    Midi1ChannelVoice { group: u8, message: MidiMessage },
+    /// A chunk of up to 6 bytes of a MIDI 1.0 System Exclusive message, without the 0xF0 and 0xF7 bytes.
+    SysEx7 { group: u8, status: ChunkStatus, data: Vec<u8> },
+    Midi2ChannelVoice { group: u8, message: Midi2ChannelVoice },
+    /// A chunk of up to 13 bytes of an 8-bit System Exclusive message.
+    SysEx8 { group: u8, status: ChunkStatus, stream_id: u8, data: Vec<u8> },
+    /// A Mixed Data Set header (when `header` is true) or payload chunk.
+    MixedDataSet { group: u8, header: bool, mds_id: u8, data: [u8; MIXED_DATA_SET_BYTES] },
+    /// A Flex Data message, like a tempo or a lyric, whose data depends on its status bank and status.
+    FlexData { group: u8, format: ChunkStatus, address: FlexDataAddress, status_bank: u8, status: u8, data: [u32; 3] },
+}
+
+/// The reasons why some UMP words could not be decoded into a `UmpMessage`.
+///
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UmpError {
+    /// The message with this message type ended before all its words were found.
+    Truncated(u8),
+    /// A message type that is reserved, or that this library does not decode (like UMP Stream messages).
+    UnsupportedType(u8),
+    /// A message with a status, or a length, that is not valid for its message type.
+    /// It contains the first word of the message.
+    InvalidMessage(u32),
+}
+
+impl fmt::Display for UmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UmpError::Truncated(message_type) => write!(f, "truncated UMP with message type {:x}", message_type),
+            UmpError::UnsupportedType(message_type) => write!(f, "unsupported UMP message type {:x}", message_type),
+            UmpError::InvalidMessage(word) => write!(f, "invalid UMP starting with {:08x}", word),
+        }
+    }
+}
+
+impl error::Error for UmpError {}
+
+impl UmpMessage {
+    /// Decode the message at the beginning of `words`.
+    ///
+    /// On success it returns the message together with the number of words that it took,
+    /// and on failure the error together with the number of words that should be skipped
+    /// to continue decoding. It returns `None` when `words` is empty.
+    ///
+    /// ```
+    /// use coremidi::{MidiMessage, UmpError, UmpMessage};
+    /// assert_eq!(UmpMessage::decode(&[0x10f8_0000]), Some(Ok((UmpMessage::System { group: 0, message: MidiMessage::TimingClock }, 1))));
+    /// assert_eq!(UmpMessage::decode(&[0x4090_3c00]), Some(Err((UmpError::Truncated(4), 1))));
+    /// assert_eq!(UmpMessage::decode(&[]), None);
+    /// ```
+    pub fn decode(words: &[u32]) -> Option<Result<(UmpMessage, usize), (UmpError, usize)>> {
+        let first = match words.first() {
+            Some(&first) => first,
+            None => return None,
+        };
+
+        let message_type = (first >> 28) as u8;
+        let len = word_count(message_type);
+        if words.len() < len {
+            return Some(Err((UmpError::Truncated(message_type), words.len())));
+        }
+
+        let words = &words[..len];
+        let group = ((first >> 24) & 0xf) as u8;
+        let result = match message_type {
+            UTILITY => decode_utility(first),
+            SYSTEM => decode_system(group, first),
+            MIDI1_CHANNEL_VOICE => decode_midi1_channel_voice(group, first),
+            DATA_64 => decode_sysex7(group, words),
+            MIDI2_CHANNEL_VOICE => decode_midi2_channel_voice(group, words),
+            DATA_128 => decode_data128(group, words),
+            FLEX_DATA => decode_flex_data(group, words),
+            _ => Err(UmpError::UnsupportedType(message_type)),
+        };
+
+        Some(match result {
+            Ok(message) => Ok((message, len)),
+            Err(err) => Err((err, len)),
+        })
+    }
+
+    /// Encode the message into UMP words.
+    ///
+    /// # Panics
+    ///
+    /// When a `System` or `Midi1ChannelVoice` message carries a `MidiMessage::SysEx`, which has to be
+    /// split with `from_midi1` instead, or when a `SysEx7` chunk has more than 6 bytes, or a `SysEx8` one more than 13.
+    pub fn to_words(&self) -> Vec<u32> {
+        let mut words = [0u32; 4];
+        let len = self.write_to_slice(&mut words);
+        words[..len].to_vec()
+    }
+
+    /// Append the UMP words for this message into `words`.
+    ///
+    /// # Panics
+    ///
+    /// For the same messages as `to_words`.
+    pub fn write_to(&self, words: &mut Vec<u32>) {
+        let mut buffer = [0u32; 4];
+        let len = self.write_to_slice(&mut buffer);
+        words.extend_from_slice(&buffer[..len]);
+    }
+
+    /// Get the number of words needed to encode this message.
+    ///
+    pub fn word_count(&self) -> usize {
+        word_count(self.message_type())
+    }
+
+    /// Get the message type, which is the first 4 bits of the UMP.
+    ///
+    pub fn message_type(&self) -> u8 {
+        match *self {
+            UmpMessage::Utility(_) => UTILITY,
+            UmpMessage::System { .. } => SYSTEM,
+            UmpMessage::Midi1ChannelVoice { .. } => MIDI1_CHANNEL_VOICE,
+            UmpMessage::SysEx7 { .. } => DATA_64,
+            UmpMessage::Midi2ChannelVoice { .. } => MIDI2_CHANNEL_VOICE,
+            UmpMessage::SysEx8 { .. } | UmpMessage::MixedDataSet { .. } => DATA_128,
+            UmpMessage::FlexData { .. } => FLEX_DATA,
+        }
+    }
+
+    /// Get the group of the message, or `None` for utility messages.
+    ///
+    pub fn group(&self) -> Option<u8> {
+        match *self {
+            UmpMessage::Utility(_) => None,
+            UmpMessage::System { group, .. } |
+            UmpMessage::Midi1ChannelVoice { group, .. } |
+            UmpMessage::SysEx7 { group, .. } |
+            UmpMessage::Midi2ChannelVoice { group, .. } |
+            UmpMessage::SysEx8 { group, .. } |
+            UmpMessage::MixedDataSet { group, .. } |
+            UmpMessage::FlexData { group, .. } => Some(group),
+        }
+    }
+
+    /// Split the payload of a System Exclusive message, without the 0xF0 and 0xF7 bytes,
+    /// into the `SysEx7` messages that carry it.
+    ///
+    /// ```
+    /// use coremidi::{ChunkStatus, UmpMessage};
+    /// let messages = UmpMessage::sysex7(0, &[1, 2, 3, 4, 5, 6, 7]);
+    /// assert_eq!(messages, vec![
+    ///     UmpMessage::SysEx7 { group: 0, status: ChunkStatus::Start, data: vec![1, 2, 3, 4, 5, 6] },
+    ///     UmpMessage::SysEx7 { group: 0, status: ChunkStatus::End, data: vec![7] },
+    /// ]);
+    /// ```
+    pub fn sysex7(group: u8, payload: &[u8]) -> Vec<UmpMessage> {
+        split_chunks(payload, SYSEX7_MAX_BYTES, |status, data| {
+            UmpMessage::SysEx7 { group, status, data: data.to_vec() }
+        })
+    }
+
+    /// Split the payload of an 8-bit System Exclusive message into the `SysEx8` messages that carry it.
+    ///
+    pub fn sysex8(group: u8, stream_id: u8, payload: &[u8]) -> Vec<UmpMessage> {
+        split_chunks(payload, SYSEX8_MAX_BYTES, |status, data| {
+            UmpMessage::SysEx8 { group, status, stream_id, data: data.to_vec() }
+        })
+    }
+
+    /// Convert a MIDI 1.0 message into the messages that carry it in the MIDI 1.0 protocol,
+    /// which is a single one except for SysEx messages longer than 6 bytes.
+    ///
+    /// ```
+    /// use coremidi::{MidiMessage, UmpMessage};
+    /// let messages = UmpMessage::from_midi1(2, &MidiMessage::NoteOn { channel: 0, note: 0x3c, velocity: 0x7f });
+    /// assert_eq!(messages[0].to_words(), vec![0x2290_3c7f]);
+    /// ```
+    pub fn from_midi1(group: u8, message: &MidiMessage) -> Vec<UmpMessage> {
+        match *message {
+            MidiMessage::SysEx(ref payload) => UmpMessage::sysex7(group, payload),
+            _ if message.channel().is_some() => vec![UmpMessage::Midi1ChannelVoice { group, message: message.clone() }],
+            _ => vec![UmpMessage::System { group, message: message.clone() }],
+        }
+    }
+
+    /// Append the MIDI 1.0 bytes carried by this message into `bytes`, which is the inverse of `from_midi1`.
+    ///
+    /// The chunks of a SysEx message only include the 0xF0 and 0xF7 bytes where the message starts and ends.
+    /// It returns false, without writing anything, for messages that can't be expressed in MIDI 1.0.
+    ///
+    pub fn write_midi1_bytes(&self, bytes: &mut Vec<u8>) -> bool {
+        match *self {
+            UmpMessage::System { ref message, .. } |
+            UmpMessage::Midi1ChannelVoice { ref message, .. } => message.write_to(bytes),
+            UmpMessage::SysEx7 { status, ref data, .. } => {
+                if status == ChunkStatus::Complete || status == ChunkStatus::Start {
+                    bytes.push(0xf0);
+                }
+                bytes.extend(data.iter().map(|b| b & 0x7f));
+                if status == ChunkStatus::Complete || status == ChunkStatus::End {
+                    bytes.push(0xf7);
+                }
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Write the UMP words for this message into `words`, without allocating.
+    /// It returns the number of words written, which is at most 4.
+    ///
+    /// Values out of range are masked, and it panics for the messages that can't be encoded, as documented in `to_words`.
+    pub(crate) fn write_to_slice(&self, words: &mut [u32; 4]) -> usize {
+        *words = [0; 4];
+        let header = |message_type: u8, group: u8| (u32::from(message_type) << 28) | (u32::from(group & 0xf) << 24);
+        match *self {
+            UmpMessage::Utility(message) => {
+                words[0] = match message {
+                    UtilityMessage::NoOp => 0,
+                    UtilityMessage::JitterReductionClock(time) => (0x1 << 20) | u32::from(time),
+                    UtilityMessage::JitterReductionTimestamp(time) => (0x2 << 20) | u32::from(time),
+                    UtilityMessage::DeltaClockstampTicksPerQuarterNote(ticks) => (0x3 << 20) | u32::from(ticks),
+                    UtilityMessage::DeltaClockstamp(ticks) => (0x4 << 20) | (ticks & 0xfffff),
+                };
+            },
+            UmpMessage::System { group, ref message } |
+            UmpMessage::Midi1ChannelVoice { group, ref message } => {
+                let mut bytes = [0u8; 3];
+                if let MidiMessage::SysEx(_) = *message {
+                    panic!("SysEx messages must be sent as SysEx7 messages");
+                }
+                message.write_to_slice(&mut bytes);
+                words[0] = header(self.message_type(), group) |
+                    (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+            },
+            UmpMessage::SysEx7 { group, status, ref data } => {
+                assert!(data.len() <= SYSEX7_MAX_BYTES, "SysEx7 messages carry up to 6 bytes");
+                let mut bytes = [0u8; 8];
+                bytes[0] = ((status.bits() << 4) as u8) | (data.len() as u8);
+                for (byte, &value) in bytes[2..].iter_mut().zip(data.iter()) {
+                    *byte = value & 0x7f;
+                }
+                words[0] = header(DATA_64, group) | (u32::from(bytes[0]) << 16) | (u32::from(bytes[2]) << 8) | u32::from(bytes[3]);
+                words[1] = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+            },
+            UmpMessage::Midi2ChannelVoice { group, message } => {
+                let (first, second) = encode_midi2_channel_voice(message);
+                words[0] = header(MIDI2_CHANNEL_VOICE, group) | first;
+                words[1] = second;
+            },
+            UmpMessage::SysEx8 { group, status, stream_id, ref data } => {
+                assert!(data.len() <= SYSEX8_MAX_BYTES, "SysEx8 messages carry up to 13 bytes");
+                let mut bytes = [0u8; 16];
+                bytes[1] = ((status.bits() << 4) as u8) | (data.len() as u8 + 1);
+                bytes[2] = stream_id;
+                bytes[3..3 + data.len()].copy_from_slice(data);
+                write_bytes(words, &bytes);
+                words[0] |= header(DATA_128, group);
+            },
+            UmpMessage::MixedDataSet { group, header: is_header, mds_id, ref data } => {
+                let mut bytes = [0u8; 16];
+                bytes[1] = (if is_header { 0x80 } else { 0x90 }) | (mds_id & 0xf);
+                bytes[2..].copy_from_slice(data);
+                write_bytes(words, &bytes);
+                words[0] |= header(DATA_128, group);
+            },
+            UmpMessage::FlexData { group, format, address, status_bank, status, data } => {
+                let (address, channel) = match address {
+                    FlexDataAddress::Channel(channel) => (0, channel & 0xf),
+                    FlexDataAddress::Group => (1, 0),
+                };
+                words[0] = header(FLEX_DATA, group) | (format.bits() << 22) | (address << 20) |
+                    (u32::from(channel) << 16) | (u32::from(status_bank) << 8) | u32::from(status);
+                words[1..].copy_from_slice(&data);
+            },
+        }
+        self.word_count()
+    }
+}
+
+/// The number of 32-bit words of a UMP, which is given by its message type.
+fn word_count(message_type: u8) -> usize {
+    match message_type {
+        0x0 | 0x1 | 0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8 | 0x9 | 0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+fn split_chunks<F>(payload: &[u8], chunk_size: usize, f: F) -> Vec<UmpMessage>
+    where F: Fn(ChunkStatus, &[u8]) -> UmpMessage
+{
+    if payload.is_empty() {
+        return vec![f(ChunkStatus::Complete, payload)];
+    }
+    let count = (payload.len() + chunk_size - 1) / chunk_size;
+    payload.chunks(chunk_size).enumerate()
+        .map(|(index, chunk)| f(ChunkStatus::for_chunk(index, count), chunk))
+        .collect()
+}
+
+fn read_bytes(words: &[u32]) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    for (chunk, word) in bytes.chunks_mut(4).zip(words.iter()) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+fn write_bytes(words: &mut [u32; 4], bytes: &[u8; 16]) {
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+}
+
+fn decode_utility(first: u32) -> Result<UmpMessage, UmpError> {
+    let message = match (first >> 20) & 0xf {
+        0x0 => UtilityMessage::NoOp,
+        0x1 => UtilityMessage::JitterReductionClock(first as u16),
+        0x2 => UtilityMessage::JitterReductionTimestamp(first as u16),
+        0x3 => UtilityMessage::DeltaClockstampTicksPerQuarterNote(first as u16),
+        0x4 => UtilityMessage::DeltaClockstamp(first & 0xfffff),
+        _ => return Err(UmpError::InvalidMessage(first)),
+    };
+    Ok(UmpMessage::Utility(message))
+}
+
+/// Decode the MIDI 1.0 message in the last three bytes of `first`.
+fn decode_midi1_bytes(first: u32) -> Option<MidiMessage> {
+    let bytes = [(first >> 16) as u8, (first >> 8) as u8 & 0x7f, first as u8 & 0x7f];
+    match MidiMessage::decode(&bytes) {
+        Some(Ok((MidiMessage::SysEx(_), _))) | None => None,
+        Some(Ok((message, _))) => Some(message),
+        Some(Err(_)) => None,
+    }
+}
+
+fn decode_system(group: u8, first: u32) -> Result<UmpMessage, UmpError> {
+    match decode_midi1_bytes(first) {
+        Some(ref message) if message.channel().is_some() => Err(UmpError::InvalidMessage(first)),
+        Some(message) => Ok(UmpMessage::System { group, message }),
+        None => Err(UmpError::InvalidMessage(first)),
+    }
+}
+
+fn decode_midi1_channel_voice(group: u8, first: u32) -> Result<UmpMessage, UmpError> {
+    match decode_midi1_bytes(first) {
+        Some(ref message) if message.channel().is_none() => Err(UmpError::InvalidMessage(first)),
+        Some(message) => Ok(UmpMessage::Midi1ChannelVoice { group, message }),
+        None => Err(UmpError::InvalidMessage(first)),
+    }
+}
+
+fn decode_sysex7(group: u8, words: &[u32]) -> Result<UmpMessage, UmpError> {
+    let first = words[0];
+    let status = (first >> 20) & 0xf;
+    let len = ((first >> 16) & 0xf) as usize;
+    if status > 3 || len > SYSEX7_MAX_BYTES {
+        return Err(UmpError::InvalidMessage(first));
+    }
+    let bytes = read_bytes(words);
+    let data = bytes[2..2 + len].iter().map(|b| b & 0x7f).collect();
+    Ok(UmpMessage::SysEx7 { group, status: ChunkStatus::from_bits(status), data })
+}
+
+fn decode_midi2_channel_voice(group: u8, words: &[u32]) -> Result<UmpMessage, UmpError> {
+    let (first, data) = (words[0], words[1]);
+    let channel = ((first >> 16) & 0xf) as u8;
+    let byte3 = (first >> 8) as u8;
+    let byte4 = first as u8;
+    let message = match (first >> 20) & 0xf {
+        0x0 => Midi2ChannelVoice::RegisteredPerNoteController { channel, note: byte3 & 0x7f, index: byte4, data },
+        0x1 => Midi2ChannelVoice::AssignablePerNoteController { channel, note: byte3 & 0x7f, index: byte4, data },
+        0x2 => Midi2ChannelVoice::RegisteredController { channel, bank: byte3 & 0x7f, index: byte4 & 0x7f, data },
+        0x3 => Midi2ChannelVoice::AssignableController { channel, bank: byte3 & 0x7f, index: byte4 & 0x7f, data },
+        0x4 => Midi2ChannelVoice::RelativeRegisteredController { channel, bank: byte3 & 0x7f, index: byte4 & 0x7f, data: data as i32 },
+        0x5 => Midi2ChannelVoice::RelativeAssignableController { channel, bank: byte3 & 0x7f, index: byte4 & 0x7f, data: data as i32 },
+        0x6 => Midi2ChannelVoice::PerNotePitchBend { channel, note: byte3 & 0x7f, data },
+        0x8 => Midi2ChannelVoice::NoteOff {
+            channel, note: byte3 & 0x7f, velocity: (data >> 16) as u16, attribute_type: byte4, attribute: data as u16,
+        },
+        0x9 => Midi2ChannelVoice::NoteOn {
+            channel, note: byte3 & 0x7f, velocity: (data >> 16) as u16, attribute_type: byte4, attribute: data as u16,
+        },
+        0xA => Midi2ChannelVoice::PolyPressure { channel, note: byte3 & 0x7f, data },
+        0xB => Midi2ChannelVoice::ControlChange { channel, index: byte3 & 0x7f, data },
+        0xC => Midi2ChannelVoice::ProgramChange {
+            channel,
+            program: (data >> 24) as u8 & 0x7f,
+            bank: if byte4 & 0x1 != 0 { Some(((data >> 8) as u8 & 0x7f, data as u8 & 0x7f)) } else { None },
+        },
+        0xD => Midi2ChannelVoice::ChannelPressure { channel, data },
+        0xE => Midi2ChannelVoice::PitchBend { channel, data },
+        0xF => Midi2ChannelVoice::PerNoteManagement {
+            channel, note: byte3 & 0x7f, detach: byte4 & 0x2 != 0, reset: byte4 & 0x1 != 0,
+        },
+        _ => return Err(UmpError::InvalidMessage(first)),
+    };
+    Ok(UmpMessage::Midi2ChannelVoice { group, message })
+}
+
+/// Encode a MIDI 2.0 Channel Voice message, returning the first word without the message type and group.
+fn encode_midi2_channel_voice(message: Midi2ChannelVoice) -> (u32, u32) {
+    let first = |status: u32, channel: u8, byte3: u8, byte4: u8| {
+        (status << 20) | (u32::from(channel & 0xf) << 16) | (u32::from(byte3) << 8) | u32::from(byte4)
+    };
+    match message {
+        Midi2ChannelVoice::RegisteredPerNoteController { channel, note, index, data } =>
+            (first(0x0, channel, note & 0x7f, index), data),
+        Midi2ChannelVoice::AssignablePerNoteController { channel, note, index, data } =>
+            (first(0x1, channel, note & 0x7f, index), data),
+        Midi2ChannelVoice::RegisteredController { channel, bank, index, data } =>
+            (first(0x2, channel, bank & 0x7f, index & 0x7f), data),
+        Midi2ChannelVoice::AssignableController { channel, bank, index, data } =>
+            (first(0x3, channel, bank & 0x7f, index & 0x7f), data),
+        Midi2ChannelVoice::RelativeRegisteredController { channel, bank, index, data } =>
+            (first(0x4, channel, bank & 0x7f, index & 0x7f), data as u32),
+        Midi2ChannelVoice::RelativeAssignableController { channel, bank, index, data } =>
+            (first(0x5, channel, bank & 0x7f, index & 0x7f), data as u32),
+        Midi2ChannelVoice::PerNotePitchBend { channel, note, data } =>
+            (first(0x6, channel, note & 0x7f, 0), data),
+        Midi2ChannelVoice::NoteOff { channel, note, velocity, attribute_type, attribute } =>
+            (first(0x8, channel, note & 0x7f, attribute_type), (u32::from(velocity) << 16) | u32::from(attribute)),
+        Midi2ChannelVoice::NoteOn { channel, note, velocity, attribute_type, attribute } =>
+            (first(0x9, channel, note & 0x7f, attribute_type), (u32::from(velocity) << 16) | u32::from(attribute)),
+        Midi2ChannelVoice::PolyPressure { channel, note, data } =>
+            (first(0xA, channel, note & 0x7f, 0), data),
+        Midi2ChannelVoice::ControlChange { channel, index, data } =>
+            (first(0xB, channel, index & 0x7f, 0), data),
+        Midi2ChannelVoice::ProgramChange { channel, program, bank } => {
+            let (flags, bank) = match bank {
+                Some((msb, lsb)) => (0x1, (u32::from(msb & 0x7f) << 8) | u32::from(lsb & 0x7f)),
+                None => (0x0, 0),
+            };
+            (first(0xC, channel, 0, flags), (u32::from(program & 0x7f) << 24) | bank)
+        },
+        Midi2ChannelVoice::ChannelPressure { channel, data } =>
+            (first(0xD, channel, 0, 0), data),
+        Midi2ChannelVoice::PitchBend { channel, data } =>
+            (first(0xE, channel, 0, 0), data),
+        Midi2ChannelVoice::PerNoteManagement { channel, note, detach, reset } => {
+            let flags = (if detach { 0x2 } else { 0 }) | (if reset { 0x1 } else { 0 });
+            (first(0xF, channel, note & 0x7f, flags), 0)
+        },
+    }
+}
+
+fn decode_data128(group: u8, words: &[u32]) -> Result<UmpMessage, UmpError> {
+    let first = words[0];
+    let status = (first >> 20) & 0xf;
+    let bytes = read_bytes(words);
+    match status {
+        0x0..=0x3 => {
+            // The number of bytes includes the stream id
+            let len = ((first >> 16) & 0xf) as usize;
+            if len == 0 || len > SYSEX8_MAX_BYTES + 1 {
+                return Err(UmpError::InvalidMessage(first));
+            }
+            Ok(UmpMessage::SysEx8 {
+                group,
+                status: ChunkStatus::from_bits(status),
+                stream_id: bytes[2],
+                data: bytes[3..2 + len].to_vec(),
+            })
+        },
+        0x8 | 0x9 => {
+            let mut data = [0u8; MIXED_DATA_SET_BYTES];
+            data.copy_from_slice(&bytes[2..]);
+            Ok(UmpMessage::MixedDataSet { group, header: status == 0x8, mds_id: ((first >> 16) & 0xf) as u8, data })
+        },
+        _ => Err(UmpError::InvalidMessage(first)),
+    }
+}
+
+fn decode_flex_data(group: u8, words: &[u32]) -> Result<UmpMessage, UmpError> {
+    let first = words[0];
+    let address = match (first >> 20) & 0x3 {
+        0 => FlexDataAddress::Channel(((first >> 16) & 0xf) as u8),
+        1 => FlexDataAddress::Group,
+        _ => return Err(UmpError::InvalidMessage(first)),
+    };
+    Ok(UmpMessage::FlexData {
+        group,
+        format: ChunkStatus::from_bits(first >> 22),
+        address,
+        status_bank: (first >> 8) as u8,
+        status: first as u8,
+        data: [words[1], words[2], words[3]],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use MidiMessage;
+    use super::{ChunkStatus, FlexDataAddress, Midi2ChannelVoice, UmpError, UmpMessage, UtilityMessage};
+
+    fn round_trip(words: &[u32], message: UmpMessage) {
+        assert_eq!(message.to_words(), words, "encoding {:?}", message);
+        assert_eq!(UmpMessage::decode(words), Some(Ok((message, words.len()))));
+    }
+
+    fn midi2(message: Midi2ChannelVoice) -> UmpMessage {
+        UmpMessage::Midi2ChannelVoice { group: 3, message }
+    }
+
+    #[test]
+    fn utility_messages() {
+        round_trip(&[0x0000_0000], UmpMessage::Utility(UtilityMessage::NoOp));
+        round_trip(&[0x0010_1234], UmpMessage::Utility(UtilityMessage::JitterReductionClock(0x1234)));
+        round_trip(&[0x0020_5678], UmpMessage::Utility(UtilityMessage::JitterReductionTimestamp(0x5678)));
+        round_trip(&[0x0030_0060], UmpMessage::Utility(UtilityMessage::DeltaClockstampTicksPerQuarterNote(96)));
+        round_trip(&[0x004a_bcde], UmpMessage::Utility(UtilityMessage::DeltaClockstamp(0xabcde)));
+        assert_eq!(UmpMessage::decode(&[0x0050_0000]), Some(Err((UmpError::InvalidMessage(0x0050_0000), 1))));
+    }
+
+    #[test]
+    fn system_messages() {
+        round_trip(&[0x15f8_0000], UmpMessage::System { group: 5, message: MidiMessage::TimingClock });
+        round_trip(&[0x10f2_1040], UmpMessage::System { group: 0, message: MidiMessage::SongPosition(0x2010) });
+        round_trip(&[0x10f1_2300], UmpMessage::System {
+            group: 0, message: MidiMessage::TimeCodeQuarterFrame { message_type: 2, value: 3 },
+        });
+        round_trip(&[0x10f3_0700], UmpMessage::System { group: 0, message: MidiMessage::SongSelect(7) });
+        round_trip(&[0x1fff_0000], UmpMessage::System { group: 15, message: MidiMessage::SystemReset });
+        for &invalid in &[0x10f0_0000, 0x10f4_0000, 0x10f7_0000, 0x1090_3c7f] {
+            assert_eq!(UmpMessage::decode(&[invalid]), Some(Err((UmpError::InvalidMessage(invalid), 1))));
+        }
+    }
+
+    #[test]
+    fn midi1_channel_voice_messages() {
+        round_trip(&[0x2191_407f], UmpMessage::Midi1ChannelVoice {
+            group: 1, message: MidiMessage::NoteOn { channel: 1, note: 0x40, velocity: 0x7f },
+        });
+        round_trip(&[0x2080_4000], UmpMessage::Midi1ChannelVoice {
+            group: 0, message: MidiMessage::NoteOff { channel: 0, note: 0x40, velocity: 0 },
+        });
+        round_trip(&[0x20a2_4010], UmpMessage::Midi1ChannelVoice {
+            group: 0, message: MidiMessage::PolyPressure { channel: 2, note: 0x40, pressure: 0x10 },
+        });
+        round_trip(&[0x20b3_0764], UmpMessage::Midi1ChannelVoice {
+            group: 0, message: MidiMessage::ControlChange { channel: 3, control: 7, value: 100 },
+        });
+        round_trip(&[0x20c4_0500], UmpMessage::Midi1ChannelVoice {
+            group: 0, message: MidiMessage::ProgramChange { channel: 4, program: 5 },
+        });
+        round_trip(&[0x20d5_3000], UmpMessage::Midi1ChannelVoice {
+            group: 0, message: MidiMessage::ChannelPressure { channel: 5, pressure: 0x30 },
+        });
+        round_trip(&[0x20e6_0040], UmpMessage::Midi1ChannelVoice {
+            group: 0, message: MidiMessage::PitchBend { channel: 6, value: 8192 },
+        });
+        assert_eq!(UmpMessage::decode(&[0x20f8_0000]), Some(Err((UmpError::InvalidMessage(0x20f8_0000), 1))));
+        assert_eq!(UmpMessage::decode(&[0x2000_0000]), Some(Err((UmpError::InvalidMessage(0x2000_0000), 1))));
+    }
+
+    #[test]
+    fn sysex7_messages() {
+        round_trip(&[0x3004_7e7f, 0x0601_0000], UmpMessage::SysEx7 {
+            group: 0, status: ChunkStatus::Complete, data: vec![0x7e, 0x7f, 0x06, 0x01],
+        });
+        round_trip(&[0x3216_0102, 0x0304_0506], UmpMessage::SysEx7 {
+            group: 2, status: ChunkStatus::Start, data: vec![1, 2, 3, 4, 5, 6],
+        });
+        round_trip(&[0x3022_0708, 0x0000_0000], UmpMessage::SysEx7 {
+            group: 0, status: ChunkStatus::Continue, data: vec![7, 8],
+        });
+        round_trip(&[0x3030_0000, 0x0000_0000], UmpMessage::SysEx7 {
+            group: 0, status: ChunkStatus::End, data: vec![],
+        });
+        assert_eq!(UmpMessage::decode(&[0x3007_0000, 0]), Some(Err((UmpError::InvalidMessage(0x3007_0000), 2))));
+        assert_eq!(UmpMessage::decode(&[0x3040_0000, 0]), Some(Err((UmpError::InvalidMessage(0x3040_0000), 2))));
+    }
+
+    #[test]
+    fn sysex7_split() {
+        assert_eq!(UmpMessage::sysex7(0, &[]), vec![
+            UmpMessage::SysEx7 { group: 0, status: ChunkStatus::Complete, data: vec![] },
+        ]);
+        let payload: Vec<u8> = (0..14).collect();
+        let messages = UmpMessage::sysex7(1, &payload);
+        let statuses: Vec<_> = messages.iter().map(|message| match *message {
+            UmpMessage::SysEx7 { status, ref data, .. } => (status, data.len()),
+            _ => panic!("unexpected message {:?}", message),
+        }).collect();
+        assert_eq!(statuses, vec![(ChunkStatus::Start, 6), (ChunkStatus::Continue, 6), (ChunkStatus::End, 2)]);
+    }
+
+    #[test]
+    fn midi2_controller_messages() {
+        round_trip(&[0x4300_3c07, 0x1234_5678], midi2(Midi2ChannelVoice::RegisteredPerNoteController {
+            channel: 0, note: 0x3c, index: 7, data: 0x1234_5678,
+        }));
+        round_trip(&[0x4311_3c80, 0x0000_0001], midi2(Midi2ChannelVoice::AssignablePerNoteController {
+            channel: 1, note: 0x3c, index: 0x80, data: 1,
+        }));
+        round_trip(&[0x4322_0102, 0x8000_0000], midi2(Midi2ChannelVoice::RegisteredController {
+            channel: 2, bank: 1, index: 2, data: 0x8000_0000,
+        }));
+        round_trip(&[0x4333_0304, 0xffff_ffff], midi2(Midi2ChannelVoice::AssignableController {
+            channel: 3, bank: 3, index: 4, data: 0xffff_ffff,
+        }));
+        round_trip(&[0x4344_0506, 0xffff_ffff], midi2(Midi2ChannelVoice::RelativeRegisteredController {
+            channel: 4, bank: 5, index: 6, data: -1,
+        }));
+        round_trip(&[0x4355_0708, 0x0000_0010], midi2(Midi2ChannelVoice::RelativeAssignableController {
+            channel: 5, bank: 7, index: 8, data: 16,
+        }));
+        round_trip(&[0x43b6_0700, 0xc000_0000], midi2(Midi2ChannelVoice::ControlChange {
+            channel: 6, index: 7, data: 0xc000_0000,
+        }));
+    }
+
+    #[test]
+    fn midi2_note_messages() {
+        round_trip(&[0x4390_3c00, 0xffff_0000], midi2(Midi2ChannelVoice::NoteOn {
+            channel: 0, note: 0x3c, velocity: 0xffff, attribute_type: 0, attribute: 0,
+        }));
+        round_trip(&[0x438f_4003, 0x8000_1234], midi2(Midi2ChannelVoice::NoteOff {
+            channel: 15, note: 0x40, velocity: 0x8000, attribute_type: 3, attribute: 0x1234,
+        }));
+        round_trip(&[0x43a1_3c00, 0x4000_0000], midi2(Midi2ChannelVoice::PolyPressure {
+            channel: 1, note: 0x3c, data: 0x4000_0000,
+        }));
+        round_trip(&[0x4362_3c00, 0x8000_0000], midi2(Midi2ChannelVoice::PerNotePitchBend {
+            channel: 2, note: 0x3c, data: 0x8000_0000,
+        }));
+        round_trip(&[0x43f3_3c03, 0x0000_0000], midi2(Midi2ChannelVoice::PerNoteManagement {
+            channel: 3, note: 0x3c, detach: true, reset: true,
+        }));
+        round_trip(&[0x43f3_3c02, 0x0000_0000], midi2(Midi2ChannelVoice::PerNoteManagement {
+            channel: 3, note: 0x3c, detach: true, reset: false,
+        }));
+    }
+
+    #[test]
+    fn midi2_channel_messages() {
+        round_trip(&[0x43c4_0001, 0x0500_0102], midi2(Midi2ChannelVoice::ProgramChange {
+            channel: 4, program: 5, bank: Some((1, 2)),
+        }));
+        round_trip(&[0x43c4_0000, 0x0500_0000], midi2(Midi2ChannelVoice::ProgramChange {
+            channel: 4, program: 5, bank: None,
+        }));
+        round_trip(&[0x43d5_0000, 0x1234_5678], midi2(Midi2ChannelVoice::ChannelPressure {
+            channel: 5, data: 0x1234_5678,
+        }));
+        round_trip(&[0x43e6_0000, 0x8000_0000], midi2(Midi2ChannelVoice::PitchBend {
+            channel: 6, data: 0x8000_0000,
+        }));
+        assert_eq!(UmpMessage::decode(&[0x4070_0000, 0]), Some(Err((UmpError::InvalidMessage(0x4070_0000), 2))));
+    }
+
+    #[test]
+    fn sysex8_and_mixed_data_set_messages() {
+        round_trip(&[0x500a_0701, 0x0203_0405, 0x0607_0809, 0x0000_0000], UmpMessage::SysEx8 {
+            group: 0, status: ChunkStatus::Complete, stream_id: 7, data: vec![1, 2, 3, 4, 5, 6, 7, 8, 9],
+        });
+        round_trip(&[0x511e_00ff, 0xfefd_fcfb, 0xfaf9_f8f7, 0xf6f5_f4f3], UmpMessage::SysEx8 {
+            group: 1, status: ChunkStatus::Start, stream_id: 0, data: (0xf3..=0xff).rev().collect(),
+        });
+        assert_eq!(UmpMessage::sysex8(0, 1, &[0; 20]).len(), 2);
+
+        let mut data = [0u8; 14];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        round_trip(&[0x5282_0102, 0x0304_0506, 0x0708_090a, 0x0b0c_0d0e], UmpMessage::MixedDataSet {
+            group: 2, header: true, mds_id: 2, data,
+        });
+        round_trip(&[0x5292_0102, 0x0304_0506, 0x0708_090a, 0x0b0c_0d0e], UmpMessage::MixedDataSet {
+            group: 2, header: false, mds_id: 2, data,
+        });
+
+        for &invalid in &[0x5000_0000, 0x500f_0000, 0x5040_0000] {
+            assert_eq!(UmpMessage::decode(&[invalid, 0, 0, 0]), Some(Err((UmpError::InvalidMessage(invalid), 4))));
+        }
+    }
+
+    #[test]
+    fn flex_data_messages() {
+        // Set Tempo to 500000 microseconds per quarter note, in units of 10 nanoseconds
+        round_trip(&[0xd010_0000, 50_000_000, 0, 0], UmpMessage::FlexData {
+            group: 0, format: ChunkStatus::Complete, address: FlexDataAddress::Group,
+            status_bank: 0, status: 0, data: [50_000_000, 0, 0],
+        });
+        round_trip(&[0xd143_0101, 0x4142_4344, 0x4546_4748, 0x494a_4b4c], UmpMessage::FlexData {
+            group: 1, format: ChunkStatus::Start, address: FlexDataAddress::Channel(3),
+            status_bank: 1, status: 1, data: [0x4142_4344, 0x4546_4748, 0x494a_4b4c],
+        });
+        assert_eq!(UmpMessage::decode(&[0xd020_0000, 0, 0, 0]), Some(Err((UmpError::InvalidMessage(0xd020_0000), 4))));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(UmpMessage::decode(&[]), None);
+        assert_eq!(UmpMessage::decode(&[0x4090_3c00]), Some(Err((UmpError::Truncated(0x4), 1))));
+        assert_eq!(UmpMessage::decode(&[0x5000_0000, 0, 0]), Some(Err((UmpError::Truncated(0x5), 3))));
+        assert_eq!(UmpMessage::decode(&[0x6000_0000, 0x2090_3c7f]), Some(Err((UmpError::UnsupportedType(0x6), 1))));
+        assert_eq!(UmpMessage::decode(&[0xb000_0000, 0, 0, 0]), Some(Err((UmpError::UnsupportedType(0xb), 3))));
+        assert_eq!(UmpMessage::decode(&[0xf000_0000, 0, 0, 0]), Some(Err((UmpError::UnsupportedType(0xf), 4))));
+    }
+
+    #[test]
+    fn word_counts_and_groups() {
+        let messages = vec![
+            (UmpMessage::Utility(UtilityMessage::NoOp), 1, None),
+            (UmpMessage::System { group: 1, message: MidiMessage::Start }, 1, Some(1)),
+            (UmpMessage::sysex7(2, &[1]).remove(0), 2, Some(2)),
+            (midi2(Midi2ChannelVoice::PitchBend { channel: 0, data: 0 }), 2, Some(3)),
+            (UmpMessage::sysex8(4, 0, &[1]).remove(0), 4, Some(4)),
+        ];
+        for (message, word_count, group) in messages {
+            assert_eq!(message.word_count(), word_count);
+            assert_eq!(message.to_words().len(), word_count);
+            assert_eq!(message.group(), group);
+        }
+    }
+
+    #[test]
+    fn midi1_bytes_round_trip() {
+        let messages = vec![
+            MidiMessage::NoteOn { channel: 1, note: 0x40, velocity: 0x7f },
+            MidiMessage::TimingClock,
+            MidiMessage::SysEx((0..10).collect()),
+        ];
+        let mut bytes = Vec::new();
+        for message in &messages {
+            for ump in UmpMessage::from_midi1(0, message) {
+                assert!(ump.write_midi1_bytes(&mut bytes));
+            }
+        }
+        let expected: Vec<u8> = messages.iter().flat_map(|message| message.to_bytes()).collect();
+        assert_eq!(bytes, expected);
+
+        let midi2_only = midi2(Midi2ChannelVoice::PitchBend { channel: 0, data: 0 });
+        assert!(!midi2_only.write_midi1_bytes(&mut bytes));
+        assert_eq!(bytes.len(), expected.len());
+    }
+
+    #[test]
+    fn encode_masks_out_of_range_values() {
+        let message = midi2(Midi2ChannelVoice::ControlChange { channel: 0x1f, index: 0xff, data: 0 });
+        assert_eq!(message.to_words(), vec![0x43bf_7f00, 0]);
+        let message = UmpMessage::SysEx7 { group: 0x10, status: ChunkStatus::Complete, data: vec![0xff] };
+        assert_eq!(message.to_words(), vec![0x3001_7f00, 0]);
+    }
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "serde")]
+
+extern crate coremidi;
+extern crate serde_json;
+
+use coremidi::{EventBuffer, EventList, PacketBuffer, PacketList, Protocol};
+
+fn events(event_list: &EventList) -> Vec<(u64, Vec<u32>)> {
+    event_list.iter().map(|packet| (packet.timestamp(), packet.data().to_vec())).collect()
+}
+
+#[test]
+fn packet_buffer_serde() {
+    let mut buffer = PacketBuffer::new(10, &[0xf0, 0x7d, 0xf7]);
+    buffer.push_data(10, &[0x90, 0x3c, 0x7f]);
+    buffer.push_data(30, &[0xf8]);
+
+    let json = serde_json::to_string(&buffer).unwrap();
+    assert_eq!(json, concat!(r#"[{"timestamp":10,"data":[240,125,247]},"#,
+                             r#"{"timestamp":10,"data":[144,60,127]},"#,
+                             r#"{"timestamp":30,"data":[248]}]"#));
+
+    let copy: PacketBuffer = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{}", &copy as &PacketList), format!("{}", &buffer as &PacketList));
+    assert!(serde_json::from_str::<PacketBuffer>(r#"[{"timestamp":0,"data":[256]}]"#).is_err());
+}
+
+#[test]
+fn event_buffer_serde() {
+    let mut buffer = EventBuffer::new(Protocol::Midi20);
+    buffer.push_data(1, &[0x4090_3c00, 0xffff_0000]);
+    buffer.push_data(2, &[0x10f8_0000]);
+
+    let json = serde_json::to_string(&buffer).unwrap();
+    assert_eq!(json, concat!(r#"{"protocol":"Midi20","packets":["#,
+                             r#"{"timestamp":1,"data":[1083194368,4294901760]},"#,
+                             r#"{"timestamp":2,"data":[284688384]}]}"#));
+
+    let copy: EventBuffer = serde_json::from_str(&json).unwrap();
+    assert_eq!(copy.protocol(), Protocol::Midi20);
+    assert_eq!(events(&copy), events(&buffer));
+
+    let too_long = format!(r#"{{"protocol":"Midi10","packets":[{{"timestamp":0,"data":{:?}}}]}}"#, vec![0; 65]);
+    assert!(serde_json::from_str::<EventBuffer>(&too_long).is_err());
+}
+
+#[cfg(not(target_os = "macos"))]
+#[test]
+fn setup_snapshot_serde() {
+    use coremidi::{Client, Setup, SetupSnapshot};
+
+    let client = Client::new("snapshot-json-client").unwrap();
+    let _source = client.virtual_source("snapshot-json-source").unwrap();
+
+    let snapshot = Setup::snapshot().unwrap();
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let loaded: SetupSnapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded, snapshot);
+    assert!(json.contains(r#""name":{"String":"snapshot-json-source"}"#));
+}
+
+#[cfg(not(target_os = "macos"))]
+#[test]
+fn objects_are_serialized_by_unique_id_and_name() {
+    use coremidi::{Client, Object};
+
+    let client = Client::new("object-json-client").unwrap();
+    let source = client.virtual_source("object-json-source").unwrap();
+    let object: &Object = &source;
+
+    let json = serde_json::to_string(object).unwrap();
+    assert_eq!(json, format!(r#"{{"unique_id":{},"name":"object-json-source"}}"#, object.unique_id().unwrap()));
+}